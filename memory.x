@@ -2,7 +2,9 @@ MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
   /* TODO Adjust these memory regions to match your device memory layout */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512K
+  FLASH : ORIGIN = 0x08000000, LENGTH = 508K
+  /* Runtime storage (see src/flash.rs). Not part of FLASH, so the linker never places code here. */
+  STORAGE : ORIGIN = 0x0807F000, LENGTH = 4K
   RAM : ORIGIN = 0x20000000, LENGTH = 64K
 }
 
@@ -0,0 +1,158 @@
+//! Accelerometer readings and free-fall detection for the Acc service.
+//!
+//! The acceleration characteristic holds the three axes as signed 16-bit little-endian values, in
+//! milli-g, in the order x, y, z. The free-fall characteristic holds a single byte, which is 1
+//! when a free fall has been detected.
+
+use core::cell::Cell;
+
+/// Acceleration along three axes, in milli-g.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Acceleration {
+    /// Acceleration along the x axis.
+    pub x: i16,
+
+    /// Acceleration along the y axis.
+    pub y: i16,
+
+    /// Acceleration along the z axis.
+    pub z: i16,
+}
+
+impl Acceleration {
+    /// Returns the square of the magnitude of the acceleration, in milli-g squared.
+    pub fn magnitude_squared(&self) -> u32 {
+        let square = |a: i16| {
+            let a = u32::from(a.wrapping_abs() as u16);
+            a * a
+        };
+        square(self.x) + square(self.y) + square(self.z)
+    }
+}
+
+/// Source of accelerometer readings.
+pub trait Accelerometer {
+    /// Returns the current acceleration, or `None` if it could not be read.
+    fn acceleration(&mut self) -> Option<Acceleration>;
+}
+
+/// Encodes an acceleration for the acceleration characteristic.
+pub fn encode_acceleration(acceleration: Acceleration) -> [u8; 6] {
+    [
+        acceleration.x as u8,
+        (acceleration.x >> 8) as u8,
+        acceleration.y as u8,
+        (acceleration.y >> 8) as u8,
+        acceleration.z as u8,
+        (acceleration.z >> 8) as u8,
+    ]
+}
+
+/// Value of the free-fall characteristic when a free fall is detected.
+pub const FREE_FALL_DETECTED: [u8; 1] = [1];
+
+/// Detects free falls from a series of accelerometer readings.
+///
+/// A free fall is detected when the magnitude of the acceleration stays below the threshold for at
+/// least the configured duration. Each free fall is reported once; the detector is re-armed as
+/// soon as the magnitude rises above the threshold again.
+#[derive(Copy, Clone, Debug)]
+pub struct FreeFallDetector {
+    threshold_mg: u16,
+    duration_ms: u32,
+    below_since_ms: Option<u32>,
+    reported: bool,
+}
+
+impl FreeFallDetector {
+    /// Creates a detector that reports a free fall when the magnitude of the acceleration stays
+    /// below `threshold_mg` milli-g for `duration_ms` milliseconds.
+    pub fn new(threshold_mg: u16, duration_ms: u32) -> FreeFallDetector {
+        FreeFallDetector {
+            threshold_mg: threshold_mg,
+            duration_ms: duration_ms,
+            below_since_ms: None,
+            reported: false,
+        }
+    }
+
+    /// Feeds a reading taken at `now_ms` to the detector. Returns true if a new free fall was
+    /// detected.
+    pub fn update(&mut self, acceleration: Acceleration, now_ms: u32) -> bool {
+        let threshold = u32::from(self.threshold_mg);
+        if acceleration.magnitude_squared() >= threshold * threshold {
+            self.below_since_ms = None;
+            self.reported = false;
+            return false;
+        }
+
+        let since = *self.below_since_ms.get_or_insert(now_ms);
+        if self.reported || now_ms.wrapping_sub(since) < self.duration_ms {
+            return false;
+        }
+        self.reported = true;
+        true
+    }
+
+    /// Forgets any free fall in progress.
+    pub fn reset(&mut self) {
+        self.below_since_ms = None;
+        self.reported = false;
+    }
+}
+
+impl Default for FreeFallDetector {
+    /// Returns a detector with a threshold of 350 mg and a duration of 100 ms.
+    fn default() -> FreeFallDetector {
+        FreeFallDetector::new(350, 100)
+    }
+}
+
+/// Accelerometer that produces plausible, deterministic readings, for boards without an
+/// accelerometer and for tests on the host.
+///
+/// At rest, the readings sway slightly around 1 g along the z axis. While
+/// [falling](SimulatedAccelerometer::set_falling), every axis reads 0.
+pub struct SimulatedAccelerometer {
+    position: Cell<i16>,
+    falling: Cell<bool>,
+}
+
+impl SimulatedAccelerometer {
+    /// Creates an accelerometer at rest.
+    pub fn new() -> SimulatedAccelerometer {
+        SimulatedAccelerometer {
+            position: Cell::new(0),
+            falling: Cell::new(false),
+        }
+    }
+
+    /// Starts or stops a simulated free fall.
+    pub fn set_falling(&self, falling: bool) {
+        self.falling.set(falling);
+    }
+}
+
+impl<'s> Accelerometer for &'s SimulatedAccelerometer {
+    fn acceleration(&mut self) -> Option<Acceleration> {
+        const HALF_PERIOD: i16 = 20;
+
+        if self.falling.get() {
+            return Some(Acceleration { x: 0, y: 0, z: 0 });
+        }
+
+        let position = self.position.get();
+        self.position.set((position + 1) % (2 * HALF_PERIOD));
+        let sway = if position < HALF_PERIOD {
+            position
+        } else {
+            2 * HALF_PERIOD - position
+        } - HALF_PERIOD / 2;
+
+        Some(Acceleration {
+            x: 5 * sway,
+            y: -3 * sway,
+            z: 1000 + sway,
+        })
+    }
+}
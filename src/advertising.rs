@@ -0,0 +1,416 @@
+//! Encoding and decoding of advertising and scan response data.
+//!
+//! Advertising and scan response data are each a sequence of AD structures of at most
+//! [`MAX_DATA_LEN`] bytes (Bluetooth Core Specification v4.1, Vol 3, Part C, Section 11). Each
+//! structure is a length byte, an AD type, and the data for that type. [`AdvertisingData`] places
+//! structures in the advertising data while they fit, and in the scan response after that.
+//! [`parse`] splits the data other devices advertise back into structures.
+
+/// Maximum length of advertising or scan response data, in bytes.
+pub const MAX_DATA_LEN: usize = 31;
+
+/// Length of the Flags structure the BlueNRG adds to the advertising data when it is made
+/// discoverable.
+pub const DISCOVERABLE_FLAGS_LEN: usize = 3;
+
+/// Flags advertised in the [Flags](AdStructure::Flags) structure.
+pub mod flags {
+    /// LE Limited Discoverable Mode.
+    pub const LE_LIMITED_DISCOVERABLE: u8 = 0x01;
+    /// LE General Discoverable Mode.
+    pub const LE_GENERAL_DISCOVERABLE: u8 = 0x02;
+    /// BR/EDR Not Supported.
+    pub const BR_EDR_NOT_SUPPORTED: u8 = 0x04;
+}
+
+/// AD types, from the Bluetooth assigned numbers.
+pub mod ad_type {
+    /// Flags.
+    pub const FLAGS: u8 = 0x01;
+    /// Incomplete List of 16-bit Service UUIDs.
+    pub const INCOMPLETE_UUID16_LIST: u8 = 0x02;
+    /// Complete List of 16-bit Service UUIDs.
+    pub const COMPLETE_UUID16_LIST: u8 = 0x03;
+    /// Incomplete List of 128-bit Service UUIDs.
+    pub const INCOMPLETE_UUID128_LIST: u8 = 0x06;
+    /// Complete List of 128-bit Service UUIDs.
+    pub const COMPLETE_UUID128_LIST: u8 = 0x07;
+    /// Shortened Local Name.
+    pub const SHORTENED_LOCAL_NAME: u8 = 0x08;
+    /// Complete Local Name.
+    pub const COMPLETE_LOCAL_NAME: u8 = 0x09;
+    /// Tx Power Level.
+    pub const TX_POWER_LEVEL: u8 = 0x0A;
+    /// Service Data - 16-bit UUID.
+    pub const SERVICE_DATA_UUID16: u8 = 0x16;
+    /// Appearance.
+    pub const APPEARANCE: u8 = 0x19;
+    /// Manufacturer Specific Data.
+    pub const MANUFACTURER_SPECIFIC_DATA: u8 = 0xFF;
+}
+
+/// Errors that can occur while building advertising data.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The structure does not fit in the remaining space.
+    TooLong,
+
+    /// The structure has no data.
+    Empty,
+}
+
+/// An AD structure.
+#[derive(Copy, Clone, Debug)]
+pub enum AdStructure<'a> {
+    /// Flags; see [`flags`].
+    Flags(u8),
+
+    /// Some of the 16-bit service UUIDs of the device.
+    IncompleteUuid16List(&'a [u16]),
+
+    /// All of the 16-bit service UUIDs of the device.
+    CompleteUuid16List(&'a [u16]),
+
+    /// Some of the 128-bit service UUIDs of the device, in little-endian order.
+    IncompleteUuid128List(&'a [[u8; 16]]),
+
+    /// All of the 128-bit service UUIDs of the device, in little-endian order.
+    CompleteUuid128List(&'a [[u8; 16]]),
+
+    /// Data defined by a company.
+    ManufacturerSpecificData {
+        /// Company identifier, from the Bluetooth assigned numbers.
+        company_id: u16,
+
+        /// Data defined by the company.
+        data: &'a [u8],
+    },
+
+    /// Data associated with a service with a 16-bit UUID.
+    ServiceData16 {
+        /// UUID of the service.
+        uuid: u16,
+
+        /// Data associated with the service.
+        data: &'a [u8],
+    },
+
+    /// External appearance of the device.
+    Appearance(u16),
+
+    /// Transmit power level, in dBm.
+    TxPowerLevel(i8),
+}
+
+impl<'a> AdStructure<'a> {
+    /// Returns the encoded length of the structure, including the length byte.
+    pub fn len(&self) -> usize {
+        2 + match self {
+            AdStructure::Flags(_) => 1,
+            AdStructure::IncompleteUuid16List(uuids) | AdStructure::CompleteUuid16List(uuids) => {
+                2 * uuids.len()
+            }
+            AdStructure::IncompleteUuid128List(uuids) | AdStructure::CompleteUuid128List(uuids) => {
+                16 * uuids.len()
+            }
+            AdStructure::ManufacturerSpecificData { data, .. }
+            | AdStructure::ServiceData16 { data, .. } => 2 + data.len(),
+            AdStructure::Appearance(_) => 2,
+            AdStructure::TxPowerLevel(_) => 1,
+        }
+    }
+
+    /// Returns true if the structure is a UUID list without any UUIDs.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            AdStructure::IncompleteUuid16List(uuids) | AdStructure::CompleteUuid16List(uuids) => {
+                uuids.is_empty()
+            }
+            AdStructure::IncompleteUuid128List(uuids) | AdStructure::CompleteUuid128List(uuids) => {
+                uuids.is_empty()
+            }
+            _ => false,
+        }
+    }
+
+    fn ad_type(&self) -> u8 {
+        match self {
+            AdStructure::Flags(_) => ad_type::FLAGS,
+            AdStructure::IncompleteUuid16List(_) => ad_type::INCOMPLETE_UUID16_LIST,
+            AdStructure::CompleteUuid16List(_) => ad_type::COMPLETE_UUID16_LIST,
+            AdStructure::IncompleteUuid128List(_) => ad_type::INCOMPLETE_UUID128_LIST,
+            AdStructure::CompleteUuid128List(_) => ad_type::COMPLETE_UUID128_LIST,
+            AdStructure::ManufacturerSpecificData { .. } => ad_type::MANUFACTURER_SPECIFIC_DATA,
+            AdStructure::ServiceData16 { .. } => ad_type::SERVICE_DATA_UUID16,
+            AdStructure::Appearance(_) => ad_type::APPEARANCE,
+            AdStructure::TxPowerLevel(_) => ad_type::TX_POWER_LEVEL,
+        }
+    }
+
+    /// Encodes the structure into `buffer`, which must be at least [`len`](AdStructure::len)
+    /// bytes long.
+    fn encode(&self, buffer: &mut [u8]) {
+        buffer[0] = (self.len() - 1) as u8;
+        buffer[1] = self.ad_type();
+        let data = &mut buffer[2..self.len()];
+        match self {
+            AdStructure::Flags(flags) => data[0] = *flags,
+            AdStructure::IncompleteUuid16List(uuids) | AdStructure::CompleteUuid16List(uuids) => {
+                for (chunk, uuid) in data.chunks_mut(2).zip(uuids.iter()) {
+                    chunk.copy_from_slice(&[*uuid as u8, (*uuid >> 8) as u8]);
+                }
+            }
+            AdStructure::IncompleteUuid128List(uuids) | AdStructure::CompleteUuid128List(uuids) => {
+                for (chunk, uuid) in data.chunks_mut(16).zip(uuids.iter()) {
+                    chunk.copy_from_slice(uuid);
+                }
+            }
+            AdStructure::ManufacturerSpecificData {
+                company_id: id,
+                data: value,
+            }
+            | AdStructure::ServiceData16 {
+                uuid: id,
+                data: value,
+            } => {
+                data[0] = *id as u8;
+                data[1] = (*id >> 8) as u8;
+                data[2..].copy_from_slice(value);
+            }
+            AdStructure::Appearance(appearance) => {
+                data.copy_from_slice(&[*appearance as u8, (*appearance >> 8) as u8])
+            }
+            AdStructure::TxPowerLevel(level) => data[0] = *level as u8,
+        }
+    }
+}
+
+/// A sequence of encoded AD structures of at most [`MAX_DATA_LEN`] bytes.
+#[derive(Copy, Clone)]
+pub struct Payload {
+    data: [u8; MAX_DATA_LEN],
+    len: usize,
+    capacity: usize,
+}
+
+impl Payload {
+    /// Returns an empty payload that may hold up to `capacity` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is greater than [`MAX_DATA_LEN`].
+    pub fn with_capacity(capacity: usize) -> Payload {
+        assert!(capacity <= MAX_DATA_LEN, "payload capacity is too large");
+        Payload {
+            data: [0; MAX_DATA_LEN],
+            len: 0,
+            capacity: capacity,
+        }
+    }
+
+    /// Appends a structure.
+    pub fn push(&mut self, structure: &AdStructure) -> Result<(), Error> {
+        if structure.is_empty() {
+            return Err(Error::Empty);
+        }
+        if structure.len() > self.remaining() {
+            return Err(Error::TooLong);
+        }
+        structure.encode(&mut self.data[self.len..]);
+        self.len += structure.len();
+
+        Ok(())
+    }
+
+    /// Returns the number of bytes that may still be added.
+    pub fn remaining(&self) -> usize {
+        self.capacity - self.len
+    }
+
+    /// Returns the encoded structures.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl core::fmt::Debug for Payload {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:?}", self.as_slice())
+    }
+}
+
+/// Advertising data and scan response data.
+#[derive(Copy, Clone, Debug)]
+pub struct AdvertisingData {
+    /// Structures sent in every advertising packet.
+    pub advertising: Payload,
+
+    /// Structures sent in response to scan requests.
+    pub scan_response: Payload,
+}
+
+impl AdvertisingData {
+    /// Returns empty advertising data. `reserved` bytes of the advertising data are left for the
+    /// controller to fill in.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `reserved` is greater than [`MAX_DATA_LEN`].
+    pub fn new(reserved: usize) -> AdvertisingData {
+        AdvertisingData {
+            advertising: Payload::with_capacity(MAX_DATA_LEN - reserved),
+            scan_response: Payload::with_capacity(MAX_DATA_LEN),
+        }
+    }
+
+    /// Returns empty advertising data that leaves room for the Flags and Complete Local Name
+    /// structures the BlueNRG adds when it is made discoverable with `local_name`.
+    pub fn for_discoverable(local_name: &[u8]) -> AdvertisingData {
+        AdvertisingData::new(core::cmp::min(
+            DISCOVERABLE_FLAGS_LEN + 2 + local_name.len(),
+            MAX_DATA_LEN,
+        ))
+    }
+
+    /// Adds a structure to the advertising data if it fits, and to the scan response otherwise.
+    pub fn push(&mut self, structure: AdStructure) -> Result<(), Error> {
+        match self.advertising.push(&structure) {
+            Err(Error::TooLong) => self.scan_response.push(&structure),
+            result => result,
+        }
+    }
+
+    /// Adds a structure to the scan response.
+    pub fn push_scan_response(&mut self, structure: AdStructure) -> Result<(), Error> {
+        self.scan_response.push(&structure)
+    }
+}
+
+/// An AD structure in received advertising or scan response data, with its data still encoded.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RawAdStructure<'a> {
+    /// AD type; see [`ad_type`].
+    pub ad_type: u8,
+
+    /// Data for that type.
+    pub data: &'a [u8],
+}
+
+/// Iterator over the AD structures in received data, returned by [`parse`].
+#[derive(Clone, Debug)]
+pub struct AdStructures<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for AdStructures<'a> {
+    type Item = RawAdStructure<'a>;
+
+    fn next(&mut self) -> Option<RawAdStructure<'a>> {
+        let len = *self.data.first()? as usize;
+        // A zero length starts the padding, and a structure that does not fit is malformed.
+        if len == 0 || len >= self.data.len() {
+            self.data = &[];
+            return None;
+        }
+
+        let structure = RawAdStructure {
+            ad_type: self.data[1],
+            data: &self.data[2..1 + len],
+        };
+        self.data = &self.data[1 + len..];
+        Some(structure)
+    }
+}
+
+/// Returns the AD structures in received advertising or scan response data. Decoding stops at
+/// the first zero length, or at the first structure that runs past the end of the data.
+pub fn parse(data: &[u8]) -> AdStructures {
+    AdStructures { data: data }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_structures() {
+        let mut payload = Payload::with_capacity(MAX_DATA_LEN);
+        payload
+            .push(&AdStructure::Flags(
+                flags::LE_GENERAL_DISCOVERABLE | flags::BR_EDR_NOT_SUPPORTED,
+            ))
+            .unwrap();
+        payload
+            .push(&AdStructure::CompleteUuid16List(&[0x180F, 0x181A]))
+            .unwrap();
+        payload.push(&AdStructure::Appearance(0x0540)).unwrap();
+        payload.push(&AdStructure::TxPowerLevel(-4)).unwrap();
+        assert_eq!(
+            payload.as_slice(),
+            [
+                0x02, 0x01, 0x06, 0x05, 0x03, 0x0F, 0x18, 0x1A, 0x18, 0x03, 0x19, 0x40, 0x05, 0x02,
+                0x0A, 0xFC
+            ]
+        );
+        assert_eq!(payload.remaining(), MAX_DATA_LEN - 16);
+    }
+
+    #[test]
+    fn rejects_structures_that_do_not_fit() {
+        let mut payload = Payload::with_capacity(4);
+        assert_eq!(
+            payload.push(&AdStructure::CompleteUuid16List(&[])),
+            Err(Error::Empty)
+        );
+        assert_eq!(payload.push(&AdStructure::Appearance(0x0540)), Ok(()));
+        assert_eq!(payload.push(&AdStructure::Flags(0)), Err(Error::TooLong));
+        assert_eq!(payload.as_slice().len(), 4);
+    }
+
+    #[test]
+    fn moves_to_the_scan_response_once_the_advertising_data_is_full() {
+        let mut data = AdvertisingData::for_discoverable(b"BlueNRG");
+        assert_eq!(data.advertising.remaining(), 19);
+        data.push(AdStructure::ServiceData16 {
+            uuid: 0x181A,
+            data: &[0; 13],
+        })
+        .unwrap();
+        data.push(AdStructure::TxPowerLevel(0)).unwrap();
+        assert_eq!(data.advertising.as_slice().len(), 17);
+        assert_eq!(data.scan_response.as_slice(), [0x02, 0x0A, 0x00]);
+
+        // A name too long to leave any room keeps every structure in the scan response.
+        let mut data = AdvertisingData::for_discoverable(&[b'a'; 40]);
+        data.push(AdStructure::Flags(0)).unwrap();
+        assert!(data.advertising.as_slice().is_empty());
+        assert_eq!(data.scan_response.as_slice(), [0x02, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn parses_received_structures() {
+        let data = [
+            0x02, 0x01, 0x06, 0x05, 0x09, b'T', b'e', b's', b't', 0x00, 0xFF,
+        ];
+        let mut structures = parse(&data);
+        assert_eq!(
+            structures.next(),
+            Some(RawAdStructure {
+                ad_type: ad_type::FLAGS,
+                data: &[0x06],
+            })
+        );
+        assert_eq!(
+            structures.next(),
+            Some(RawAdStructure {
+                ad_type: ad_type::COMPLETE_LOCAL_NAME,
+                data: b"Test",
+            })
+        );
+        // The zero length starts the padding.
+        assert_eq!(structures.next(), None);
+
+        // A structure that runs past the end of the data is dropped.
+        assert_eq!(parse(&[0x03, 0x19, 0x40]).count(), 0);
+    }
+}
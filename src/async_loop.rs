@@ -0,0 +1,672 @@
+//! An `async` version of the [event loop](crate::EventLoop).
+//!
+//! Initialization, advertising, and discovery are written as straight-line code: each step sends
+//! its command and awaits the reply, which [`command::correlate`] picks out of the events the
+//! controller sends. Events that do not answer the command are handled on the way, as in the
+//! event loop. Once the device advertises or discovers, the async event loop serves the events
+//! with the same reactions as the event loop, and runs the straight-line sequences again whenever
+//! advertising or discovery has to restart. It applies the same
+//! [recovery](crate::EventLoop::set_recovery_policy) and
+//! [timeout](crate::EventLoop::set_timeout_policy) policies:
+//!
+//! ```ignore
+//! static EXECUTOR: executor::Executor = executor::Executor::new();
+//!
+//! let mut event_loop = event_loop.into_async();
+//! EXECUTOR.block_on(async {
+//!     event_loop.reach(State::Complete).await?;
+//!     event_loop.reach(State::Connected).await?;
+//!     ...
+//! });
+//! ```
+//!
+//! The futures are executor-agnostic. While the controller has nothing to read, they register
+//! their task with the [`Transport`], which wakes it once there is, or periodically, so the
+//! timeouts and notifications are handled. The [event queue](crate::EventLoop::set_event_queue)
+//! is the transport unless [another one](AsyncEventLoop::set_transport) is set. Without a
+//! transport, the futures wake themselves every time they are polled, so any executor keeps
+//! polling them. The [`executor`](crate::executor) module provides a simple executor. On the
+//! host, the [simulator](crate::sim) stands in for the controller.
+
+use crate::command::{self, Reply};
+use crate::{check_status, log_event, queue, scanner, Error, EventLoop, State};
+use bluenrg::LocalVersionInfoExt;
+use core::fmt::Debug;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+type Event = hci::Event<bluenrg::event::BlueNRGEvent>;
+type ReturnParameters = hci::event::command::ReturnParameters<bluenrg::event::BlueNRGEvent>;
+type Status = hci::Status<bluenrg::event::Status>;
+
+/// Tells the async event loop when the controller has packets to read.
+pub trait Transport {
+    /// Returns true if the controller may have packets to read.
+    fn is_ready(&self) -> bool;
+
+    /// Wakes `waker` once, when the controller may have packets to read, or when the timers
+    /// should be checked. Replaces any waker registered before.
+    fn register_waker(&self, waker: &Waker);
+
+    /// Forgets the registered waker, if any.
+    fn clear_waker(&self);
+}
+
+impl Transport for queue::PacketQueue {
+    fn is_ready(&self) -> bool {
+        !self.is_idle()
+    }
+
+    fn register_waker(&self, waker: &Waker) {
+        queue::EventQueue::register_waker(self, waker);
+    }
+
+    fn clear_waker(&self) {
+        queue::EventQueue::clear_waker(self);
+    }
+}
+
+/// Drives the BlueNRG through initialization and then services its events, as a future.
+///
+/// Created with [`EventLoop::into_async`](crate::EventLoop::into_async), which keeps everything
+/// set on the event loop.
+pub struct AsyncEventLoop<'a, SPI: 'a, CS: 'a, RESET: 'a, DR: 'a, TIMER>
+where
+    TIMER: embedded_hal::timer::CountDown,
+{
+    event_loop: EventLoop<'a, SPI, CS, RESET, DR, TIMER>,
+    transport: Option<&'a dyn Transport>,
+}
+
+/// Why a sequence of the async event loop stopped before finishing.
+enum Stop<E> {
+    /// The recovery policy restarted initialization.
+    Restarted,
+
+    /// The recovery policy halted after this error.
+    Halted(Error<E>),
+}
+
+type Flow<T, E> = Result<T, Stop<E>>;
+
+/// What a [`NextEvent`] future found.
+enum Next {
+    /// The controller sent an event.
+    Event(Event),
+
+    /// The periodic work made this state due.
+    Due(State),
+}
+
+/// Waits for the next event from the controller. Every time it is woken without one, it also
+/// checks the deadline of `state` and runs the periodic work.
+struct NextEvent<'p, 'a: 'p, SPI: 'a, CS: 'a, RESET: 'a, DR: 'a, TIMER>
+where
+    TIMER: embedded_hal::timer::CountDown,
+{
+    event_loop: &'p mut EventLoop<'a, SPI, CS, RESET, DR, TIMER>,
+    transport: Option<&'a dyn Transport>,
+    state: State,
+    registered: bool,
+}
+
+impl<'p, 'a, SPI, CS, RESET, DR, TIMER, E> Future for NextEvent<'p, 'a, SPI, CS, RESET, DR, TIMER>
+where
+    SPI: embedded_hal::blocking::spi::Transfer<u8, Error = E>
+        + embedded_hal::blocking::spi::Write<u8, Error = E>,
+    CS: embedded_hal::digital::OutputPin,
+    RESET: embedded_hal::digital::OutputPin,
+    DR: embedded_hal::digital::InputPin,
+    TIMER: embedded_hal::timer::CountDown,
+    TIMER::Time: Copy,
+    E: Debug,
+{
+    type Output = Result<Next, Error<E>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let ps = &mut this.event_loop.data;
+        loop {
+            if this.transport.map_or(true, |t| t.is_ready()) {
+                let result = match ps.event_queue {
+                    Some(queue) => match ps.receive() {
+                        Ok(()) => queue.pop().ok_or(nb::Error::WouldBlock),
+                        Err(e) => return Poll::Ready(Err(e)),
+                    },
+                    None => ps.bnrg.with_spi(&mut ps.spi, |c| c.read()),
+                };
+                match result {
+                    Ok(hci::host::uart::Packet::Event(e)) => {
+                        if let Some(logger) = ps.logger.as_mut() {
+                            log_event(&mut **logger, &e);
+                        }
+                        return Poll::Ready(Ok(Next::Event(e)));
+                    }
+                    Err(nb::Error::WouldBlock) => (),
+                    Err(nb::Error::Other(e)) => return Poll::Ready(Err(Error::from(e))),
+                }
+            }
+
+            ps.tick();
+            if ps.deadline_passed() {
+                return Poll::Ready(Err(Error::Timeout));
+            }
+            if let Some(next) = ps.poll(this.state) {
+                return Poll::Ready(Ok(Next::Due(next)));
+            }
+
+            match this.transport {
+                Some(transport) => {
+                    transport.register_waker(cx.waker());
+                    this.registered = true;
+
+                    // The controller may have become ready before the waker was registered.
+                    if !transport.is_ready() {
+                        return Poll::Pending;
+                    }
+                }
+                None => {
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl<'p, 'a, SPI, CS, RESET, DR, TIMER> Drop for NextEvent<'p, 'a, SPI, CS, RESET, DR, TIMER>
+where
+    TIMER: embedded_hal::timer::CountDown,
+{
+    fn drop(&mut self) {
+        // The waker may not outlive the task, which the executor may drop once this completes.
+        match self.transport {
+            Some(transport) if self.registered => transport.clear_waker(),
+            _ => (),
+        }
+    }
+}
+
+impl<'a, SPI, CS, RESET, DR, TIMER, E> AsyncEventLoop<'a, SPI, CS, RESET, DR, TIMER>
+where
+    SPI: embedded_hal::blocking::spi::Transfer<u8, Error = E>
+        + embedded_hal::blocking::spi::Write<u8, Error = E>,
+    CS: embedded_hal::digital::OutputPin,
+    RESET: embedded_hal::digital::OutputPin,
+    DR: embedded_hal::digital::InputPin,
+    TIMER: embedded_hal::timer::CountDown,
+    TIMER::Time: Copy,
+    E: Debug,
+{
+    pub(crate) fn new(
+        event_loop: EventLoop<'a, SPI, CS, RESET, DR, TIMER>,
+    ) -> AsyncEventLoop<'a, SPI, CS, RESET, DR, TIMER> {
+        let transport = event_loop
+            .data
+            .event_queue
+            .map(|queue| queue as &dyn Transport);
+        AsyncEventLoop {
+            event_loop: event_loop,
+            transport: transport,
+        }
+    }
+
+    /// Sets what wakes the async event loop once the controller has packets to read. Packets are
+    /// still read through the [event queue](crate::EventLoop::set_event_queue) if there is one,
+    /// and straight from the controller otherwise.
+    pub fn set_transport(&mut self, transport: &'a dyn Transport) {
+        self.transport = Some(transport);
+    }
+
+    /// Returns the state the event loop is in.
+    pub fn state(&self) -> State {
+        self.event_loop.state()
+    }
+
+    /// Returns the event loop, to inspect it or change its settings between steps.
+    pub fn event_loop(&mut self) -> &mut EventLoop<'a, SPI, CS, RESET, DR, TIMER> {
+        &mut self.event_loop
+    }
+
+    /// Runs the event loop until the recovery policy decides to halt, and returns the error that
+    /// caused it. [`state`](AsyncEventLoop::state) returns the state that failed.
+    pub async fn run(&mut self) -> Error<E> {
+        loop {
+            if let Err(e) = self.step().await {
+                return e;
+            }
+        }
+    }
+
+    /// Steps the event loop until it enters `state`. Returns right away if it is already there.
+    ///
+    /// The state is checked between steps, so the states that initialization, advertising, and
+    /// discovery pass through cannot be reached. Errors are handled according to the recovery
+    /// policy, so the event loop may restart on the way. An error is only returned if the policy
+    /// decides to halt.
+    pub async fn reach(&mut self, state: State) -> Result<(), Error<E>> {
+        while self.event_loop.state() != state {
+            self.step().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Initializes the controller and starts advertising or discovering, restarts advertising or
+    /// discovery, or handles the next event, depending on the state the event loop is in.
+    ///
+    /// An event loop turned async in the middle of initialization or advertising starts
+    /// initialization over.
+    pub async fn step(&mut self) -> Result<(), Error<E>> {
+        let result = match self.event_loop.state {
+            State::GettingVersionInfo => self.start().await,
+            State::ConfiguringWhiteList
+            | State::GeneratingPrivateAddress
+            | State::SetDiscoverable
+            | State::StartingDiscovery => {
+                let state = self.event_loop.state;
+                self.restart_advertising(state).await
+            }
+            State::Complete
+            | State::StoppingAdvertising
+            | State::Connected
+            | State::Disconnected
+            | State::UpdatingCharacteristic
+            | State::AllowingRead
+            | State::AnsweringSecurityRequest
+            | State::UpdatingBondedDevices
+            | State::Discovering => self.serve().await,
+            _ => {
+                self.event_loop.restart();
+                self.start().await
+            }
+        };
+        match result {
+            Ok(()) | Err(Stop::Restarted) => Ok(()),
+            Err(Stop::Halted(e)) => Err(e),
+        }
+    }
+
+    /// Initializes the controller, then starts advertising or discovering, depending on the
+    /// role.
+    async fn start(&mut self) -> Flow<(), E> {
+        self.initialize().await?;
+        match self.event_loop.data.role {
+            scanner::Role::Peripheral => self.advertise(State::ConfiguringWhiteList).await,
+            scanner::Role::Observer => {
+                let state = self.event_loop.data.start_discovery();
+                self.discover(state).await
+            }
+        }
+    }
+
+    /// Runs the sequence that starts advertising or discovery again from `state`.
+    async fn restart_advertising(&mut self, state: State) -> Flow<(), E> {
+        match self.event_loop.data.role {
+            scanner::Role::Peripheral => self.advertise(state).await,
+            scanner::Role::Observer => self.discover(state).await,
+        }
+    }
+
+    async fn initialize(&mut self) -> Flow<(), E> {
+        use bluenrg::event::command::ReturnParameters as Vendor;
+
+        let version = self
+            .command(State::GettingVersionInfo, |r| match r {
+                ReturnParameters::ReadLocalVersionInformation(v) => Some((v.status, v)),
+                _ => None,
+            })
+            .await?;
+        self.event_loop.data.fw_version = Some(version.bluenrg_version());
+
+        self.reset().await?;
+
+        self.command(State::SettingAddress, |r| match r {
+            ReturnParameters::Vendor(Vendor::HalWriteConfigData(s))
+            | ReturnParameters::LeSetRandomAddress(s) => Some((s, ())),
+            _ => None,
+        })
+        .await?;
+
+        let mut state = self.event_loop.data.identity_address_set();
+        while let State::GeneratingIdentityRoot(index) = state {
+            let random = self
+                .command(state, |r| match r {
+                    ReturnParameters::LeRand(p) => Some((p.status, p.random_number)),
+                    _ => None,
+                })
+                .await?;
+            state = self.event_loop.data.identity_root_generated(index, random);
+        }
+        if state == State::WritingIdentityRoot {
+            self.command(State::WritingIdentityRoot, |r| match r {
+                ReturnParameters::Vendor(Vendor::HalWriteConfigData(s)) => Some((s, ())),
+                _ => None,
+            })
+            .await?;
+            let irk = self
+                .command(State::DerivingIdentityKey, |r| match r {
+                    ReturnParameters::LeEncrypt(p) => Some((p.status, p.encrypted_data.0)),
+                    _ => None,
+                })
+                .await?;
+            self.event_loop.data.privacy.as_mut().unwrap().set_irk(irk);
+        }
+
+        self.command(State::InitGatt, |r| match r {
+            ReturnParameters::Vendor(Vendor::GattInit(s)) => Some((s, ())),
+            _ => None,
+        })
+        .await?;
+
+        let gap = self
+            .command(State::InitGap, |r| match r {
+                ReturnParameters::Vendor(Vendor::GapInit(p)) => Some((p.status, p)),
+                _ => None,
+            })
+            .await?;
+        let ps = &mut self.event_loop.data;
+        ps.gap_service_handle = Some(gap.service_handle);
+        ps.dev_name_handle = Some(gap.dev_name_handle);
+        ps.appearance_handle = Some(gap.appearance_handle);
+        if ps.role == scanner::Role::Observer {
+            // An observer has no GATT server to set up.
+            return Ok(());
+        }
+
+        for &state in &[State::SetDeviceName, State::SetAppearance] {
+            self.command(state, |r| match r {
+                ReturnParameters::Vendor(Vendor::GattUpdateCharacteristicValue(s)) => Some((s, ())),
+                _ => None,
+            })
+            .await?;
+        }
+
+        self.command(State::SetAuthenticationRequirement, |r| match r {
+            ReturnParameters::Vendor(Vendor::GapSetAuthenticationRequirement(s)) => Some((s, ())),
+            _ => None,
+        })
+        .await?;
+
+        self.command(State::SetIoCapability, |r| match r {
+            ReturnParameters::Vendor(Vendor::GapSetIoCapability(s)) => Some((s, ())),
+            _ => None,
+        })
+        .await?;
+
+        let bonded = self
+            .command(State::GettingBondedDevices, |r| match r {
+                ReturnParameters::Vendor(Vendor::GapGetBondedDevices(p)) => Some((p.status, p)),
+                _ => None,
+            })
+            .await?;
+        self.event_loop
+            .data
+            .bonded_devices_read(bonded.bonded_addresses());
+
+        for index in 0..self.event_loop.data.database.len() {
+            let handle = self
+                .command(State::AddAttribute(index), |r| match r {
+                    ReturnParameters::Vendor(Vendor::GattAddService(p)) => {
+                        Some((p.status, p.service_handle.0))
+                    }
+                    ReturnParameters::Vendor(Vendor::GattAddCharacteristic(p)) => {
+                        Some((p.status, p.characteristic_handle.0))
+                    }
+                    ReturnParameters::Vendor(Vendor::GattAddCharacteristicDescriptor(p)) => {
+                        Some((p.status, p.descriptor_handle.0))
+                    }
+                    _ => None,
+                })
+                .await?;
+            self.event_loop.data.database.set_handle(index, handle);
+        }
+
+        self.command(State::SetTxPowerLevel, |r| match r {
+            ReturnParameters::Vendor(Vendor::HalSetTxPowerLevel(s)) => Some((s, ())),
+            _ => None,
+        })
+        .await?;
+
+        self.command(State::SetScanResponse, |r| match r {
+            ReturnParameters::LeSetScanResponseData(s) => Some((s, ())),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Resets the controller, and waits until it has started again.
+    async fn reset(&mut self) -> Flow<(), E> {
+        use bluenrg::event::BlueNRGEvent;
+
+        loop {
+            self.enter(State::Resetting)?;
+            loop {
+                let event = match self.next_event(State::Resetting).await {
+                    Ok(Next::Event(e)) => e,
+                    Ok(Next::Due(_)) => continue,
+                    Err(e) => {
+                        self.recover(e)?;
+                        break;
+                    }
+                };
+                if let hci::Event::Vendor(BlueNRGEvent::HalInitialized(_)) = event {
+                    return Ok(());
+                }
+                if let Err(e) = self.dispatch(State::Resetting, event) {
+                    self.recover(e)?;
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Starts advertising from `state`: configures the white list, sets a new private address
+    /// if one is due, and sets the controller discoverable or connectable.
+    async fn advertise(&mut self, mut state: State) -> Flow<(), E> {
+        use bluenrg::event::command::ReturnParameters as Vendor;
+
+        if state == State::ConfiguringWhiteList {
+            self.command(State::ConfiguringWhiteList, |r| match r {
+                ReturnParameters::Vendor(Vendor::GapConfigureWhiteList(s)) => Some((s, ())),
+                _ => None,
+            })
+            .await?;
+            state = self.event_loop.data.start_advertising();
+        }
+        if state == State::GeneratingPrivateAddress {
+            self.set_private_address().await?;
+        }
+
+        self.command(State::SetDiscoverable, |r| match r {
+            ReturnParameters::Vendor(Vendor::GapSetDiscoverable(s))
+            | ReturnParameters::Vendor(Vendor::GapSetUndirectedConnectable(s))
+            | ReturnParameters::Vendor(Vendor::GapSetDirectConnectable(s)) => Some((s, ())),
+            _ => None,
+        })
+        .await?;
+        self.enter(State::Complete)
+    }
+
+    /// Starts discovering nearby devices from `state`, after setting a new private address if
+    /// one is due.
+    async fn discover(&mut self, state: State) -> Flow<(), E> {
+        if state == State::GeneratingPrivateAddress {
+            self.set_private_address().await?;
+        }
+
+        // The procedure runs in the background, and reports that it ended with an event.
+        loop {
+            self.enter(State::StartingDiscovery)?;
+            match self.reply(State::StartingDiscovery).await {
+                Ok(None) => break,
+                Ok(Some(_)) => self.recover(Error::UnexpectedEvent)?,
+                Err(e) => self.recover(e)?,
+            }
+        }
+        self.enter(State::Discovering)
+    }
+
+    /// Generates a new resolvable private address, and sets it as the random address.
+    async fn set_private_address(&mut self) -> Flow<(), E> {
+        let random = self
+            .command(State::GeneratingPrivateAddress, |r| match r {
+                ReturnParameters::LeRand(p) => Some((p.status, p.random_number)),
+                _ => None,
+            })
+            .await?;
+        self.event_loop
+            .data
+            .privacy
+            .as_mut()
+            .unwrap()
+            .prand_generated(random);
+
+        let hash = self
+            .command(State::HashingPrivateAddress, |r| match r {
+                ReturnParameters::LeEncrypt(p) => Some((p.status, p.encrypted_data.0)),
+                _ => None,
+            })
+            .await?;
+        self.event_loop
+            .data
+            .privacy
+            .as_mut()
+            .unwrap()
+            .prand_hashed(&hash);
+
+        self.command(State::SettingPrivateAddress, |r| match r {
+            ReturnParameters::LeSetRandomAddress(s) => Some((s, ())),
+            _ => None,
+        })
+        .await?;
+        self.event_loop.data.private_address_set();
+
+        Ok(())
+    }
+
+    /// Handles the next event, or the periodic work that became due, while advertising,
+    /// connected, or discovering. Leaves the states that restart advertising or discovery for the
+    /// next step.
+    async fn serve(&mut self) -> Flow<(), E> {
+        let state = self.event_loop.state;
+        if self.event_loop.action_pending {
+            self.enter(state)?;
+        }
+
+        let result = match self.next_event(state).await {
+            Ok(Next::Event(e)) => state.react_to_event(&mut self.event_loop.data, e),
+            Ok(Next::Due(next)) => Ok(next),
+            Err(e) => Err(e),
+        };
+        let next = match result {
+            Ok(next) => next,
+            Err(e) => return self.recover(e),
+        };
+        match next {
+            _ if next == state => Ok(()),
+            State::ConfiguringWhiteList
+            | State::GeneratingPrivateAddress
+            | State::SetDiscoverable
+            | State::StartingDiscovery => {
+                self.event_loop.state = next;
+                self.event_loop.action_pending = true;
+                self.event_loop.retries = 0;
+                Ok(())
+            }
+            _ => self.enter(next),
+        }
+    }
+
+    /// Sends the command for `state`, and waits for its reply. `extract` returns the status and
+    /// the result of the command from its return parameters. The command is sent again if it
+    /// fails and the recovery policy retries it.
+    async fn command<T, F>(&mut self, state: State, extract: F) -> Flow<T, E>
+    where
+        F: Fn(ReturnParameters) -> Option<(Status, T)>,
+    {
+        loop {
+            self.enter(state)?;
+            let error = match self.reply(state).await {
+                Ok(Some(params)) => match extract(params) {
+                    Some((status, value)) => match check_status(status) {
+                        Ok(()) => return Ok(value),
+                        Err(e) => e,
+                    },
+                    None => Error::UnexpectedEvent,
+                },
+                Ok(None) => Error::UnexpectedEvent,
+                Err(e) => e,
+            };
+            self.recover(error)?;
+        }
+    }
+
+    /// Waits for the reply to the command sent in `state`, and handles the events that do not
+    /// answer it. Returns the return parameters of a Command Complete event, or `None` once a
+    /// Command Status event reports that the controller started the command.
+    async fn reply(&mut self, state: State) -> Result<Option<ReturnParameters>, Error<E>> {
+        let opcode = state.opcode(&self.event_loop.data);
+        loop {
+            let event = match self.next_event(state).await? {
+                Next::Event(e) => e,
+                Next::Due(_) => continue,
+            };
+            match command::correlate(opcode, event)? {
+                Reply::Complete(params) => return Ok(Some(params)),
+                Reply::Accepted => return Ok(None),
+                Reply::Unrelated(event) => self.dispatch(state, event)?,
+            }
+        }
+    }
+
+    /// Handles an event that arrived while waiting in `state` for a reply or for the controller
+    /// to start.
+    fn dispatch(&mut self, state: State, event: Event) -> Result<(), Error<E>> {
+        // Only the reply decides what comes next; the event just updates the program state.
+        state.dispatch(&mut self.event_loop.data, event)?;
+
+        Ok(())
+    }
+
+    /// Enters `state`: sends its command, and starts its deadline. The command is sent again if
+    /// sending fails and the recovery policy retries it.
+    fn enter(&mut self, state: State) -> Flow<(), E> {
+        if state != self.event_loop.state {
+            self.event_loop.state = state;
+            self.event_loop.retries = 0;
+            if let State::Complete | State::Connected | State::Discovering = state {
+                // Initialization succeeded, so later failures get every restart again.
+                self.event_loop.restarts = 0;
+            }
+        }
+
+        while let Err(e) = state.act(&mut self.event_loop.data) {
+            self.recover(e)?;
+        }
+        self.event_loop.action_pending = false;
+        self.event_loop.data.start_deadline(state);
+
+        Ok(())
+    }
+
+    /// Applies the recovery policy to `error`. Returns if the policy retries the current state.
+    fn recover(&mut self, error: Error<E>) -> Flow<(), E> {
+        let state = self.event_loop.state;
+        match self.event_loop.recover(error) {
+            Ok(()) if self.event_loop.state == state => Ok(()),
+            Ok(()) => Err(Stop::Restarted),
+            Err(e) => Err(Stop::Halted(e)),
+        }
+    }
+
+    fn next_event(&mut self, state: State) -> NextEvent<'_, 'a, SPI, CS, RESET, DR, TIMER> {
+        NextEvent {
+            event_loop: &mut self.event_loop,
+            transport: self.transport,
+            state: state,
+            registered: false,
+        }
+    }
+}
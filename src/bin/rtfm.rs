@@ -0,0 +1,434 @@
+//! The firmware as an RTFM application.
+//!
+//! Instead of one loop that does everything with interrupts disabled, the work is split into
+//! tasks that RTFM schedules by priority:
+//!
+//! - `EXTI0` records that the BlueNRG raised its data ready line, and spawns `handle_events`.
+//! - `TIM7` runs every 10 ms, and spawns `handle_events` to drive notifications, blink patterns,
+//!   and timeouts, and `sample_sensors` every 100 ms.
+//! - `sample_sensors` reads the accelerometer.
+//! - `USART3_EXTI28` applies the commands clients write to the LED characteristic, and advances
+//!   blink patterns. The event loop pends it through the `LedMailbox` it drives as its LED.
+//! - `answer_read` answers read permit requests, such as those of the Time characteristic, by
+//!   writing the fresh value and allowing the read.
+//! - `handle_events` steps the event loop through everything else until it is idle, at the
+//!   lowest priority, and hands read requests over to `answer_read`.
+//!
+//! `handle_events` and `answer_read` share the event loop, and with it the `BlueNRG` driver, as
+//! an RTFM resource. The interrupts stay short and never wait for it. Build with
+//! `--features rtfm`.
+
+#![no_main]
+#![no_std]
+
+extern crate bluenrg;
+extern crate bluetooth_hci as hci;
+extern crate cortex_m;
+extern crate cortex_m_rt;
+extern crate cortex_m_rtfm as rtfm;
+extern crate embedded_hal;
+extern crate nb;
+// Links the panic handler. Without a debugger, semihosting would fault.
+#[cfg(not(feature = "semihosting"))]
+extern crate panic_halt;
+#[cfg(feature = "semihosting")]
+extern crate panic_semihosting;
+extern crate spbtle_rf_stm32f303re_test;
+extern crate stm32f30x;
+extern crate stm32f30x_hal as hal;
+
+use core::cell::Cell;
+use cortex_m::interrupt::Mutex;
+use embedded_hal::timer::CountDown;
+use hal::flash::FlashExt;
+use hal::gpio::gpioa::{PA0, PA1, PA5, PA6, PA7, PA8};
+use hal::gpio::gpiob::PB3;
+use hal::gpio::GpioExt;
+use hal::gpio::{Input, Output, PullDown, PushPull, AF5};
+use hal::rcc::RccExt;
+use hal::time::U32Ext;
+use main::accelerometer::{Acceleration, Accelerometer};
+use main::led::LedControl;
+use rtfm::app;
+use spbtle_rf_stm32f303re_test as main;
+
+type Spi = hal::spi::Spi<stm32f30x::SPI1, (PB3<AF5>, PA6<AF5>, PA7<AF5>)>;
+type ChipSelect = PA1<Output<PushPull>>;
+type ResetPin = PA8<Output<PushPull>>;
+type DataReady = PA0<Input<PullDown>>;
+type BlueNRG = bluenrg::BlueNRG<'static, Spi, ChipSelect, ResetPin, DataReady>;
+type EventLoop = main::EventLoop<
+    'static,
+    Spi,
+    ChipSelect,
+    ResetPin,
+    DataReady,
+    hal::timer::Timer<stm32f30x::TIM6>,
+>;
+
+/// Everything the event loop is made of.
+struct Hardware {
+    bnrg: &'static mut BlueNRG,
+    timer: hal::timer::Timer<stm32f30x::TIM6>,
+    spi: Spi,
+    config: main::config::DeviceConfig,
+    pin_mode: Option<main::security::PinMode>,
+    environmental_sensor: &'static mut main::sensors::SimulatedEnvironmentalSensor,
+    led: &'static mut &'static LedMailbox,
+    clock: &'static mut main::time::SysTickClock,
+    accelerometer: &'static mut &'static SampledAccelerometer,
+    logger: &'static mut main::logger::ItmLogger,
+    store: Option<&'static mut main::storage::Store<main::flash::InternalFlash>>,
+}
+
+impl Hardware {
+    fn into_event_loop(self) -> EventLoop {
+        let mut event_loop =
+            main::EventLoop::new(self.bnrg, self.timer, 200.hz(), self.spi, self.config);
+        event_loop.set_environmental_sensor(self.environmental_sensor);
+        event_loop.set_led(self.led);
+        event_loop.set_clock(self.clock);
+        event_loop.set_accelerometer(self.accelerometer);
+        event_loop.set_logger(self.logger);
+        if let Some(pin_mode) = self.pin_mode {
+            event_loop.set_pin_mode(pin_mode);
+        }
+        if let Some(store) = self.store {
+            event_loop.set_bond_store(store);
+        }
+        event_loop.set_timeout_period(Some(100.hz()));
+        event_loop.set_event_queue(&EVENT_QUEUE);
+
+        event_loop
+    }
+}
+
+/// The event loop, as an RTFM resource shared by `handle_events` and `answer_read`.
+struct SharedEventLoop {
+    event_loop: EventLoop,
+    halted: bool,
+}
+
+// The event loop is made of the parts of `Hardware`, which is `Send`, but holds the sensors, LED
+// mailbox, clock, and logger as trait objects, which forget it.
+unsafe impl Send for SharedEventLoop {}
+
+fn assert_send<T: Send>() {}
+
+/// What a call to `SharedEventLoop::step` left to do.
+#[derive(Copy, Clone, PartialEq)]
+enum Progress {
+    /// The event loop has more to do.
+    Busy,
+
+    /// The event loop waits for the next interrupt.
+    Idle,
+
+    /// The other task has to step the event loop.
+    HandOver,
+
+    /// The event loop halted, and is never stepped again.
+    Halted,
+}
+
+impl SharedEventLoop {
+    fn new(hardware: Hardware) -> SharedEventLoop {
+        assert_send::<Hardware>();
+        SharedEventLoop {
+            event_loop: hardware.into_event_loop(),
+            halted: false,
+        }
+    }
+
+    /// Steps the event loop once, if it is answering a read exactly when `reading` is true.
+    fn step(&mut self, reading: bool) -> Progress {
+        if self.halted {
+            return Progress::Halted;
+        }
+        if self.event_loop.is_answering_read() != reading {
+            return Progress::HandOver;
+        }
+
+        // The event loop logs the error that halted it.
+        if self.event_loop.step().is_err() {
+            self.halted = true;
+            return Progress::Halted;
+        }
+        if self.event_loop.is_idle() {
+            Progress::Idle
+        } else {
+            Progress::Busy
+        }
+    }
+}
+
+/// The latest LED command and clock tick from the event loop, left for `USART3_EXTI28`, which
+/// runs as soon as the step that left them ends.
+struct LedMailbox {
+    command: Mutex<Cell<Option<main::led::Command>>>,
+    now_ms: Mutex<Cell<Option<u32>>>,
+}
+
+impl LedMailbox {
+    const fn new() -> LedMailbox {
+        LedMailbox {
+            command: Mutex::new(Cell::new(None)),
+            now_ms: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    /// Removes the command and the time of the tick left in the mailbox.
+    fn take(&self) -> (Option<main::led::Command>, Option<u32>) {
+        cortex_m::interrupt::free(|cs| {
+            (
+                self.command.borrow(cs).take(),
+                self.now_ms.borrow(cs).take(),
+            )
+        })
+    }
+}
+
+impl<'m> LedControl for &'m LedMailbox {
+    fn apply(&mut self, command: main::led::Command) {
+        cortex_m::interrupt::free(|cs| self.command.borrow(cs).set(Some(command)));
+        rtfm::pend(stm32f30x::Interrupt::USART3_EXTI28);
+    }
+
+    fn tick(&mut self, now_ms: u32) {
+        cortex_m::interrupt::free(|cs| self.now_ms.borrow(cs).set(Some(now_ms)));
+        rtfm::pend(stm32f30x::Interrupt::USART3_EXTI28);
+    }
+}
+
+/// Latest accelerometer reading, written by `sample_sensors` and read by the event loop.
+struct SampledAccelerometer {
+    latest: Mutex<Cell<Option<Acceleration>>>,
+}
+
+impl SampledAccelerometer {
+    const fn new() -> SampledAccelerometer {
+        SampledAccelerometer {
+            latest: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    fn store(&self, acceleration: Option<Acceleration>) {
+        cortex_m::interrupt::free(|cs| self.latest.borrow(cs).set(acceleration));
+    }
+}
+
+impl<'s> Accelerometer for &'s SampledAccelerometer {
+    fn acceleration(&mut self) -> Option<Acceleration> {
+        cortex_m::interrupt::free(|cs| self.latest.borrow(cs).get())
+    }
+}
+
+/// Packets received from the BlueNRG, filled after the data ready interrupt fires.
+static EVENT_QUEUE: main::queue::PacketQueue = main::queue::EventQueue::new();
+
+static SAMPLED_ACCELEROMETER: SampledAccelerometer = SampledAccelerometer::new();
+
+static LED_MAILBOX: LedMailbox = LedMailbox::new();
+
+/// Number of `TIM7` periods between accelerometer samples.
+const SAMPLE_PERIODS: u32 = 10;
+
+#[app(device = stm32f30x)]
+const APP: () = {
+    static mut EVENT_LOOP: SharedEventLoop = ();
+    static mut LED: main::led::Led<PA5<Output<PushPull>>> = ();
+    static mut WAKE_TIMER: hal::timer::Timer<stm32f30x::TIM7> = ();
+    static mut ACCELEROMETER: main::accelerometer::SimulatedAccelerometer = ();
+
+    #[init]
+    fn init() -> init::LateResources {
+        static mut RX_BUFFER: [u8; 128] = [0; 128];
+        static mut BNRG: Option<BlueNRG> = None;
+        static mut ENVIRONMENTAL_SENSOR: Option<main::sensors::SimulatedEnvironmentalSensor> = None;
+        static mut LED_MAILBOX_WRITER: &'static LedMailbox = &LED_MAILBOX;
+        static mut CLOCK: Option<main::time::SysTickClock> = None;
+        static mut LOGGER: Option<main::logger::ItmLogger> = None;
+        static mut STORE: Option<main::storage::Store<main::flash::InternalFlash>> = None;
+        static mut ACCELEROMETER_READER: &'static SampledAccelerometer = &SAMPLED_ACCELEROMETER;
+
+        let mut rcc = device.RCC.constrain();
+        let mut gpioa = device.GPIOA.split(&mut rcc.ahb);
+        let mut gpiob = device.GPIOB.split(&mut rcc.ahb);
+        let sck = gpiob.pb3.into_af5(&mut gpiob.moder, &mut gpiob.afrl);
+        let miso = gpioa.pa6.into_af5(&mut gpioa.moder, &mut gpioa.afrl);
+        let mosi = gpioa.pa7.into_af5(&mut gpioa.moder, &mut gpioa.afrl);
+        let clocks = rcc.cfgr.freeze(&mut device.FLASH.constrain().acr);
+        let spi = hal::spi::Spi::spi1(
+            device.SPI1,
+            (sck, miso, mosi),
+            embedded_hal::spi::Mode {
+                polarity: embedded_hal::spi::Polarity::IdleLow,
+                phase: embedded_hal::spi::Phase::CaptureOnFirstTransition,
+            },
+            1.mhz(),
+            clocks,
+            &mut rcc.apb2,
+        );
+
+        let data_ready = gpioa
+            .pa0
+            .into_pull_down_input(&mut gpioa.moder, &mut gpioa.pupdr);
+        let chip_select = gpioa
+            .pa1
+            .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper);
+        let reset_pin = gpioa
+            .pa8
+            .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper);
+        let mut tim6 = hal::timer::Timer::tim6(device.TIM6, 200.hz(), clocks, &mut rcc.apb1);
+
+        // Interrupt on the rising edge of the data ready line. PA0 is EXTI0's default source.
+        // RTFM enables the interrupt itself.
+        device.EXTI.imr1.modify(|_, w| w.mr0().set_bit());
+        device.EXTI.rtsr1.modify(|_, w| w.tr0().set_bit());
+
+        let mut wake_timer = hal::timer::Timer::tim7(device.TIM7, 100.hz(), clocks, &mut rcc.apb1);
+        wake_timer.listen(hal::timer::Event::TimeOut);
+
+        *BNRG = Some(bluenrg::BlueNRG::new(
+            RX_BUFFER,
+            chip_select,
+            data_ready,
+            reset_pin,
+        ));
+        let bnrg = BNRG.as_mut().unwrap();
+        bnrg.reset(&mut tim6, 200.hz());
+
+        *ENVIRONMENTAL_SENSOR = Some(main::sensors::SimulatedEnvironmentalSensor::new());
+        let led = main::led::Led::new(
+            gpioa
+                .pa5
+                .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper),
+        );
+        // SysTick runs from HCLK / 8.
+        *CLOCK = Some(main::time::SysTickClock::new(
+            core.SYST,
+            clocks.hclk().0 / 8_000,
+        ));
+        // Messages are dropped until a debug probe enables tracing on stimulus port 0.
+        *LOGGER = Some(main::logger::ItmLogger::new(core.ITM, 0));
+
+        let unique_id = unsafe { main::config::unique_id() };
+        let mut config = main::config::DeviceConfig::default();
+        config.address = main::config::Address::from_unique_id(&unique_id);
+        let mut pin_mode = None;
+        *STORE = main::storage::Store::open(unsafe { main::flash::InternalFlash::new() }).ok();
+        if let Some(store) = STORE.as_mut() {
+            config = config.load(store);
+            let _ = config.save(store);
+            pin_mode = Some(main::security::PinMode::PerBoot(main::security::boot_seed(
+                store, &unique_id,
+            )));
+        }
+
+        // The data ready line may have gone high before the interrupt was enabled.
+        rtfm::pend(stm32f30x::Interrupt::EXTI0);
+
+        init::LateResources {
+            EVENT_LOOP: SharedEventLoop::new(Hardware {
+                bnrg: bnrg,
+                timer: tim6,
+                spi: spi,
+                config: config,
+                pin_mode: pin_mode,
+                environmental_sensor: ENVIRONMENTAL_SENSOR.as_mut().unwrap(),
+                led: LED_MAILBOX_WRITER,
+                clock: CLOCK.as_mut().unwrap(),
+                accelerometer: ACCELEROMETER_READER,
+                logger: LOGGER.as_mut().unwrap(),
+                store: STORE.as_mut(),
+            }),
+            LED: led,
+            WAKE_TIMER: wake_timer,
+            ACCELEROMETER: main::accelerometer::SimulatedAccelerometer::new(),
+        }
+    }
+
+    #[interrupt(priority = 3, spawn = [handle_events])]
+    fn EXTI0() {
+        // Clear the pending bit, or the handler runs again as soon as it returns.
+        unsafe { (*stm32f30x::EXTI::ptr()).pr1.write(|w| w.pr0().set_bit()) };
+        EVENT_QUEUE.data_ready();
+        // If a run is already spawned, it reads the data.
+        let _ = spawn.handle_events();
+    }
+
+    // Above the ceiling of the event loop, so the period is never stretched by a step.
+    #[interrupt(priority = 3, resources = [WAKE_TIMER], spawn = [handle_events, sample_sensors])]
+    fn TIM7() {
+        static mut PERIODS: u32 = 0;
+
+        // Clears the update flag.
+        let _ = resources.WAKE_TIMER.wait();
+        let _ = spawn.handle_events();
+
+        *PERIODS += 1;
+        if *PERIODS == SAMPLE_PERIODS {
+            *PERIODS = 0;
+            // If the last sample is still being taken, skip this one.
+            let _ = spawn.sample_sensors();
+        }
+    }
+
+    #[task(priority = 2, resources = [ACCELEROMETER])]
+    fn sample_sensors() {
+        let mut accelerometer: &main::accelerometer::SimulatedAccelerometer =
+            resources.ACCELEROMETER;
+        SAMPLED_ACCELEROMETER.store(accelerometer.acceleration());
+    }
+
+    /// Applies the LED commands written by clients, and advances blink patterns.
+    #[interrupt(priority = 2, resources = [LED])]
+    fn USART3_EXTI28() {
+        let (command, now_ms) = LED_MAILBOX.take();
+        if let Some(command) = command {
+            resources.LED.apply(command);
+        }
+        if let Some(now_ms) = now_ms {
+            resources.LED.tick(now_ms);
+        }
+    }
+
+    /// Answers a client's read request, from the read permit request until the read is allowed.
+    #[task(priority = 2, resources = [EVENT_LOOP], spawn = [handle_events])]
+    fn answer_read() {
+        loop {
+            match resources.EVENT_LOOP.step(true) {
+                Progress::Busy => (),
+                Progress::HandOver => {
+                    // Events may have arrived during the read.
+                    let _ = spawn.handle_events();
+                    return;
+                }
+                Progress::Idle | Progress::Halted => return,
+            }
+        }
+    }
+
+    /// Handles everything the event loop has to do, except answering reads.
+    #[task(priority = 1, resources = [EVENT_LOOP], spawn = [answer_read])]
+    fn handle_events() {
+        loop {
+            // The lock is released between steps, so the LED and the sensors are served in
+            // between.
+            match resources.EVENT_LOOP.lock(|shared| shared.step(false)) {
+                Progress::Busy => (),
+                Progress::HandOver => {
+                    let _ = spawn.answer_read();
+                    return;
+                }
+                Progress::Idle | Progress::Halted => return,
+            }
+        }
+    }
+
+    // Dispatch the software tasks, one interrupt per priority.
+    extern "C" {
+        fn USART1_EXTI25();
+        fn USART2_EXTI26();
+    }
+};
@@ -0,0 +1,150 @@
+//! Correlation of the events the controller sends with the command the event loop is waiting
+//! for.
+//!
+//! The controller answers every command with a Command Complete event, or with a Command Status
+//! event if the command finishes later. Both identify the command by its opcode. [`correlate`]
+//! sorts each event into an answer to the pending command or an unrelated event, which the event
+//! loop then dispatches on its own, so no event is dropped while a command is in flight.
+
+use crate::{check_status, Error};
+
+type Event = hci::Event<bluenrg::event::BlueNRGEvent>;
+type ReturnParameters = hci::event::command::ReturnParameters<bluenrg::event::BlueNRGEvent>;
+
+/// Opcodes of the commands the event loop sends.
+pub mod opcode {
+    /// HCI Disconnect.
+    pub const DISCONNECT: u16 = 0x0406;
+    /// HCI Read Local Version Information.
+    pub const READ_LOCAL_VERSION_INFORMATION: u16 = 0x1001;
+    /// HCI LE Set Random Address.
+    pub const LE_SET_RANDOM_ADDRESS: u16 = 0x2005;
+    /// HCI LE Set Scan Response Data.
+    pub const LE_SET_SCAN_RESPONSE_DATA: u16 = 0x2009;
+    /// HCI LE Encrypt.
+    pub const LE_ENCRYPT: u16 = 0x2017;
+    /// HCI LE Rand.
+    pub const LE_RAND: u16 = 0x2018;
+    /// BlueNRG HAL Write Config Data.
+    pub const HAL_WRITE_CONFIG_DATA: u16 = 0xFC0C;
+    /// BlueNRG HAL Set Tx Power Level.
+    pub const HAL_SET_TX_POWER_LEVEL: u16 = 0xFC0F;
+    /// BlueNRG GAP Set Non-Discoverable.
+    pub const GAP_SET_NONDISCOVERABLE: u16 = 0xFC81;
+    /// BlueNRG GAP Set Discoverable.
+    pub const GAP_SET_DISCOVERABLE: u16 = 0xFC83;
+    /// BlueNRG GAP Set Direct Connectable.
+    pub const GAP_SET_DIRECT_CONNECTABLE: u16 = 0xFC84;
+    /// BlueNRG GAP Set I/O Capability.
+    pub const GAP_SET_IO_CAPABILITY: u16 = 0xFC85;
+    /// BlueNRG GAP Set Authentication Requirement.
+    pub const GAP_SET_AUTHENTICATION_REQUIREMENT: u16 = 0xFC86;
+    /// BlueNRG GAP Pass Key Response.
+    pub const GAP_PASS_KEY_RESPONSE: u16 = 0xFC88;
+    /// BlueNRG GAP Authorization Response.
+    pub const GAP_AUTHORIZATION_RESPONSE: u16 = 0xFC89;
+    /// BlueNRG GAP Init.
+    pub const GAP_INIT: u16 = 0xFC8A;
+    /// BlueNRG GAP Set Undirected Connectable.
+    pub const GAP_SET_UNDIRECTED_CONNECTABLE: u16 = 0xFC8C;
+    /// BlueNRG GAP Configure White List.
+    pub const GAP_CONFIGURE_WHITE_LIST: u16 = 0xFC92;
+    /// BlueNRG GAP Allow Rebond.
+    pub const GAP_ALLOW_REBOND: u16 = 0xFC95;
+    /// BlueNRG GAP Start General Discovery Procedure.
+    pub const GAP_START_GENERAL_DISCOVERY_PROCEDURE: u16 = 0xFC97;
+    /// BlueNRG GAP Get Bonded Devices.
+    pub const GAP_GET_BONDED_DEVICES: u16 = 0xFCA3;
+    /// BlueNRG GATT Init.
+    pub const GATT_INIT: u16 = 0xFD01;
+    /// BlueNRG GATT Add Service.
+    pub const GATT_ADD_SERVICE: u16 = 0xFD02;
+    /// BlueNRG GATT Add Characteristic.
+    pub const GATT_ADD_CHARACTERISTIC: u16 = 0xFD04;
+    /// BlueNRG GATT Add Characteristic Descriptor.
+    pub const GATT_ADD_CHARACTERISTIC_DESCRIPTOR: u16 = 0xFD05;
+    /// BlueNRG GATT Update Characteristic Value.
+    pub const GATT_UPDATE_CHARACTERISTIC_VALUE: u16 = 0xFD06;
+    /// BlueNRG GATT Allow Read.
+    pub const GATT_ALLOW_READ: u16 = 0xFD27;
+}
+
+/// Returns the opcode of the command that returned `params`, if it is one the event loop sends.
+pub fn return_opcode(params: &ReturnParameters) -> Option<u16> {
+    use bluenrg::event::command::ReturnParameters as Vendor;
+
+    match params {
+        ReturnParameters::ReadLocalVersionInformation(_) => {
+            Some(opcode::READ_LOCAL_VERSION_INFORMATION)
+        }
+        ReturnParameters::LeSetRandomAddress(_) => Some(opcode::LE_SET_RANDOM_ADDRESS),
+        ReturnParameters::LeSetScanResponseData(_) => Some(opcode::LE_SET_SCAN_RESPONSE_DATA),
+        ReturnParameters::LeEncrypt(_) => Some(opcode::LE_ENCRYPT),
+        ReturnParameters::LeRand(_) => Some(opcode::LE_RAND),
+        ReturnParameters::Vendor(v) => match v {
+            Vendor::HalWriteConfigData(_) => Some(opcode::HAL_WRITE_CONFIG_DATA),
+            Vendor::HalSetTxPowerLevel(_) => Some(opcode::HAL_SET_TX_POWER_LEVEL),
+            Vendor::GapSetNonDiscoverable(_) => Some(opcode::GAP_SET_NONDISCOVERABLE),
+            Vendor::GapSetDiscoverable(_) => Some(opcode::GAP_SET_DISCOVERABLE),
+            Vendor::GapSetDirectConnectable(_) => Some(opcode::GAP_SET_DIRECT_CONNECTABLE),
+            Vendor::GapSetUndirectedConnectable(_) => Some(opcode::GAP_SET_UNDIRECTED_CONNECTABLE),
+            Vendor::GapConfigureWhiteList(_) => Some(opcode::GAP_CONFIGURE_WHITE_LIST),
+            Vendor::GapSetAuthenticationRequirement(_) => {
+                Some(opcode::GAP_SET_AUTHENTICATION_REQUIREMENT)
+            }
+            Vendor::GapSetIoCapability(_) => Some(opcode::GAP_SET_IO_CAPABILITY),
+            Vendor::GapPassKeyResponse(_) => Some(opcode::GAP_PASS_KEY_RESPONSE),
+            Vendor::GapAuthorizationResponse(_) => Some(opcode::GAP_AUTHORIZATION_RESPONSE),
+            Vendor::GapInit(_) => Some(opcode::GAP_INIT),
+            Vendor::GapAllowRebond(_) => Some(opcode::GAP_ALLOW_REBOND),
+            Vendor::GapGetBondedDevices(_) => Some(opcode::GAP_GET_BONDED_DEVICES),
+            Vendor::GattInit(_) => Some(opcode::GATT_INIT),
+            Vendor::GattAddService(_) => Some(opcode::GATT_ADD_SERVICE),
+            Vendor::GattAddCharacteristic(_) => Some(opcode::GATT_ADD_CHARACTERISTIC),
+            Vendor::GattAddCharacteristicDescriptor(_) => {
+                Some(opcode::GATT_ADD_CHARACTERISTIC_DESCRIPTOR)
+            }
+            Vendor::GattUpdateCharacteristicValue(_) => {
+                Some(opcode::GATT_UPDATE_CHARACTERISTIC_VALUE)
+            }
+            Vendor::GattAllowRead(_) => Some(opcode::GATT_ALLOW_READ),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// An event, sorted by [`correlate`].
+pub enum Reply {
+    /// The pending command completed, and returned these parameters.
+    Complete(ReturnParameters),
+
+    /// The controller started the pending command, and reports its result with a later event.
+    Accepted,
+
+    /// The event does not answer the pending command.
+    Unrelated(Event),
+}
+
+/// Sorts `event` by whether it answers the command with the `pending` opcode, if any.
+///
+/// A Command Status event with an error for the pending command is an [`Error::CommandFailed`].
+/// Command Complete and Command Status events for any other command are unrelated, as are those
+/// that only return flow control credits: a reply may arrive after its command timed out, or
+/// when nothing is pending at all.
+pub fn correlate<E>(pending: Option<u16>, event: Event) -> Result<Reply, Error<E>> {
+    match event {
+        hci::Event::CommandStatus(ref status) if pending == Some(status.opcode.0) => {
+            check_status(status.status)?;
+            Ok(Reply::Accepted)
+        }
+        hci::Event::CommandComplete(cmd) => {
+            if pending.is_some() && return_opcode(&cmd.return_params) == pending {
+                Ok(Reply::Complete(cmd.return_params))
+            } else {
+                Ok(Reply::Unrelated(hci::Event::CommandComplete(cmd)))
+            }
+        }
+        event => Ok(Reply::Unrelated(event)),
+    }
+}
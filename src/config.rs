@@ -0,0 +1,270 @@
+//! Identity and radio settings of the device.
+
+use crate::flash::Flash;
+use crate::storage::{self, key, Store};
+use core::time::Duration;
+
+/// Maximum length of the device name, in bytes.
+pub const MAX_NAME_LEN: usize = 20;
+
+/// Address of the STM32F3 96-bit unique device ID.
+pub const UNIQUE_ID_ADDRESS: usize = 0x1FFF_F7AC;
+
+/// Bluetooth device address the device uses.
+#[derive(Copy, Clone, Debug)]
+pub enum Address {
+    /// A public address, written to the controller's configuration data.
+    Public(hci::BdAddr),
+
+    /// A static random address, set with LE Set Random Address. The two most significant bits
+    /// must be set.
+    StaticRandom(hci::BdAddr),
+}
+
+impl Address {
+    /// Returns a static random address derived from a 96-bit unique device ID, so every board
+    /// gets a different, stable address.
+    pub fn from_unique_id(unique_id: &[u8; 12]) -> Address {
+        let mut address = [0; 6];
+        for (i, byte) in address.iter_mut().enumerate() {
+            *byte = unique_id[i] ^ unique_id[i + 6];
+        }
+
+        // A static random address must not be all zeros or all ones in its random part.
+        if address[..5].iter().all(|&b| b == 0) && address[5] & 0x3F == 0 {
+            address[0] = 0x01;
+        } else if address[..5].iter().all(|&b| b == 0xFF) && address[5] & 0x3F == 0x3F {
+            address[0] = 0xFE;
+        }
+        address[5] |= 0xC0;
+
+        Address::StaticRandom(hci::BdAddr(address))
+    }
+
+    /// Returns the device address.
+    pub fn bd_addr(&self) -> hci::BdAddr {
+        match self {
+            Address::Public(addr) | Address::StaticRandom(addr) => *addr,
+        }
+    }
+}
+
+/// Reads the 96-bit unique device ID of the STM32F3.
+///
+/// # Safety
+///
+/// Must only be called on an STM32F3; on any other device, the address may not be readable.
+pub unsafe fn unique_id() -> [u8; 12] {
+    let mut id = [0; 12];
+    for (i, byte) in id.iter_mut().enumerate() {
+        *byte = core::ptr::read_volatile((UNIQUE_ID_ADDRESS + i) as *const u8);
+    }
+
+    id
+}
+
+/// Identity and radio settings of the device, applied during initialization.
+#[derive(Copy, Clone, Debug)]
+pub struct DeviceConfig {
+    /// Bluetooth device address.
+    pub address: Address,
+
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+
+    /// Appearance reported in the GAP Appearance characteristic.
+    pub appearance: u16,
+
+    /// Transmit power level.
+    pub tx_power: bluenrg::hal::PowerLevel,
+
+    /// Minimum and maximum advertising interval. With `None`, the controller uses its default
+    /// interval.
+    pub advertising_interval: Option<(Duration, Duration)>,
+}
+
+impl DeviceConfig {
+    /// Returns a configuration with the given address and name, the unknown appearance, a
+    /// transmit power of -2.1 dBm, and the default advertising interval.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is longer than [`MAX_NAME_LEN`].
+    pub fn new(address: Address, name: &[u8]) -> DeviceConfig {
+        let mut config = DeviceConfig {
+            address: address,
+            name: [0; MAX_NAME_LEN],
+            name_len: 0,
+            appearance: 0,
+            tx_power: bluenrg::hal::PowerLevel::DbmNeg2_1,
+            advertising_interval: None,
+        };
+        config.set_name(name);
+
+        config
+    }
+
+    /// Returns the device name.
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+
+    /// Sets the device name.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is longer than [`MAX_NAME_LEN`].
+    pub fn set_name(&mut self, name: &[u8]) {
+        assert!(name.len() <= MAX_NAME_LEN, "device name is too long");
+        self.name[..name.len()].copy_from_slice(name);
+        self.name_len = name.len();
+    }
+
+    /// Returns this configuration with every setting that has a value in `store` replaced by
+    /// that value.
+    pub fn load<F: Flash>(mut self, store: &Store<F>) -> DeviceConfig {
+        let mut buffer = [0; storage::MAX_VALUE_LEN];
+
+        if let Some(7) = store.read(key::ADDRESS, &mut buffer) {
+            let mut addr = [0; 6];
+            addr.copy_from_slice(&buffer[1..7]);
+            match buffer[0] {
+                ADDRESS_PUBLIC => self.address = Address::Public(hci::BdAddr(addr)),
+                ADDRESS_STATIC_RANDOM => self.address = Address::StaticRandom(hci::BdAddr(addr)),
+                _ => (),
+            }
+        }
+        if let Some(len) = store.read(key::NAME, &mut buffer) {
+            if len <= MAX_NAME_LEN {
+                self.set_name(&buffer[..len]);
+            }
+        }
+        if let Some(2) = store.read(key::APPEARANCE, &mut buffer) {
+            self.appearance = u16::from(buffer[0]) | (u16::from(buffer[1]) << 8);
+        }
+        if let Some(2) = store.read(key::TX_POWER, &mut buffer) {
+            let value = u16::from(buffer[0]) | (u16::from(buffer[1]) << 8);
+            if let Some(level) = TX_POWER_LEVELS.iter().find(|&&l| l as u16 == value) {
+                self.tx_power = *level;
+            }
+        }
+
+        self
+    }
+
+    /// Writes the address, name, appearance, and transmit power level to `store`.
+    pub fn save<F: Flash>(&self, store: &mut Store<F>) -> Result<(), storage::Error> {
+        let (address_type, addr) = match self.address {
+            Address::Public(addr) => (ADDRESS_PUBLIC, addr),
+            Address::StaticRandom(addr) => (ADDRESS_STATIC_RANDOM, addr),
+        };
+        let mut address = [address_type, 0, 0, 0, 0, 0, 0];
+        address[1..].copy_from_slice(&addr.0);
+        store.write(key::ADDRESS, &address)?;
+
+        store.write(key::NAME, self.name())?;
+        store.write(
+            key::APPEARANCE,
+            &[self.appearance as u8, (self.appearance >> 8) as u8],
+        )?;
+
+        let tx_power = self.tx_power as u16;
+        store.write(key::TX_POWER, &[tx_power as u8, (tx_power >> 8) as u8])
+    }
+}
+
+const ADDRESS_PUBLIC: u8 = 0;
+const ADDRESS_STATIC_RANDOM: u8 = 1;
+
+const TX_POWER_LEVELS: [bluenrg::hal::PowerLevel; 16] = [
+    bluenrg::hal::PowerLevel::DbmNeg18,
+    bluenrg::hal::PowerLevel::DbmNeg15,
+    bluenrg::hal::PowerLevel::DbmNeg14_7,
+    bluenrg::hal::PowerLevel::DbmNeg11_7,
+    bluenrg::hal::PowerLevel::DbmNeg11_4,
+    bluenrg::hal::PowerLevel::DbmNeg8_4,
+    bluenrg::hal::PowerLevel::DbmNeg8_1,
+    bluenrg::hal::PowerLevel::DbmNeg5_1,
+    bluenrg::hal::PowerLevel::DbmNeg4_9,
+    bluenrg::hal::PowerLevel::DbmNeg2_1,
+    bluenrg::hal::PowerLevel::DbmNeg1_8,
+    bluenrg::hal::PowerLevel::Dbm1_4,
+    bluenrg::hal::PowerLevel::Dbm1_7,
+    bluenrg::hal::PowerLevel::Dbm4_7,
+    bluenrg::hal::PowerLevel::Dbm5_0,
+    bluenrg::hal::PowerLevel::Dbm8_0,
+];
+
+impl Default for DeviceConfig {
+    /// Returns a configuration named "BlueNRG" with the public address 02:80:E1:00:34:12.
+    fn default() -> DeviceConfig {
+        DeviceConfig::new(
+            Address::Public(hci::BdAddr([0x12, 0x34, 0x00, 0xE1, 0x80, 0x02])),
+            b"BlueNRG",
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_a_static_random_address_from_the_unique_id() {
+        let unique_id = [
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x10, 0x20, 0x30, 0x40, 0x50, 0x60,
+        ];
+        match Address::from_unique_id(&unique_id) {
+            Address::StaticRandom(addr) => {
+                assert_eq!(addr.0, [0x11, 0x22, 0x33, 0x44, 0x55, 0xE6])
+            }
+            a => panic!("unexpected address {:?}", a),
+        }
+    }
+
+    #[test]
+    fn never_derives_a_random_part_of_all_zeros_or_ones() {
+        let zeros = Address::from_unique_id(&[0; 12]).bd_addr();
+        assert_eq!(zeros.0, [0x01, 0x00, 0x00, 0x00, 0x00, 0xC0]);
+
+        let mut unique_id = [0; 12];
+        unique_id[..6].copy_from_slice(&[0xFF; 6]);
+        let ones = Address::from_unique_id(&unique_id).bd_addr();
+        assert_eq!(ones.0, [0xFE, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    #[should_panic(expected = "device name is too long")]
+    fn rejects_names_that_are_too_long() {
+        DeviceConfig::new(Address::from_unique_id(&[0; 12]), &[b'a'; MAX_NAME_LEN + 1]);
+    }
+
+    #[test]
+    fn round_trips_through_the_store() {
+        let mut store = Store::open(crate::flash::RamFlash::new()).unwrap();
+        let mut config = DeviceConfig::new(Address::from_unique_id(&[0; 12]), b"Sensor");
+        config.appearance = 0x0540;
+        config.tx_power = bluenrg::hal::PowerLevel::Dbm4_7;
+        config.save(&mut store).unwrap();
+
+        let loaded = DeviceConfig::default().load(&store);
+        assert_eq!(loaded.address.bd_addr().0, config.address.bd_addr().0);
+        assert_eq!(loaded.name(), b"Sensor");
+        assert_eq!(loaded.appearance, 0x0540);
+        assert_eq!(
+            loaded.tx_power as u16,
+            bluenrg::hal::PowerLevel::Dbm4_7 as u16
+        );
+    }
+
+    #[test]
+    fn keeps_the_defaults_missing_from_the_store() {
+        let store = Store::open(crate::flash::RamFlash::new()).unwrap();
+        let loaded = DeviceConfig::default().load(&store);
+        assert_eq!(loaded.name(), b"BlueNRG");
+        match loaded.address {
+            Address::Public(addr) => assert_eq!(addr.0, [0x12, 0x34, 0x00, 0xE1, 0x80, 0x02]),
+            a => panic!("unexpected address {:?}", a),
+        }
+    }
+}
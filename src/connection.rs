@@ -0,0 +1,42 @@
+//! Tracking of the connection to a central device.
+
+use core::time::Duration;
+
+/// Parameters of the current connection.
+#[derive(Copy, Clone, Debug)]
+pub struct Connection {
+    /// Handle the controller uses to identify the connection.
+    pub conn_handle: hci::ConnectionHandle,
+
+    /// Address of the connected central device.
+    pub peer_address: hci::BdAddrType,
+
+    /// Time between connection events.
+    pub interval: Duration,
+
+    /// Number of connection events the peripheral may skip.
+    pub latency: u16,
+
+    /// Time without a valid packet after which the connection is considered lost.
+    pub supervision_timeout: Duration,
+}
+
+impl Connection {
+    /// Returns the connection described by a successful LE Connection Complete event.
+    pub fn from_event<VS>(event: &hci::event::LeConnectionComplete<VS>) -> Connection {
+        Connection {
+            conn_handle: event.conn_handle,
+            peer_address: event.peer_bd_addr,
+            interval: event.conn_interval.interval(),
+            latency: event.conn_interval.conn_latency(),
+            supervision_timeout: event.conn_interval.supervision_timeout(),
+        }
+    }
+
+    /// Updates the connection parameters from a successful LE Connection Update Complete event.
+    pub fn update<VS>(&mut self, event: &hci::event::LeConnectionUpdateComplete<VS>) {
+        self.interval = event.conn_interval.interval();
+        self.latency = event.conn_interval.conn_latency();
+        self.supervision_timeout = event.conn_interval.supervision_timeout();
+    }
+}
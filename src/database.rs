@@ -0,0 +1,459 @@
+//! Declarative description of the GATT database.
+//!
+//! The database is a flat list of attributes built with [`Database`]'s builder methods. Each
+//! characteristic belongs to the service added before it, and each descriptor belongs to the
+//! characteristic added before it. The event loop walks the list in order, registers every
+//! attribute with the controller, and records the handle the controller assigns to it.
+
+use crate::Error;
+use bluenrg::gatt::{
+    AccessPermission, CharacteristicEvent, CharacteristicHandle, CharacteristicPermission,
+    CharacteristicProperty, DescriptorPermission, ServiceHandle, ServiceType, Uuid,
+};
+
+/// Maximum number of attributes (services, characteristics, and descriptors) in the database.
+pub const MAX_ATTRIBUTES: usize = 32;
+
+/// Description of a service.
+#[derive(Copy, Clone, Debug)]
+pub struct Service {
+    /// UUID of the service.
+    pub uuid: Uuid,
+
+    /// Whether the service is primary or secondary.
+    pub service_type: ServiceType,
+
+    /// Maximum number of attribute records the service may contain, including the service
+    /// declaration itself.
+    pub max_attribute_records: u8,
+}
+
+/// Description of a characteristic.
+#[derive(Copy, Clone, Debug)]
+pub struct Characteristic {
+    /// UUID of the characteristic.
+    pub uuid: Uuid,
+
+    /// Maximum length of the characteristic value.
+    pub value_len: usize,
+
+    /// Properties of the characteristic.
+    pub properties: CharacteristicProperty,
+
+    /// Security requirements for accessing the characteristic value.
+    pub security_permissions: CharacteristicPermission,
+
+    /// Events the controller reports to the host when the characteristic is accessed.
+    pub event_mask: CharacteristicEvent,
+
+    /// Minimum encryption key size required to access the characteristic.
+    pub encryption_key_size: u8,
+
+    /// True if the value has a variable length.
+    pub is_variable: bool,
+}
+
+/// Description of a characteristic descriptor.
+#[derive(Copy, Clone, Debug)]
+pub struct Descriptor {
+    /// UUID of the descriptor.
+    pub uuid: Uuid,
+
+    /// Maximum length of the descriptor value.
+    pub max_len: usize,
+
+    /// Initial value of the descriptor.
+    pub value: &'static [u8],
+
+    /// Security requirements for accessing the descriptor.
+    pub security_permissions: DescriptorPermission,
+
+    /// Whether the descriptor may be read or written.
+    pub access_permissions: AccessPermission,
+
+    /// Events the controller reports to the host when the descriptor is accessed.
+    pub event_mask: CharacteristicEvent,
+
+    /// Minimum encryption key size required to access the descriptor.
+    pub encryption_key_size: u8,
+
+    /// True if the value has a variable length.
+    pub is_variable: bool,
+}
+
+/// An entry in the database.
+#[derive(Copy, Clone, Debug)]
+pub enum Attribute {
+    /// A service, which contains the characteristics that follow it.
+    Service(Service),
+
+    /// A characteristic, which contains the descriptors that follow it.
+    Characteristic(Characteristic),
+
+    /// A descriptor of the preceding characteristic.
+    Descriptor(Descriptor),
+}
+
+/// Command that registers an attribute with the controller.
+pub enum Command {
+    /// Add a service.
+    AddService(bluenrg::gatt::AddServiceParameters),
+
+    /// Add a characteristic to a service.
+    AddCharacteristic(bluenrg::gatt::AddCharacteristicParameters),
+
+    /// Add a descriptor to a characteristic.
+    AddDescriptor(bluenrg::gatt::AddDescriptorParameters<'static>),
+}
+
+/// Handles of a registered characteristic.
+#[derive(Copy, Clone, Debug)]
+pub struct CharacteristicHandles {
+    /// UUID of the characteristic.
+    pub uuid: Uuid,
+
+    /// Handle of the service that contains the characteristic.
+    pub service: ServiceHandle,
+
+    /// Handle of the characteristic declaration.
+    pub characteristic: CharacteristicHandle,
+
+    /// Properties of the characteristic.
+    pub properties: CharacteristicProperty,
+}
+
+impl CharacteristicHandles {
+    /// Returns the handle of the characteristic value, which immediately follows the declaration.
+    pub fn value_handle(&self) -> u16 {
+        self.characteristic.0 + 1
+    }
+
+    /// Returns the handle of the Client Characteristic Configuration descriptor, which the
+    /// controller adds right after the value of characteristics that support notifications or
+    /// indications. Returns `None` for other characteristics, which have no such descriptor.
+    pub fn client_config_handle(&self) -> Option<u16> {
+        if self
+            .properties
+            .intersects(CharacteristicProperty::NOTIFY | CharacteristicProperty::INDICATE)
+        {
+            Some(self.characteristic.0 + 2)
+        } else {
+            None
+        }
+    }
+}
+
+/// The GATT database, and the handles the controller assigned to each attribute.
+pub struct Database {
+    attributes: [Option<Attribute>; MAX_ATTRIBUTES],
+    handles: [Option<u16>; MAX_ATTRIBUTES],
+    len: usize,
+}
+
+impl Database {
+    /// Returns an empty database.
+    pub fn new() -> Database {
+        Database {
+            attributes: [None; MAX_ATTRIBUTES],
+            handles: [None; MAX_ATTRIBUTES],
+            len: 0,
+        }
+    }
+
+    /// Adds a service to the database.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the database is full.
+    pub fn service(self, service: Service) -> Database {
+        self.push(Attribute::Service(service))
+    }
+
+    /// Adds a characteristic to the most recently added service.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the database is full or no service has been added.
+    pub fn characteristic(self, characteristic: Characteristic) -> Database {
+        assert!(
+            self.attributes[..self.len].iter().any(|a| match a {
+                Some(Attribute::Service(_)) => true,
+                _ => false,
+            }),
+            "characteristic added before any service"
+        );
+        self.push(Attribute::Characteristic(characteristic))
+    }
+
+    /// Adds a descriptor to the most recently added characteristic.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the database is full, or the most recently added attribute is a service.
+    pub fn descriptor(self, descriptor: Descriptor) -> Database {
+        assert!(
+            match self.len.checked_sub(1).and_then(|i| self.attributes[i]) {
+                Some(Attribute::Characteristic(_)) | Some(Attribute::Descriptor(_)) => true,
+                _ => false,
+            },
+            "descriptor added without a characteristic"
+        );
+        self.push(Attribute::Descriptor(descriptor))
+    }
+
+    fn push(mut self, attribute: Attribute) -> Database {
+        assert!(self.len < MAX_ATTRIBUTES, "GATT database is full");
+        self.attributes[self.len] = Some(attribute);
+        self.len += 1;
+
+        self
+    }
+
+    /// Returns the number of attributes in the database.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the database has no attributes.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the attribute at `index`.
+    pub fn attribute(&self, index: usize) -> Option<&Attribute> {
+        self.attributes[..self.len]
+            .get(index)
+            .and_then(|a| a.as_ref())
+    }
+
+    /// Returns the handle assigned to the attribute at `index`, if it has been registered.
+    pub fn handle(&self, index: usize) -> Option<u16> {
+        self.handles[..self.len].get(index).and_then(|h| *h)
+    }
+
+    /// Returns the handle of the service with the given UUID, if it has been registered.
+    pub fn service_handle(&self, uuid: Uuid) -> Option<ServiceHandle> {
+        self.find(|a| match a {
+            Attribute::Service(s) => s.uuid == uuid,
+            _ => false,
+        })
+        .and_then(|i| self.handle(i))
+        .map(ServiceHandle)
+    }
+
+    /// Returns the handle of the characteristic with the given UUID, if it has been registered.
+    pub fn characteristic_handle(&self, uuid: Uuid) -> Option<CharacteristicHandle> {
+        self.find(|a| match a {
+            Attribute::Characteristic(c) => c.uuid == uuid,
+            _ => false,
+        })
+        .and_then(|i| self.handle(i))
+        .map(CharacteristicHandle)
+    }
+
+    /// Returns the handles of the characteristic with the given UUID, if it has been registered.
+    pub fn characteristic(&self, uuid: Uuid) -> Option<CharacteristicHandles> {
+        self.find(|a| match a {
+            Attribute::Characteristic(c) => c.uuid == uuid,
+            _ => false,
+        })
+        .and_then(|i| self.characteristic_handles(i))
+    }
+
+    /// Returns the handles of the characteristic whose value has the given attribute handle.
+    pub fn characteristic_by_value_handle(&self, handle: u16) -> Option<CharacteristicHandles> {
+        (0..self.len)
+            .filter_map(|i| self.characteristic_handles(i))
+            .find(|c| c.value_handle() == handle)
+    }
+
+    /// Returns the handles of the characteristic whose Client Characteristic Configuration
+    /// descriptor has the given attribute handle. Only characteristics that support notifications
+    /// or indications have the descriptor.
+    pub fn characteristic_by_config_handle(&self, handle: u16) -> Option<CharacteristicHandles> {
+        (0..self.len)
+            .filter_map(|i| self.characteristic_handles(i))
+            .find(|c| c.client_config_handle() == Some(handle))
+    }
+
+    fn characteristic_handles(&self, index: usize) -> Option<CharacteristicHandles> {
+        match self.attributes[index] {
+            Some(Attribute::Characteristic(c)) => Some(CharacteristicHandles {
+                uuid: c.uuid,
+                service: self.parent_service(index)?,
+                characteristic: CharacteristicHandle(self.handle(index)?),
+                properties: c.properties,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Forgets all registered handles, so the database can be registered again after the
+    /// controller is reset.
+    pub fn clear_handles(&mut self) {
+        self.handles = [None; MAX_ATTRIBUTES];
+    }
+
+    pub(crate) fn set_handle(&mut self, index: usize, handle: u16) {
+        self.handles[index] = Some(handle);
+    }
+
+    fn find<F>(&self, predicate: F) -> Option<usize>
+    where
+        F: Fn(&Attribute) -> bool,
+    {
+        self.attributes[..self.len].iter().position(|a| match a {
+            Some(a) => predicate(a),
+            None => false,
+        })
+    }
+
+    fn parent_service(&self, index: usize) -> Option<ServiceHandle> {
+        (0..index)
+            .rev()
+            .find(|&i| match self.attributes[i] {
+                Some(Attribute::Service(_)) => true,
+                _ => false,
+            })
+            .and_then(|i| self.handle(i))
+            .map(ServiceHandle)
+    }
+
+    fn parent_characteristic(&self, index: usize) -> Option<CharacteristicHandle> {
+        (0..index)
+            .rev()
+            .find(|&i| match self.attributes[i] {
+                Some(Attribute::Characteristic(_)) => true,
+                _ => false,
+            })
+            .and_then(|i| self.handle(i))
+            .map(CharacteristicHandle)
+    }
+
+    /// Returns the command that registers the attribute at `index`. The attributes before it
+    /// must already have been registered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the attribute's parent has not been registered.
+    pub fn command<E>(
+        &self,
+        index: usize,
+        fw_version: &bluenrg::Version,
+    ) -> Result<Command, Error<E>> {
+        match self.attributes[index] {
+            Some(Attribute::Service(s)) => {
+                Ok(Command::AddService(bluenrg::gatt::AddServiceParameters {
+                    uuid: s.uuid,
+                    service_type: s.service_type,
+                    max_attribute_records: s.max_attribute_records,
+                }))
+            }
+            Some(Attribute::Characteristic(c)) => Ok(Command::AddCharacteristic(
+                bluenrg::gatt::AddCharacteristicParameters {
+                    service_handle: self.parent_service(index).expect("service not registered"),
+                    characteristic_uuid: c.uuid,
+                    characteristic_value_len: c.value_len,
+                    characteristic_properties: c.properties,
+                    security_permissions: c.security_permissions,
+                    gatt_event_mask: c.event_mask,
+                    encryption_key_size: encryption_key_size(c.encryption_key_size)?,
+                    is_variable: c.is_variable,
+                    fw_version_before_v72: fw_version.major < 7
+                        || (fw_version.major == 7 && fw_version.minor < 2),
+                },
+            )),
+            Some(Attribute::Descriptor(d)) => Ok(Command::AddDescriptor(
+                bluenrg::gatt::AddDescriptorParameters {
+                    service_handle: self.parent_service(index).expect("service not registered"),
+                    characteristic_handle: self
+                        .parent_characteristic(index)
+                        .expect("characteristic not registered"),
+                    descriptor_uuid: d.uuid,
+                    descriptor_value_max_len: d.max_len,
+                    descriptor_value: d.value,
+                    security_permissions: d.security_permissions,
+                    access_permissions: d.access_permissions,
+                    gatt_event_mask: d.event_mask,
+                    encryption_key_size: encryption_key_size(d.encryption_key_size)?,
+                    is_variable: d.is_variable,
+                },
+            )),
+            None => panic!("no attribute at index {}", index),
+        }
+    }
+}
+
+fn encryption_key_size<E>(size: u8) -> Result<bluenrg::gatt::EncryptionKeySize, Error<E>> {
+    bluenrg::gatt::EncryptionKeySize::with_value(size.into()).map_err(|_| Error::InvalidParameters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVICE_UUID: Uuid = Uuid::Uuid16(0x180F);
+    const NOTIFIED_UUID: Uuid = Uuid::Uuid16(0x2A19);
+    const READ_ONLY_UUID: Uuid = Uuid::Uuid16(0x2A1A);
+
+    fn characteristic(uuid: Uuid, properties: CharacteristicProperty) -> Characteristic {
+        Characteristic {
+            uuid: uuid,
+            value_len: 1,
+            properties: properties,
+            security_permissions: CharacteristicPermission::empty(),
+            event_mask: CharacteristicEvent::empty(),
+            encryption_key_size: 16,
+            is_variable: false,
+        }
+    }
+
+    fn registered_database() -> Database {
+        let mut database = Database::new()
+            .service(Service {
+                uuid: SERVICE_UUID,
+                service_type: ServiceType::Primary,
+                max_attribute_records: 6,
+            })
+            .characteristic(characteristic(
+                NOTIFIED_UUID,
+                CharacteristicProperty::READ | CharacteristicProperty::NOTIFY,
+            ))
+            .characteristic(characteristic(READ_ONLY_UUID, CharacteristicProperty::READ));
+        database.set_handle(0, 0x0010);
+        database.set_handle(1, 0x0011);
+        database.set_handle(2, 0x0014);
+
+        database
+    }
+
+    #[test]
+    fn finds_the_client_config_of_notified_characteristics_only() {
+        let database = registered_database();
+
+        let notified = database.characteristic(NOTIFIED_UUID).unwrap();
+        assert_eq!(notified.value_handle(), 0x0012);
+        assert_eq!(notified.client_config_handle(), Some(0x0013));
+        assert_eq!(
+            database
+                .characteristic_by_config_handle(0x0013)
+                .map(|c| c.uuid),
+            Some(NOTIFIED_UUID)
+        );
+
+        // The read-only characteristic has no descriptor, so the handle after its value belongs
+        // to whatever the controller registered next.
+        let read_only = database.characteristic(READ_ONLY_UUID).unwrap();
+        assert_eq!(read_only.value_handle(), 0x0015);
+        assert_eq!(read_only.client_config_handle(), None);
+        assert!(database.characteristic_by_config_handle(0x0016).is_none());
+    }
+
+    #[test]
+    fn forgets_handles_after_a_reset() {
+        let mut database = registered_database();
+        database.clear_handles();
+        assert!(database.characteristic(NOTIFIED_UUID).is_none());
+        assert!(database.service_handle(SERVICE_UUID).is_none());
+    }
+}
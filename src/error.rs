@@ -0,0 +1,128 @@
+//! Errors reported by the event loop, and the policy for recovering from them.
+
+use crate::State;
+use core::fmt;
+use core::fmt::Debug;
+
+/// Errors that can occur while driving the BlueNRG.
+///
+/// `E` is the error type of the SPI bus.
+#[derive(Debug)]
+pub enum Error<E> {
+    /// Communication with the controller over SPI failed.
+    Comm(E),
+
+    /// The controller sent a packet that could not be parsed.
+    BadPacket,
+
+    /// A command was not sent because its parameters were invalid.
+    InvalidParameters,
+
+    /// The controller reported that a command failed.
+    CommandFailed(hci::Status<bluenrg::event::Status>),
+
+    /// The controller completed a command other than the one the current state is waiting for.
+    /// Always restarts initialization, whatever the [recovery policy](RecoveryPolicy).
+    UnexpectedEvent,
+
+    /// The controller did not respond in time.
+    Timeout,
+}
+
+impl<E: Debug> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Comm(e) => write!(f, "SPI communication failed: {:?}", e),
+            Error::BadPacket => write!(f, "received a malformed packet from the controller"),
+            Error::InvalidParameters => write!(f, "command parameters were invalid"),
+            Error::CommandFailed(s) => write!(f, "command failed with status {:?}", s),
+            Error::UnexpectedEvent => write!(f, "controller completed an unexpected command"),
+            Error::Timeout => write!(f, "timed out waiting for the controller"),
+        }
+    }
+}
+
+impl<E> From<hci::host::uart::Error<E, bluenrg::event::BlueNRGError>> for Error<E> {
+    fn from(e: hci::host::uart::Error<E, bluenrg::event::BlueNRGError>) -> Self {
+        match e {
+            hci::host::uart::Error::Comm(e) => Error::Comm(e),
+            _ => Error::BadPacket,
+        }
+    }
+}
+
+impl<E, V> From<hci::host::Error<E, V>> for Error<E> {
+    fn from(e: hci::host::Error<E, V>) -> Self {
+        match e {
+            hci::host::Error::Comm(e) => Error::Comm(e),
+            _ => Error::InvalidParameters,
+        }
+    }
+}
+
+impl<E> From<bluenrg::gap::Error<E>> for Error<E> {
+    fn from(e: bluenrg::gap::Error<E>) -> Self {
+        match e {
+            bluenrg::gap::Error::Comm(e) => Error::Comm(e),
+            _ => Error::InvalidParameters,
+        }
+    }
+}
+
+impl<E> From<bluenrg::gatt::Error<E>> for Error<E> {
+    fn from(e: bluenrg::gatt::Error<E>) -> Self {
+        match e {
+            bluenrg::gatt::Error::Comm(e) => Error::Comm(e),
+            _ => Error::InvalidParameters,
+        }
+    }
+}
+
+/// What the event loop should do when a state fails.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Recovery {
+    /// Send the state's command again, up to the given number of times. Once the retries are
+    /// exhausted, the event loop restarts as for [`Recovery::Restart`].
+    Retry(u8),
+
+    /// Reset the controller and restart initialization from [`State::GettingVersionInfo`].
+    Restart,
+
+    /// Stop the event loop and report the error.
+    Halt,
+}
+
+/// Selects the recovery for errors that occur in each state.
+pub type RecoveryPolicy = fn(State) -> Recovery;
+
+/// The recovery policy used unless another one is set on the event loop.
+///
+/// Failures to talk to the controller at all restart initialization, since the controller is in
+/// an unknown state. Any other command is retried twice before restarting.
+pub fn default_recovery_policy(state: State) -> Recovery {
+    match state {
+        State::GettingVersionInfo
+        | State::Resetting
+        | State::Complete
+        | State::Connected
+        | State::Discovering => Recovery::Restart,
+        _ => Recovery::Retry(2),
+    }
+}
+
+/// Selects how long the event loop waits for the controller in each state, as a number of
+/// periods of the timeout timer. With `None`, the event loop waits forever.
+pub type TimeoutPolicy = fn(State) -> Option<u16>;
+
+/// The timeout policy used unless another one is set on the event loop.
+///
+/// States that wait for a central device, for nearby devices, or for the delay before advertising
+/// restarts never time out. Resetting the controller gets 100 periods, and every command gets 50;
+/// with a 10 ms period, that is 1 s and 500 ms.
+pub fn default_timeout_policy(state: State) -> Option<u16> {
+    match state {
+        State::Complete | State::Connected | State::Disconnected | State::Discovering => None,
+        State::Resetting => Some(100),
+        _ => Some(50),
+    }
+}
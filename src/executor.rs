@@ -0,0 +1,87 @@
+//! A minimal single-threaded executor for the [async event loop](crate::async_loop).
+//!
+//! The executor lives in a `static`, so the wakers it hands out stay valid however long the
+//! futures, or the [event queue](crate::queue::EventQueue), keep them:
+//!
+//! ```ignore
+//! static EXECUTOR: executor::Executor = executor::Executor::new();
+//!
+//! let error = EXECUTOR.block_on_with(event_loop.run(), cortex_m::asm::wfi);
+//! ```
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+
+// The data of every waker is a pointer to the `woken` flag of an executor that lives in a
+// `static`, so wakers may be cloned, kept, and dropped freely without counting them.
+unsafe fn clone(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &VTABLE)
+}
+
+unsafe fn wake(data: *const ()) {
+    (*(data as *const AtomicBool)).store(true, Ordering::Release);
+}
+
+unsafe fn drop(_data: *const ()) {}
+
+/// Runs a future to completion on the current thread.
+///
+/// Only one future at a time may run on an executor. A waker kept from an earlier future only
+/// causes the current one to be polled once more.
+pub struct Executor {
+    woken: AtomicBool,
+}
+
+impl Executor {
+    /// Returns a new executor, to store in a `static`.
+    pub const fn new() -> Executor {
+        Executor {
+            woken: AtomicBool::new(false),
+        }
+    }
+
+    /// Runs `future` to completion, and returns its output.
+    pub fn block_on<F: Future>(&'static self, future: F) -> F::Output {
+        self.block_on_with(future, || ())
+    }
+
+    /// Runs `future` to completion, and returns its output. `idle` is called whenever the future
+    /// is pending and has not been woken, and may put the processor to sleep until an interrupt
+    /// wakes the future.
+    pub fn block_on_with<F, I>(&'static self, mut future: F, mut idle: I) -> F::Output
+    where
+        F: Future,
+        I: FnMut(),
+    {
+        let waker = unsafe {
+            Waker::from_raw(RawWaker::new(
+                &self.woken as *const AtomicBool as *const (),
+                &VTABLE,
+            ))
+        };
+        let mut context = Context::from_waker(&waker);
+
+        // The future is never moved again once pinned here.
+        let mut future = unsafe { Pin::new_unchecked(&mut future) };
+        self.woken.store(true, Ordering::Release);
+        loop {
+            if self.woken.swap(false, Ordering::Acquire) {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                    return output;
+                }
+            } else {
+                idle();
+            }
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Executor {
+        Executor::new()
+    }
+}
@@ -0,0 +1,186 @@
+//! Access to the flash region reserved for runtime storage.
+//!
+//! The region is the last 4K of the STM32F303RE's internal flash, which `memory.x` keeps out of
+//! the `FLASH` region the program is linked into. It is split into two 2K pages, the STM32F3's
+//! erase unit, and programmed one half-word at a time.
+
+/// Start of the reserved region in the STM32F303RE's address space. Must match `STORAGE` in
+/// `memory.x`.
+pub const STORAGE_ADDRESS: usize = 0x0807_F000;
+
+/// Size of a flash page, in bytes.
+pub const PAGE_SIZE: usize = 2048;
+
+/// Number of pages in the reserved region.
+pub const PAGE_COUNT: usize = 2;
+
+/// Errors reported by the flash controller.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FlashError {
+    /// A half-word was programmed without being erased first.
+    Programming,
+
+    /// The page is write protected.
+    WriteProtection,
+}
+
+/// A region of NOR flash made of [`PAGE_COUNT`] pages of [`PAGE_SIZE`] bytes. Addresses are
+/// offsets from the start of the region.
+///
+/// Erased flash reads as 0xFF, and programming can only clear bits. A half-word must be erased
+/// before it is programmed with anything but 0.
+pub trait Flash {
+    /// Reads `buffer.len()` bytes starting at `address`.
+    fn read(&self, address: usize, buffer: &mut [u8]);
+
+    /// Erases the page with the given index.
+    fn erase(&mut self, page: usize) -> Result<(), FlashError>;
+
+    /// Programs the half-word at `address`, which must be even.
+    fn program(&mut self, address: usize, half_word: u16) -> Result<(), FlashError>;
+}
+
+/// The reserved region of the STM32F303RE's internal flash.
+pub struct InternalFlash {
+    _private: (),
+}
+
+const FLASH_KEYR: *mut u32 = 0x4002_2004 as *mut u32;
+const FLASH_SR: *mut u32 = 0x4002_200C as *mut u32;
+const FLASH_CR: *mut u32 = 0x4002_2010 as *mut u32;
+const FLASH_AR: *mut u32 = 0x4002_2014 as *mut u32;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+const SR_BSY: u32 = 1 << 0;
+const SR_PGERR: u32 = 1 << 2;
+const SR_WRPRTERR: u32 = 1 << 4;
+const SR_EOP: u32 = 1 << 5;
+
+const CR_PG: u32 = 1 << 0;
+const CR_PER: u32 = 1 << 1;
+const CR_STRT: u32 = 1 << 6;
+const CR_LOCK: u32 = 1 << 7;
+
+impl InternalFlash {
+    /// Returns the reserved region of the internal flash.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called on an STM32F303RE whose linker script reserves the region, and only
+    /// once, since the flash controller is accessed directly.
+    pub unsafe fn new() -> InternalFlash {
+        InternalFlash { _private: () }
+    }
+
+    /// Runs `operation` with the flash controller unlocked and `mode` set in the control
+    /// register, then waits for it to finish.
+    fn with_mode<F>(&mut self, mode: u32, operation: F) -> Result<(), FlashError>
+    where
+        F: FnOnce(),
+    {
+        unsafe {
+            if core::ptr::read_volatile(FLASH_CR) & CR_LOCK != 0 {
+                core::ptr::write_volatile(FLASH_KEYR, KEY1);
+                core::ptr::write_volatile(FLASH_KEYR, KEY2);
+            }
+            while core::ptr::read_volatile(FLASH_SR) & SR_BSY != 0 {}
+
+            core::ptr::write_volatile(FLASH_CR, mode);
+            operation();
+            while core::ptr::read_volatile(FLASH_SR) & SR_BSY != 0 {}
+
+            let status = core::ptr::read_volatile(FLASH_SR);
+            core::ptr::write_volatile(FLASH_SR, SR_EOP | SR_PGERR | SR_WRPRTERR);
+            core::ptr::write_volatile(FLASH_CR, CR_LOCK);
+
+            if status & SR_WRPRTERR != 0 {
+                Err(FlashError::WriteProtection)
+            } else if status & SR_PGERR != 0 {
+                Err(FlashError::Programming)
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Flash for InternalFlash {
+    fn read(&self, address: usize, buffer: &mut [u8]) {
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte =
+                unsafe { core::ptr::read_volatile((STORAGE_ADDRESS + address + i) as *const u8) };
+        }
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), FlashError> {
+        let address = STORAGE_ADDRESS + page * PAGE_SIZE;
+        self.with_mode(CR_PER, || unsafe {
+            core::ptr::write_volatile(FLASH_AR, address as u32);
+            core::ptr::write_volatile(FLASH_CR, CR_PER | CR_STRT);
+        })
+    }
+
+    fn program(&mut self, address: usize, half_word: u16) -> Result<(), FlashError> {
+        let address = STORAGE_ADDRESS + address;
+        self.with_mode(CR_PG, || unsafe {
+            core::ptr::write_volatile(address as *mut u16, half_word);
+        })
+    }
+}
+
+/// Flash held in RAM, for tests on the host. It enforces the same programming rules as the
+/// internal flash, and counts how often each page is erased. A clone keeps the contents, as the
+/// internal flash does across a reset.
+#[derive(Clone)]
+pub struct RamFlash {
+    memory: [u8; PAGE_SIZE * PAGE_COUNT],
+    erase_counts: [u32; PAGE_COUNT],
+}
+
+impl RamFlash {
+    /// Returns erased flash.
+    pub fn new() -> RamFlash {
+        RamFlash {
+            memory: [0xFF; PAGE_SIZE * PAGE_COUNT],
+            erase_counts: [0; PAGE_COUNT],
+        }
+    }
+
+    /// Returns the contents of the flash.
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Returns the number of times the page with the given index has been erased.
+    pub fn erase_count(&self, page: usize) -> u32 {
+        self.erase_counts[page]
+    }
+}
+
+impl Flash for RamFlash {
+    fn read(&self, address: usize, buffer: &mut [u8]) {
+        buffer.copy_from_slice(&self.memory[address..address + buffer.len()]);
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), FlashError> {
+        for byte in self.memory[page * PAGE_SIZE..(page + 1) * PAGE_SIZE].iter_mut() {
+            *byte = 0xFF;
+        }
+        self.erase_counts[page] += 1;
+
+        Ok(())
+    }
+
+    fn program(&mut self, address: usize, half_word: u16) -> Result<(), FlashError> {
+        let current = u16::from(self.memory[address]) | (u16::from(self.memory[address + 1]) << 8);
+        if current != 0xFFFF && half_word != 0 {
+            return Err(FlashError::Programming);
+        }
+        self.memory[address] = half_word as u8;
+        self.memory[address + 1] = (half_word >> 8) as u8;
+
+        Ok(())
+    }
+}
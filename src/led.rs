@@ -0,0 +1,294 @@
+//! Control of an LED through writes to the LED characteristic.
+//!
+//! The first byte of a write selects the command:
+//!
+//! - `00` turns the LED off.
+//! - `01` turns the LED on.
+//! - `02` toggles the LED.
+//! - `03 on off count` blinks the LED. `on` and `off` are the times the LED stays on and off, in
+//!   units of 10 ms, and `count` is the number of repetitions, or 0 to blink forever. `on` must
+//!   not be 0.
+//!
+//! Blink patterns advance when the LED is [ticked](LedControl::tick).
+
+/// Unit of the on and off times of a blink command, in milliseconds.
+pub const BLINK_TIME_UNIT_MS: u32 = 10;
+
+/// A command written to the LED characteristic.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Turn the LED off.
+    Off,
+
+    /// Turn the LED on.
+    On,
+
+    /// Toggle the LED.
+    Toggle,
+
+    /// Blink the LED.
+    Blink {
+        /// How long the LED stays on in each repetition, in milliseconds.
+        on_ms: u32,
+
+        /// How long the LED stays off in each repetition, in milliseconds.
+        off_ms: u32,
+
+        /// Number of repetitions, or `None` to blink until another command is received.
+        count: Option<u8>,
+    },
+}
+
+impl Command {
+    /// Decodes a value written to the LED characteristic. Returns `None` if the value is not a
+    /// valid command.
+    pub fn decode(data: &[u8]) -> Option<Command> {
+        match data {
+            [0x00] => Some(Command::Off),
+            [0x01] => Some(Command::On),
+            [0x02] => Some(Command::Toggle),
+            [0x03, on, off, count] if *on > 0 => Some(Command::Blink {
+                on_ms: u32::from(*on) * BLINK_TIME_UNIT_MS,
+                off_ms: u32::from(*off) * BLINK_TIME_UNIT_MS,
+                count: if *count == 0 { None } else { Some(*count) },
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Something that can be driven by LED commands.
+pub trait LedControl {
+    /// Applies a command. Any blink pattern in progress is cancelled.
+    fn apply(&mut self, command: Command);
+
+    /// Advances the blink pattern in progress, if any. `now_ms` is the current time, in
+    /// milliseconds, from a monotonic clock.
+    fn tick(&mut self, now_ms: u32);
+}
+
+#[derive(Copy, Clone)]
+struct Blink {
+    on_ms: u32,
+    off_ms: u32,
+    remaining: Option<u8>,
+    last_change_ms: Option<u32>,
+}
+
+/// An LED connected to an output pin, lit while the pin is high.
+pub struct Led<PIN> {
+    pin: PIN,
+    lit: bool,
+    blink: Option<Blink>,
+}
+
+impl<PIN> Led<PIN>
+where
+    PIN: embedded_hal::digital::OutputPin,
+{
+    /// Creates an LED on `pin`, and turns it off.
+    pub fn new(mut pin: PIN) -> Led<PIN> {
+        pin.set_low();
+        Led {
+            pin: pin,
+            lit: false,
+            blink: None,
+        }
+    }
+
+    /// Returns true if the LED is lit.
+    pub fn is_lit(&self) -> bool {
+        self.lit
+    }
+
+    /// Returns the pin the LED is connected to.
+    pub fn pin(&self) -> &PIN {
+        &self.pin
+    }
+
+    fn set(&mut self, lit: bool) {
+        if lit {
+            self.pin.set_high();
+        } else {
+            self.pin.set_low();
+        }
+        self.lit = lit;
+    }
+}
+
+impl<PIN> LedControl for Led<PIN>
+where
+    PIN: embedded_hal::digital::OutputPin,
+{
+    fn apply(&mut self, command: Command) {
+        self.blink = None;
+        match command {
+            Command::Off => self.set(false),
+            Command::On => self.set(true),
+            Command::Toggle => {
+                let lit = !self.lit;
+                self.set(lit);
+            }
+            Command::Blink {
+                on_ms,
+                off_ms,
+                count,
+            } => {
+                self.set(false);
+                self.blink = Some(Blink {
+                    on_ms: on_ms,
+                    off_ms: off_ms,
+                    remaining: count,
+                    last_change_ms: None,
+                });
+            }
+        }
+    }
+
+    fn tick(&mut self, now_ms: u32) {
+        let mut blink = match self.blink {
+            Some(blink) => blink,
+            None => return,
+        };
+
+        let elapsed = match blink.last_change_ms {
+            Some(last) => now_ms.wrapping_sub(last),
+            None => {
+                // The first tick starts the pattern.
+                blink.last_change_ms = Some(now_ms);
+                self.set(true);
+                self.blink = Some(blink);
+                return;
+            }
+        };
+
+        if self.lit {
+            if elapsed >= blink.on_ms {
+                self.set(false);
+                blink.last_change_ms = Some(now_ms);
+                if let Some(remaining) = blink.remaining {
+                    if remaining <= 1 {
+                        self.blink = None;
+                        return;
+                    }
+                    blink.remaining = Some(remaining - 1);
+                }
+            }
+        } else if elapsed >= blink.off_ms {
+            self.set(true);
+            blink.last_change_ms = Some(now_ms);
+        }
+        self.blink = Some(blink);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Output pin that records its level.
+    #[derive(Default)]
+    struct MockPin {
+        high: bool,
+        changes: usize,
+    }
+
+    impl embedded_hal::digital::OutputPin for MockPin {
+        fn set_low(&mut self) {
+            self.high = false;
+            self.changes += 1;
+        }
+
+        fn set_high(&mut self) {
+            self.high = true;
+            self.changes += 1;
+        }
+    }
+
+    #[test]
+    fn decodes_commands() {
+        assert_eq!(Command::decode(&[0x00]), Some(Command::Off));
+        assert_eq!(Command::decode(&[0x01]), Some(Command::On));
+        assert_eq!(Command::decode(&[0x02]), Some(Command::Toggle));
+        assert_eq!(
+            Command::decode(&[0x03, 5, 10, 2]),
+            Some(Command::Blink {
+                on_ms: 50,
+                off_ms: 100,
+                count: Some(2),
+            })
+        );
+        assert_eq!(
+            Command::decode(&[0x03, 5, 0, 0]),
+            Some(Command::Blink {
+                on_ms: 50,
+                off_ms: 0,
+                count: None,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        assert_eq!(Command::decode(&[]), None);
+        assert_eq!(Command::decode(&[0x04]), None);
+        assert_eq!(Command::decode(&[0x01, 0x00]), None);
+        assert_eq!(Command::decode(&[0x03, 0, 10, 1]), None);
+        assert_eq!(Command::decode(&[0x03, 5, 10]), None);
+    }
+
+    #[test]
+    fn drives_the_pin() {
+        let mut led = Led::new(MockPin::default());
+        assert!(!led.pin().high);
+        led.apply(Command::On);
+        assert!(led.is_lit() && led.pin().high);
+        led.apply(Command::Toggle);
+        assert!(!led.is_lit() && !led.pin().high);
+        led.apply(Command::Toggle);
+        assert!(led.pin().high);
+        led.apply(Command::Off);
+        assert!(!led.pin().high);
+    }
+
+    #[test]
+    fn blinks_the_requested_number_of_times() {
+        let mut led = Led::new(MockPin::default());
+        led.apply(Command::Blink {
+            on_ms: 50,
+            off_ms: 100,
+            count: Some(2),
+        });
+        assert!(!led.pin().high);
+
+        let mut levels = std::vec::Vec::new();
+        for now_ms in (1000..1400).step_by(10) {
+            led.tick(now_ms);
+            levels.push(led.pin().high);
+        }
+        // On at 1000 for 50 ms, off for 100 ms, on again at 1150, then off for good at 1200.
+        assert!(levels[..5].iter().all(|&high| high));
+        assert!(levels[5..15].iter().all(|&high| !high));
+        assert!(levels[15..20].iter().all(|&high| high));
+        assert!(levels[20..].iter().all(|&high| !high));
+    }
+
+    #[test]
+    fn commands_cancel_blinking() {
+        let mut led = Led::new(MockPin::default());
+        led.apply(Command::Blink {
+            on_ms: 50,
+            off_ms: 50,
+            count: None,
+        });
+        led.tick(0);
+        assert!(led.pin().high);
+        led.apply(Command::Off);
+        let changes = led.pin().changes;
+        for now_ms in (0..500).step_by(10) {
+            led.tick(now_ms);
+        }
+        assert!(!led.pin().high);
+        assert_eq!(led.pin().changes, changes);
+    }
+}
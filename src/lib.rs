@@ -3,26 +3,65 @@
 extern crate bluenrg;
 extern crate bluetooth_hci as hci;
 extern crate cortex_m;
-extern crate cortex_m_rt;
+#[cfg(feature = "semihosting")]
 extern crate cortex_m_semihosting;
 extern crate embedded_hal;
 #[macro_use(block)]
 extern crate nb;
-extern crate panic_semihosting;
-extern crate stm32f30x;
-extern crate stm32f30x_hal as hal;
+extern crate void;
+
+#[cfg(any(test, feature = "std"))]
+#[macro_use]
+extern crate std;
 
 use bluenrg::gap::Commands as GapCommands;
 use bluenrg::gatt::Commands as GattCommands;
 use bluenrg::hal::Commands as HalCommands;
 use bluenrg::LocalVersionInfoExt;
 use core::fmt::Debug;
-use core::fmt::Write;
-use cortex_m_semihosting::hio;
-use hal::time::U32Ext;
 use hci::host::uart::Hci;
 use hci::host::Hci as Host;
 
+pub mod accelerometer;
+pub mod advertising;
+pub mod async_loop;
+pub mod command;
+pub mod config;
+pub mod connection;
+pub mod database;
+pub mod error;
+pub mod executor;
+pub mod flash;
+pub mod led;
+pub mod logger;
+pub mod privacy;
+pub mod queue;
+pub mod reconnection;
+pub mod scanner;
+pub mod security;
+pub mod sensors;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+pub mod storage;
+pub mod subscription;
+pub mod time;
+pub mod trace;
+
+#[cfg(test)]
+mod tests;
+
+pub use error::{
+    default_recovery_policy, default_timeout_policy, Error, Recovery, RecoveryPolicy, TimeoutPolicy,
+};
+
+/// Number of times initialization is restarted before the event loop halts, unless changed with
+/// [`EventLoop::set_max_restarts`].
+pub const DEFAULT_MAX_RESTARTS: u8 = 3;
+
+/// Time between accelerometer notifications, in milliseconds, unless changed with
+/// [`EventLoop::set_acceleration_period`].
+pub const DEFAULT_ACCELERATION_PERIOD_MS: u32 = 100;
+
 const ACC_SERVICE_UUID: bluenrg::gatt::Uuid = bluenrg::gatt::Uuid::Uuid128([
     0x02, 0x36, 0x6e, 0x80, 0xcf, 0x3a, 0x11, 0xe1, 0x9a, 0xb4, 0x00, 0x02, 0xa5, 0xd5, 0xc5, 0x1b,
 ]);
@@ -66,132 +105,616 @@ const LED_CHARACTERISTIC_UUID: bluenrg::gatt::Uuid = bluenrg::gatt::Uuid::Uuid12
     0x0c, 0x36, 0x6e, 0x80, 0xcf, 0x3a, 0x11, 0xe1, 0x9a, 0xb4, 0x00, 0x02, 0xa5, 0xd5, 0xc5, 0x1b,
 ]);
 
-macro_rules! must {
-    ($expr:expr) => {
-        match $expr {
-            Ok(e) => e,
-            Err(_) => loop {
-                cortex_m::asm::wfi()
-            },
-        }
-    };
+const ENCRYPTION_KEY_SIZE: u8 = 16;
+
+fn gatt_database() -> database::Database {
+    use bluenrg::gatt::{CharacteristicEvent, CharacteristicPermission, CharacteristicProperty};
+
+    database::Database::new()
+        .service(primary_service(ACC_SERVICE_UUID, 7))
+        .characteristic(database::Characteristic {
+            uuid: ACC_FREE_FALL_UUID,
+            value_len: 1,
+            properties: CharacteristicProperty::NOTIFY,
+            security_permissions: CharacteristicPermission::empty(),
+            event_mask: CharacteristicEvent::empty(),
+            encryption_key_size: ENCRYPTION_KEY_SIZE,
+            is_variable: false,
+        })
+        .characteristic(database::Characteristic {
+            uuid: ACC_UUID,
+            value_len: 6,
+            properties: CharacteristicProperty::NOTIFY | CharacteristicProperty::READ,
+            security_permissions: CharacteristicPermission::empty(),
+            event_mask: CharacteristicEvent::CONFIRM_READ,
+            encryption_key_size: ENCRYPTION_KEY_SIZE,
+            is_variable: false,
+        })
+        .service(primary_service(ENVIRONMENTAL_SENSOR_SERVICE_UUID, 10))
+        .characteristic(database::Characteristic {
+            uuid: TEMPERATURE_CHARACTERISTIC_UUID,
+            value_len: 2,
+            properties: CharacteristicProperty::READ,
+            security_permissions: CharacteristicPermission::empty(),
+            event_mask: CharacteristicEvent::CONFIRM_READ,
+            encryption_key_size: ENCRYPTION_KEY_SIZE,
+            is_variable: false,
+        })
+        .descriptor(presentation_format(
+            TEMPERATURE_DESCRIPTOR_UUID,
+            &[0x0E, 0xFF, 0x2F, 0x27, 0, 0, 0],
+        ))
+        .characteristic(database::Characteristic {
+            uuid: PRESSURE_CHARACTERISTIC_UUID,
+            value_len: 3,
+            properties: CharacteristicProperty::READ,
+            security_permissions: CharacteristicPermission::empty(),
+            event_mask: CharacteristicEvent::CONFIRM_READ,
+            encryption_key_size: ENCRYPTION_KEY_SIZE,
+            is_variable: false,
+        })
+        .descriptor(presentation_format(
+            PRESSURE_DESCRIPTOR_UUID,
+            &[0x0F, 0xFB, 0x80, 0x27, 0, 0, 0],
+        ))
+        .characteristic(database::Characteristic {
+            uuid: HUMIDITY_CHARACTERISTIC_UUID,
+            value_len: 2,
+            properties: CharacteristicProperty::READ,
+            security_permissions: CharacteristicPermission::empty(),
+            event_mask: CharacteristicEvent::CONFIRM_READ,
+            encryption_key_size: ENCRYPTION_KEY_SIZE,
+            is_variable: false,
+        })
+        .descriptor(presentation_format(
+            HUMIDITY_DESCRIPTOR_UUID,
+            &[0x06, 0xFF, 0x00, 0x27, 0, 0, 0],
+        ))
+        .service(primary_service(TIME_SERVICE_UUID, 7))
+        .characteristic(database::Characteristic {
+            uuid: TIME_CHARACTERISTIC_UUID,
+            value_len: 4,
+            properties: CharacteristicProperty::READ,
+            security_permissions: CharacteristicPermission::ENCRYPTED_READ,
+            event_mask: CharacteristicEvent::CONFIRM_READ,
+            encryption_key_size: ENCRYPTION_KEY_SIZE,
+            is_variable: false,
+        })
+        .characteristic(database::Characteristic {
+            uuid: MINUTE_CHARACTERISTIC_UUID,
+            value_len: 4,
+            properties: CharacteristicProperty::READ | CharacteristicProperty::NOTIFY,
+            security_permissions: CharacteristicPermission::empty(),
+            event_mask: CharacteristicEvent::CONFIRM_READ,
+            encryption_key_size: ENCRYPTION_KEY_SIZE,
+            is_variable: true,
+        })
+        .service(primary_service(LED_SERVICE_UUID, 7))
+        .characteristic(database::Characteristic {
+            uuid: LED_CHARACTERISTIC_UUID,
+            value_len: 4,
+            properties: CharacteristicProperty::WRITE
+                | CharacteristicProperty::WRITE_WITHOUT_RESPONSE,
+            security_permissions: CharacteristicPermission::AUTHENTICATED_WRITE,
+            event_mask: CharacteristicEvent::ATTRIBUTE_WRITE,
+            encryption_key_size: ENCRYPTION_KEY_SIZE,
+            is_variable: true,
+        })
 }
 
-fn must_succeed<V>(s: &hci::Status<V>) {
-    match s {
-        &hci::Status::Success => (),
-        _ => loop {
-            cortex_m::asm::wfi()
-        },
+fn primary_service(uuid: bluenrg::gatt::Uuid, max_attribute_records: u8) -> database::Service {
+    database::Service {
+        uuid: uuid,
+        service_type: bluenrg::gatt::ServiceType::Primary,
+        max_attribute_records: max_attribute_records,
     }
 }
 
-fn print_event<Out: Write>(out: &mut Out, event: hci::Event<bluenrg::event::BlueNRGEvent>) {
+/// Returns a read-only Characteristic Presentation Format descriptor (0x2904) with the given
+/// value.
+fn presentation_format(uuid: bluenrg::gatt::Uuid, value: &'static [u8]) -> database::Descriptor {
+    database::Descriptor {
+        uuid: uuid,
+        max_len: 7,
+        value: value,
+        security_permissions: bluenrg::gatt::DescriptorPermission::empty(),
+        access_permissions: bluenrg::gatt::AccessPermission::READ,
+        event_mask: bluenrg::gatt::CharacteristicEvent::empty(),
+        encryption_key_size: ENCRYPTION_KEY_SIZE,
+        is_variable: false,
+    }
+}
+
+fn check_status<E>(status: hci::Status<bluenrg::event::Status>) -> Result<(), Error<E>> {
+    match status {
+        hci::Status::Success => Ok(()),
+        s => Err(Error::CommandFailed(s)),
+    }
+}
+
+fn log_event(logger: &mut dyn logger::Logger, event: &hci::Event<bluenrg::event::BlueNRGEvent>) {
+    use logger::{log, Level};
+
     match event {
         hci::Event::CommandComplete(cmd) => {
-            writeln!(
-                out,
-                "Command complete; space left for {} packets",
-                cmd.num_hci_command_packets
-            )
-            .unwrap();
-            match cmd.return_params {
+            log(
+                logger,
+                Level::Debug,
+                format_args!(
+                    "Command complete; space left for {} packets",
+                    cmd.num_hci_command_packets
+                ),
+            );
+            match &cmd.return_params {
                 hci::event::command::ReturnParameters::ReadLocalVersionInformation(v) => {
-                    writeln!(out, "  hci_version = {}", v.hci_version).unwrap();
-                    writeln!(out, "  hci_revision = {}", v.hci_revision).unwrap();
-                    writeln!(out, "  lmp_version = {}", v.lmp_version).unwrap();
-                    writeln!(out, "  manufacturer_name = {}", v.manufacturer_name).unwrap();
-                    writeln!(out, "  lmp_subversion = {}", v.lmp_subversion).unwrap();
-
                     let bnrg = v.bluenrg_version();
-                    writeln!(out, "  HW Version = {}", bnrg.hw_version).unwrap();
-                    writeln!(
-                        out,
-                        "  FW Version = {}.{}.{}",
-                        bnrg.major, bnrg.minor, bnrg.patch
-                    )
-                    .unwrap();
+                    log(
+                        logger,
+                        Level::Info,
+                        format_args!(
+                            "HCI version {}.{}, LMP version {}.{}, manufacturer {}",
+                            v.hci_version,
+                            v.hci_revision,
+                            v.lmp_version,
+                            v.lmp_subversion,
+                            v.manufacturer_name
+                        ),
+                    );
+                    log(
+                        logger,
+                        Level::Info,
+                        format_args!(
+                            "HW Version = {}, FW Version = {}.{}.{}",
+                            bnrg.hw_version, bnrg.major, bnrg.minor, bnrg.patch
+                        ),
+                    );
                 }
-                // _p => writeln!(out, "other command complete").unwrap(),
-                p => writeln!(out, "{:?}", p).unwrap(),
+                p => log(logger, Level::Debug, format_args!("  {:?}", p)),
             }
         }
-        e => writeln!(out, "{:?}", e).unwrap(),
-        // _ => writeln!(out, "other event").unwrap(),
+        e => log(logger, Level::Debug, format_args!("{:?}", e)),
     }
 }
 
-fn print_error<Out: Write, E: Debug>(out: &mut Out, error: E) {
-    writeln!(out, "Error: {:?}", error).unwrap();
-    // writeln!(out, "error").unwrap();
-    loop {}
-}
-
-pub struct EventLoop<'a> {
+/// Drives the BlueNRG through initialization and then services its events.
+///
+/// The event loop is generic over the peripherals that [`bluenrg::BlueNRG`] needs, so it can run
+/// against the STM32F303RE peripherals on the target or against mocks on the host:
+///
+/// - `SPI` is the SPI bus connected to the BlueNRG.
+/// - `CS` is the chip select output pin.
+/// - `RESET` is the reset output pin.
+/// - `DR` is the data ready input pin.
+/// - `TIMER` is the timer used to hold the reset pin low while resetting the controller, and to
+///   time out states in which the controller does not answer.
+pub struct EventLoop<'a, SPI: 'a, CS: 'a, RESET: 'a, DR: 'a, TIMER>
+where
+    TIMER: embedded_hal::timer::CountDown,
+{
     state: State,
-    data: ProgramState<'a>,
+    data: ProgramState<'a, SPI, CS, RESET, DR, TIMER>,
+
+    action_pending: bool,
+    retries: u8,
+    restarts: u8,
+    max_restarts: u8,
+    recovery_policy: RecoveryPolicy,
 }
 
-impl<'a> EventLoop<'a> {
+impl<'a, SPI, CS, RESET, DR, TIMER, E> EventLoop<'a, SPI, CS, RESET, DR, TIMER>
+where
+    SPI: embedded_hal::blocking::spi::Transfer<u8, Error = E>
+        + embedded_hal::blocking::spi::Write<u8, Error = E>,
+    CS: embedded_hal::digital::OutputPin,
+    RESET: embedded_hal::digital::OutputPin,
+    DR: embedded_hal::digital::InputPin,
+    TIMER: embedded_hal::timer::CountDown,
+    TIMER::Time: Copy,
+    E: Debug,
+{
+    /// Creates a new event loop. `reset_time` is passed to `timer` whenever the BlueNRG is reset,
+    /// and `config` is applied to the controller during initialization.
     pub fn new(
-        bnrg: &'a mut BlueNRG<'a>,
-        tim6: hal::timer::Timer<stm32f30x::TIM6>,
-        spi: Spi,
-    ) -> EventLoop<'a> {
+        bnrg: &'a mut bluenrg::BlueNRG<'a, SPI, CS, RESET, DR>,
+        timer: TIMER,
+        reset_time: TIMER::Time,
+        spi: SPI,
+        config: config::DeviceConfig,
+    ) -> EventLoop<'a, SPI, CS, RESET, DR, TIMER> {
+        let advertising_data = advertising::AdvertisingData::for_discoverable(config.name());
         EventLoop {
             state: State::GettingVersionInfo,
             data: ProgramState {
                 bnrg: bnrg,
-                tim6: tim6,
+                timer: timer,
+                reset_time: reset_time,
                 spi: spi,
 
+                config: config,
+                advertising_data: advertising_data,
+
                 fw_version: None,
 
                 gap_service_handle: None,
                 dev_name_handle: None,
                 appearance_handle: None,
-                acc_service_handle: None,
 
-                environmental_sensor_service_handle: None,
-                temperature_characteristic_handle: None,
-                humidity_characteristic_handle: None,
-                pressure_characteristic_handle: None,
+                database: gatt_database(),
+
+                connection: None,
+                advertising_restart_delay: None,
+                advertising_restart_periods_left: None,
+                advertising_mode: None,
+                reconnection: reconnection::ReconnectionPolicy::new(),
+                privacy: None,
+                private_address_owner: privacy::AddressOwner::Host,
+                role: scanner::Role::Peripheral,
+                devices: scanner::DeviceTable::new(),
 
-                time_service_handle: None,
-                led_service_handle: None,
+                environmental_sensor: None,
+                led: None,
+                clock: None,
+                accelerometer: None,
+                acceleration_period_ms: DEFAULT_ACCELERATION_PERIOD_MS,
+                last_acceleration_ms: None,
+                free_fall: accelerometer::FreeFallDetector::default(),
+                free_fall_pending: false,
+                subscriptions: subscription::Subscriptions::new(),
+                notified_minute: None,
+                pending_update: None,
+                pending_read: None,
+                queued_reads: [None; MAX_QUEUED_READS],
+                logger: None,
+                security: security::SecurityManager::default(),
+                timeout_period: None,
+                timeout_policy: default_timeout_policy,
+                timeout_periods_left: None,
+                ticking: false,
+                event_queue: None,
             },
+
+            action_pending: true,
+            retries: 0,
+            restarts: 0,
+            max_restarts: DEFAULT_MAX_RESTARTS,
+            recovery_policy: default_recovery_policy,
         }
     }
 
-    pub fn run(&mut self) {
+    /// Sets the policy used to recover from errors in each state. The policy does not apply to
+    /// [unexpected events](Error::UnexpectedEvent), which always restart initialization, since
+    /// retrying could send a command that already ran.
+    pub fn set_recovery_policy(&mut self, policy: RecoveryPolicy) {
+        self.recovery_policy = policy;
+    }
+
+    /// Sets the period of the timer that times out states in which the controller does not
+    /// answer. The number of periods each state may take is set by the
+    /// [timeout policy](EventLoop::set_timeout_policy). With `None`, the event loop waits
+    /// forever.
+    ///
+    /// When a state times out, the controller is reset and initialization restarts, as long as
+    /// the [maximum number of restarts](EventLoop::set_max_restarts) is not reached.
+    pub fn set_timeout_period(&mut self, period: Option<TIMER::Time>) {
+        self.data.timeout_period = period;
+    }
+
+    /// Sets the policy that selects how long each state may wait for the controller.
+    pub fn set_timeout_policy(&mut self, policy: TimeoutPolicy) {
+        self.data.timeout_policy = policy;
+    }
+
+    /// Sets the number of times initialization may be restarted before the event loop halts. The
+    /// count starts over once initialization succeeds.
+    pub fn set_max_restarts(&mut self, max_restarts: u8) {
+        self.max_restarts = max_restarts;
+    }
+
+    /// Sets how long to wait after a central disconnects before advertising again, as a number of
+    /// [timeout periods](EventLoop::set_timeout_period). With `None`, or without a timeout
+    /// period, advertising restarts immediately.
+    ///
+    /// The delay is counted in periods of the timeout timer, alongside the deadline of the
+    /// current state, so neither restarts the other. The event loop does not block while it
+    /// waits; with an [event queue](EventLoop::set_event_queue), the caller must step it
+    /// periodically until advertising restarts.
+    pub fn set_advertising_restart_delay(&mut self, delay: Option<u16>) {
+        self.data.advertising_restart_delay = delay;
+    }
+
+    /// Sets how long the pairing window stays open, in milliseconds. Timing the window requires a
+    /// [clock](EventLoop::set_clock); without one, the window stays open until a device pairs.
+    pub fn set_pairing_window(&mut self, duration_ms: u32) {
+        self.data.reconnection.set_pairing_window(duration_ms);
+    }
+
+    /// Sets whether the device advertises directly to a bonded central device after it
+    /// disconnects, before falling back to the white list.
+    pub fn set_directed_reconnection(&mut self, enabled: bool) {
+        self.data.reconnection.set_directed_reconnection(enabled);
+    }
+
+    /// Makes the device discoverable by any central device for the
+    /// [pairing window](EventLoop::set_pairing_window), or until a device pairs. Once a device has
+    /// bonded, only bonded devices may connect outside of the pairing window.
+    pub fn open_pairing_window(&mut self) {
+        let now_ms = self.data.clock.as_mut().map(|clock| clock.now_ms());
+        self.data.reconnection.open_pairing_window(now_ms);
+    }
+
+    /// Makes the device advertise with a resolvable private address that changes every
+    /// `rotation_period_ms`, which requires a [clock](EventLoop::set_clock); without one, the
+    /// address changes every time advertising starts. The identity root is loaded from
+    /// `key_store`, or generated and saved there the first time. The private address replaces the
+    /// controller's random address, so only a [public](config::Address::Public) configured address
+    /// stays usable as the identity address. Must be set before the event loop runs, since the
+    /// keys are only written during initialization.
+    pub fn set_privacy(
+        &mut self,
+        key_store: &'a mut dyn privacy::KeyStore,
+        rotation_period_ms: u32,
+    ) {
+        let mut privacy = privacy::Privacy::new(key_store);
+        privacy.set_rotation_period(rotation_period_ms);
+        self.data.privacy = Some(privacy);
+    }
+
+    /// Sets the side that should generate and rotate the private address, once
+    /// [privacy](EventLoop::set_privacy) is enabled. The event loop owns it unless this is set to
+    /// [`Controller`](privacy::AddressOwner::Controller), which only takes effect on firmware
+    /// whose GAP Init takes the privacy flag; see [`privacy::address_owner`]. Must be set before
+    /// the event loop runs.
+    pub fn set_private_address_owner(&mut self, owner: privacy::AddressOwner) {
+        self.data.private_address_owner = owner;
+    }
+
+    /// Sets the role the controller is initialized with. In the
+    /// [observer](scanner::Role::Observer) role, the event loop discovers nearby devices, which
+    /// [`devices`](EventLoop::devices) returns, instead of advertising. Must be set before the
+    /// event loop runs.
+    pub fn set_role(&mut self, role: scanner::Role) {
+        self.data.role = role;
+    }
+
+    /// Sets the source of the values served by the Environmental Sensor service. Without a
+    /// sensor, reads of those characteristics return the last value written.
+    pub fn set_environmental_sensor(&mut self, sensor: &'a mut dyn sensors::EnvironmentalSensor) {
+        self.data.environmental_sensor = Some(sensor);
+    }
+
+    /// Sets the LED driven by writes to the LED characteristic.
+    pub fn set_led(&mut self, led: &'a mut dyn led::LedControl) {
+        self.data.led = Some(led);
+    }
+
+    /// Sets the clock that drives the Time service and LED blink patterns. Without a clock, reads
+    /// of the Time service return the last value written, and the Minute characteristic is never
+    /// notified.
+    pub fn set_clock(&mut self, clock: &'a mut dyn time::Clock) {
+        self.data.clock = Some(clock);
+    }
+
+    /// Sets the source of the values served by the Acc service. Readings are notified
+    /// periodically while connected, which requires a [clock](EventLoop::set_clock).
+    pub fn set_accelerometer(&mut self, accelerometer: &'a mut dyn accelerometer::Accelerometer) {
+        self.data.accelerometer = Some(accelerometer);
+    }
+
+    /// Sets the time between accelerometer notifications, in milliseconds.
+    pub fn set_acceleration_period(&mut self, period_ms: u32) {
+        self.data.acceleration_period_ms = period_ms;
+    }
+
+    /// Sets the detector that decides when to notify the free-fall characteristic.
+    pub fn set_free_fall_detector(&mut self, detector: accelerometer::FreeFallDetector) {
+        self.data.free_fall = detector;
+    }
+
+    /// Sets where log messages are written. Without a logger, messages are dropped.
+    pub fn set_logger(&mut self, logger: &'a mut dyn logger::Logger) {
+        self.data.logger = Some(logger);
+    }
+
+    /// Sets how the passkey for pairing is chosen. Must be set before the event loop runs, since
+    /// the authentication requirements are only written during initialization.
+    pub fn set_pin_mode(&mut self, pin_mode: security::PinMode) {
+        self.data.security.set_pin_mode(pin_mode);
+    }
+
+    /// Sets the store that keeps the list of bonded devices across power cycles. The stored list
+    /// is loaded right away, and saved again whenever the event loop reads it from the controller.
+    pub fn set_bond_store(&mut self, store: &'a mut dyn security::BondStore) {
+        self.data.security.set_bond_store(store);
+    }
+
+    /// Sets the agent that supplies passkeys and authorization decisions, and receives the
+    /// outcome of every pairing.
+    pub fn set_pairing_agent(&mut self, agent: &'a mut dyn security::PairingAgent) {
+        self.data.security.set_agent(agent);
+    }
+
+    /// Makes the event loop read packets from the controller only after the data ready
+    /// interrupt calls [`data_ready`](queue::EventQueue::data_ready) on `queue`, and handle them
+    /// from `queue`. [`step`](EventLoop::step) then returns instead of waiting for an event, so
+    /// the caller can sleep while the event loop [is idle](EventLoop::is_idle).
+    pub fn set_event_queue(&mut self, queue: &'a queue::PacketQueue) {
+        self.data.event_queue = Some(queue);
+    }
+
+    /// Returns true if the event loop has nothing to do until the next interrupt. Always false
+    /// without an [event queue](EventLoop::set_event_queue).
+    pub fn is_idle(&self) -> bool {
+        !self.action_pending && self.data.event_queue.map_or(false, |q| q.is_idle())
+    }
+
+    /// Returns true from a client's read permit request until the event loop has allowed the
+    /// read, after writing the fresh value of the characteristic if it supplies one.
+    pub fn is_answering_read(&self) -> bool {
+        self.data.pending_read.is_some()
+    }
+
+    /// Runs the event loop until the recovery policy decides to halt, and returns the error that
+    /// caused it. [`state`](EventLoop::state) returns the state that failed.
+    pub fn run(&mut self) -> Error<E> {
         loop {
-            self.state.act(&mut self.data);
-            self.state = self.state.react(&mut self.data);
+            if let Err(e) = self.step() {
+                return e;
+            }
+        }
+    }
+
+    /// Turns the event loop into an [`AsyncEventLoop`](async_loop::AsyncEventLoop), which keeps
+    /// everything set on it. It carries on from the current state, unless the event loop is in the
+    /// middle of initialization or advertising, which then starts over.
+    pub fn into_async(self) -> async_loop::AsyncEventLoop<'a, SPI, CS, RESET, DR, TIMER> {
+        async_loop::AsyncEventLoop::new(self)
+    }
+
+    /// Performs the action for the current state if it has not been performed yet, then waits
+    /// for the next event and transitions to the next state.
+    ///
+    /// Errors are handled according to the recovery policy. An error is only returned if the
+    /// policy decides to halt.
+    pub fn step(&mut self) -> Result<(), Error<E>> {
+        if self.action_pending {
+            if let Err(e) = self.state.act(&mut self.data) {
+                return self.recover(e);
+            }
+            self.action_pending = false;
+            self.data.start_deadline(self.state);
+        }
+
+        match self.state.react(&mut self.data) {
+            Ok(next) => {
+                if next != self.state {
+                    self.state = next;
+                    self.action_pending = true;
+                    self.retries = 0;
+                    if let State::Complete | State::Connected | State::Discovering = next {
+                        // Initialization succeeded, so later failures get every restart again.
+                        self.restarts = 0;
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => self.recover(e),
         }
     }
+
+    /// Returns the current state of the event loop.
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Sets the advertising and scan response data. Build it with
+    /// [`AdvertisingData::for_discoverable`](advertising::AdvertisingData::for_discoverable), so
+    /// it leaves room for the structures the BlueNRG adds itself. Must be set before the event
+    /// loop runs, since the scan response is only written during initialization.
+    pub fn set_advertising_data(&mut self, data: advertising::AdvertisingData) {
+        self.data.advertising_data = data;
+    }
+
+    /// Returns the configuration applied to the controller.
+    pub fn config(&self) -> &config::DeviceConfig {
+        &self.data.config
+    }
+
+    /// Returns how the device is advertising, or `None` if it is not advertising.
+    pub fn advertising_mode(&self) -> Option<reconnection::AdvertisingMode> {
+        self.data.advertising_mode
+    }
+
+    /// Returns the private address the device advertises with, or `None` without privacy, before
+    /// the first one is generated, or if the controller generates it.
+    pub fn private_address(&self) -> Option<hci::BdAddr> {
+        self.data.privacy.as_ref()?.address()
+    }
+
+    /// Returns the nearby devices heard in the [observer](scanner::Role::Observer) role.
+    pub fn devices(&self) -> &scanner::DeviceTable {
+        &self.data.devices
+    }
+
+    /// Returns the current connection, if a central device is connected.
+    pub fn connection(&self) -> Option<&connection::Connection> {
+        self.data.connection.as_ref()
+    }
+
+    /// Returns the GATT database, including the handles assigned to the attributes registered so
+    /// far.
+    pub fn database(&self) -> &database::Database {
+        &self.data.database
+    }
+
+    /// Returns the security manager, which reports the outcome of the last pairing and the
+    /// devices bonded with the controller.
+    pub fn security(&self) -> &security::SecurityManager<'a> {
+        &self.data.security
+    }
+
+    /// Returns the SPI bus, so mocks can be inspected after the event loop has used them.
+    pub fn spi(&mut self) -> &mut SPI {
+        &mut self.data.spi
+    }
+
+    fn recover(&mut self, error: Error<E>) -> Result<(), Error<E>> {
+        let state = self.state;
+        let recovery = match error {
+            // The controller is stuck, so sending the command again would not help.
+            Error::Timeout => Recovery::Restart,
+            // The stray reply may be the late answer to the command itself, so sending it again
+            // could run it twice, which adds services and characteristics twice.
+            Error::UnexpectedEvent => Recovery::Restart,
+            _ => (self.recovery_policy)(state),
+        };
+        match recovery {
+            Recovery::Retry(n) if self.retries < n => {
+                self.data.log(
+                    logger::Level::Warn,
+                    format_args!("Retrying state {:?} after error: {}", state, error),
+                );
+                self.retries += 1;
+                self.action_pending = true;
+                Ok(())
+            }
+            Recovery::Retry(_) | Recovery::Restart if self.restarts < self.max_restarts => {
+                self.data.log(
+                    logger::Level::Warn,
+                    format_args!("Restarting after error in state {:?}: {}", state, error),
+                );
+                self.restarts += 1;
+                self.restart();
+                Ok(())
+            }
+            _ => {
+                self.data.log(
+                    logger::Level::Error,
+                    format_args!("Halting after error in state {:?}: {}", state, error),
+                );
+                Err(error)
+            }
+        }
+    }
+
+    fn restart(&mut self) {
+        self.data.restart();
+        self.state = State::GettingVersionInfo;
+        self.action_pending = true;
+        self.retries = 0;
+    }
 }
 
-type Spi = hal::spi::Spi<
-    stm32f30x::SPI1,
-    (
-        hal::gpio::gpiob::PB3<hal::gpio::AF5>,
-        hal::gpio::gpioa::PA6<hal::gpio::AF5>,
-        hal::gpio::gpioa::PA7<hal::gpio::AF5>,
-    ),
->;
-
-type BlueNRG<'a> = bluenrg::BlueNRG<
-    'a,
-    Spi,
-    hal::gpio::gpioa::PA1<hal::gpio::Output<hal::gpio::PushPull>>,
-    hal::gpio::gpioa::PA8<hal::gpio::Output<hal::gpio::PushPull>>,
-    hal::gpio::gpioa::PA0<hal::gpio::Input<hal::gpio::PullDown>>,
->;
-
-struct ProgramState<'a> {
-    bnrg: &'a mut BlueNRG<'a>,
-    tim6: hal::timer::Timer<stm32f30x::TIM6>,
-    spi: Spi,
+struct ProgramState<'a, SPI: 'a, CS: 'a, RESET: 'a, DR: 'a, TIMER>
+where
+    TIMER: embedded_hal::timer::CountDown,
+{
+    bnrg: &'a mut bluenrg::BlueNRG<'a, SPI, CS, RESET, DR>,
+    timer: TIMER,
+    reset_time: TIMER::Time,
+    spi: SPI,
+
+    config: config::DeviceConfig,
+    advertising_data: advertising::AdvertisingData,
 
     fw_version: Option<bluenrg::Version>,
 
@@ -199,756 +722,1596 @@ struct ProgramState<'a> {
     dev_name_handle: Option<bluenrg::gatt::CharacteristicHandle>,
     appearance_handle: Option<bluenrg::gatt::CharacteristicHandle>,
 
-    acc_service_handle: Option<bluenrg::gatt::ServiceHandle>,
+    database: database::Database,
 
-    environmental_sensor_service_handle: Option<bluenrg::gatt::ServiceHandle>,
-    temperature_characteristic_handle: Option<bluenrg::gatt::CharacteristicHandle>,
-    humidity_characteristic_handle: Option<bluenrg::gatt::CharacteristicHandle>,
-    pressure_characteristic_handle: Option<bluenrg::gatt::CharacteristicHandle>,
+    connection: Option<connection::Connection>,
+    advertising_restart_delay: Option<u16>,
+    advertising_restart_periods_left: Option<u16>,
+    advertising_mode: Option<reconnection::AdvertisingMode>,
+    reconnection: reconnection::ReconnectionPolicy,
+    privacy: Option<privacy::Privacy<'a>>,
+    private_address_owner: privacy::AddressOwner,
+    role: scanner::Role,
+    devices: scanner::DeviceTable,
 
-    time_service_handle: Option<bluenrg::gatt::ServiceHandle>,
-    led_service_handle: Option<bluenrg::gatt::ServiceHandle>,
+    environmental_sensor: Option<&'a mut dyn sensors::EnvironmentalSensor>,
+    led: Option<&'a mut dyn led::LedControl>,
+    clock: Option<&'a mut dyn time::Clock>,
+    accelerometer: Option<&'a mut dyn accelerometer::Accelerometer>,
+    acceleration_period_ms: u32,
+    last_acceleration_ms: Option<u32>,
+    free_fall: accelerometer::FreeFallDetector,
+    free_fall_pending: bool,
+    subscriptions: subscription::Subscriptions,
+    notified_minute: Option<u32>,
+    pending_update: Option<PendingUpdate>,
+    pending_read: Option<hci::ConnectionHandle>,
+    queued_reads: [Option<QueuedRead>; MAX_QUEUED_READS],
+    logger: Option<&'a mut dyn logger::Logger>,
+    security: security::SecurityManager<'a>,
+    timeout_period: Option<TIMER::Time>,
+    timeout_policy: TimeoutPolicy,
+    timeout_periods_left: Option<u16>,
+    ticking: bool,
+    event_queue: Option<&'a queue::PacketQueue>,
 }
 
+impl<'a, SPI, CS, RESET, DR, TIMER> ProgramState<'a, SPI, CS, RESET, DR, TIMER>
+where
+    TIMER: embedded_hal::timer::CountDown,
+{
+    /// Resets the controller, and forgets everything learned from it since initialization
+    /// started.
+    fn restart<E>(&mut self)
+    where
+        SPI: embedded_hal::blocking::spi::Transfer<u8, Error = E>
+            + embedded_hal::blocking::spi::Write<u8, Error = E>,
+        CS: embedded_hal::digital::OutputPin,
+        RESET: embedded_hal::digital::OutputPin,
+        DR: embedded_hal::digital::InputPin,
+        TIMER::Time: Copy,
+    {
+        // The reset uses the timer, so the periods counted so far are lost.
+        self.bnrg.reset(&mut self.timer, self.reset_time);
+        self.ticking = false;
+        self.advertising_restart_periods_left = None;
+        self.database.clear_handles();
+        self.connection = None;
+        self.advertising_mode = None;
+        if let Some(privacy) = self.privacy.as_mut() {
+            privacy.clear();
+        }
+        self.subscriptions.clear();
+        self.free_fall.reset();
+        self.free_fall_pending = false;
+        self.pending_update = None;
+        self.pending_read = None;
+        self.queued_reads = [None; MAX_QUEUED_READS];
+        self.security.clear_pending();
+        self.timeout_periods_left = None;
+        if let Some(queue) = self.event_queue {
+            // Events sent before the reset are stale.
+            queue.clear();
+        }
+    }
+
+    /// Starts the deadline for the controller to answer in `state`, or clears it if the state may
+    /// wait forever.
+    fn start_deadline(&mut self, state: State)
+    where
+        TIMER::Time: Copy,
+    {
+        self.timeout_periods_left = match self.timeout_period {
+            Some(_) => (self.timeout_policy)(state).map(|periods| self.start_ticking(periods)),
+            None => None,
+        };
+    }
+
+    /// Starts counting the delay before advertising restarts, if there is one.
+    fn start_advertising_restart_delay(&mut self)
+    where
+        TIMER::Time: Copy,
+    {
+        self.advertising_restart_periods_left = match self.timeout_period {
+            Some(_) => self
+                .advertising_restart_delay
+                .map(|periods| self.start_ticking(periods)),
+            None => None,
+        };
+    }
+
+    /// Starts the timeout timer, unless it is already counting periods for something else, and
+    /// returns the number of ticks that make at least `periods` whole periods. Requires a timeout
+    /// period.
+    fn start_ticking(&mut self, periods: u16) -> u16
+    where
+        TIMER::Time: Copy,
+    {
+        if self.ticking {
+            // The period in progress is already partly over.
+            return periods.saturating_add(1);
+        }
+        self.timer.start(self.timeout_period.unwrap());
+        self.ticking = true;
+
+        periods
+    }
+
+    /// Counts the periods of the timeout timer that passed since the last call, towards the
+    /// deadline of the current state and the delay before advertising restarts. Never blocks.
+    fn tick(&mut self)
+    where
+        TIMER::Time: Copy,
+    {
+        let period = match self.timeout_period {
+            Some(period) if self.ticking => period,
+            _ => return,
+        };
+        if self.timer.wait().is_err() {
+            return;
+        }
+        self.timeout_periods_left = self.timeout_periods_left.map(|n| n.saturating_sub(1));
+        self.advertising_restart_periods_left = self
+            .advertising_restart_periods_left
+            .map(|n| n.saturating_sub(1));
+        if self.timeout_periods_left.is_some() || self.advertising_restart_periods_left.is_some() {
+            self.timer.start(period);
+        } else {
+            self.ticking = false;
+        }
+    }
+
+    /// Returns true if the deadline started with [`start_deadline`](ProgramState::start_deadline)
+    /// has passed, and clears it.
+    fn deadline_passed(&mut self) -> bool {
+        if self.timeout_periods_left == Some(0) {
+            self.timeout_periods_left = None;
+            return true;
+        }
+
+        false
+    }
+
+    /// Reads the packets waiting in the controller into the event queue, if the data ready
+    /// interrupt has fired since the last call.
+    fn receive<E>(&mut self) -> Result<(), Error<E>>
+    where
+        SPI: embedded_hal::blocking::spi::Transfer<u8, Error = E>
+            + embedded_hal::blocking::spi::Write<u8, Error = E>,
+        CS: embedded_hal::digital::OutputPin,
+        RESET: embedded_hal::digital::OutputPin,
+        DR: embedded_hal::digital::InputPin,
+    {
+        let queue = match self.event_queue {
+            Some(queue) => queue,
+            None => return Ok(()),
+        };
+        if !queue.take_data_ready() {
+            return Ok(());
+        }
+
+        while !queue.is_full() {
+            match self.bnrg.with_spi(&mut self.spi, |c| c.read()) {
+                Ok(packet) => {
+                    let _ = queue.push(packet);
+                }
+                Err(nb::Error::WouldBlock) => return Ok(()),
+                Err(nb::Error::Other(e)) => return Err(Error::from(e)),
+            }
+        }
+
+        // The data ready line stays high, so no new interrupt will fire. Read the rest once
+        // there is room again.
+        queue.data_ready();
+        Ok(())
+    }
+
+    /// Returns the state that handles whatever is left to do for the connection, once the
+    /// command in flight has completed.
+    fn next_connected_state(&mut self) -> State {
+        if self.connection.is_none() {
+            // The client disconnected while the command was in flight.
+            self.pending_update = None;
+            self.pending_read = None;
+            self.queued_reads = [None; MAX_QUEUED_READS];
+            self.security.clear_pending();
+            return State::Disconnected;
+        }
+
+        if self.pending_read.is_some() {
+            State::AllowingRead
+        } else if let Some(read) = self.take_queued_read() {
+            self.read_requested(read.conn_handle, read.attribute_handle)
+        } else if self.security.pending_response().is_some() {
+            State::AnsweringSecurityRequest
+        } else if self.security.bonds_stale() {
+            State::UpdatingBondedDevices
+        } else {
+            State::Connected
+        }
+    }
+
+    /// Returns how the device should advertise now.
+    fn wanted_advertising_mode(&mut self) -> reconnection::AdvertisingMode {
+        let now_ms = self.clock.as_mut().map(|clock| clock.now_ms());
+        self.reconnection
+            .mode(self.security.bonded_devices(), now_ms)
+    }
+
+    /// Returns the state that follows setting the identity address: the one that prepares the
+    /// privacy keys, if privacy is enabled.
+    fn identity_address_set(&self) -> State {
+        match self.privacy.as_ref() {
+            Some(privacy) if privacy.identity_root().is_some() => State::WritingIdentityRoot,
+            Some(_) => State::GeneratingIdentityRoot(0),
+            None => State::InitGatt,
+        }
+    }
+
+    /// Records half of a new identity root, and returns the state that generates the other half
+    /// or writes the identity root to the controller.
+    fn identity_root_generated(&mut self, index: usize, random: u64) -> State {
+        match self
+            .privacy
+            .as_mut()
+            .unwrap()
+            .identity_root_generated(index, random)
+        {
+            None => State::GeneratingIdentityRoot(index + 1),
+            Some(saved) => {
+                if !saved {
+                    self.log(
+                        logger::Level::Warn,
+                        format_args!("Identity root not stored; it changes on the next boot"),
+                    );
+                }
+                State::WritingIdentityRoot
+            }
+        }
+    }
+
+    /// Returns the state that starts advertising, after setting a new private address if one is
+    /// due.
+    fn start_advertising(&mut self) -> State {
+        if self.private_address_due() {
+            State::GeneratingPrivateAddress
+        } else {
+            State::SetDiscoverable
+        }
+    }
+
+    /// Returns the state that starts discovering nearby devices, after setting a new private
+    /// address if one is due.
+    fn start_discovery(&mut self) -> State {
+        if self.private_address_due() {
+            State::GeneratingPrivateAddress
+        } else {
+            State::StartingDiscovery
+        }
+    }
+
+    /// Returns true if the event loop owns the private address, and has to set a new one before
+    /// advertising or discovering.
+    fn private_address_due(&mut self) -> bool {
+        let now_ms = self.clock.as_mut().map(|clock| clock.now_ms());
+        let controller_owned = self.controller_owns_private_address();
+        match self.privacy.as_ref() {
+            Some(privacy) => !controller_owned && privacy.address_due(now_ms),
+            None => false,
+        }
+    }
+
+    /// Returns true if the private address has to change while advertising. Without a clock, it
+    /// only changes when advertising restarts.
+    fn private_address_expired(&mut self) -> bool {
+        let now_ms = self.clock.as_mut().map(|clock| clock.now_ms());
+        now_ms.is_some() && self.private_address_due()
+    }
+
+    /// Returns true if privacy is enabled, and the controller generates the private address
+    /// because GAP Init was given the privacy flag.
+    fn controller_owns_private_address(&self) -> bool {
+        match (self.privacy.as_ref(), self.fw_version.as_ref()) {
+            (Some(_), Some(fw_version)) => {
+                privacy::address_owner(self.private_address_owner, fw_version)
+                    == privacy::AddressOwner::Controller
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the type of the address the controller advertises and scans with.
+    fn own_address_type(&self) -> bluenrg::gap::OwnAddressType {
+        use bluenrg::gap::OwnAddressType;
+
+        match self.config.address {
+            // The controller generates the private address, and falls back to the identity
+            // address.
+            config::Address::Public(_) if self.controller_owns_private_address() => {
+                OwnAddressType::PrivateFallbackPublic
+            }
+            config::Address::StaticRandom(_) if self.controller_owns_private_address() => {
+                OwnAddressType::PrivateFallbackRandom
+            }
+            // The event loop sets the private address as the random address.
+            _ if self.privacy.is_some() => OwnAddressType::Random,
+            config::Address::Public(_) => OwnAddressType::Public,
+            config::Address::StaticRandom(_) => OwnAddressType::Random,
+        }
+    }
+
+    /// Records that the controller uses the new private address.
+    fn private_address_set(&mut self) {
+        let now_ms = self.clock.as_mut().map(|clock| clock.now_ms());
+        let privacy = self.privacy.as_mut().unwrap();
+        privacy.address_set(now_ms);
+        let address = privacy.address();
+        self.log(
+            logger::Level::Info,
+            format_args!("Private address: {:?}", address),
+        );
+    }
+
+    /// Records the devices in an advertising report.
+    fn advertising_report(&mut self, report: &hci::event::LeAdvertisingReport) {
+        let now_ms = self.clock.as_mut().map(|clock| clock.now_ms());
+        for advertisement in report.iter() {
+            let new = self.devices.report(
+                advertisement.address,
+                advertisement.rssi,
+                advertisement.data,
+                now_ms,
+            );
+            if new {
+                self.log(
+                    logger::Level::Info,
+                    format_args!(
+                        "Found {:?}, RSSI {:?}",
+                        advertisement.address, advertisement.rssi
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Records the list of bonded devices read from the controller.
+    fn bonded_devices_read(&mut self, devices: &[hci::BdAddrType]) {
+        let saved = self.security.set_bonded_devices(devices);
+        self.log(
+            logger::Level::Info,
+            format_args!("{} bonded devices", devices.len()),
+        );
+        if !saved {
+            self.log(
+                logger::Level::Warn,
+                format_args!("Could not save the bonded devices"),
+            );
+        }
+    }
+
+    /// Writes a message to the logger, if there is one.
+    fn log(&mut self, level: logger::Level, args: core::fmt::Arguments) {
+        if let Some(logger) = self.logger.as_mut() {
+            logger::log(&mut **logger, level, args);
+        }
+    }
+
+    /// Prepares to answer a read permit request for `attribute_handle`, and returns the state
+    /// that answers it.
+    fn read_requested(
+        &mut self,
+        conn_handle: hci::ConnectionHandle,
+        attribute_handle: u16,
+    ) -> State {
+        self.pending_read = Some(conn_handle);
+
+        let characteristic = match self
+            .database
+            .characteristic_by_value_handle(attribute_handle)
+        {
+            Some(c) => c,
+            None => return State::AllowingRead,
+        };
+        let mut value = [0; MAX_VALUE_LEN];
+        match self.read_value(characteristic.uuid, &mut value) {
+            Some(len) => {
+                self.pending_update = Some(PendingUpdate {
+                    characteristic: characteristic,
+                    value: value,
+                    len: len,
+                });
+                State::UpdatingCharacteristic
+            }
+            None => State::AllowingRead,
+        }
+    }
+
+    /// Keeps a read permit request that arrived while a command was in flight, until
+    /// [`next_connected_state`](ProgramState::next_connected_state) answers it. Returns false if
+    /// there is no room, in which case the client's request times out.
+    fn queue_read(&mut self, conn_handle: hci::ConnectionHandle, attribute_handle: u16) -> bool {
+        match self.queued_reads.iter_mut().find(|read| read.is_none()) {
+            Some(slot) => {
+                *slot = Some(QueuedRead {
+                    conn_handle: conn_handle,
+                    attribute_handle: attribute_handle,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the oldest queued read permit request.
+    fn take_queued_read(&mut self) -> Option<QueuedRead> {
+        let read = self.queued_reads[0].take()?;
+        self.queued_reads.rotate_left(1);
+        Some(read)
+    }
+
+    /// Handles a client writing to an attribute.
+    fn attribute_modified(
+        &mut self,
+        conn_handle: hci::ConnectionHandle,
+        attribute_handle: u16,
+        data: &[u8],
+    ) {
+        let led_value_handle = self
+            .database
+            .characteristic(LED_CHARACTERISTIC_UUID)
+            .map(|c| c.value_handle());
+        if led_value_handle == Some(attribute_handle) {
+            if let (Some(led), Some(command)) = (self.led.as_mut(), led::Command::decode(data)) {
+                led.apply(command);
+            }
+        } else if let Some(c) = self
+            .database
+            .characteristic_by_config_handle(attribute_handle)
+        {
+            let value_handle = c.value_handle();
+            if !self.subscriptions.set(
+                conn_handle,
+                value_handle,
+                subscription::Subscription::decode(data),
+            ) {
+                // The controller already accepted the write, so the client is not told.
+                self.log(
+                    logger::Level::Warn,
+                    format_args!(
+                        "No room for the subscription to attribute {:#06x}",
+                        value_handle
+                    ),
+                );
+            }
+
+            if c.uuid == MINUTE_CHARACTERISTIC_UUID {
+                // The first notification is sent when the next minute starts.
+                self.notified_minute = self
+                    .clock
+                    .as_mut()
+                    .map(|clock| clock.now_ms() / time::MS_PER_MINUTE);
+            }
+        }
+    }
+
+    /// Performs periodic work while waiting for an event in `state`, and returns the state that
+    /// sends any update that became due.
+    fn poll(&mut self, state: State) -> Option<State> {
+        if state == State::Disconnected && self.advertising_restart_due() {
+            return Some(State::ConfiguringWhiteList);
+        }
+
+        if let (State::Complete, Some(mode)) = (state, self.advertising_mode) {
+            // The pairing window opened or closed, or the private address has to change.
+            if self.wanted_advertising_mode() != mode || self.private_address_expired() {
+                return Some(State::StoppingAdvertising);
+            }
+        }
+
+        let now_ms = self.clock.as_mut()?.now_ms();
+        if let Some(led) = self.led.as_mut() {
+            led.tick(now_ms);
+        }
+
+        if state != State::Connected {
+            return None;
+        }
+        let conn_handle = self.connection.as_ref()?.conn_handle;
+
+        // Updating a value makes the controller notify or indicate the subscribed client.
+        let update = self
+            .acceleration_update(conn_handle, now_ms)
+            .or_else(|| self.minute_update(conn_handle, now_ms))?;
+        self.pending_update = Some(update);
+        Some(State::UpdatingCharacteristic)
+    }
+
+    /// Returns true once the delay started when the central disconnected has passed, and clears
+    /// it.
+    fn advertising_restart_due(&mut self) -> bool {
+        match self.advertising_restart_periods_left {
+            Some(0) | None => {
+                self.advertising_restart_periods_left = None;
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    /// Samples the accelerometer if a sample is due, and returns the update that reports it. A
+    /// free fall detected by one sample is reported as soon as the client can receive it.
+    fn acceleration_update(
+        &mut self,
+        conn_handle: hci::ConnectionHandle,
+        now_ms: u32,
+    ) -> Option<PendingUpdate> {
+        if self.free_fall_pending {
+            let update = self.subscribed_update(
+                conn_handle,
+                ACC_FREE_FALL_UUID,
+                &accelerometer::FREE_FALL_DETECTED,
+            );
+            if update.is_some() {
+                self.free_fall_pending = false;
+                return update;
+            }
+        }
+
+        if let Some(last) = self.last_acceleration_ms {
+            if now_ms.wrapping_sub(last) < self.acceleration_period_ms {
+                return None;
+            }
+        }
+        let acceleration = self.accelerometer.as_mut()?.acceleration()?;
+        self.last_acceleration_ms = Some(now_ms);
+        if self.free_fall.update(acceleration, now_ms) {
+            self.free_fall_pending =
+                self.database
+                    .characteristic(ACC_FREE_FALL_UUID)
+                    .map_or(false, |c| {
+                        self.subscriptions
+                            .get(conn_handle, c.value_handle())
+                            .is_active()
+                    });
+        }
+        self.subscribed_update(
+            conn_handle,
+            ACC_UUID,
+            &accelerometer::encode_acceleration(acceleration),
+        )
+    }
+
+    /// Returns the update of the Minute characteristic if a new minute started since the last
+    /// notification.
+    fn minute_update(
+        &mut self,
+        conn_handle: hci::ConnectionHandle,
+        now_ms: u32,
+    ) -> Option<PendingUpdate> {
+        let minute = now_ms / time::MS_PER_MINUTE;
+        if self.notified_minute == Some(minute) {
+            return None;
+        }
+        let update = self.subscribed_update(
+            conn_handle,
+            MINUTE_CHARACTERISTIC_UUID,
+            &time::encode_minutes(now_ms),
+        )?;
+        self.notified_minute = Some(minute);
+        Some(update)
+    }
+
+    /// Returns an update that writes `value` to the characteristic with the given UUID, or `None`
+    /// if the client on `conn_handle` cannot receive it now.
+    fn subscribed_update(
+        &self,
+        conn_handle: hci::ConnectionHandle,
+        uuid: bluenrg::gatt::Uuid,
+        value: &[u8],
+    ) -> Option<PendingUpdate> {
+        let characteristic = self.database.characteristic(uuid)?;
+        if !self
+            .subscriptions
+            .can_send(conn_handle, characteristic.value_handle())
+        {
+            return None;
+        }
+        let mut buffer = [0; MAX_VALUE_LEN];
+        let len = copy_value(value, &mut buffer);
+        Some(PendingUpdate {
+            characteristic: characteristic,
+            value: buffer,
+            len: len,
+        })
+    }
+
+    /// Reads the current value of the characteristic with the given UUID into `buffer`, and
+    /// returns its length. Returns `None` if the value is not available.
+    fn read_value(&mut self, uuid: bluenrg::gatt::Uuid, buffer: &mut [u8]) -> Option<usize> {
+        if uuid == TEMPERATURE_CHARACTERISTIC_UUID {
+            let sensor = self.environmental_sensor.as_mut()?;
+            Some(copy_value(
+                &sensors::encode_temperature(sensor.temperature()?),
+                buffer,
+            ))
+        } else if uuid == PRESSURE_CHARACTERISTIC_UUID {
+            let sensor = self.environmental_sensor.as_mut()?;
+            Some(copy_value(
+                &sensors::encode_pressure(sensor.pressure()?),
+                buffer,
+            ))
+        } else if uuid == HUMIDITY_CHARACTERISTIC_UUID {
+            let sensor = self.environmental_sensor.as_mut()?;
+            Some(copy_value(
+                &sensors::encode_humidity(sensor.humidity()?),
+                buffer,
+            ))
+        } else if uuid == ACC_UUID {
+            let acceleration = self.accelerometer.as_mut()?.acceleration()?;
+            Some(copy_value(
+                &accelerometer::encode_acceleration(acceleration),
+                buffer,
+            ))
+        } else if uuid == TIME_CHARACTERISTIC_UUID {
+            let now_ms = self.clock.as_mut()?.now_ms();
+            Some(copy_value(&time::encode_uptime(now_ms), buffer))
+        } else if uuid == MINUTE_CHARACTERISTIC_UUID {
+            let now_ms = self.clock.as_mut()?.now_ms();
+            Some(copy_value(&time::encode_minutes(now_ms), buffer))
+        } else {
+            None
+        }
+    }
+}
+
+/// Maximum length of a characteristic value the event loop writes to the controller.
+const MAX_VALUE_LEN: usize = 20;
+
+/// A characteristic value waiting to be written to the controller.
+struct PendingUpdate {
+    characteristic: database::CharacteristicHandles,
+    value: [u8; MAX_VALUE_LEN],
+    len: usize,
+}
+
+/// Number of read permit requests kept while a command is in flight.
+const MAX_QUEUED_READS: usize = 4;
+
+/// A read permit request that arrived while a command was in flight.
 #[derive(Copy, Clone)]
-enum State {
+struct QueuedRead {
+    conn_handle: hci::ConnectionHandle,
+    attribute_handle: u16,
+}
+
+fn copy_value(value: &[u8], buffer: &mut [u8]) -> usize {
+    buffer[..value.len()].copy_from_slice(value);
+    value.len()
+}
+
+/// States of the initialization sequence.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum State {
     GettingVersionInfo,
     Resetting,
     SettingAddress,
+    /// Generating half of a new identity root with the controller's random number generator.
+    GeneratingIdentityRoot(usize),
+    /// Writing the identity root to the controller's configuration data.
+    WritingIdentityRoot,
+    /// Deriving the identity resolving key from the identity root.
+    DerivingIdentityKey,
     InitGatt,
     InitGap,
     SetDeviceName,
+    SetAppearance,
     SetAuthenticationRequirement,
-    AddAccService,
-    AddAccFreeFallCharacteristic,
-    AddAccCharacteristic,
-    AddEnvironmentalSensorService,
-    AddTemperatureCharacteristic,
-    AddTemperatureCharacteristicDescriptor,
-    AddPressureCharacteristic,
-    AddPressureCharacteristicDescriptor,
-    AddHumidityCharacteristic,
-    AddHumidityCharacteristicDescriptor,
-    AddTimeService,
-    AddTimeCharacteristic,
-    AddMinuteCharacteristic,
-    AddLedService,
-    AddLedCharacteristic,
+    SetIoCapability,
+    /// Reading the list of bonded devices from the controller.
+    GettingBondedDevices,
+    /// Registering the attribute at the given index of the GATT database.
+    AddAttribute(usize),
     SetTxPowerLevel,
-    SetEmptyScanResponse,
+    SetScanResponse,
+    /// Adding the bonded devices to the controller's white list.
+    ConfiguringWhiteList,
+    /// Generating the random part of a new private address.
+    GeneratingPrivateAddress,
+    /// Hashing the random part of the new private address with the identity resolving key.
+    HashingPrivateAddress,
+    /// Setting the new private address before advertising with it.
+    SettingPrivateAddress,
+    /// Starting to advertise, in the mode the reconnection policy chooses.
     SetDiscoverable,
+    /// Initialization is complete, and the device is advertising.
     Complete,
+    /// Stopping advertising, to restart it in another mode.
+    StoppingAdvertising,
+    /// A central device is connected.
+    Connected,
+    /// The central device disconnected. Advertising restarts after the configured delay, once the
+    /// white list is configured again.
+    Disconnected,
+    /// Writing a new characteristic value to the controller while connected.
+    UpdatingCharacteristic,
+    /// Allowing the pending read request to proceed.
+    AllowingRead,
+    /// Sending the security manager's answer to a passkey, authorization, or rebond request.
+    AnsweringSecurityRequest,
+    /// Reading the list of bonded devices again after a pairing.
+    UpdatingBondedDevices,
+    /// Starting the general discovery procedure, in the observer role.
+    StartingDiscovery,
+    /// Recording the devices heard until the general discovery procedure ends.
+    Discovering,
 }
 
 impl State {
-    fn act<'a>(&self, ps: &mut ProgramState<'a>) {
+    fn attribute(database: &database::Database, index: usize) -> State {
+        if index < database.len() {
+            State::AddAttribute(index)
+        } else {
+            State::SetTxPowerLevel
+        }
+    }
+
+    fn act<'a, SPI, CS, RESET, DR, TIMER, E>(
+        &self,
+        ps: &mut ProgramState<'a, SPI, CS, RESET, DR, TIMER>,
+    ) -> Result<(), Error<E>>
+    where
+        SPI: embedded_hal::blocking::spi::Transfer<u8, Error = E>
+            + embedded_hal::blocking::spi::Write<u8, Error = E>,
+        CS: embedded_hal::digital::OutputPin,
+        RESET: embedded_hal::digital::OutputPin,
+        DR: embedded_hal::digital::InputPin,
+        TIMER: embedded_hal::timer::CountDown,
+        TIMER::Time: Copy,
+        E: Debug,
+    {
         match self {
-            &State::GettingVersionInfo => {
-                ps.bnrg.with_spi(&mut ps.spi, |c| {
-                    block!(c.read_local_version_information()).unwrap()
-                });
-            }
+            &State::GettingVersionInfo => ps
+                .bnrg
+                .with_spi(&mut ps.spi, |c| block!(c.read_local_version_information()))
+                .map_err(Error::Comm),
             &State::Resetting => {
-                ps.bnrg.reset(&mut ps.tim6, 200.hz());
-            }
-            &State::SettingAddress => {
-                ps.bnrg.with_spi(&mut ps.spi, |c| {
-                    let config = bluenrg::hal::ConfigData::public_address(hci::BdAddr([
-                        0x12, 0x34, 0x00, 0xE1, 0x80, 0x02,
-                    ]))
-                    .build();
-                    block!(c.write_config_data(&config)).unwrap()
-                });
+                ps.bnrg.reset(&mut ps.timer, ps.reset_time);
+                Ok(())
             }
-            &State::InitGatt => ps.bnrg.with_spi(&mut ps.spi, |c| {
-                block!(GattCommands::init(c as &mut GattCommands<Error = _>)).unwrap();
-            }),
-            &State::InitGap => ps.bnrg.with_spi(&mut ps.spi, |c| {
-                block!(GapCommands::init(
-                    c as &mut GapCommands<Error = _>,
-                    bluenrg::gap::Role::PERIPHERAL,
-                    false,
-                    7,
-                ))
-                .unwrap();
-            }),
-            &State::SetDeviceName => {
-                let service = ps.gap_service_handle.unwrap();
-                let characteristic = ps.dev_name_handle.unwrap();
-                ps.bnrg
+            &State::SettingAddress => match ps.config.address {
+                config::Address::Public(addr) => ps
+                    .bnrg
                     .with_spi(&mut ps.spi, |c| {
-                        block!(c.update_characteristic_value(
-                            &bluenrg::gatt::UpdateCharacteristicValueParameters {
-                                service_handle: service,
-                                characteristic_handle: characteristic,
-                                offset: 0,
-                                value: b"BlueNRG",
-                            }
-                        ))
+                        let config = bluenrg::hal::ConfigData::public_address(addr).build();
+                        block!(c.write_config_data(&config))
                     })
-                    .unwrap()
-            }
-            &State::SetAuthenticationRequirement => ps.bnrg.with_spi(&mut ps.spi, |c| {
-                block!(c.set_authentication_requirement(
-                    &bluenrg::gap::AuthenticationRequirements {
-                        mitm_protection_required: true,
-                        out_of_band_auth: bluenrg::gap::OutOfBandAuthentication::Disabled,
-                        encryption_key_size_range: (7, 16),
-                        fixed_pin: bluenrg::gap::Pin::Fixed(123456),
-                        bonding_required: true,
-                    }
-                ))
-                .unwrap();
-            }),
-            &State::AddAccService => ps.bnrg.with_spi(&mut ps.spi, |c| {
-                block!(c.add_service(&bluenrg::gatt::AddServiceParameters {
-                    uuid: ACC_SERVICE_UUID,
-                    service_type: bluenrg::gatt::ServiceType::Primary,
-                    max_attribute_records: 7,
-                }))
-                .unwrap();
-            }),
-            &State::AddAccFreeFallCharacteristic => {
-                let acc_service_handle = ps.acc_service_handle.unwrap();
-                let fw_version = ps.fw_version.clone().unwrap();
+                    .map_err(Error::Comm),
+                config::Address::StaticRandom(addr) => ps
+                    .bnrg
+                    .with_spi(&mut ps.spi, |c| block!(c.le_set_random_address(addr)))
+                    .map_err(Error::from),
+            },
+            &State::GeneratingIdentityRoot(_) | &State::GeneratingPrivateAddress => ps
+                .bnrg
+                .with_spi(&mut ps.spi, |c| block!(c.le_rand()))
+                .map_err(Error::Comm),
+            &State::WritingIdentityRoot => {
+                let identity_root = ps.privacy.as_ref().unwrap().identity_root().unwrap();
                 ps.bnrg
                     .with_spi(&mut ps.spi, |c| {
-                        block!(
-                            c.add_characteristic(&bluenrg::gatt::AddCharacteristicParameters {
-                                service_handle: acc_service_handle,
-                                characteristic_uuid: ACC_FREE_FALL_UUID,
-                                characteristic_value_len: 1,
-                                characteristic_properties:
-                                    bluenrg::gatt::CharacteristicProperty::NOTIFY,
-                                security_permissions:
-                                    bluenrg::gatt::CharacteristicPermission::empty(),
-                                gatt_event_mask: bluenrg::gatt::CharacteristicEvent::empty(),
-                                encryption_key_size: must!(
-                                    bluenrg::gatt::EncryptionKeySize::with_value(16)
-                                ),
-                                is_variable: false,
-                                fw_version_before_v72: fw_version.major < 7
-                                    || (fw_version.major == 7 && fw_version.minor < 2)
-                            })
+                        let config = bluenrg::hal::ConfigData::identity_root(
+                            &hci::host::EncryptionKey(identity_root),
                         )
+                        .build();
+                        block!(c.write_config_data(&config))
                     })
-                    .unwrap();
+                    .map_err(Error::Comm)
             }
-            &State::AddAccCharacteristic => {
-                let acc_service_handle = ps.acc_service_handle.unwrap();
-                let fw_version = ps.fw_version.clone().unwrap();
+            &State::DerivingIdentityKey | &State::HashingPrivateAddress => {
+                let privacy = ps.privacy.as_ref().unwrap();
+                let (key, plaintext) = if *self == State::DerivingIdentityKey {
+                    (privacy.identity_root().unwrap(), privacy::IRK_PLAINTEXT)
+                } else {
+                    (privacy.irk().unwrap(), privacy.prand_plaintext())
+                };
                 ps.bnrg
                     .with_spi(&mut ps.spi, |c| {
-                        block!(
-                            c.add_characteristic(&bluenrg::gatt::AddCharacteristicParameters {
-                                service_handle: acc_service_handle,
-                                characteristic_uuid: ACC_UUID,
-                                characteristic_value_len: 6,
-                                characteristic_properties:
-                                    bluenrg::gatt::CharacteristicProperty::NOTIFY
-                                        | bluenrg::gatt::CharacteristicProperty::READ,
-                                security_permissions:
-                                    bluenrg::gatt::CharacteristicPermission::empty(),
-                                gatt_event_mask: bluenrg::gatt::CharacteristicEvent::CONFIRM_READ,
-                                encryption_key_size: must!(
-                                    bluenrg::gatt::EncryptionKeySize::with_value(16)
-                                ),
-                                is_variable: false,
-                                fw_version_before_v72: fw_version.major < 7
-                                    || (fw_version.major == 7 && fw_version.minor < 2)
-                            })
-                        )
+                        block!(c.le_encrypt(&hci::host::AesParameters {
+                            key: hci::host::EncryptionKey(key),
+                            plaintext_data: hci::host::PlaintextBlock(plaintext),
+                        }))
                     })
-                    .unwrap();
-            }
-            &State::AddEnvironmentalSensorService => ps.bnrg.with_spi(&mut ps.spi, |c| {
-                block!(c.add_service(&bluenrg::gatt::AddServiceParameters {
-                    uuid: ENVIRONMENTAL_SENSOR_SERVICE_UUID,
-                    service_type: bluenrg::gatt::ServiceType::Primary,
-                    max_attribute_records: 10,
-                }))
-                .unwrap();
-            }),
-            &State::AddTemperatureCharacteristic => {
-                let env_service_handle = ps.environmental_sensor_service_handle.unwrap();
-                let fw_version = ps.fw_version.clone().unwrap();
+                    .map_err(Error::Comm)
+            }
+            &State::SettingPrivateAddress => {
+                let address = ps.privacy.as_ref().unwrap().address().unwrap();
+                ps.bnrg
+                    .with_spi(&mut ps.spi, |c| block!(c.le_set_random_address(address)))
+                    .map_err(Error::from)
+            }
+            &State::InitGatt => ps
+                .bnrg
+                .with_spi(&mut ps.spi, |c| {
+                    block!(GattCommands::init(c as &mut GattCommands<Error = _>))
+                })
+                .map_err(Error::Comm),
+            &State::InitGap => {
+                let name_len = ps.config.name().len() as u8;
+                let privacy_enabled = ps.controller_owns_private_address();
+                let role = match ps.role {
+                    scanner::Role::Peripheral => bluenrg::gap::Role::PERIPHERAL,
+                    scanner::Role::Observer => bluenrg::gap::Role::CENTRAL,
+                };
                 ps.bnrg
                     .with_spi(&mut ps.spi, |c| {
-                        block!(
-                            c.add_characteristic(&bluenrg::gatt::AddCharacteristicParameters {
-                                service_handle: env_service_handle,
-                                characteristic_uuid: TEMPERATURE_CHARACTERISTIC_UUID,
-                                characteristic_value_len: 2,
-                                characteristic_properties:
-                                    bluenrg::gatt::CharacteristicProperty::READ,
-                                security_permissions:
-                                    bluenrg::gatt::CharacteristicPermission::empty(),
-                                gatt_event_mask: bluenrg::gatt::CharacteristicEvent::CONFIRM_READ,
-                                encryption_key_size: must!(
-                                    bluenrg::gatt::EncryptionKeySize::with_value(16)
-                                ),
-                                is_variable: false,
-                                fw_version_before_v72: fw_version.major < 7
-                                    || (fw_version.major == 7 && fw_version.minor < 2)
-                            })
-                        )
+                        block!(GapCommands::init(
+                            c as &mut GapCommands<Error = _>,
+                            role,
+                            privacy_enabled,
+                            name_len,
+                        ))
                     })
-                    .unwrap();
+                    .map_err(Error::Comm)
             }
-            &State::AddTemperatureCharacteristicDescriptor => {
-                let env_service_handle = ps.environmental_sensor_service_handle.unwrap();
-                let temperature_characteristic_handle =
-                    ps.temperature_characteristic_handle.unwrap();
+            &State::SetDeviceName => {
+                let service = ps.gap_service_handle.unwrap();
+                let characteristic = ps.dev_name_handle.unwrap();
+                let name = ps.config.name();
                 ps.bnrg
                     .with_spi(&mut ps.spi, |c| {
-                        block!(c.add_characteristic_descriptor(
-                            &bluenrg::gatt::AddDescriptorParameters {
-                                service_handle: env_service_handle,
-                                characteristic_handle: temperature_characteristic_handle,
-                                descriptor_uuid: TEMPERATURE_DESCRIPTOR_UUID,
-                                descriptor_value_max_len: 7,
-                                descriptor_value: &[0x0E, 0xFF, 0x2F, 0x27, 0, 0, 0],
-                                security_permissions: bluenrg::gatt::DescriptorPermission::empty(),
-                                access_permissions: bluenrg::gatt::AccessPermission::READ,
-                                gatt_event_mask: bluenrg::gatt::CharacteristicEvent::empty(),
-                                encryption_key_size: must!(
-                                    bluenrg::gatt::EncryptionKeySize::with_value(16)
-                                ),
-                                is_variable: false,
+                        block!(c.update_characteristic_value(
+                            &bluenrg::gatt::UpdateCharacteristicValueParameters {
+                                service_handle: service,
+                                characteristic_handle: characteristic,
+                                offset: 0,
+                                value: name,
                             }
                         ))
                     })
-                    .unwrap();
+                    .map_err(Error::from)
             }
-            &State::AddPressureCharacteristic => {
-                let env_service_handle = ps.environmental_sensor_service_handle.unwrap();
-                let fw_version = ps.fw_version.clone().unwrap();
+            &State::SetAppearance => {
+                let service = ps.gap_service_handle.unwrap();
+                let characteristic = ps.appearance_handle.unwrap();
+                let appearance = [
+                    ps.config.appearance as u8,
+                    (ps.config.appearance >> 8) as u8,
+                ];
                 ps.bnrg
                     .with_spi(&mut ps.spi, |c| {
-                        block!(
-                            c.add_characteristic(&bluenrg::gatt::AddCharacteristicParameters {
-                                service_handle: env_service_handle,
-                                characteristic_uuid: PRESSURE_CHARACTERISTIC_UUID,
-                                characteristic_value_len: 3,
-                                characteristic_properties:
-                                    bluenrg::gatt::CharacteristicProperty::READ,
-                                security_permissions:
-                                    bluenrg::gatt::CharacteristicPermission::empty(),
-                                gatt_event_mask: bluenrg::gatt::CharacteristicEvent::CONFIRM_READ,
-                                encryption_key_size: must!(
-                                    bluenrg::gatt::EncryptionKeySize::with_value(16)
-                                ),
-                                is_variable: false,
-                                fw_version_before_v72: fw_version.major < 7
-                                    || (fw_version.major == 7 && fw_version.minor < 2)
-                            })
-                        )
+                        block!(c.update_characteristic_value(
+                            &bluenrg::gatt::UpdateCharacteristicValueParameters {
+                                service_handle: service,
+                                characteristic_handle: characteristic,
+                                offset: 0,
+                                value: &appearance,
+                            }
+                        ))
                     })
-                    .unwrap();
+                    .map_err(Error::from)
             }
-            &State::AddPressureCharacteristicDescriptor => {
-                let env_service_handle = ps.environmental_sensor_service_handle.unwrap();
-                let pressure_characteristic_handle = ps.pressure_characteristic_handle.unwrap();
+            &State::SetAuthenticationRequirement => {
+                if let security::PinMode::PerBoot(_) = ps.security.pin_mode() {
+                    let passkey = ps.security.fixed_passkey().unwrap();
+                    ps.log(
+                        logger::Level::Info,
+                        format_args!("Passkey for pairing: {:06}", passkey),
+                    );
+                }
+                let pin = ps.security.pin();
                 ps.bnrg
                     .with_spi(&mut ps.spi, |c| {
-                        block!(c.add_characteristic_descriptor(
-                            &bluenrg::gatt::AddDescriptorParameters {
-                                service_handle: env_service_handle,
-                                characteristic_handle: pressure_characteristic_handle,
-                                descriptor_uuid: PRESSURE_DESCRIPTOR_UUID,
-                                descriptor_value_max_len: 7,
-                                descriptor_value: &[0x0F, 0xFB, 0x80, 0x27, 0, 0, 0],
-                                security_permissions: bluenrg::gatt::DescriptorPermission::empty(),
-                                access_permissions: bluenrg::gatt::AccessPermission::READ,
-                                gatt_event_mask: bluenrg::gatt::CharacteristicEvent::empty(),
-                                encryption_key_size: must!(
-                                    bluenrg::gatt::EncryptionKeySize::with_value(16)
-                                ),
-                                is_variable: false,
+                        block!(c.set_authentication_requirement(
+                            &bluenrg::gap::AuthenticationRequirements {
+                                mitm_protection_required: true,
+                                out_of_band_auth: bluenrg::gap::OutOfBandAuthentication::Disabled,
+                                encryption_key_size_range: (7, 16),
+                                fixed_pin: pin,
+                                bonding_required: true,
                             }
                         ))
                     })
-                    .unwrap();
+                    .map_err(Error::from)
             }
-            &State::AddHumidityCharacteristic => {
-                let environmental_sensor_service_handle =
-                    ps.environmental_sensor_service_handle.unwrap();
+            &State::SetIoCapability => {
+                let capability = ps.security.io_capability();
+                ps.bnrg
+                    .with_spi(&mut ps.spi, |c| block!(c.set_io_capability(capability)))
+                    .map_err(Error::Comm)
+            }
+            &State::GettingBondedDevices | &State::UpdatingBondedDevices => ps
+                .bnrg
+                .with_spi(&mut ps.spi, |c| block!(c.get_bonded_devices()))
+                .map_err(Error::Comm),
+            &State::AddAttribute(index) => {
                 let fw_version = ps.fw_version.clone().unwrap();
+                match ps.database.command(index, &fw_version)? {
+                    database::Command::AddService(params) => ps
+                        .bnrg
+                        .with_spi(&mut ps.spi, |c| block!(c.add_service(&params)))
+                        .map_err(Error::Comm),
+                    database::Command::AddCharacteristic(params) => ps
+                        .bnrg
+                        .with_spi(&mut ps.spi, |c| block!(c.add_characteristic(&params)))
+                        .map_err(Error::Comm),
+                    database::Command::AddDescriptor(params) => ps
+                        .bnrg
+                        .with_spi(&mut ps.spi, |c| {
+                            block!(c.add_characteristic_descriptor(&params))
+                        })
+                        .map_err(Error::from),
+                }
+            }
+            &State::SetTxPowerLevel => {
+                let tx_power = ps.config.tx_power;
+                ps.bnrg
+                    .with_spi(&mut ps.spi, |c| block!(c.set_tx_power_level(tx_power)))
+                    .map_err(Error::Comm)
+            }
+            &State::SetScanResponse => {
+                let scan_response = ps.advertising_data.scan_response.as_slice();
                 ps.bnrg
                     .with_spi(&mut ps.spi, |c| {
-                        block!(
-                            c.add_characteristic(&bluenrg::gatt::AddCharacteristicParameters {
-                                service_handle: environmental_sensor_service_handle,
-                                characteristic_uuid: HUMIDITY_CHARACTERISTIC_UUID,
-                                characteristic_value_len: 2,
-                                characteristic_properties:
-                                    bluenrg::gatt::CharacteristicProperty::READ,
-                                security_permissions:
-                                    bluenrg::gatt::CharacteristicPermission::empty(),
-                                gatt_event_mask: bluenrg::gatt::CharacteristicEvent::CONFIRM_READ,
-                                encryption_key_size: must!(
-                                    bluenrg::gatt::EncryptionKeySize::with_value(16)
-                                ),
-                                is_variable: false,
-                                fw_version_before_v72: fw_version.major < 7
-                                    || (fw_version.major == 7 && fw_version.minor < 2)
-                            })
-                        )
+                        block!(c.le_set_scan_response_data(scan_response))
                     })
-                    .unwrap();
+                    .map_err(Error::from)
             }
-            &State::AddHumidityCharacteristicDescriptor => {
-                let env_service_handle = ps.environmental_sensor_service_handle.unwrap();
-                let humidity_characteristic_handle = ps.humidity_characteristic_handle.unwrap();
+            &State::ConfiguringWhiteList => ps
+                .bnrg
+                .with_spi(&mut ps.spi, |c| block!(c.configure_white_list()))
+                .map_err(Error::Comm),
+            &State::SetDiscoverable => {
+                use bluenrg::gap::AdvertisingFilterPolicy;
+                use reconnection::AdvertisingMode;
+
+                let mode = ps.wanted_advertising_mode();
+                ps.advertising_mode = Some(mode);
+                ps.log(logger::Level::Info, format_args!("Advertising: {:?}", mode));
+
+                let address_type = ps.own_address_type();
+                let advertising_interval = ps.config.advertising_interval;
+                let name = ps.config.name();
+                let advertising_data = ps.advertising_data.advertising.as_slice();
                 ps.bnrg
-                    .with_spi(&mut ps.spi, |c| {
-                        block!(c.add_characteristic_descriptor(
-                            &bluenrg::gatt::AddDescriptorParameters {
-                                service_handle: env_service_handle,
-                                characteristic_handle: humidity_characteristic_handle,
-                                descriptor_uuid: HUMIDITY_DESCRIPTOR_UUID,
-                                descriptor_value_max_len: 7,
-                                descriptor_value: &[0x06, 0xFF, 0x00, 0x27, 0, 0, 0],
-                                security_permissions: bluenrg::gatt::DescriptorPermission::empty(),
-                                access_permissions: bluenrg::gatt::AccessPermission::READ,
-                                gatt_event_mask: bluenrg::gatt::CharacteristicEvent::empty(),
-                                encryption_key_size: must!(
-                                    bluenrg::gatt::EncryptionKeySize::with_value(16)
-                                ),
-                                is_variable: false,
+                    .with_spi(&mut ps.spi, |c| match mode {
+                        AdvertisingMode::Discoverable => {
+                            block!(c.set_discoverable(&bluenrg::gap::DiscoverableParameters {
+                                advertising_type:
+                                    bluenrg::gap::AdvertisingType::ConnectableUndirected,
+                                advertising_interval: advertising_interval,
+                                address_type: address_type,
+                                filter_policy: AdvertisingFilterPolicy::AllowConnectionAndScan,
+                                local_name: Some(bluenrg::gap::LocalName::Complete(name)),
+                                advertising_data: advertising_data,
+                                conn_interval: (None, None),
+                            }))
+                        }
+                        AdvertisingMode::WhiteList => block!(c.set_undirected_connectable(
+                            &bluenrg::gap::UndirectedConnectableParameters {
+                                filter_policy: AdvertisingFilterPolicy::WhiteListConnectionAndScan,
+                                own_address_type: address_type,
                             }
-                        ))
+                        )),
+                        AdvertisingMode::Directed(peer) => block!(c.set_direct_connectable(
+                            &bluenrg::gap::DirectConnectableParameters {
+                                own_address_type: address_type,
+                                initiator_address: peer,
+                            }
+                        )),
                     })
-                    .unwrap();
-            }
-            &State::AddTimeService => ps.bnrg.with_spi(&mut ps.spi, |c| {
-                block!(c.add_service(&bluenrg::gatt::AddServiceParameters {
-                    uuid: TIME_SERVICE_UUID,
-                    service_type: bluenrg::gatt::ServiceType::Primary,
-                    max_attribute_records: 7,
-                }))
-                .unwrap();
-            }),
-            &State::AddTimeCharacteristic => {
-                let time_service_handle = ps.time_service_handle.unwrap();
-                let fw_version = ps.fw_version.clone().unwrap();
+                    .map_err(Error::from)
+            }
+            &State::StoppingAdvertising => ps
+                .bnrg
+                .with_spi(&mut ps.spi, |c| block!(c.set_nondiscoverable()))
+                .map_err(Error::Comm),
+            &State::Disconnected => {
+                // Advertising restarts from poll once the delay has passed.
+                ps.start_advertising_restart_delay();
+                Ok(())
+            }
+            &State::UpdatingCharacteristic => {
+                let update = ps.pending_update.as_ref().unwrap();
                 ps.bnrg
                     .with_spi(&mut ps.spi, |c| {
-                        block!(
-                            c.add_characteristic(&bluenrg::gatt::AddCharacteristicParameters {
-                                service_handle: time_service_handle,
-                                characteristic_uuid: TIME_CHARACTERISTIC_UUID,
-                                characteristic_value_len: 4,
-                                characteristic_properties:
-                                    bluenrg::gatt::CharacteristicProperty::READ,
-                                security_permissions:
-                                    bluenrg::gatt::CharacteristicPermission::empty(),
-                                gatt_event_mask: bluenrg::gatt::CharacteristicEvent::empty(),
-                                encryption_key_size: must!(
-                                    bluenrg::gatt::EncryptionKeySize::with_value(16)
-                                ),
-                                is_variable: false,
-                                fw_version_before_v72: fw_version.major < 7
-                                    || (fw_version.major == 7 && fw_version.minor < 2)
-                            })
-                        )
+                        block!(c.update_characteristic_value(
+                            &bluenrg::gatt::UpdateCharacteristicValueParameters {
+                                service_handle: update.characteristic.service,
+                                characteristic_handle: update.characteristic.characteristic,
+                                offset: 0,
+                                value: &update.value[..update.len],
+                            }
+                        ))
                     })
-                    .unwrap();
+                    .map_err(Error::from)
             }
-            &State::AddMinuteCharacteristic => {
-                let time_service_handle = ps.time_service_handle.unwrap();
-                let fw_version = ps.fw_version.clone().unwrap();
+            &State::AllowingRead => {
+                let conn_handle = ps.pending_read.unwrap();
                 ps.bnrg
+                    .with_spi(&mut ps.spi, |c| block!(c.allow_read(conn_handle)))
+                    .map_err(Error::Comm)
+            }
+            &State::AnsweringSecurityRequest => match ps.security.pending_response().unwrap() {
+                security::Response::Passkey(conn_handle, passkey) => ps
+                    .bnrg
                     .with_spi(&mut ps.spi, |c| {
-                        block!(
-                            c.add_characteristic(&bluenrg::gatt::AddCharacteristicParameters {
-                                service_handle: time_service_handle,
-                                characteristic_uuid: MINUTE_CHARACTERISTIC_UUID,
-                                characteristic_value_len: 4,
-                                characteristic_properties:
-                                    bluenrg::gatt::CharacteristicProperty::READ
-                                        | bluenrg::gatt::CharacteristicProperty::NOTIFY,
-                                security_permissions:
-                                    bluenrg::gatt::CharacteristicPermission::empty(),
-                                gatt_event_mask: bluenrg::gatt::CharacteristicEvent::CONFIRM_READ,
-                                encryption_key_size: must!(
-                                    bluenrg::gatt::EncryptionKeySize::with_value(16)
-                                ),
-                                is_variable: true,
-                                fw_version_before_v72: fw_version.major < 7
-                                    || (fw_version.major == 7 && fw_version.minor < 2)
-                            })
-                        )
+                        block!(c.pass_key_response(conn_handle, passkey))
                     })
-                    .unwrap();
-            }
-            &State::AddLedService => ps.bnrg.with_spi(&mut ps.spi, |c| {
-                block!(c.add_service(&bluenrg::gatt::AddServiceParameters {
-                    uuid: LED_SERVICE_UUID,
-                    service_type: bluenrg::gatt::ServiceType::Primary,
-                    max_attribute_records: 7,
-                }))
-                .unwrap();
-            }),
-            &State::AddLedCharacteristic => {
-                let led_service_handle = ps.led_service_handle.unwrap();
-                let fw_version = ps.fw_version.clone().unwrap();
+                    .map_err(Error::from),
+                security::Response::Authorization(conn_handle, authorized) => {
+                    let authorization = if authorized {
+                        bluenrg::gap::Authorization::Authorized
+                    } else {
+                        bluenrg::gap::Authorization::Rejected
+                    };
+                    ps.bnrg
+                        .with_spi(&mut ps.spi, |c| {
+                            block!(c.authorization_response(conn_handle, authorization))
+                        })
+                        .map_err(Error::Comm)
+                }
+                security::Response::Rebond(conn_handle) => ps
+                    .bnrg
+                    .with_spi(&mut ps.spi, |c| block!(c.allow_rebond(conn_handle)))
+                    .map_err(Error::Comm),
+                security::Response::Reject(conn_handle) => ps
+                    .bnrg
+                    .with_spi(&mut ps.spi, |c| {
+                        block!(c.disconnect(conn_handle, hci::Status::AuthFailure))
+                    })
+                    .map_err(Error::from),
+            },
+            &State::StartingDiscovery => {
+                let own_address_type = ps.own_address_type();
                 ps.bnrg
                     .with_spi(&mut ps.spi, |c| {
-                        block!(
-                        c.add_characteristic(&bluenrg::gatt::AddCharacteristicParameters {
-                            service_handle: led_service_handle,
-                            characteristic_uuid: LED_CHARACTERISTIC_UUID,
-                            characteristic_value_len: 4,
-                            characteristic_properties: bluenrg::gatt::CharacteristicProperty::WRITE
-                                | bluenrg::gatt::CharacteristicProperty::WRITE_WITHOUT_RESPONSE,
-                            security_permissions: bluenrg::gatt::CharacteristicPermission::empty(),
-                            gatt_event_mask: bluenrg::gatt::CharacteristicEvent::ATTRIBUTE_WRITE,
-                            encryption_key_size: must!(
-                                bluenrg::gatt::EncryptionKeySize::with_value(16)
-                            ),
-                            is_variable: true,
-                            fw_version_before_v72: fw_version.major < 7
-                                || (fw_version.major == 7 && fw_version.minor < 2)
-                        })
-                    )
+                        block!(c.start_general_discovery_procedure(
+                            &bluenrg::gap::DiscoveryProcedureParameters {
+                                scan_window: scanner::scan_window(),
+                                own_address_type: own_address_type,
+                                // The device table merges the reports, and keeps the RSSI current.
+                                filter_duplicates: false,
+                            }
+                        ))
                     })
-                    .unwrap();
+                    .map_err(Error::Comm)
             }
-            &State::SetTxPowerLevel => ps.bnrg.with_spi(&mut ps.spi, |c| {
-                block!(c.set_tx_power_level(bluenrg::hal::PowerLevel::DbmNeg2_1)).unwrap();
-            }),
-            &State::SetEmptyScanResponse => ps.bnrg.with_spi(&mut ps.spi, |c| {
-                block!(c.le_set_scan_response_data(&[])).unwrap();
-            }),
-            &State::SetDiscoverable => ps.bnrg.with_spi(&mut ps.spi, |c| {
-                block!(c.set_discoverable(&bluenrg::gap::DiscoverableParameters {
-                    advertising_type: bluenrg::gap::AdvertisingType::ConnectableUndirected,
-                    advertising_interval: None,
-                    address_type: bluenrg::gap::OwnAddressType::Public,
-                    filter_policy: bluenrg::gap::AdvertisingFilterPolicy::AllowConnectionAndScan,
-                    local_name: Some(bluenrg::gap::LocalName::Complete(b"BlueNRG")),
-                    advertising_data: &[],
-                    conn_interval: (None, None),
-                }))
-                .unwrap();
-            }),
-            &State::Complete => {
-                cortex_m::asm::wfi();
+            &State::Complete | &State::Connected | &State::Discovering => Ok(()),
+        }
+    }
+
+    fn react<'a, SPI, CS, RESET, DR, TIMER, E>(
+        &self,
+        ps: &mut ProgramState<'a, SPI, CS, RESET, DR, TIMER>,
+    ) -> Result<Self, Error<E>>
+    where
+        SPI: embedded_hal::blocking::spi::Transfer<u8, Error = E>
+            + embedded_hal::blocking::spi::Write<u8, Error = E>,
+        CS: embedded_hal::digital::OutputPin,
+        RESET: embedded_hal::digital::OutputPin,
+        DR: embedded_hal::digital::InputPin,
+        TIMER: embedded_hal::timer::CountDown,
+        TIMER::Time: Copy,
+        E: Debug,
+    {
+        let packet = loop {
+            let result = match ps.event_queue {
+                Some(queue) => {
+                    ps.receive()?;
+                    queue.pop().ok_or(nb::Error::WouldBlock)
+                }
+                None => ps.bnrg.with_spi(&mut ps.spi, |c| c.read()),
+            };
+            match result {
+                Ok(packet) => break packet,
+                Err(nb::Error::WouldBlock) => {
+                    ps.tick();
+                    if ps.deadline_passed() {
+                        return Err(Error::Timeout);
+                    }
+                    if let Some(next) = ps.poll(*self) {
+                        return Ok(next);
+                    }
+                    if ps.event_queue.is_some() {
+                        // Let the caller sleep until the next interrupt.
+                        return Ok(*self);
+                    }
+                }
+                Err(nb::Error::Other(e)) => return Err(Error::from(e)),
             }
+        };
+
+        let hci::host::uart::Packet::Event(e) = packet;
+        if let Some(logger) = ps.logger.as_mut() {
+            log_event(&mut **logger, &e);
         }
+        self.react_to_event(ps, e)
     }
 
-    fn react<'a>(&self, ps: &mut ProgramState<'a>) -> Self {
-        let mut stdout = hio::hstdout().unwrap();
-        match block!(ps.bnrg.with_spi(&mut ps.spi, |c| c.read())) {
-            Ok(p) => {
-                let hci::host::uart::Packet::Event(e) = p;
-                print_event(&mut stdout, e.clone());
-                self.react_to_event(ps, e)
+    /// Returns the opcode of the command this state sends, or `None` if it does not send one.
+    fn opcode<'a, SPI, CS, RESET, DR, TIMER>(
+        &self,
+        ps: &ProgramState<'a, SPI, CS, RESET, DR, TIMER>,
+    ) -> Option<u16>
+    where
+        TIMER: embedded_hal::timer::CountDown,
+    {
+        use command::opcode;
+
+        match self {
+            &State::GettingVersionInfo => Some(opcode::READ_LOCAL_VERSION_INFORMATION),
+            &State::SettingAddress => match ps.config.address {
+                config::Address::Public(_) => Some(opcode::HAL_WRITE_CONFIG_DATA),
+                config::Address::StaticRandom(_) => Some(opcode::LE_SET_RANDOM_ADDRESS),
+            },
+            &State::GeneratingIdentityRoot(_) | &State::GeneratingPrivateAddress => {
+                Some(opcode::LE_RAND)
             }
-            Err(e) => {
-                print_error(&mut stdout, e);
-                *self
+            &State::WritingIdentityRoot => Some(opcode::HAL_WRITE_CONFIG_DATA),
+            &State::DerivingIdentityKey | &State::HashingPrivateAddress => Some(opcode::LE_ENCRYPT),
+            &State::SettingPrivateAddress => Some(opcode::LE_SET_RANDOM_ADDRESS),
+            &State::InitGatt => Some(opcode::GATT_INIT),
+            &State::InitGap => Some(opcode::GAP_INIT),
+            &State::SetDeviceName | &State::SetAppearance | &State::UpdatingCharacteristic => {
+                Some(opcode::GATT_UPDATE_CHARACTERISTIC_VALUE)
             }
+            &State::SetAuthenticationRequirement => {
+                Some(opcode::GAP_SET_AUTHENTICATION_REQUIREMENT)
+            }
+            &State::SetIoCapability => Some(opcode::GAP_SET_IO_CAPABILITY),
+            &State::GettingBondedDevices | &State::UpdatingBondedDevices => {
+                Some(opcode::GAP_GET_BONDED_DEVICES)
+            }
+            &State::AnsweringSecurityRequest => match ps.security.pending_response()? {
+                security::Response::Passkey(..) => Some(opcode::GAP_PASS_KEY_RESPONSE),
+                security::Response::Authorization(..) => Some(opcode::GAP_AUTHORIZATION_RESPONSE),
+                security::Response::Rebond(_) => Some(opcode::GAP_ALLOW_REBOND),
+                security::Response::Reject(_) => Some(opcode::DISCONNECT),
+            },
+            &State::AddAttribute(index) => match ps.database.attribute(index)? {
+                database::Attribute::Service(_) => Some(opcode::GATT_ADD_SERVICE),
+                database::Attribute::Characteristic(_) => Some(opcode::GATT_ADD_CHARACTERISTIC),
+                database::Attribute::Descriptor(_) => {
+                    Some(opcode::GATT_ADD_CHARACTERISTIC_DESCRIPTOR)
+                }
+            },
+            &State::SetTxPowerLevel => Some(opcode::HAL_SET_TX_POWER_LEVEL),
+            &State::SetScanResponse => Some(opcode::LE_SET_SCAN_RESPONSE_DATA),
+            &State::ConfiguringWhiteList => Some(opcode::GAP_CONFIGURE_WHITE_LIST),
+            &State::SetDiscoverable => match ps.advertising_mode? {
+                reconnection::AdvertisingMode::Discoverable => Some(opcode::GAP_SET_DISCOVERABLE),
+                reconnection::AdvertisingMode::WhiteList => {
+                    Some(opcode::GAP_SET_UNDIRECTED_CONNECTABLE)
+                }
+                reconnection::AdvertisingMode::Directed(_) => {
+                    Some(opcode::GAP_SET_DIRECT_CONNECTABLE)
+                }
+            },
+            &State::StoppingAdvertising => Some(opcode::GAP_SET_NONDISCOVERABLE),
+            &State::AllowingRead => Some(opcode::GATT_ALLOW_READ),
+            &State::StartingDiscovery => Some(opcode::GAP_START_GENERAL_DISCOVERY_PROCEDURE),
+            &State::Resetting
+            | &State::Complete
+            | &State::Connected
+            | &State::Disconnected
+            | &State::Discovering => None,
         }
     }
 
-    fn react_to_event<'a>(
+    fn react_to_event<'a, SPI, CS, RESET, DR, TIMER, E>(
         &self,
-        ps: &mut ProgramState<'a>,
+        ps: &mut ProgramState<'a, SPI, CS, RESET, DR, TIMER>,
         event: hci::event::Event<bluenrg::event::BlueNRGEvent>,
-    ) -> Self {
+    ) -> Result<Self, Error<E>>
+    where
+        TIMER: embedded_hal::timer::CountDown,
+    {
+        match command::correlate(self.opcode(ps), event)? {
+            command::Reply::Complete(params) => self.command_complete(ps, params),
+            command::Reply::Accepted => Ok(self.command_accepted(ps)),
+            command::Reply::Unrelated(event) => self.dispatch(ps, event),
+        }
+    }
+
+    /// Handles the controller starting the command this state sent, when the command reports
+    /// its result with a later event.
+    fn command_accepted<'a, SPI, CS, RESET, DR, TIMER>(
+        &self,
+        ps: &mut ProgramState<'a, SPI, CS, RESET, DR, TIMER>,
+    ) -> State
+    where
+        TIMER: embedded_hal::timer::CountDown,
+    {
         match self {
-            &State::GettingVersionInfo => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::ReadLocalVersionInformation(p) =
-                        cmd.return_params
-                    {
-                        must_succeed(&p.status);
-                        ps.fw_version = Some(p.bluenrg_version());
-                        return State::Resetting;
-                    }
-                }
+            &State::StartingDiscovery => State::Discovering,
+            &State::AnsweringSecurityRequest => {
+                // The rejected connection ends with a Disconnection Complete event.
+                ps.security.response_sent();
+                ps.next_connected_state()
             }
-            &State::Resetting => {
-                if let hci::Event::Vendor(bluenrg::event::BlueNRGEvent::HalInitialized(_)) = event {
-                    return State::SettingAddress;
-                }
+            _ => *self,
+        }
+    }
+
+    /// Handles the completion of the command this state sent.
+    fn command_complete<'a, SPI, CS, RESET, DR, TIMER, E>(
+        &self,
+        ps: &mut ProgramState<'a, SPI, CS, RESET, DR, TIMER>,
+        params: hci::event::command::ReturnParameters<bluenrg::event::BlueNRGEvent>,
+    ) -> Result<Self, Error<E>>
+    where
+        TIMER: embedded_hal::timer::CountDown,
+    {
+        use bluenrg::event::command::ReturnParameters as Vendor;
+        use hci::event::command::ReturnParameters;
+
+        match (*self, params) {
+            (State::GettingVersionInfo, ReturnParameters::ReadLocalVersionInformation(p)) => {
+                check_status(p.status)?;
+                ps.fw_version = Some(p.bluenrg_version());
+                Ok(State::Resetting)
             }
-            &State::SettingAddress => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::HalWriteConfigData(s),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&s);
-                        return State::InitGatt;
-                    }
-                }
+            (State::SettingAddress, ReturnParameters::Vendor(Vendor::HalWriteConfigData(s)))
+            | (State::SettingAddress, ReturnParameters::LeSetRandomAddress(s)) => {
+                check_status(s)?;
+                Ok(ps.identity_address_set())
             }
-            &State::InitGatt => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattInit(s),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&s);
-                        return State::InitGap;
-                    }
-                }
+            (State::GeneratingIdentityRoot(index), ReturnParameters::LeRand(p)) => {
+                check_status(p.status)?;
+                Ok(ps.identity_root_generated(index, p.random_number))
             }
-            &State::InitGap => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GapInit(params),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&params.status);
-                        ps.gap_service_handle = Some(params.service_handle);
-                        ps.dev_name_handle = Some(params.dev_name_handle);
-                        ps.appearance_handle = Some(params.appearance_handle);
-                        return State::SetDeviceName;
-                    }
-                }
+            (
+                State::WritingIdentityRoot,
+                ReturnParameters::Vendor(Vendor::HalWriteConfigData(s)),
+            ) => {
+                check_status(s)?;
+                Ok(State::DerivingIdentityKey)
             }
-            &State::SetDeviceName => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattUpdateCharacteristicValue(s),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&s);
-                        return State::SetAuthenticationRequirement;
-                    }
-                }
+            (State::DerivingIdentityKey, ReturnParameters::LeEncrypt(p)) => {
+                check_status(p.status)?;
+                ps.privacy.as_mut().unwrap().set_irk(p.encrypted_data.0);
+                Ok(State::InitGatt)
             }
-            &State::SetAuthenticationRequirement => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GapSetAuthenticationRequirement(
-                            s,
-                        ),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&s);
-                        return State::AddAccService;
-                    }
-                }
+            (State::InitGatt, ReturnParameters::Vendor(Vendor::GattInit(s))) => {
+                check_status(s)?;
+                Ok(State::InitGap)
             }
-            &State::AddAccService => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddService(params),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&params.status);
-                        ps.acc_service_handle = Some(params.service_handle);
-                        return State::AddAccFreeFallCharacteristic;
-                    }
+            (State::InitGap, ReturnParameters::Vendor(Vendor::GapInit(p))) => {
+                check_status(p.status)?;
+                ps.gap_service_handle = Some(p.service_handle);
+                ps.dev_name_handle = Some(p.dev_name_handle);
+                ps.appearance_handle = Some(p.appearance_handle);
+                match ps.role {
+                    scanner::Role::Peripheral => Ok(State::SetDeviceName),
+                    scanner::Role::Observer => Ok(ps.start_discovery()),
                 }
             }
-            &State::AddAccFreeFallCharacteristic => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddCharacteristic(p),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&p.status);
-                        return State::AddAccCharacteristic;
-                    }
-                }
+            (
+                State::SetDeviceName,
+                ReturnParameters::Vendor(Vendor::GattUpdateCharacteristicValue(s)),
+            ) => {
+                check_status(s)?;
+                Ok(State::SetAppearance)
             }
-            &State::AddAccCharacteristic => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddCharacteristic(p),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&p.status);
-                        return State::AddEnvironmentalSensorService;
-                    }
-                }
+            (
+                State::SetAppearance,
+                ReturnParameters::Vendor(Vendor::GattUpdateCharacteristicValue(s)),
+            ) => {
+                check_status(s)?;
+                Ok(State::SetAuthenticationRequirement)
             }
-            &State::AddEnvironmentalSensorService => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddService(params),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&params.status);
-                        ps.environmental_sensor_service_handle = Some(params.service_handle);
-                        return State::AddTemperatureCharacteristic;
-                    }
-                }
+            (
+                State::SetAuthenticationRequirement,
+                ReturnParameters::Vendor(Vendor::GapSetAuthenticationRequirement(s)),
+            ) => {
+                check_status(s)?;
+                Ok(State::SetIoCapability)
             }
-            &State::AddTemperatureCharacteristic => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddCharacteristic(params),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&params.status);
-                        ps.temperature_characteristic_handle = Some(params.characteristic_handle);
-                        return State::AddTemperatureCharacteristicDescriptor;
-                    }
-                }
+            (State::SetIoCapability, ReturnParameters::Vendor(Vendor::GapSetIoCapability(s))) => {
+                check_status(s)?;
+                Ok(State::GettingBondedDevices)
             }
-            &State::AddTemperatureCharacteristicDescriptor => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddCharacteristicDescriptor(
-                            p,
-                        ),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&p.status);
-                        return State::AddPressureCharacteristic;
-                    }
+            (
+                State::GettingBondedDevices,
+                ReturnParameters::Vendor(Vendor::GapGetBondedDevices(p)),
+            ) => {
+                check_status(p.status)?;
+                ps.bonded_devices_read(p.bonded_addresses());
+                Ok(State::attribute(&ps.database, 0))
+            }
+            (State::AddAttribute(index), ReturnParameters::Vendor(Vendor::GattAddService(p))) => {
+                attribute_added(&mut ps.database, index, p.status, p.service_handle.0)
+            }
+            (
+                State::AddAttribute(index),
+                ReturnParameters::Vendor(Vendor::GattAddCharacteristic(p)),
+            ) => attribute_added(&mut ps.database, index, p.status, p.characteristic_handle.0),
+            (
+                State::AddAttribute(index),
+                ReturnParameters::Vendor(Vendor::GattAddCharacteristicDescriptor(p)),
+            ) => attribute_added(&mut ps.database, index, p.status, p.descriptor_handle.0),
+            (State::SetTxPowerLevel, ReturnParameters::Vendor(Vendor::HalSetTxPowerLevel(s))) => {
+                check_status(s)?;
+                Ok(State::SetScanResponse)
+            }
+            (State::SetScanResponse, ReturnParameters::LeSetScanResponseData(s)) => {
+                check_status(s)?;
+                Ok(State::ConfiguringWhiteList)
+            }
+            (
+                State::ConfiguringWhiteList,
+                ReturnParameters::Vendor(Vendor::GapConfigureWhiteList(s)),
+            ) => {
+                check_status(s)?;
+                Ok(ps.start_advertising())
+            }
+            (State::GeneratingPrivateAddress, ReturnParameters::LeRand(p)) => {
+                check_status(p.status)?;
+                ps.privacy
+                    .as_mut()
+                    .unwrap()
+                    .prand_generated(p.random_number);
+                Ok(State::HashingPrivateAddress)
+            }
+            (State::HashingPrivateAddress, ReturnParameters::LeEncrypt(p)) => {
+                check_status(p.status)?;
+                ps.privacy
+                    .as_mut()
+                    .unwrap()
+                    .prand_hashed(&p.encrypted_data.0);
+                Ok(State::SettingPrivateAddress)
+            }
+            (State::SettingPrivateAddress, ReturnParameters::LeSetRandomAddress(s)) => {
+                check_status(s)?;
+                ps.private_address_set();
+                match ps.role {
+                    scanner::Role::Peripheral => Ok(State::SetDiscoverable),
+                    scanner::Role::Observer => Ok(State::StartingDiscovery),
                 }
             }
-            &State::AddPressureCharacteristic => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddCharacteristic(params),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&params.status);
-                        ps.pressure_characteristic_handle = Some(params.characteristic_handle);
-                        return State::AddPressureCharacteristicDescriptor;
-                    }
+            (State::SetDiscoverable, ReturnParameters::Vendor(Vendor::GapSetDiscoverable(s)))
+            | (
+                State::SetDiscoverable,
+                ReturnParameters::Vendor(Vendor::GapSetUndirectedConnectable(s)),
+            )
+            | (
+                State::SetDiscoverable,
+                ReturnParameters::Vendor(Vendor::GapSetDirectConnectable(s)),
+            ) => {
+                check_status(s)?;
+                Ok(State::Complete)
+            }
+            (
+                State::StoppingAdvertising,
+                ReturnParameters::Vendor(Vendor::GapSetNonDiscoverable(s)),
+            ) => {
+                // Advertising may already have stopped, because a central device connected or
+                // directed advertising timed out while the command was in flight.
+                if ps.advertising_mode.take().is_some() {
+                    check_status(s)?;
+                }
+                if ps.connection.is_some() {
+                    return Ok(ps.next_connected_state());
                 }
+                Ok(ps.start_advertising())
             }
-            &State::AddPressureCharacteristicDescriptor => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddCharacteristicDescriptor(
-                            p,
-                        ),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&p.status);
-                        return State::AddHumidityCharacteristic;
-                    }
+            (
+                State::UpdatingCharacteristic,
+                ReturnParameters::Vendor(Vendor::GattUpdateCharacteristicValue(s)),
+            ) => {
+                check_status(s)?;
+                if let (Some(update), Some(conn)) = (ps.pending_update.take(), ps.connection) {
+                    ps.subscriptions
+                        .sent(conn.conn_handle, update.characteristic.value_handle());
                 }
+                Ok(ps.next_connected_state())
             }
-            &State::AddHumidityCharacteristic => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddCharacteristic(params),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&params.status);
-                        ps.humidity_characteristic_handle = Some(params.characteristic_handle);
-                        return State::AddHumidityCharacteristicDescriptor;
-                    }
+            (State::AllowingRead, ReturnParameters::Vendor(Vendor::GattAllowRead(s))) => {
+                ps.pending_read = None;
+                if ps.connection.is_some() {
+                    check_status(s)?;
                 }
+                Ok(ps.next_connected_state())
             }
-            &State::AddHumidityCharacteristicDescriptor => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddCharacteristicDescriptor(
-                            p,
-                        ),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&p.status);
-                        return State::AddTimeService;
-                    }
+            (
+                State::AnsweringSecurityRequest,
+                ReturnParameters::Vendor(Vendor::GapPassKeyResponse(s)),
+            )
+            | (
+                State::AnsweringSecurityRequest,
+                ReturnParameters::Vendor(Vendor::GapAuthorizationResponse(s)),
+            )
+            | (
+                State::AnsweringSecurityRequest,
+                ReturnParameters::Vendor(Vendor::GapAllowRebond(s)),
+            ) => {
+                ps.security.response_sent();
+                if ps.connection.is_some() {
+                    check_status(s)?;
                 }
+                Ok(ps.next_connected_state())
+            }
+            (
+                State::UpdatingBondedDevices,
+                ReturnParameters::Vendor(Vendor::GapGetBondedDevices(p)),
+            ) => {
+                check_status(p.status)?;
+                ps.bonded_devices_read(p.bonded_addresses());
+                Ok(ps.next_connected_state())
             }
-            &State::AddTimeService => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddService(params),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&params.status);
-                        ps.time_service_handle = Some(params.service_handle);
-                        return State::AddTimeCharacteristic;
+
+            // `correlate` only returns the parameters of the command this state sent.
+            _ => Err(Error::UnexpectedEvent),
+        }
+    }
+
+    /// Handles an event that does not answer the command this state sent.
+    fn dispatch<'a, SPI, CS, RESET, DR, TIMER, E>(
+        &self,
+        ps: &mut ProgramState<'a, SPI, CS, RESET, DR, TIMER>,
+        event: hci::event::Event<bluenrg::event::BlueNRGEvent>,
+    ) -> Result<Self, Error<E>>
+    where
+        TIMER: embedded_hal::timer::CountDown,
+    {
+        use bluenrg::event::BlueNRGEvent;
+
+        match event {
+            hci::Event::Vendor(BlueNRGEvent::HalInitialized(_)) if *self == State::Resetting => {
+                Ok(State::SettingAddress)
+            }
+            hci::Event::LeConnectionComplete(c)
+                if *self == State::Complete || *self == State::StoppingAdvertising =>
+            {
+                if let hci::Status::Success = c.status {
+                    ps.connection = Some(connection::Connection::from_event(&c));
+                    ps.advertising_mode = None;
+                    ps.reconnection.connected();
+                    if *self == State::StoppingAdvertising {
+                        // The command in flight finishes first.
+                        return Ok(*self);
                     }
+                    return Ok(State::Connected);
                 }
-            }
-            &State::AddTimeCharacteristic => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddCharacteristic(p),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&p.status);
-                        return State::AddMinuteCharacteristic;
+
+                if let Some(reconnection::AdvertisingMode::Directed(_)) = ps.advertising_mode {
+                    // The central device did not reconnect before directed advertising timed
+                    // out.
+                    ps.advertising_mode = None;
+                    ps.reconnection.directed_advertising_ended();
+                    if *self == State::Complete {
+                        return Ok(ps.start_advertising());
                     }
                 }
+                Ok(*self)
             }
-            &State::AddMinuteCharacteristic => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddCharacteristic(p),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&p.status);
-                        return State::AddLedService;
-                    }
+            hci::Event::LeAdvertisingReport(report) => {
+                ps.advertising_report(&report);
+                Ok(*self)
+            }
+            hci::Event::Vendor(BlueNRGEvent::GapProcedureComplete(_))
+                if *self == State::Discovering =>
+            {
+                // The general discovery procedure ends after about 10 s. Start it again, so the
+                // table keeps up with the devices that come and go.
+                Ok(ps.start_discovery())
+            }
+            hci::Event::LeConnectionUpdateComplete(u) => {
+                if let (hci::Status::Success, Some(conn)) = (u.status, ps.connection.as_mut()) {
+                    conn.update(&u);
                 }
+                Ok(*self)
             }
-            &State::AddLedService => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddService(params),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&params.status);
-                        ps.led_service_handle = Some(params.service_handle);
-                        return State::AddLedCharacteristic;
+            hci::Event::DisconnectionComplete(d) => {
+                if let hci::Status::Success = d.status {
+                    if let Some(conn) = ps.connection.take() {
+                        ps.reconnection
+                            .disconnected(conn.peer_address, ps.security.bonded_devices());
+                    }
+                    ps.subscriptions.remove_connection(d.conn_handle);
+                    ps.queued_reads = [None; MAX_QUEUED_READS];
+                    if *self == State::Connected {
+                        return Ok(State::Disconnected);
                     }
                 }
+
+                // A command in flight finishes first, and then sees the connection is gone.
+                Ok(*self)
             }
-            &State::AddLedCharacteristic => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GattAddCharacteristic(p),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&p.status);
-                        return State::SetTxPowerLevel;
-                    }
+            hci::Event::Vendor(BlueNRGEvent::GattServerConfirmation(conn_handle)) => {
+                ps.subscriptions.confirmed(conn_handle);
+                Ok(*self)
+            }
+            hci::Event::Vendor(BlueNRGEvent::GattAttributeModified(m)) => {
+                ps.attribute_modified(m.conn_handle, m.attr_handle.0, m.data());
+                Ok(*self)
+            }
+            hci::Event::Vendor(BlueNRGEvent::GattReadPermitRequest(r)) => {
+                let attribute_handle = r.attribute_handle.0;
+                if *self == State::Connected {
+                    return Ok(ps.read_requested(r.conn_handle, attribute_handle));
+                }
+
+                // Answered once the command in flight completes.
+                if !ps.queue_read(r.conn_handle, attribute_handle) {
+                    ps.log(
+                        logger::Level::Warn,
+                        format_args!(
+                            "No room for the read request of attribute {:#06x}",
+                            attribute_handle
+                        ),
+                    );
                 }
+                Ok(*self)
             }
-            &State::SetTxPowerLevel => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::HalSetTxPowerLevel(s),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&s);
-                        return State::SetEmptyScanResponse;
+            hci::Event::Vendor(e) => {
+                let conn_handle = ps.connection.map(|c| c.conn_handle);
+                let now_ms = ps.clock.as_mut().map(|clock| clock.now_ms());
+                ps.security
+                    .set_pairing_window_open(ps.reconnection.is_pairing_window_open(now_ms));
+                if !ps.security.handle_event(&e, conn_handle) {
+                    return Ok(*self);
+                }
+                if let (BlueNRGEvent::GapPairingComplete(_), Some(outcome)) =
+                    (&e, ps.security.last_pairing())
+                {
+                    ps.log(
+                        logger::Level::Info,
+                        format_args!("Pairing finished: {:?}", outcome.status),
+                    );
+                    if outcome.status == security::PairingStatus::Success {
+                        ps.reconnection.close_pairing_window();
                     }
                 }
+
+                // Answers wait for any command in flight.
+                if *self == State::Connected {
+                    return Ok(ps.next_connected_state());
+                }
+                Ok(*self)
             }
-            &State::SetEmptyScanResponse => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::LeSetScanResponseData(s) =
-                        cmd.return_params
-                    {
-                        must_succeed(&s);
-                        return State::SetDiscoverable;
-                    }
+
+            hci::Event::CommandComplete(cmd) => {
+                // A late reply, for example to a command that timed out, or flow control credits.
+                if let Some(opcode) = command::return_opcode(&cmd.return_params) {
+                    ps.log(
+                        logger::Level::Warn,
+                        format_args!(
+                            "Ignored a reply to command {:#06x}, which is not in flight",
+                            opcode
+                        ),
+                    );
                 }
+                Ok(*self)
             }
-            &State::SetDiscoverable => {
-                if let hci::Event::CommandComplete(cmd) = event {
-                    if let hci::event::command::ReturnParameters::Vendor(
-                        bluenrg::event::command::ReturnParameters::GapSetDiscoverable(s),
-                    ) = cmd.return_params
-                    {
-                        must_succeed(&s);
-                        return State::Complete;
-                    }
+            hci::Event::CommandStatus(status) => {
+                if status.opcode.0 != 0 {
+                    ps.log(
+                        logger::Level::Warn,
+                        format_args!(
+                            "Ignored the status of command {:#06x}, which is not in flight: {:?}",
+                            status.opcode.0, status.status
+                        ),
+                    );
                 }
+                Ok(*self)
             }
-            &State::Complete => (),
-        }
 
-        *self
+            // Already logged; nothing else to do.
+            _ => Ok(*self),
+        }
     }
 }
+
+/// Records the handle of the attribute at `index`, and returns the state that registers the
+/// next one.
+fn attribute_added<E>(
+    database: &mut database::Database,
+    index: usize,
+    status: hci::Status<bluenrg::event::Status>,
+    handle: u16,
+) -> Result<State, Error<E>> {
+    check_status(status)?;
+    database.set_handle(index, handle);
+    Ok(State::attribute(database, index + 1))
+}
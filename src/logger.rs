@@ -0,0 +1,364 @@
+//! Leveled log messages, written to a pluggable sink.
+//!
+//! The event loop writes its messages to a [`Logger`] set with
+//! [`EventLoop::set_logger`](crate::EventLoop::set_logger), and drops them if there is none.
+//! Messages above [`MAX_LEVEL`] are compiled out. The limit is chosen with the `max-level-off`,
+//! `max-level-error`, `max-level-warn`, `max-level-info`, and `max-level-debug` features; without
+//! any of them, every level is kept.
+//!
+//! The [`ItmLogger`] and [`UartLogger`] keep working without a debugger. The
+//! `SemihostingLogger` does not, so it is only built with the `semihosting` feature.
+
+use core::fmt;
+use core::fmt::Write;
+
+/// Importance of a log message.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// The event loop cannot continue.
+    Error,
+
+    /// Something failed, but the event loop recovered.
+    Warn,
+
+    /// Changes in the state of the device, such as connections.
+    Info,
+
+    /// Events received from the controller.
+    Debug,
+
+    /// Everything else.
+    Trace,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        })
+    }
+}
+
+/// Most verbose level that is compiled in. `None` if logging is disabled.
+#[cfg(feature = "max-level-off")]
+pub const MAX_LEVEL: Option<Level> = None;
+
+/// Most verbose level that is compiled in. `None` if logging is disabled.
+#[cfg(all(not(feature = "max-level-off"), feature = "max-level-error"))]
+pub const MAX_LEVEL: Option<Level> = Some(Level::Error);
+
+/// Most verbose level that is compiled in. `None` if logging is disabled.
+#[cfg(all(
+    not(any(feature = "max-level-off", feature = "max-level-error")),
+    feature = "max-level-warn"
+))]
+pub const MAX_LEVEL: Option<Level> = Some(Level::Warn);
+
+/// Most verbose level that is compiled in. `None` if logging is disabled.
+#[cfg(all(
+    not(any(
+        feature = "max-level-off",
+        feature = "max-level-error",
+        feature = "max-level-warn"
+    )),
+    feature = "max-level-info"
+))]
+pub const MAX_LEVEL: Option<Level> = Some(Level::Info);
+
+/// Most verbose level that is compiled in. `None` if logging is disabled.
+#[cfg(all(
+    not(any(
+        feature = "max-level-off",
+        feature = "max-level-error",
+        feature = "max-level-warn",
+        feature = "max-level-info"
+    )),
+    feature = "max-level-debug"
+))]
+pub const MAX_LEVEL: Option<Level> = Some(Level::Debug);
+
+/// Most verbose level that is compiled in. `None` if logging is disabled.
+#[cfg(not(any(
+    feature = "max-level-off",
+    feature = "max-level-error",
+    feature = "max-level-warn",
+    feature = "max-level-info",
+    feature = "max-level-debug"
+)))]
+pub const MAX_LEVEL: Option<Level> = Some(Level::Trace);
+
+/// Returns true if messages with the given level are compiled in.
+pub fn enabled(level: Level) -> bool {
+    match MAX_LEVEL {
+        Some(max) => level <= max,
+        None => false,
+    }
+}
+
+/// A sink for log messages.
+///
+/// Implementations must not block indefinitely or panic when their output is unavailable, so the
+/// event loop keeps running without a debugger or a listener attached.
+pub trait Logger {
+    /// Writes a message. `args` does not end with a newline.
+    fn log(&mut self, level: Level, args: fmt::Arguments);
+}
+
+/// Writes a message to `logger` if its level is compiled in.
+pub fn log(logger: &mut dyn Logger, level: Level, args: fmt::Arguments) {
+    if enabled(level) {
+        logger.log(level, args);
+    }
+}
+
+fn write_line<W: Write>(out: &mut W, level: Level, args: fmt::Arguments) -> fmt::Result {
+    writeln!(out, "[{}] {}", level, args)
+}
+
+/// Writes messages to the debugger's console through semihosting.
+///
+/// Semihosting stops the processor until the debugger answers, and faults when no debugger is
+/// attached, so this logger is only suitable while debugging.
+#[cfg(feature = "semihosting")]
+pub struct SemihostingLogger {
+    stdout: Option<cortex_m_semihosting::hio::HStdout>,
+    failed: bool,
+}
+
+#[cfg(feature = "semihosting")]
+impl SemihostingLogger {
+    /// Creates a logger. The console is opened with the first message.
+    pub fn new() -> SemihostingLogger {
+        SemihostingLogger {
+            stdout: None,
+            failed: false,
+        }
+    }
+}
+
+#[cfg(feature = "semihosting")]
+impl Default for SemihostingLogger {
+    fn default() -> SemihostingLogger {
+        SemihostingLogger::new()
+    }
+}
+
+#[cfg(feature = "semihosting")]
+impl Logger for SemihostingLogger {
+    fn log(&mut self, level: Level, args: fmt::Arguments) {
+        if self.stdout.is_none() && !self.failed {
+            self.stdout = cortex_m_semihosting::hio::hstdout().ok();
+            self.failed = self.stdout.is_none();
+        }
+        if let Some(stdout) = self.stdout.as_mut() {
+            let _ = write_line(stdout, level, args);
+        }
+    }
+}
+
+/// Writes messages to a stimulus port of the ITM, which the debug probe reads from the SWO pin.
+///
+/// Messages are dropped while the ITM or the port is disabled, which is the case until a debug
+/// probe enables tracing.
+pub struct ItmLogger {
+    itm: cortex_m::peripheral::ITM,
+    port: usize,
+}
+
+impl ItmLogger {
+    /// Creates a logger that writes to the given stimulus port.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `port` is not less than 32.
+    pub fn new(itm: cortex_m::peripheral::ITM, port: usize) -> ItmLogger {
+        assert!(port < 32, "invalid ITM stimulus port");
+        ItmLogger {
+            itm: itm,
+            port: port,
+        }
+    }
+
+    fn port_enabled(&self) -> bool {
+        const TCR_ITMENA: u32 = 1 << 0;
+
+        self.itm.tcr.read() & TCR_ITMENA != 0 && self.itm.ter[0].read() & (1 << self.port) != 0
+    }
+}
+
+impl Logger for ItmLogger {
+    fn log(&mut self, level: Level, args: fmt::Arguments) {
+        if self.port_enabled() {
+            let stim = &mut self.itm.stim[self.port];
+            cortex_m::itm::write_fmt(stim, format_args!("[{}] {}\n", level, args));
+        }
+    }
+}
+
+/// Writes messages to a serial port.
+pub struct UartLogger<TX> {
+    tx: TX,
+}
+
+impl<TX> UartLogger<TX>
+where
+    TX: embedded_hal::serial::Write<u8>,
+{
+    /// Creates a logger that writes to the transmit half of a serial port.
+    pub fn new(tx: TX) -> UartLogger<TX> {
+        UartLogger { tx: tx }
+    }
+
+    /// Returns the transmit half of the serial port.
+    pub fn release(self) -> TX {
+        self.tx
+    }
+}
+
+impl<TX> Write for UartLogger<TX>
+where
+    TX: embedded_hal::serial::Write<u8>,
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            block!(self.tx.write(byte)).map_err(|_| fmt::Error)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<TX> Logger for UartLogger<TX>
+where
+    TX: embedded_hal::serial::Write<u8>,
+{
+    fn log(&mut self, level: Level, args: fmt::Arguments) {
+        // A failed write loses the rest of the message, but not the next one.
+        let _ = write_line(self, level, args);
+    }
+}
+
+/// Size of the buffer of a [`MemoryLogger`], in bytes.
+pub const MEMORY_LOG_LEN: usize = 2048;
+
+/// Keeps messages in memory, for tests on the host.
+///
+/// Messages are kept until the buffer is full. Messages that do not fit are dropped whole, and
+/// counted.
+pub struct MemoryLogger {
+    buffer: [u8; MEMORY_LOG_LEN],
+    len: usize,
+    dropped: usize,
+}
+
+impl MemoryLogger {
+    /// Creates an empty log.
+    pub fn new() -> MemoryLogger {
+        MemoryLogger {
+            buffer: [0; MEMORY_LOG_LEN],
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Returns the messages logged so far, one per line.
+    pub fn contents(&self) -> &str {
+        // Only whole messages are kept, so the buffer is always valid UTF-8.
+        core::str::from_utf8(&self.buffer[..self.len]).unwrap_or("")
+    }
+
+    /// Returns the number of messages that did not fit in the buffer.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Removes every message.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.dropped = 0;
+    }
+}
+
+impl Default for MemoryLogger {
+    fn default() -> MemoryLogger {
+        MemoryLogger::new()
+    }
+}
+
+struct Cursor<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> Write for Cursor<'b> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buffer.len() {
+            return Err(fmt::Error);
+        }
+        self.buffer[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+
+        Ok(())
+    }
+}
+
+impl Logger for MemoryLogger {
+    fn log(&mut self, level: Level, args: fmt::Arguments) {
+        let mut cursor = Cursor {
+            buffer: &mut self.buffer[self.len..],
+            len: 0,
+        };
+        match write_line(&mut cursor, level, args) {
+            Ok(()) => self.len += cursor.len,
+            Err(_) => self.dropped += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_messages_in_memory() {
+        let mut logger = MemoryLogger::new();
+        log(
+            &mut logger,
+            Level::Info,
+            format_args!("Connected to {:?}", 1),
+        );
+        log(&mut logger, Level::Warn, format_args!("Retrying"));
+        assert_eq!(
+            logger.contents(),
+            "[INFO] Connected to 1\n[WARN] Retrying\n"
+        );
+        assert_eq!(logger.dropped(), 0);
+
+        logger.clear();
+        assert_eq!(logger.contents(), "");
+    }
+
+    #[test]
+    fn drops_whole_messages_that_do_not_fit() {
+        let mut logger = MemoryLogger::new();
+        let long = [b'x'; MEMORY_LOG_LEN - 20];
+        let long = core::str::from_utf8(&long).unwrap();
+        log(&mut logger, Level::Error, format_args!("{}", long));
+        log(&mut logger, Level::Error, format_args!("{}", long));
+        log(&mut logger, Level::Info, format_args!("ok"));
+
+        // The second message is dropped, but the shorter one after it still fits.
+        assert_eq!(logger.dropped(), 1);
+        assert!(logger.contents().starts_with("[ERROR] xxx"));
+        assert!(logger.contents().ends_with("xxx\n[INFO] ok\n"));
+        assert_eq!(
+            logger.contents().len(),
+            "[ERROR] \n[INFO] ok\n".len() + long.len()
+        );
+    }
+}
@@ -1,36 +1,44 @@
-#![no_std]
-#![feature(lang_items)]
-#![feature(start)]
-
-extern crate bluenrg;
-extern crate bluetooth_hci as hci;
-extern crate cortex_m;
-extern crate cortex_m_rt;
-extern crate cortex_m_semihosting;
-extern crate embedded_hal;
-extern crate nb;
-extern crate panic_semihosting;
-extern crate spbtle_rf_stm32f303re_test;
-extern crate stm32f30x;
-extern crate stm32f30x_hal as hal;
-
-use hal::flash::FlashExt;
-use hal::gpio::GpioExt;
-use hal::rcc::RccExt;
-use hal::time::U32Ext;
-use spbtle_rf_stm32f303re_test as main;
-
-#[start]
-#[inline(never)]
-fn main(_argc: isize, _argv: *const *const u8) -> isize {
-    cortex_m::interrupt::free(|_cs| {
+//! The firmware for the NUCLEO-F303RE with an X-NUCLEO-IDB05A1 expansion board.
+//!
+//! The firmware only builds for the STM32F303RE. On the host, where `cargo test` builds every
+//! binary, `main` does nothing.
+
+#![cfg_attr(target_arch = "arm", no_std)]
+#![cfg_attr(target_arch = "arm", no_main)]
+
+#[cfg(target_arch = "arm")]
+mod firmware {
+    // Links the panic handler. Without a debugger, semihosting would fault.
+    #[cfg(not(feature = "semihosting"))]
+    extern crate panic_halt;
+    #[cfg(feature = "semihosting")]
+    extern crate panic_semihosting;
+
+    use core::cell::RefCell;
+    use cortex_m_rt::entry;
+    use embedded_hal::digital::InputPin;
+    use hal::flash::FlashExt;
+    use hal::gpio::GpioExt;
+    use hal::rcc::RccExt;
+    use hal::time::U32Ext;
+    use spbtle_rf_stm32f303re_test as main;
+    use stm32f30x::interrupt;
+    use stm32f30x_hal as hal;
+
+    /// Packets received from the BlueNRG, filled after the data ready interrupt fires.
+    static EVENT_QUEUE: main::queue::PacketQueue = main::queue::EventQueue::new();
+
+    #[entry]
+    fn main() -> ! {
         // Enable I2C1
         let peripherals = stm32f30x::Peripherals::take().unwrap();
+        let mut core_peripherals = cortex_m::Peripherals::take().unwrap();
         peripherals.RCC.ahbenr.modify(|_, w| w.iopaen().set_bit());
 
         let mut rcc = peripherals.RCC.constrain();
         let mut gpioa = peripherals.GPIOA.split(&mut rcc.ahb);
         let mut gpiob = peripherals.GPIOB.split(&mut rcc.ahb);
+        let mut gpioc = peripherals.GPIOC.split(&mut rcc.ahb);
         let sck = gpiob.pb3.into_af5(&mut gpiob.moder, &mut gpiob.afrl);
         let miso = gpioa.pa6.into_af5(&mut gpioa.moder, &mut gpioa.afrl);
         let mosi = gpioa.pa7.into_af5(&mut gpioa.moder, &mut gpioa.afrl);
@@ -56,17 +64,123 @@ fn main(_argc: isize, _argv: *const *const u8) -> isize {
         let reset_pin = gpioa
             .pa8
             .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper);
+        // The user button, B1, pulls PC13 low while it is pressed.
+        let button = gpioc
+            .pc13
+            .into_floating_input(&mut gpioc.moder, &mut gpioc.pupdr);
         let mut tim6 = hal::timer::Timer::tim6(peripherals.TIM6, 200.hz(), clocks, &mut rcc.apb1);
+
+        // Interrupt on the rising edge of the data ready line. PA0 is EXTI0's default source.
+        peripherals.EXTI.imr1.modify(|_, w| w.mr0().set_bit());
+        peripherals.EXTI.rtsr1.modify(|_, w| w.tr0().set_bit());
+        core_peripherals.NVIC.enable(stm32f30x::Interrupt::EXTI0);
+
+        // Wake up every 10 ms for periodic work and timeouts.
+        let mut tim7 = hal::timer::Timer::tim7(peripherals.TIM7, 100.hz(), clocks, &mut rcc.apb1);
+        tim7.listen(hal::timer::Event::TimeOut);
+        core_peripherals.NVIC.enable(stm32f30x::Interrupt::TIM7);
+
         let mut rx_buffer: [u8; 128] = [0; 128];
 
+        // SysTick runs from HCLK / 8. The clock is shared with the HCI trace.
+        let clock = RefCell::new(main::time::SysTickClock::new(
+            core_peripherals.SYST,
+            clocks.hclk().0 / 8_000,
+        ));
+        let mut shared_clock = &clock;
+        let tracer = main::trace::Tracer::new(Some(&clock));
+        let spi = tracer.spi(spi);
+        let chip_select = tracer.chip_select(chip_select);
+
         let mut bnrg = bluenrg::BlueNRG::new(&mut rx_buffer, chip_select, data_ready, reset_pin);
         bnrg.reset(&mut tim6, 200.hz());
 
-        main::EventLoop::new(&mut bnrg, tim6, spi).run();
-    });
+        let mut environmental_sensor = main::sensors::SimulatedEnvironmentalSensor::new();
+        let accelerometer = main::accelerometer::SimulatedAccelerometer::new();
+        let mut accelerometer = &accelerometer;
+        let mut led = main::led::Led::new(
+            gpioa
+                .pa5
+                .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper),
+        );
+
+        let unique_id = unsafe { main::config::unique_id() };
+        let mut config = main::config::DeviceConfig::default();
+        config.address = main::config::Address::from_unique_id(&unique_id);
+        let mut pin_mode = None;
+        let mut store =
+            main::storage::Store::open(unsafe { main::flash::InternalFlash::new() }).ok();
+        if let Some(store) = store.as_mut() {
+            config = config.load(store);
+            let _ = config.save(store);
+            pin_mode = Some(main::security::PinMode::PerBoot(main::security::boot_seed(
+                store, &unique_id,
+            )));
+        }
+
+        // Messages are dropped until a debug probe enables tracing on stimulus port 0.
+        let mut logger = main::logger::ItmLogger::new(core_peripherals.ITM, 0);
+
+        let mut event_loop = main::EventLoop::new(&mut bnrg, tim6, 200.hz(), spi, config);
+        event_loop.set_environmental_sensor(&mut environmental_sensor);
+        event_loop.set_led(&mut led);
+        event_loop.set_clock(&mut shared_clock);
+        event_loop.set_accelerometer(&mut accelerometer);
+        event_loop.set_logger(&mut logger);
+        if let Some(pin_mode) = pin_mode {
+            event_loop.set_pin_mode(pin_mode);
+        }
+        if let Some(store) = store.as_mut() {
+            event_loop.set_bond_store(store);
+        }
+        event_loop.set_timeout_period(Some(100.hz()));
+        event_loop.set_event_queue(&EVENT_QUEUE);
+
+        // The data ready line may have gone high before the interrupt was enabled.
+        EVENT_QUEUE.data_ready();
+
+        // The event loop logs the error that halted it.
+        let mut button_was_pressed = false;
+        while event_loop.step().is_ok() {
+            // Pressing the button lets a new central device pair. TIM7 wakes the loop often enough
+            // to see every press.
+            let button_pressed = button.is_low();
+            if button_pressed && !button_was_pressed {
+                event_loop.open_pairing_window();
+            }
+            button_was_pressed = button_pressed;
+
+            // With interrupts masked, an interrupt that fires after the check still wakes the core.
+            cortex_m::interrupt::free(|_| {
+                if event_loop.is_idle() {
+                    cortex_m::asm::wfi();
+                }
+            });
+        }
+        #[cfg(feature = "semihosting")]
+        let _ = main::trace::write_btsnoop(&tracer, "trace.btsnoop\0");
+        loop {
+            cortex_m::asm::wfi();
+        }
+    }
+
+    #[interrupt]
+    fn EXTI0() {
+        // Clear the pending bit, or the handler runs again as soon as it returns.
+        unsafe { (*stm32f30x::EXTI::ptr()).pr1.write(|w| w.pr0().set_bit()) };
+        EVENT_QUEUE.data_ready();
+    }
 
-    1 // should not be here, so let's return failure.
+    #[interrupt]
+    fn TIM7() {
+        // The interrupt only wakes up the main loop.
+        unsafe {
+            (*stm32f30x::TIM7::ptr())
+                .sr
+                .modify(|_, w| w.uif().clear_bit())
+        };
+    }
 }
 
-#[lang = "eh_personality"]
-extern "C" fn eh_personality() {}
+#[cfg(not(target_arch = "arm"))]
+fn main() {}
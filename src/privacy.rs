@@ -0,0 +1,223 @@
+//! LE privacy with resolvable private addresses.
+//!
+//! With privacy, the device advertises with a resolvable private address instead of its identity
+//! address, and changes that address every rotation period. The address is a random part and a
+//! hash of it under the identity resolving key (IRK), so only the central devices that received
+//! the IRK while bonding can tell that two addresses belong to the same device (Bluetooth Core
+//! Specification v4.1, Vol 3, Part C, Section 10.8.2).
+//!
+//! The controller derives its IRK from the identity root in its configuration data, and hands it
+//! out when bonding. The identity root is generated once with the controller's random number
+//! generator, kept in a [`KeyStore`], and written to the controller during initialization.
+//!
+//! Exactly one side generates and rotates the address, as chosen by [`address_owner`]:
+//!
+//! - With [`AddressOwner::Host`], the event loop derives the same IRK with LE Encrypt, and
+//!   generates each address with LE Rand and LE Encrypt before it sets it with LE Set Random
+//!   Address, which works the same on every firmware version. GAP Init is not given the privacy
+//!   flag, since the controller would otherwise replace the addresses the event loop sets.
+//! - With [`AddressOwner::Controller`], GAP Init is given the privacy flag, and the controller
+//!   generates and rotates the address itself, on its own schedule. Only BlueNRG-MS firmware 7.x
+//!   has the flag; BlueNRG firmware 6.x does not, so the host owns the address there.
+//!
+//! The identity root is written in both cases, so the controller hands out the matching IRK. The
+//! BlueNRG-MS documentation does not say whether firmware 7.x distributes the IRK when GAP Init
+//! is not given the privacy flag, so [`AddressOwner::Controller`] is the safer choice on 7.x when
+//! bonded central devices must resolve the address.
+
+use crate::flash::Flash;
+use crate::storage::{key, Store};
+
+/// Length of the identity root and the identity resolving key, in bytes.
+pub const KEY_LEN: usize = 16;
+
+/// Time between changes of the private address, in milliseconds, unless changed with
+/// [`set_rotation_period`](Privacy::set_rotation_period). The specification recommends 15
+/// minutes.
+pub const DEFAULT_ROTATION_PERIOD_MS: u32 = 15 * 60 * 1000;
+
+/// Plaintext that the identity root encrypts to the IRK: d1(IR, 1, 0), from the Bluetooth Core
+/// Specification v4.1, Vol 3, Part H, Appendix B.
+pub const IRK_PLAINTEXT: [u8; 16] = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// Persistent storage for the identity root.
+pub trait KeyStore {
+    /// Returns the stored identity root, if there is one.
+    fn load_identity_root(&mut self) -> Option<[u8; KEY_LEN]>;
+
+    /// Stores the identity root. Returns false if it could not be stored.
+    fn save_identity_root(&mut self, identity_root: &[u8; KEY_LEN]) -> bool;
+}
+
+impl<F: Flash> KeyStore for Store<F> {
+    fn load_identity_root(&mut self) -> Option<[u8; KEY_LEN]> {
+        let mut identity_root = [0; KEY_LEN];
+        match self.read(key::IDENTITY_ROOT, &mut identity_root) {
+            Some(KEY_LEN) => Some(identity_root),
+            _ => None,
+        }
+    }
+
+    fn save_identity_root(&mut self, identity_root: &[u8; KEY_LEN]) -> bool {
+        self.write(key::IDENTITY_ROOT, identity_root).is_ok()
+    }
+}
+
+/// Which side generates and rotates the resolvable private address.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AddressOwner {
+    /// The event loop generates the address, and changes it every rotation period.
+    Host,
+
+    /// The controller generates and rotates the address, once GAP Init is given the privacy flag.
+    /// The rotation period is not used.
+    Controller,
+}
+
+/// Returns the side that owns the private address on firmware `fw_version`, when `preferred`
+/// was asked for. Firmware without the privacy flag leaves the address to the host.
+pub fn address_owner(preferred: AddressOwner, fw_version: &bluenrg::Version) -> AddressOwner {
+    if gap_init_takes_privacy_flag(fw_version) {
+        preferred
+    } else {
+        AddressOwner::Host
+    }
+}
+
+/// Returns true if GAP Init on firmware `fw_version` takes the privacy flag.
+pub fn gap_init_takes_privacy_flag(fw_version: &bluenrg::Version) -> bool {
+    fw_version.major >= 7
+}
+
+/// Returns the resolvable private address made of the random part `prand` and its `hash`, both
+/// least significant byte first.
+pub fn resolvable_private_address(prand: [u8; 3], hash: [u8; 3]) -> hci::BdAddr {
+    hci::BdAddr([
+        hash[0],
+        hash[1],
+        hash[2],
+        prand[0],
+        prand[1],
+        (prand[2] & 0x3F) | 0x40,
+    ])
+}
+
+/// Returns true if `addr` is a resolvable private address.
+pub fn is_resolvable_private_address(addr: &hci::BdAddr) -> bool {
+    addr.0[5] & 0xC0 == 0x40
+}
+
+/// Keys and current private address of a device with privacy.
+pub struct Privacy<'a> {
+    key_store: &'a mut dyn KeyStore,
+    rotation_period_ms: u32,
+
+    identity_root: Option<[u8; KEY_LEN]>,
+    new_identity_root: [u8; KEY_LEN],
+    irk: Option<[u8; KEY_LEN]>,
+
+    prand: [u8; 3],
+    address: Option<hci::BdAddr>,
+    address_set_ms: Option<u32>,
+}
+
+impl<'a> Privacy<'a> {
+    /// Creates the privacy state with the identity root in `key_store`, if there is one, and the
+    /// default rotation period.
+    pub fn new(key_store: &'a mut dyn KeyStore) -> Privacy<'a> {
+        let identity_root = key_store.load_identity_root();
+        Privacy {
+            key_store: key_store,
+            rotation_period_ms: DEFAULT_ROTATION_PERIOD_MS,
+            identity_root: identity_root,
+            new_identity_root: [0; KEY_LEN],
+            irk: None,
+            prand: [0; 3],
+            address: None,
+            address_set_ms: None,
+        }
+    }
+
+    /// Sets the time between changes of the private address, in milliseconds.
+    pub fn set_rotation_period(&mut self, period_ms: u32) {
+        self.rotation_period_ms = period_ms;
+    }
+
+    /// Returns the identity root, or `None` if it still has to be generated.
+    pub fn identity_root(&self) -> Option<[u8; KEY_LEN]> {
+        self.identity_root
+    }
+
+    /// Records 8 random bytes for the half of a new identity root at `index`, 0 or 1. Once both
+    /// halves are known, the identity root is stored, and the result of storing it returned.
+    pub fn identity_root_generated(&mut self, index: usize, random: u64) -> Option<bool> {
+        self.new_identity_root[8 * index..8 * index + 8].copy_from_slice(&random.to_le_bytes());
+        if index == 0 {
+            return None;
+        }
+
+        self.identity_root = Some(self.new_identity_root);
+        Some(self.key_store.save_identity_root(&self.new_identity_root))
+    }
+
+    /// Returns the IRK derived from the identity root, or `None` if it has not been derived since
+    /// the controller was reset.
+    pub fn irk(&self) -> Option<[u8; KEY_LEN]> {
+        self.irk
+    }
+
+    /// Records the IRK, as encrypted from [`IRK_PLAINTEXT`] under the identity root.
+    pub fn set_irk(&mut self, irk: [u8; KEY_LEN]) {
+        self.irk = Some(irk);
+    }
+
+    /// Returns true if a new private address has to be set before advertising at `now_ms`.
+    /// Without the time, the address changes every time advertising starts.
+    pub fn address_due(&self, now_ms: Option<u32>) -> bool {
+        match (self.address_set_ms, now_ms) {
+            (Some(set_ms), Some(now_ms)) => now_ms.wrapping_sub(set_ms) >= self.rotation_period_ms,
+            _ => true,
+        }
+    }
+
+    /// Records 8 random bytes, of which the first 3 become the random part of the next address.
+    pub fn prand_generated(&mut self, random: u64) {
+        self.prand.copy_from_slice(&random.to_le_bytes()[..3]);
+        // The two most significant bits mark the address as resolvable.
+        self.prand[2] = (self.prand[2] & 0x3F) | 0x40;
+    }
+
+    /// Returns the plaintext to encrypt under the IRK to hash the random part of the next
+    /// address: ah(IRK, prand), from the Bluetooth Core Specification v4.1, Vol 3, Part H,
+    /// Section 2.2.2.
+    pub fn prand_plaintext(&self) -> [u8; 16] {
+        let mut plaintext = [0; 16];
+        plaintext[..3].copy_from_slice(&self.prand);
+        plaintext
+    }
+
+    /// Records the random part encrypted under the IRK, and returns the next address.
+    pub fn prand_hashed(&mut self, encrypted: &[u8; 16]) -> hci::BdAddr {
+        let address =
+            resolvable_private_address(self.prand, [encrypted[0], encrypted[1], encrypted[2]]);
+        self.address = Some(address);
+        address
+    }
+
+    /// Returns the current private address, if one was generated.
+    pub fn address(&self) -> Option<hci::BdAddr> {
+        self.address
+    }
+
+    /// Records that the controller uses the current private address since `now_ms`.
+    pub fn address_set(&mut self, now_ms: Option<u32>) {
+        self.address_set_ms = now_ms;
+    }
+
+    /// Forgets the IRK and the private address, when the controller is reset.
+    pub fn clear(&mut self) {
+        self.irk = None;
+        self.address = None;
+        self.address_set_ms = None;
+    }
+}
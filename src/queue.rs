@@ -0,0 +1,346 @@
+//! An interrupt-safe queue of packets received from the controller.
+//!
+//! The controller raises its data ready line when it has events for the host. On the target,
+//! that line triggers an interrupt whose handler only calls [`EventQueue::data_ready`]. The
+//! event loop then reads the waiting packets into the queue and handles them one at a time, and
+//! the main loop sleeps while [`EventLoop::is_idle`](crate::EventLoop::is_idle) returns true.
+//!
+//! The queue is a single-producer, single-consumer ring buffer that needs no critical sections,
+//! so it may be shared between an interrupt handler and the main loop through a `static`. On the
+//! host, a test injects the "interrupt" by calling [`data_ready`](EventQueue::data_ready), or
+//! pushes packets directly:
+//!
+//! ```ignore
+//! static QUEUE: queue::PacketQueue = queue::EventQueue::new();
+//!
+//! event_loop.set_event_queue(&QUEUE);
+//! QUEUE.data_ready();
+//! event_loop.step()?;
+//! assert!(event_loop.is_idle());
+//! ```
+//!
+//! The [async event loop](crate::async_loop) registers its task with the queue instead of
+//! sleeping, and [`data_ready`](EventQueue::data_ready) wakes it.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::Waker;
+
+/// Number of packets the queue holds. Must be a power of two.
+pub const QUEUE_LEN: usize = 8;
+
+/// A packet received from the BlueNRG.
+pub type Packet = hci::host::uart::Packet<bluenrg::event::BlueNRGEvent>;
+
+/// A queue of packets received from the BlueNRG.
+pub type PacketQueue = EventQueue<Packet>;
+
+// States of the waker slot. `wake` may interrupt `register_waker`, but not the other way around.
+const WAITING: usize = 0;
+const REGISTERING: usize = 0b01;
+const WAKING: usize = 0b10;
+
+/// A fixed-size queue that one context may push to while another pops from it.
+///
+/// Only one context may call [`push`](EventQueue::push), and only one context may call
+/// [`pop`](EventQueue::pop), [`clear`](EventQueue::clear),
+/// [`register_waker`](EventQueue::register_waker), and [`clear_waker`](EventQueue::clear_waker).
+/// Any context may call the other methods.
+pub struct EventQueue<T> {
+    slots: [UnsafeCell<Option<T>>; QUEUE_LEN],
+
+    // Both indices only ever increase, and wrap around together with the slot index.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+
+    dropped: AtomicUsize,
+    data_ready: AtomicBool,
+
+    waker: UnsafeCell<Option<Waker>>,
+    waker_state: AtomicUsize,
+}
+
+unsafe impl<T: Send> Sync for EventQueue<T> {}
+
+impl<T> EventQueue<T> {
+    /// Creates an empty queue.
+    pub const fn new() -> EventQueue<T> {
+        EventQueue {
+            slots: [
+                UnsafeCell::new(None),
+                UnsafeCell::new(None),
+                UnsafeCell::new(None),
+                UnsafeCell::new(None),
+                UnsafeCell::new(None),
+                UnsafeCell::new(None),
+                UnsafeCell::new(None),
+                UnsafeCell::new(None),
+            ],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            data_ready: AtomicBool::new(false),
+            waker: UnsafeCell::new(None),
+            waker_state: AtomicUsize::new(WAITING),
+        }
+    }
+
+    /// Adds an item at the end of the queue. If the queue is full, the item is returned and
+    /// counted as dropped.
+    pub fn push(&self, item: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == QUEUE_LEN {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return Err(item);
+        }
+
+        // The consumer does not touch the slot until the new tail is published.
+        unsafe {
+            *self.slots[tail % QUEUE_LEN].get() = Some(item);
+        }
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Removes the item at the front of the queue.
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        // The producer does not touch the slot until the new head is published.
+        let item = unsafe { (*self.slots[head % QUEUE_LEN].get()).take() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+
+        item
+    }
+
+    /// Removes every item.
+    pub fn clear(&self) {
+        while self.pop().is_some() {}
+    }
+
+    /// Returns the number of items in the queue.
+    pub fn len(&self) -> usize {
+        self.tail
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    /// Returns true if the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns true if no more items fit in the queue.
+    pub fn is_full(&self) -> bool {
+        self.len() == QUEUE_LEN
+    }
+
+    /// Returns the number of items that did not fit in the queue.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Records that the controller has data to read, and wakes the registered task. Called from
+    /// the data ready interrupt.
+    pub fn data_ready(&self) {
+        self.data_ready.store(true, Ordering::Release);
+        self.wake();
+    }
+
+    /// Makes [`wake`](EventQueue::wake) and [`data_ready`](EventQueue::data_ready) wake `waker`,
+    /// once. Replaces any waker registered before. The queue keeps the waker until it is woken or
+    /// [cleared](EventQueue::clear_waker), so a task that stops waiting must clear it.
+    pub fn register_waker(&self, waker: &Waker) {
+        self.replace_waker(Some(waker.clone()));
+    }
+
+    /// Forgets the registered waker, if any.
+    pub fn clear_waker(&self) {
+        self.replace_waker(None);
+    }
+
+    fn replace_waker(&self, waker: Option<Waker>) {
+        match self.waker_state.compare_exchange(
+            WAITING,
+            REGISTERING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                // `wake` does not touch the slot while registering.
+                unsafe {
+                    *self.waker.get() = waker;
+                }
+                if self
+                    .waker_state
+                    .compare_exchange(REGISTERING, WAITING, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    // `wake` was called while registering, and left the waker to us.
+                    let waker = unsafe { (*self.waker.get()).take() };
+                    self.waker_state.store(WAITING, Ordering::Release);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            // Interrupted the wake-up of the previous waker.
+            Err(_) => {
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    /// Wakes the registered task, if any, without recording that there is data to read. Called
+    /// from periodic interrupts, so the task handles timeouts and notifications.
+    pub fn wake(&self) {
+        if self.waker_state.fetch_or(WAKING, Ordering::AcqRel) == WAITING {
+            let waker = unsafe { (*self.waker.get()).take() };
+            self.waker_state.fetch_and(!WAKING, Ordering::Release);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Returns true, and forgets it, if [`data_ready`](EventQueue::data_ready) was called since
+    /// the last call.
+    pub fn take_data_ready(&self) -> bool {
+        self.data_ready.swap(false, Ordering::Acquire)
+    }
+
+    /// Returns true if the queue is empty and the controller has not signalled any data since
+    /// the last [`take_data_ready`](EventQueue::take_data_ready).
+    pub fn is_idle(&self) -> bool {
+        self.is_empty() && !self.data_ready.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Default for EventQueue<T> {
+    fn default() -> EventQueue<T> {
+        EventQueue::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pops_items_in_order() {
+        let queue = EventQueue::new();
+        assert!(queue.is_empty());
+        for i in 0..3 {
+            queue.push(i).unwrap();
+        }
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.pop(), Some(0));
+        assert_eq!(queue.pop(), Some(1));
+        queue.push(3).unwrap();
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn drops_items_while_full() {
+        let queue = EventQueue::new();
+        for i in 0..QUEUE_LEN {
+            queue.push(i).unwrap();
+        }
+        assert!(queue.is_full());
+        assert_eq!(queue.push(QUEUE_LEN), Err(QUEUE_LEN));
+        assert_eq!(queue.dropped(), 1);
+
+        // Space frees up as items are popped, across the wrap-around of the slots.
+        assert_eq!(queue.pop(), Some(0));
+        queue.push(QUEUE_LEN + 1).unwrap();
+        assert_eq!(queue.len(), QUEUE_LEN);
+        queue.clear();
+        assert!(queue.is_empty());
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn remembers_data_ready_until_taken() {
+        let queue: EventQueue<u8> = EventQueue::new();
+        assert!(queue.is_idle());
+        queue.data_ready();
+        assert!(!queue.is_idle());
+        assert!(queue.take_data_ready());
+        assert!(!queue.take_data_ready());
+        assert!(queue.is_idle());
+
+        queue.push(1).unwrap();
+        assert!(!queue.is_idle());
+    }
+
+    #[test]
+    fn wakes_the_registered_task_once() {
+        use core::task::{RawWaker, RawWakerVTable};
+
+        static WAKES: AtomicUsize = AtomicUsize::new(0);
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+        unsafe fn clone(data: *const ()) -> RawWaker {
+            RawWaker::new(data, &VTABLE)
+        }
+        unsafe fn wake(_data: *const ()) {
+            WAKES.fetch_add(1, Ordering::Relaxed);
+        }
+        unsafe fn drop(_data: *const ()) {}
+
+        let waker = unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) };
+
+        let queue: EventQueue<u8> = EventQueue::new();
+        queue.data_ready();
+        assert_eq!(WAKES.load(Ordering::Relaxed), 0);
+
+        queue.register_waker(&waker);
+        queue.data_ready();
+        queue.wake();
+        assert_eq!(WAKES.load(Ordering::Relaxed), 1);
+
+        queue.register_waker(&waker);
+        queue.clear_waker();
+        queue.wake();
+        assert_eq!(WAKES.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn passes_items_from_an_interrupt() {
+        static QUEUE: EventQueue<usize> = EventQueue::new();
+        const COUNT: usize = 10_000;
+
+        // A thread stands in for the interrupt handler that fills the queue.
+        let interrupt = std::thread::spawn(|| {
+            for i in 0..COUNT {
+                let mut item = i;
+                while let Err(rejected) = QUEUE.push(item) {
+                    item = rejected;
+                    std::thread::yield_now();
+                }
+                QUEUE.data_ready();
+            }
+        });
+
+        let mut expected = 0;
+        while expected < COUNT {
+            match QUEUE.pop() {
+                Some(item) => {
+                    assert_eq!(item, expected);
+                    expected += 1;
+                }
+                None => std::thread::yield_now(),
+            }
+        }
+        interrupt.join().unwrap();
+        assert!(QUEUE.take_data_ready());
+        assert!(QUEUE.is_idle());
+    }
+}
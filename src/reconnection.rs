@@ -0,0 +1,129 @@
+//! Choice of how the device advertises once central devices have bonded with it.
+//!
+//! Until a central device bonds, the device is discoverable by everyone, so a central device can
+//! find it and pair. After that, it only accepts connections from the bonded devices, which the
+//! event loop adds to the controller's white list, and it is discoverable only while the pairing
+//! window is open, for example after a button press. When a bonded central device disconnects,
+//! the device first advertises directly to it, so it reconnects quickly, and falls back to the
+//! white list if it does not reconnect before directed advertising times out.
+
+/// Time the pairing window stays open, in milliseconds, unless changed with
+/// [`set_pairing_window`](ReconnectionPolicy::set_pairing_window).
+pub const DEFAULT_PAIRING_WINDOW_MS: u32 = 60_000;
+
+/// How the device advertises.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AdvertisingMode {
+    /// Discoverable by any central device, which may also connect.
+    Discoverable,
+
+    /// Not discoverable, and connectable only by the devices in the controller's white list.
+    WhiteList,
+
+    /// Connectable only by the given device, until directed advertising times out.
+    Directed(hci::BdAddrType),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PairingWindow {
+    Closed,
+
+    /// Open since the given time, or until a device pairs if the time is not known.
+    Open(Option<u32>),
+}
+
+/// Decides how the device advertises, from its bonds, the pairing window, and the last central
+/// device that disconnected.
+pub struct ReconnectionPolicy {
+    pairing_window_ms: u32,
+    directed: bool,
+
+    window: PairingWindow,
+    reconnect_to: Option<hci::BdAddrType>,
+}
+
+impl ReconnectionPolicy {
+    /// Creates a policy with the default pairing window, that reconnects with directed
+    /// advertising.
+    pub fn new() -> ReconnectionPolicy {
+        ReconnectionPolicy {
+            pairing_window_ms: DEFAULT_PAIRING_WINDOW_MS,
+            directed: true,
+            window: PairingWindow::Closed,
+            reconnect_to: None,
+        }
+    }
+
+    /// Sets how long the pairing window stays open, in milliseconds.
+    pub fn set_pairing_window(&mut self, duration_ms: u32) {
+        self.pairing_window_ms = duration_ms;
+    }
+
+    /// Sets whether the device advertises directly to a bonded central device after it
+    /// disconnects.
+    pub fn set_directed_reconnection(&mut self, enabled: bool) {
+        self.directed = enabled;
+        if !enabled {
+            self.reconnect_to = None;
+        }
+    }
+
+    /// Opens the pairing window at `now_ms`. Without the time, the window stays open until a
+    /// device pairs.
+    pub fn open_pairing_window(&mut self, now_ms: Option<u32>) {
+        self.window = PairingWindow::Open(now_ms);
+    }
+
+    /// Closes the pairing window.
+    pub fn close_pairing_window(&mut self) {
+        self.window = PairingWindow::Closed;
+    }
+
+    /// Returns true if the pairing window is open at `now_ms`.
+    pub fn is_pairing_window_open(&self, now_ms: Option<u32>) -> bool {
+        match (self.window, now_ms) {
+            (PairingWindow::Closed, _) => false,
+            (PairingWindow::Open(Some(opened_ms)), Some(now_ms)) => {
+                now_ms.wrapping_sub(opened_ms) < self.pairing_window_ms
+            }
+            (PairingWindow::Open(_), _) => true,
+        }
+    }
+
+    /// Records that a central device connected, which ends any directed advertising.
+    pub fn connected(&mut self) {
+        self.reconnect_to = None;
+    }
+
+    /// Records that the central device `peer` disconnected. If it is one of the `bonded`
+    /// devices, the device advertises directly to it next.
+    pub fn disconnected(&mut self, peer: hci::BdAddrType, bonded: &[hci::BdAddrType]) {
+        if self.directed && bonded.contains(&peer) {
+            self.reconnect_to = Some(peer);
+        }
+    }
+
+    /// Records that directed advertising timed out without the central device reconnecting.
+    pub fn directed_advertising_ended(&mut self) {
+        self.reconnect_to = None;
+    }
+
+    /// Returns how the device should advertise at `now_ms`, given the `bonded` devices.
+    pub fn mode(&self, bonded: &[hci::BdAddrType], now_ms: Option<u32>) -> AdvertisingMode {
+        if let Some(peer) = self.reconnect_to {
+            AdvertisingMode::Directed(peer)
+        } else if bonded.is_empty() || self.is_pairing_window_open(now_ms) {
+            AdvertisingMode::Discoverable
+        } else {
+            AdvertisingMode::WhiteList
+        }
+    }
+}
+
+impl Default for ReconnectionPolicy {
+    /// Returns a policy with the default pairing window, that reconnects with directed
+    /// advertising.
+    fn default() -> ReconnectionPolicy {
+        ReconnectionPolicy::new()
+    }
+}
@@ -0,0 +1,311 @@
+//! Discovery of nearby devices, in the observer role.
+//!
+//! In the [observer](Role::Observer) role, the event loop runs the GAP general discovery
+//! procedure over and over, and records every LE Advertising Report in a [`DeviceTable`]. The
+//! table keeps one entry per device address, with the signal strength of its last report and what
+//! the device said about itself in its advertising and scan response data: its name, its service
+//! UUIDs, and its manufacturer data.
+
+use crate::advertising::{self, ad_type};
+use core::time::Duration;
+
+/// Maximum number of devices the table keeps.
+pub const MAX_DEVICES: usize = 8;
+
+/// Longest local name a device can advertise, in bytes.
+pub const MAX_NAME_LEN: usize = advertising::MAX_DATA_LEN - 2;
+
+/// Maximum number of service UUIDs kept for each device.
+pub const MAX_SERVICE_UUIDS: usize = 4;
+
+/// Longest manufacturer data a device can advertise after its company identifier, in bytes.
+pub const MAX_MANUFACTURER_DATA_LEN: usize = advertising::MAX_DATA_LEN - 4;
+
+/// Time between the starts of two scans while discovering, in milliseconds.
+pub const SCAN_INTERVAL_MS: u64 = 100;
+
+/// Time the controller listens at the start of every scan interval, in milliseconds.
+pub const SCAN_WINDOW_MS: u64 = 50;
+
+/// The GAP role the event loop initializes the controller with.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Role {
+    /// Advertises the GATT services, and serves one central device at a time.
+    Peripheral,
+
+    /// Discovers nearby devices, and keeps a table of them. The controller is initialized as a
+    /// central device, which the general discovery procedure requires, but never connects.
+    Observer,
+}
+
+/// Returns the scan window used while discovering.
+pub fn scan_window() -> hci::types::ScanWindow {
+    // Both durations are within the range the controller accepts.
+    hci::types::ScanWindow::start_every(Duration::from_millis(SCAN_INTERVAL_MS))
+        .and_then(|b| b.open_for(Duration::from_millis(SCAN_WINDOW_MS)))
+        .unwrap()
+}
+
+/// A device heard while discovering.
+#[derive(Copy, Clone, Debug)]
+pub struct Device {
+    address: hci::BdAddrType,
+    rssi: Option<i8>,
+    last_seen_ms: Option<u32>,
+
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    name_complete: bool,
+
+    service_uuids: [bluenrg::gatt::Uuid; MAX_SERVICE_UUIDS],
+    service_uuid_count: usize,
+
+    company_id: Option<u16>,
+    manufacturer_data: [u8; MAX_MANUFACTURER_DATA_LEN],
+    manufacturer_data_len: usize,
+}
+
+impl Device {
+    fn new(address: hci::BdAddrType) -> Device {
+        Device {
+            address: address,
+            rssi: None,
+            last_seen_ms: None,
+            name: [0; MAX_NAME_LEN],
+            name_len: 0,
+            name_complete: false,
+            service_uuids: [bluenrg::gatt::Uuid::Uuid16(0); MAX_SERVICE_UUIDS],
+            service_uuid_count: 0,
+            company_id: None,
+            manufacturer_data: [0; MAX_MANUFACTURER_DATA_LEN],
+            manufacturer_data_len: 0,
+        }
+    }
+
+    /// Returns the address of the device.
+    pub fn address(&self) -> hci::BdAddrType {
+        self.address
+    }
+
+    /// Returns the signal strength of the last report, in dBm, or `None` if the controller did not
+    /// measure it.
+    pub fn rssi(&self) -> Option<i8> {
+        self.rssi
+    }
+
+    /// Returns the time of the last report, in milliseconds, if the event loop has a clock.
+    pub fn last_seen_ms(&self) -> Option<u32> {
+        self.last_seen_ms
+    }
+
+    /// Returns the local name of the device, if it advertised one.
+    pub fn name(&self) -> Option<&[u8]> {
+        if self.name_len == 0 {
+            None
+        } else {
+            Some(&self.name[..self.name_len])
+        }
+    }
+
+    /// Returns true if the [name](Device::name) is complete, and false if it is shortened.
+    pub fn is_name_complete(&self) -> bool {
+        self.name_complete
+    }
+
+    /// Returns the service UUIDs the device advertised, up to [`MAX_SERVICE_UUIDS`].
+    pub fn service_uuids(&self) -> &[bluenrg::gatt::Uuid] {
+        &self.service_uuids[..self.service_uuid_count]
+    }
+
+    /// Returns the company identifier and the data of the last manufacturer specific data the
+    /// device advertised.
+    pub fn manufacturer_data(&self) -> Option<(u16, &[u8])> {
+        let company_id = self.company_id?;
+        Some((
+            company_id,
+            &self.manufacturer_data[..self.manufacturer_data_len],
+        ))
+    }
+
+    /// Records a report of the device.
+    fn heard(&mut self, rssi: Option<i8>, data: &[u8], now_ms: Option<u32>) {
+        self.rssi = rssi;
+        self.last_seen_ms = now_ms;
+
+        for structure in advertising::parse(data) {
+            let data = structure.data;
+            match structure.ad_type {
+                ad_type::COMPLETE_LOCAL_NAME => self.set_name(data, true),
+                // A shortened name does not replace the complete one from a scan response.
+                ad_type::SHORTENED_LOCAL_NAME if !self.name_complete => self.set_name(data, false),
+                ad_type::INCOMPLETE_UUID16_LIST | ad_type::COMPLETE_UUID16_LIST => {
+                    for uuid in data.chunks_exact(2) {
+                        self.add_service_uuid(bluenrg::gatt::Uuid::Uuid16(u16::from_le_bytes([
+                            uuid[0], uuid[1],
+                        ])));
+                    }
+                }
+                ad_type::INCOMPLETE_UUID128_LIST | ad_type::COMPLETE_UUID128_LIST => {
+                    for uuid in data.chunks_exact(16) {
+                        let mut bytes = [0; 16];
+                        bytes.copy_from_slice(uuid);
+                        self.add_service_uuid(bluenrg::gatt::Uuid::Uuid128(bytes));
+                    }
+                }
+                ad_type::MANUFACTURER_SPECIFIC_DATA if data.len() >= 2 => {
+                    let len = core::cmp::min(data.len() - 2, MAX_MANUFACTURER_DATA_LEN);
+                    self.company_id = Some(u16::from_le_bytes([data[0], data[1]]));
+                    self.manufacturer_data[..len].copy_from_slice(&data[2..2 + len]);
+                    self.manufacturer_data_len = len;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn set_name(&mut self, name: &[u8], complete: bool) {
+        let len = core::cmp::min(name.len(), MAX_NAME_LEN);
+        self.name[..len].copy_from_slice(&name[..len]);
+        self.name_len = len;
+        self.name_complete = complete;
+    }
+
+    fn add_service_uuid(&mut self, uuid: bluenrg::gatt::Uuid) {
+        if self.service_uuid_count < MAX_SERVICE_UUIDS && !self.service_uuids().contains(&uuid) {
+            self.service_uuids[self.service_uuid_count] = uuid;
+            self.service_uuid_count += 1;
+        }
+    }
+}
+
+/// The devices heard while discovering, one entry per address.
+pub struct DeviceTable {
+    devices: [Device; MAX_DEVICES],
+    len: usize,
+}
+
+impl DeviceTable {
+    /// Creates an empty table.
+    pub fn new() -> DeviceTable {
+        DeviceTable {
+            devices: [Device::new(hci::BdAddrType::Public(hci::BdAddr([0; 6]))); MAX_DEVICES],
+            len: 0,
+        }
+    }
+
+    /// Returns the devices in the table, in the order they were first heard.
+    pub fn devices(&self) -> &[Device] {
+        &self.devices[..self.len]
+    }
+
+    /// Returns the device with the given address, if it is in the table.
+    pub fn get(&self, address: &hci::BdAddrType) -> Option<&Device> {
+        self.devices().iter().find(|d| d.address == *address)
+    }
+
+    /// Forgets every device.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Records an advertising report of `data` from `address`, heard with the signal strength
+    /// `rssi` at `now_ms`. Returns true if the device is new to the table.
+    ///
+    /// Advertising and scan response data from the same address update the same entry. Once the
+    /// table is full, a new device replaces the one with the weakest signal if its own signal is
+    /// stronger, and is dropped otherwise.
+    pub fn report(
+        &mut self,
+        address: hci::BdAddrType,
+        rssi: Option<i8>,
+        data: &[u8],
+        now_ms: Option<u32>,
+    ) -> bool {
+        if let Some(device) = self.devices[..self.len]
+            .iter_mut()
+            .find(|d| d.address == address)
+        {
+            device.heard(rssi, data, now_ms);
+            return false;
+        }
+
+        let index = if self.len < MAX_DEVICES {
+            self.len += 1;
+            self.len - 1
+        } else {
+            // A signal strength that was not measured counts as the weakest.
+            let (index, weakest) = self
+                .devices
+                .iter()
+                .enumerate()
+                .map(|(i, d)| (i, d.rssi))
+                .min_by_key(|&(_, rssi)| rssi)
+                .unwrap();
+            if rssi <= weakest {
+                return false;
+            }
+            index
+        };
+        self.devices[index] = Device::new(address);
+        self.devices[index].heard(rssi, data, now_ms);
+
+        true
+    }
+}
+
+impl Default for DeviceTable {
+    /// Returns an empty table.
+    fn default() -> DeviceTable {
+        DeviceTable::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(last: u8) -> hci::BdAddrType {
+        hci::BdAddrType::Public(hci::BdAddr([last, 0, 0, 0, 0, 0]))
+    }
+
+    #[test]
+    fn merges_the_scan_response_into_the_advertising_report() {
+        let mut table = DeviceTable::new();
+        assert!(table.report(public(1), Some(-70), &[3, 0x08, b'T', b'e'], Some(10)));
+        assert!(!table.report(
+            public(1),
+            Some(-60),
+            &[5, 0x09, b'T', b'e', b's', b't', 3, 0x03, 0x0F, 0x18],
+            Some(20)
+        ));
+
+        // The shortened name in a later advertisement does not replace the complete one.
+        assert!(!table.report(public(1), Some(-65), &[3, 0x08, b'T', b'e'], Some(30)));
+        let device = table.get(&public(1)).unwrap();
+        assert_eq!(device.name(), Some(&b"Test"[..]));
+        assert!(device.is_name_complete());
+        assert_eq!(
+            device.service_uuids(),
+            [bluenrg::gatt::Uuid::Uuid16(0x180F)]
+        );
+        assert_eq!(device.rssi(), Some(-65));
+        assert_eq!(device.last_seen_ms(), Some(30));
+        assert_eq!(table.devices().len(), 1);
+    }
+
+    #[test]
+    fn replaces_the_weakest_device_once_full() {
+        let mut table = DeviceTable::new();
+        for i in 0..MAX_DEVICES as u8 {
+            assert!(table.report(public(i), Some(-50 - i as i8), &[], None));
+        }
+
+        assert!(!table.report(public(0xFF), Some(-90), &[], None));
+        assert!(table.get(&public(0xFF)).is_none());
+
+        assert!(table.report(public(0xFF), Some(-40), &[], None));
+        assert!(table.get(&public(0xFF)).is_some());
+        assert!(table.get(&public(MAX_DEVICES as u8 - 1)).is_none());
+        assert_eq!(table.devices().len(), MAX_DEVICES);
+    }
+}
@@ -0,0 +1,487 @@
+//! Pairing and bonding with central devices.
+//!
+//! The BlueNRG runs the Security Manager protocol itself. The host only chooses how the passkey
+//! is entered, and answers the controller when it needs a passkey, asks whether a device may
+//! access an attribute, or reports that a bonded device lost its keys. The [`SecurityManager`]
+//! decides those answers, from its [`PinMode`] or from a [`PairingAgent`], and keeps the outcome
+//! of the last pairing and the list of bonded devices, which the event loop reads back from the
+//! controller during initialization and after every successful pairing.
+
+use crate::flash::Flash;
+use crate::storage::{self, key, Store};
+use bluenrg::event::BlueNRGEvent;
+
+/// Maximum number of bonded devices the security manager keeps.
+pub const MAX_BONDED_DEVICES: usize = 8;
+
+/// Largest passkey that can be entered with six digits.
+pub const MAX_PASSKEY: u32 = 999_999;
+
+/// How the passkey for pairing is chosen.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PinMode {
+    /// Every pairing uses the same passkey, which the controller supplies itself.
+    Fixed(u32),
+
+    /// A passkey derived from the seed when the event loop is created, and logged during
+    /// initialization so the user can enter it on the central device. The seed must differ on
+    /// every boot.
+    PerBoot(u32),
+
+    /// Every pairing asks the [`PairingAgent`] for the passkey. Without an agent, or without a
+    /// passkey from it, pairing is rejected.
+    Agent,
+}
+
+/// Returns a passkey of at most six digits derived from `seed`.
+pub fn passkey_from_seed(seed: u32) -> u32 {
+    // One round of xorshift, so nearby seeds give unrelated passkeys.
+    let mut x = seed ^ 0x9E37_79B9;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+
+    x % (MAX_PASSKEY + 1)
+}
+
+/// Returns a seed for [`PinMode::PerBoot`] that differs on every boot. The seed mixes a boot
+/// counter, which is incremented in `store`, with the unique device ID, so boards that booted
+/// equally often still get different passkeys.
+///
+/// Every call appends an 8-byte record to the store. With the other stored values, a page fills
+/// after about 200 boots, and the two pages take turns, so each page is erased about once every
+/// 400 boots. The STM32F303 flash is specified for 10 000 erase cycles, which lasts about four
+/// million boots. Call it once per boot, not once per pairing.
+pub fn boot_seed<F: Flash>(store: &mut Store<F>, unique_id: &[u8; 12]) -> u32 {
+    let mut buffer = [0; 4];
+    let count = match store.read(key::BOOT_COUNT, &mut buffer) {
+        Some(4) => u32::from_le_bytes(buffer),
+        _ => 0,
+    }
+    .wrapping_add(1);
+    // If the counter cannot be saved, this boot still gets a passkey; the next one repeats it.
+    let _ = store.write(key::BOOT_COUNT, &count.to_le_bytes());
+
+    unique_id.chunks(4).fold(count, |seed, word| {
+        seed.rotate_left(7) ^ u32::from_le_bytes([word[0], word[1], word[2], word[3]])
+    })
+}
+
+/// Persistent storage for the list of bonded devices, so the list survives a power cycle.
+pub trait BondStore {
+    /// Loads the stored bonded devices into `devices`, and returns how many there are.
+    fn load_bonded_devices(&mut self, devices: &mut [hci::BdAddrType; MAX_BONDED_DEVICES])
+        -> usize;
+
+    /// Replaces the stored bonded devices with `devices`. Returns false if they could not be
+    /// stored.
+    fn save_bonded_devices(&mut self, devices: &[hci::BdAddrType]) -> bool;
+}
+
+/// Length of a stored bonded device: the address type, 0 for public and 1 for random, followed
+/// by the address.
+const BOND_LEN: usize = 7;
+
+/// Each bonded device is stored under its own key, from [`key::FIRST_BOND`], so a change only
+/// rewrites the devices that moved.
+impl<F: Flash> BondStore for Store<F> {
+    fn load_bonded_devices(
+        &mut self,
+        devices: &mut [hci::BdAddrType; MAX_BONDED_DEVICES],
+    ) -> usize {
+        let mut len = 0;
+        for bond_key in key::FIRST_BOND..=key::LAST_BOND {
+            if len == MAX_BONDED_DEVICES {
+                break;
+            }
+            let mut buffer = [0; storage::MAX_VALUE_LEN];
+            if self.read(bond_key, &mut buffer) != Some(BOND_LEN) {
+                continue;
+            }
+            let mut addr = hci::BdAddr([0; 6]);
+            addr.0.copy_from_slice(&buffer[1..BOND_LEN]);
+            devices[len] = match buffer[0] {
+                0 => hci::BdAddrType::Public(addr),
+                1 => hci::BdAddrType::Random(addr),
+                _ => continue,
+            };
+            len += 1;
+        }
+
+        len
+    }
+
+    fn save_bonded_devices(&mut self, devices: &[hci::BdAddrType]) -> bool {
+        let mut saved = true;
+        for (i, bond_key) in (key::FIRST_BOND..=key::LAST_BOND).enumerate() {
+            let result = match devices.get(i) {
+                Some(device) => {
+                    let (addr_type, addr) = match device {
+                        hci::BdAddrType::Public(addr) => (0, addr),
+                        hci::BdAddrType::Random(addr) => (1, addr),
+                    };
+                    let mut value = [addr_type; BOND_LEN];
+                    value[1..].copy_from_slice(&addr.0);
+                    self.write(bond_key, &value)
+                }
+                None => self.remove(bond_key),
+            };
+            saved &= result.is_ok();
+        }
+
+        saved
+    }
+}
+
+/// Result of a pairing attempt.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PairingStatus {
+    /// The devices paired, and bonded if both asked for bonding.
+    Success,
+
+    /// The central device stopped responding during pairing.
+    Timeout,
+
+    /// Pairing failed, for example because the passkeys did not match.
+    Failed,
+}
+
+/// Outcome of a pairing attempt with a central device.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PairingOutcome {
+    /// Connection on which the central device paired.
+    pub conn_handle: hci::ConnectionHandle,
+
+    /// Result of the pairing.
+    pub status: PairingStatus,
+}
+
+/// Input and output the user has for pairing, and the decisions that need the user.
+///
+/// The event loop calls these methods while handling events, so they must return promptly. If no
+/// passkey is available, [`passkey`](PairingAgent::passkey) returns `None`, and the pairing is
+/// rejected.
+pub trait PairingAgent {
+    /// Returns what the device can display and enter, which decides how pairing authenticates
+    /// the central device.
+    fn io_capability(&self) -> bluenrg::gap::IoCapability;
+
+    /// Returns the passkey for pairing on `conn_handle`. A device with a display generates one
+    /// and shows it; a device with a keyboard returns the one the user typed.
+    fn passkey(&mut self, conn_handle: hci::ConnectionHandle) -> Option<u32>;
+
+    /// Returns true if the device on `conn_handle` may access attributes that need
+    /// authorization.
+    fn authorize(&mut self, conn_handle: hci::ConnectionHandle) -> bool;
+
+    /// Returns true if the device on `conn_handle`, which lost the keys of an existing bond, may
+    /// pair again. Only asked while the pairing window is closed; without an agent, the device
+    /// may not.
+    fn allow_rebond(&mut self, conn_handle: hci::ConnectionHandle) -> bool;
+
+    /// Reports the outcome of a pairing attempt.
+    fn pairing_complete(&mut self, outcome: PairingOutcome);
+}
+
+/// An answer to the controller that the event loop has to send.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Response {
+    /// Send the passkey for pairing on the connection.
+    Passkey(hci::ConnectionHandle, u32),
+
+    /// Allow or reject access to attributes that need authorization.
+    Authorization(hci::ConnectionHandle, bool),
+
+    /// Allow the device on the connection to pair again.
+    Rebond(hci::ConnectionHandle),
+
+    /// Reject pairing on the connection, by disconnecting with an authentication failure. The
+    /// controller has no other way to refuse a passkey request.
+    Reject(hci::ConnectionHandle),
+}
+
+/// Decides how the device pairs, and keeps track of the devices it bonded with.
+pub struct SecurityManager<'a> {
+    pin_mode: PinMode,
+    agent: Option<&'a mut dyn PairingAgent>,
+
+    pending: Option<Response>,
+    pairing_window_open: bool,
+    bonds_stale: bool,
+    bond_store: Option<&'a mut dyn BondStore>,
+
+    last_pairing: Option<PairingOutcome>,
+    bonded: [hci::BdAddrType; MAX_BONDED_DEVICES],
+    bonded_len: usize,
+}
+
+impl<'a> SecurityManager<'a> {
+    /// Creates a security manager that pairs with the given passkey mode.
+    pub fn new(pin_mode: PinMode) -> SecurityManager<'a> {
+        SecurityManager {
+            pin_mode: pin_mode,
+            agent: None,
+            pending: None,
+            pairing_window_open: false,
+            bonds_stale: false,
+            bond_store: None,
+            last_pairing: None,
+            bonded: [hci::BdAddrType::Public(hci::BdAddr([0; 6])); MAX_BONDED_DEVICES],
+            bonded_len: 0,
+        }
+    }
+
+    /// Changes how the passkey is chosen. Takes effect the next time the event loop initializes
+    /// the controller.
+    pub fn set_pin_mode(&mut self, pin_mode: PinMode) {
+        self.pin_mode = pin_mode;
+    }
+
+    /// Sets the agent that supplies passkeys in [`PinMode::Agent`], and that makes the decisions
+    /// and receives the reports in every mode.
+    pub fn set_agent(&mut self, agent: &'a mut dyn PairingAgent) {
+        self.agent = Some(agent);
+    }
+
+    /// Records whether the pairing window is open. While it is, a device that lost its bond may
+    /// pair again without asking the agent.
+    pub fn set_pairing_window_open(&mut self, open: bool) {
+        self.pairing_window_open = open;
+    }
+
+    /// Sets the store that keeps the list of bonded devices across power cycles, and loads the
+    /// list from it. The list read from the controller replaces it during initialization.
+    pub fn set_bond_store(&mut self, store: &'a mut dyn BondStore) {
+        self.bonded_len = store.load_bonded_devices(&mut self.bonded);
+        self.bond_store = Some(store);
+    }
+
+    /// Returns how the passkey is chosen.
+    pub fn pin_mode(&self) -> PinMode {
+        self.pin_mode
+    }
+
+    /// Returns the passkey used for every pairing, or `None` if the agent supplies it.
+    pub fn fixed_passkey(&self) -> Option<u32> {
+        match self.pin_mode {
+            PinMode::Fixed(pin) => Some(pin),
+            PinMode::PerBoot(seed) => Some(passkey_from_seed(seed)),
+            PinMode::Agent => None,
+        }
+    }
+
+    /// Returns the passkey setting for the controller's authentication requirements.
+    pub fn pin(&self) -> bluenrg::gap::Pin {
+        match self.fixed_passkey() {
+            Some(pin) => bluenrg::gap::Pin::Fixed(pin),
+            None => bluenrg::gap::Pin::Requested,
+        }
+    }
+
+    /// Returns the I/O capability reported to central devices. With a fixed passkey, the device
+    /// acts as if it displayed it.
+    pub fn io_capability(&self) -> bluenrg::gap::IoCapability {
+        match (self.pin_mode, self.agent.as_ref()) {
+            (PinMode::Agent, Some(agent)) => agent.io_capability(),
+            _ => bluenrg::gap::IoCapability::Display,
+        }
+    }
+
+    /// Returns the outcome of the last pairing attempt since the event loop started.
+    pub fn last_pairing(&self) -> Option<PairingOutcome> {
+        self.last_pairing
+    }
+
+    /// Returns the devices bonded with the controller, as last read from it.
+    pub fn bonded_devices(&self) -> &[hci::BdAddrType] {
+        &self.bonded[..self.bonded_len]
+    }
+
+    /// Handles a GAP event from the controller. `conn_handle` is the current connection, if
+    /// any. Returns true if the event was a security event.
+    pub fn handle_event(
+        &mut self,
+        event: &BlueNRGEvent,
+        conn_handle: Option<hci::ConnectionHandle>,
+    ) -> bool {
+        match event {
+            BlueNRGEvent::GapPassKeyRequest(conn_handle) => {
+                let passkey = match (self.fixed_passkey(), self.agent.as_mut()) {
+                    (Some(pin), _) => Some(pin),
+                    (None, Some(agent)) => agent.passkey(*conn_handle),
+                    (None, None) => None,
+                };
+                self.pending = Some(match passkey.filter(|&p| p <= MAX_PASSKEY) {
+                    Some(passkey) => Response::Passkey(*conn_handle, passkey),
+                    None => Response::Reject(*conn_handle),
+                });
+            }
+            BlueNRGEvent::GapAuthorizationRequest(conn_handle) => {
+                // Without an agent, nobody can grant authorization.
+                let authorized = self
+                    .agent
+                    .as_mut()
+                    .map_or(false, |agent| agent.authorize(*conn_handle));
+                self.pending = Some(Response::Authorization(*conn_handle, authorized));
+            }
+            BlueNRGEvent::GapBondLost => {
+                // Any device can claim to be a bonded device that lost its keys, so it only
+                // replaces the bond if the user agrees, by opening the pairing window or through
+                // the agent.
+                if let Some(conn_handle) = conn_handle {
+                    let allowed = self.pairing_window_open
+                        || self
+                            .agent
+                            .as_mut()
+                            .map_or(false, |agent| agent.allow_rebond(conn_handle));
+                    if allowed {
+                        self.pending = Some(Response::Rebond(conn_handle));
+                    }
+                }
+            }
+            BlueNRGEvent::GapPairingComplete(p) => {
+                let outcome = PairingOutcome {
+                    conn_handle: p.conn_handle,
+                    status: match p.status {
+                        bluenrg::event::GapPairingStatus::Success => PairingStatus::Success,
+                        bluenrg::event::GapPairingStatus::Timeout => PairingStatus::Timeout,
+                        bluenrg::event::GapPairingStatus::Failed => PairingStatus::Failed,
+                    },
+                };
+                self.last_pairing = Some(outcome);
+                self.bonds_stale = outcome.status == PairingStatus::Success;
+                if let Some(agent) = self.agent.as_mut() {
+                    agent.pairing_complete(outcome);
+                }
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Returns the answer the event loop has to send next, if any.
+    pub fn pending_response(&self) -> Option<Response> {
+        self.pending
+    }
+
+    /// Records that the pending answer was sent.
+    pub fn response_sent(&mut self) {
+        self.pending = None;
+    }
+
+    /// Returns true if the list of bonded devices has to be read again from the controller.
+    pub fn bonds_stale(&self) -> bool {
+        self.bonds_stale
+    }
+
+    /// Replaces the list of bonded devices with the one read from the controller, and saves it in
+    /// the [bond store](SecurityManager::set_bond_store), if there is one. Devices beyond
+    /// [`MAX_BONDED_DEVICES`] are dropped. Returns false if the list could not be saved.
+    pub fn set_bonded_devices(&mut self, devices: &[hci::BdAddrType]) -> bool {
+        let len = core::cmp::min(devices.len(), MAX_BONDED_DEVICES);
+        self.bonded[..len].copy_from_slice(&devices[..len]);
+        self.bonded_len = len;
+        self.bonds_stale = false;
+
+        let bonded = &self.bonded[..len];
+        self.bond_store
+            .as_mut()
+            .map_or(true, |store| store.save_bonded_devices(bonded))
+    }
+
+    /// Forgets any answer the event loop has not sent, when the connection ends or the
+    /// controller is reset.
+    pub fn clear_pending(&mut self) {
+        self.pending = None;
+    }
+}
+
+impl<'a> Default for SecurityManager<'a> {
+    /// Returns a security manager that uses the fixed passkey 123456.
+    fn default() -> SecurityManager<'a> {
+        SecurityManager::new(PinMode::Fixed(123_456))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::RamFlash;
+
+    #[test]
+    fn stores_bonded_devices() {
+        let devices = [
+            hci::BdAddrType::Public(hci::BdAddr([1, 2, 3, 4, 5, 6])),
+            hci::BdAddrType::Random(hci::BdAddr([6, 5, 4, 3, 2, 0xC1])),
+        ];
+        let mut store = Store::open(RamFlash::new()).unwrap();
+        assert!(store.save_bonded_devices(&devices));
+
+        let mut store = Store::open(store.flash().clone()).unwrap();
+        let mut loaded = [hci::BdAddrType::Public(hci::BdAddr([0; 6])); MAX_BONDED_DEVICES];
+        assert_eq!(store.load_bonded_devices(&mut loaded), 2);
+        assert_eq!(loaded[..2], devices);
+
+        // A shorter list removes the devices that are no longer bonded.
+        assert!(store.save_bonded_devices(&devices[1..]));
+        assert_eq!(store.load_bonded_devices(&mut loaded), 1);
+        assert_eq!(loaded[0], devices[1]);
+    }
+
+    #[test]
+    fn loads_and_saves_bonds_through_the_store() {
+        let device = hci::BdAddrType::Public(hci::BdAddr([1, 2, 3, 4, 5, 6]));
+        let mut store = Store::open(RamFlash::new()).unwrap();
+        store.save_bonded_devices(&[device]);
+
+        let mut security = SecurityManager::default();
+        security.set_bond_store(&mut store);
+        assert_eq!(security.bonded_devices(), [device]);
+        assert!(security.set_bonded_devices(&[]));
+        assert!(security.bonded_devices().is_empty());
+        drop(security);
+
+        let mut loaded = [device; MAX_BONDED_DEVICES];
+        assert_eq!(store.load_bonded_devices(&mut loaded), 0);
+    }
+
+    #[test]
+    fn rejects_pairing_without_a_passkey() {
+        let conn_handle = hci::ConnectionHandle(0x0801);
+        let mut security = SecurityManager::new(PinMode::Agent);
+        assert!(security.handle_event(
+            &BlueNRGEvent::GapPassKeyRequest(conn_handle),
+            Some(conn_handle)
+        ));
+        assert_eq!(
+            security.pending_response(),
+            Some(Response::Reject(conn_handle))
+        );
+
+        let mut security = SecurityManager::new(PinMode::Fixed(123_456));
+        security.handle_event(
+            &BlueNRGEvent::GapPassKeyRequest(conn_handle),
+            Some(conn_handle),
+        );
+        assert_eq!(
+            security.pending_response(),
+            Some(Response::Passkey(conn_handle, 123_456))
+        );
+    }
+
+    #[test]
+    fn rebonds_only_while_the_pairing_window_is_open() {
+        let conn_handle = hci::ConnectionHandle(0x0801);
+        let mut security = SecurityManager::default();
+        security.handle_event(&BlueNRGEvent::GapBondLost, Some(conn_handle));
+        assert_eq!(security.pending_response(), None);
+
+        security.set_pairing_window_open(true);
+        security.handle_event(&BlueNRGEvent::GapBondLost, Some(conn_handle));
+        assert_eq!(
+            security.pending_response(),
+            Some(Response::Rebond(conn_handle))
+        );
+    }
+}
@@ -0,0 +1,152 @@
+//! Environmental sensor readings served by the Environmental Sensor service.
+//!
+//! Each reading is encoded in the format declared by the characteristic's presentation format
+//! (0x2904) descriptor:
+//!
+//! - Temperature is a signed 16-bit value with exponent -1, in degrees Celsius.
+//! - Pressure is a signed 24-bit value with exponent -5, in bar (so the value is in pascals).
+//! - Humidity is an unsigned 16-bit value with exponent -1, in percent.
+
+/// Source of environmental readings.
+pub trait EnvironmentalSensor {
+    /// Returns the temperature, in tenths of a degree Celsius, or `None` if it could not be read.
+    fn temperature(&mut self) -> Option<i16>;
+
+    /// Returns the barometric pressure, in pascals, or `None` if it could not be read.
+    fn pressure(&mut self) -> Option<i32>;
+
+    /// Returns the relative humidity, in tenths of a percent, or `None` if it could not be read.
+    fn humidity(&mut self) -> Option<u16>;
+}
+
+/// Encodes a temperature in tenths of a degree Celsius for the temperature characteristic.
+pub fn encode_temperature(temperature: i16) -> [u8; 2] {
+    [temperature as u8, (temperature >> 8) as u8]
+}
+
+/// Encodes a pressure in pascals for the pressure characteristic. Pressures that do not fit in 24
+/// bits are saturated.
+pub fn encode_pressure(pressure: i32) -> [u8; 3] {
+    const MAX: i32 = (1 << 23) - 1;
+    const MIN: i32 = -(1 << 23);
+
+    let pressure = if pressure > MAX {
+        MAX
+    } else if pressure < MIN {
+        MIN
+    } else {
+        pressure
+    };
+    [
+        pressure as u8,
+        (pressure >> 8) as u8,
+        (pressure >> 16) as u8,
+    ]
+}
+
+/// Encodes a relative humidity in tenths of a percent for the humidity characteristic.
+pub fn encode_humidity(humidity: u16) -> [u8; 2] {
+    [humidity as u8, (humidity >> 8) as u8]
+}
+
+/// Environmental sensor that produces plausible, deterministic readings, for boards without
+/// sensors and for tests on the host.
+///
+/// Every reading moves the value one step along a triangle wave around its initial value.
+pub struct SimulatedEnvironmentalSensor {
+    temperature: Wave,
+    pressure: Wave,
+    humidity: Wave,
+}
+
+impl SimulatedEnvironmentalSensor {
+    /// Creates a sensor that starts at 21.5 degrees Celsius, 101325 Pa, and 45% humidity.
+    pub fn new() -> SimulatedEnvironmentalSensor {
+        SimulatedEnvironmentalSensor {
+            temperature: Wave::new(215, 1, 10),
+            pressure: Wave::new(101_325, 10, 10),
+            humidity: Wave::new(450, 5, 10),
+        }
+    }
+}
+
+impl EnvironmentalSensor for SimulatedEnvironmentalSensor {
+    fn temperature(&mut self) -> Option<i16> {
+        Some(self.temperature.next() as i16)
+    }
+
+    fn pressure(&mut self) -> Option<i32> {
+        Some(self.pressure.next())
+    }
+
+    fn humidity(&mut self) -> Option<u16> {
+        Some(self.humidity.next() as u16)
+    }
+}
+
+struct Wave {
+    center: i32,
+    step: i32,
+    half_period: i32,
+    position: i32,
+}
+
+impl Wave {
+    fn new(center: i32, step: i32, half_period: i32) -> Wave {
+        Wave {
+            center: center,
+            step: step,
+            half_period: half_period,
+            position: 0,
+        }
+    }
+
+    fn next(&mut self) -> i32 {
+        let period = 2 * self.half_period;
+        let phase = self.position % period;
+        self.position = (self.position + 1) % period;
+
+        let offset = if phase < self.half_period {
+            phase
+        } else {
+            period - phase
+        };
+        self.center + self.step * (offset - self.half_period / 2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_readings_little_endian() {
+        assert_eq!(encode_temperature(215), [0xD7, 0x00]);
+        assert_eq!(encode_temperature(-15), [0xF1, 0xFF]);
+        assert_eq!(encode_pressure(101_325), [0xCD, 0x8B, 0x01]);
+        assert_eq!(encode_humidity(450), [0xC2, 0x01]);
+    }
+
+    #[test]
+    fn saturates_pressures_that_do_not_fit() {
+        assert_eq!(encode_pressure(1 << 23), [0xFF, 0xFF, 0x7F]);
+        assert_eq!(encode_pressure(-(1 << 23) - 1), [0x00, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn simulated_readings_follow_a_triangle_wave() {
+        let mut sensor = SimulatedEnvironmentalSensor::new();
+        let temperatures: std::vec::Vec<i16> =
+            (0..20).map(|_| sensor.temperature().unwrap()).collect();
+        assert_eq!(temperatures[0], 210);
+        assert_eq!(temperatures[10], 220);
+        for pair in temperatures.windows(2) {
+            assert_eq!((pair[1] - pair[0]).abs(), 1);
+        }
+
+        // The wave repeats, so the readings stay around the initial value.
+        assert_eq!(sensor.temperature(), Some(210));
+        assert_eq!(sensor.pressure(), Some(101_275));
+        assert_eq!(sensor.humidity(), Some(425));
+    }
+}
@@ -0,0 +1,1314 @@
+//! A software stand-in for the SPBTLE-RF module.
+//!
+//! The [`Simulator`] speaks the BlueNRG SPI framing: every transaction starts with a 5-byte
+//! header, to which the controller replies with a ready byte and the sizes of its write and read
+//! buffers. Commands written by the host are parsed when chip select is released, recorded in a
+//! log, and answered with canned Command Complete or Command Status events. Events are returned
+//! to the host through read transactions, and the data ready line is high whenever there is
+//! something to read.
+//!
+//! The module is built for this crate's own tests, and for other crates with the `sim` feature.
+//!
+//! The simulator is meant to be shared by reference between the SPI bus and the three pins that
+//! [`bluenrg::BlueNRG`] needs, so a test can drive the [`EventLoop`](crate::EventLoop) and then
+//! inspect the exact commands that were sent:
+//!
+//! ```ignore
+//! let sim = sim::Simulator::new();
+//! let mut rx_buffer = [0; 128];
+//! let mut bnrg = bluenrg::BlueNRG::new(
+//!     &mut rx_buffer,
+//!     sim.chip_select(),
+//!     sim.data_ready(),
+//!     sim.reset_pin(),
+//! );
+//! let mut event_loop = EventLoop::new(
+//!     &mut bnrg,
+//!     sim::Timer,
+//!     0,
+//!     sim.spi(),
+//!     config::DeviceConfig::default(),
+//! );
+//! while event_loop.state() != State::Complete {
+//!     event_loop.step();
+//! }
+//! assert_eq!(sim.commands()[0].opcode(), sim::opcode::READ_LOCAL_VERSION_INFORMATION);
+//! ```
+//!
+//! The simulator enforces the security permissions of the characteristics the host adds, as the
+//! controller does. Until the link is encrypted or authenticated, as the characteristic
+//! requires, the central's writes and reads never reach the host; the controller answers them
+//! with an ATT error, which the simulator records instead:
+//!
+//! ```ignore
+//! sim.connect(0x0801, PEER, 40);
+//! sim.write_attribute(0x0801, led_value_handle, &[1, 0, 0, 0]);
+//! run_until_idle(&mut event_loop);
+//! assert_eq!(
+//!     sim.att_errors()[0].error,
+//!     sim::att_error::INSUFFICIENT_AUTHENTICATION
+//! );
+//! assert!(!led_is_on());
+//!
+//! sim.pairing_complete(0x0801, PEER, 0);
+//! sim.write_attribute(0x0801, led_value_handle, &[1, 0, 0, 0]);
+//! run_until_idle(&mut event_loop);
+//! assert!(led_is_on());
+//! ```
+//!
+//! The simulator also tracks how the controller advertises, so central devices can only
+//! [connect](Simulator::connect) while it is advertising, and only from the white list when
+//! advertising is restricted to it:
+//!
+//! ```ignore
+//! sim.add_bond(PEER);
+//! run_until(&mut event_loop, State::Complete);
+//! assert_eq!(
+//!     event_loop.advertising_mode(),
+//!     Some(reconnection::AdvertisingMode::WhiteList)
+//! );
+//! assert!(!sim.connect(0x0801, STRANGER, 40));
+//! assert!(sim.connect(0x0801, PEER, 40));
+//! ```
+//!
+//! For [privacy](crate::EventLoop::set_privacy), the simulator answers LE Rand and LE Encrypt,
+//! although its encryption is not AES, and records the random address the host sets:
+//!
+//! ```ignore
+//! event_loop.set_privacy(&mut key_store, privacy::DEFAULT_ROTATION_PERIOD_MS);
+//! run_until(&mut event_loop, State::Complete);
+//! let address = hci::BdAddr(sim.random_address().unwrap());
+//! assert!(privacy::is_resolvable_private_address(&address));
+//! ```
+//!
+//! In the [observer](crate::scanner::Role::Observer) role, nearby devices can
+//! [advertise](Simulator::advertising_report) while the controller is discovering:
+//!
+//! ```ignore
+//! event_loop.set_role(scanner::Role::Observer);
+//! run_until(&mut event_loop, State::Discovering);
+//! assert!(sim.advertising_report(PEER, -60, &[5, 0x09, b'T', b'e', b's', b't']));
+//! run_until_idle(&mut event_loop);
+//! let device = event_loop
+//!     .devices()
+//!     .get(&hci::BdAddrType::Public(hci::BdAddr(PEER)))
+//!     .unwrap();
+//! assert_eq!(device.name(), Some(&b"Test"[..]));
+//! assert_eq!(device.rssi(), Some(-60));
+//! ```
+
+use core::cell::{Ref, RefCell};
+use void::Void;
+
+/// Opcodes of the commands the simulator answers.
+pub use crate::command::opcode;
+
+/// Maximum number of commands kept in the command log. Commands sent after the log is full are
+/// still answered, but not recorded.
+pub const MAX_COMMANDS: usize = 64;
+
+/// Maximum number of bytes of events that may be waiting to be read by the host.
+pub const MAX_PENDING_EVENT_BYTES: usize = 1024;
+
+const MAX_PARAMETER_LEN: usize = 255;
+const COMMAND_HEADER_LEN: usize = 4;
+const MAX_OVERRIDES: usize = 8;
+
+/// Maximum number of bonded devices the simulated controller remembers.
+pub const MAX_BONDS: usize = 8;
+
+/// Maximum number of rejected ATT requests kept in the log. Requests rejected after the log is
+/// full are still rejected, but not recorded.
+pub const MAX_ATT_ERRORS: usize = 16;
+
+const MAX_PROTECTED_ATTRIBUTES: usize = 16;
+
+const ACCESS_WRITE: u8 = 0x0A;
+const ACCESS_READ: u8 = 0x0B;
+const READY: u8 = 0x02;
+
+const PACKET_TYPE_COMMAND: u8 = 0x01;
+const PACKET_TYPE_EVENT: u8 = 0x04;
+const EVENT_DISCONNECTION_COMPLETE: u8 = 0x05;
+const EVENT_ENCRYPTION_CHANGE: u8 = 0x08;
+const EVENT_COMMAND_COMPLETE: u8 = 0x0E;
+const EVENT_COMMAND_STATUS: u8 = 0x0F;
+const EVENT_LE_META: u8 = 0x3E;
+const LE_SUBEVENT_CONNECTION_COMPLETE: u8 = 0x01;
+const LE_SUBEVENT_ADVERTISING_REPORT: u8 = 0x02;
+const ADVERTISING_EVENT_CONNECTABLE_UNDIRECTED: u8 = 0x00;
+const STATUS_DIRECTED_ADVERTISING_TIMEOUT: u8 = 0x3C;
+const ROLE_PERIPHERAL: u8 = 0x01;
+const EVENT_VENDOR: u8 = 0xFF;
+const VENDOR_EVENT_HAL_INITIALIZED: u16 = 0x0001;
+const VENDOR_EVENT_GAP_PAIRING_COMPLETE: u16 = 0x0401;
+const VENDOR_EVENT_GAP_PASS_KEY_REQUEST: u16 = 0x0402;
+const VENDOR_EVENT_GAP_AUTHORIZATION_REQUEST: u16 = 0x0403;
+const VENDOR_EVENT_GAP_BOND_LOST: u16 = 0x0405;
+const VENDOR_EVENT_GAP_PROCEDURE_COMPLETE: u16 = 0x0407;
+const GAP_PROCEDURE_GENERAL_DISCOVERY: u8 = 0x02;
+const VENDOR_EVENT_GATT_ATTRIBUTE_MODIFIED: u16 = 0x0C01;
+const VENDOR_EVENT_GATT_READ_PERMIT_REQUEST: u16 = 0x0C14;
+const VENDOR_EVENT_GATT_SERVER_CONFIRMATION: u16 = 0x0C17;
+const RESET_REASON_NORMAL: u8 = 0x01;
+
+const STATUS_SUCCESS: u8 = 0x00;
+const STATUS_UNKNOWN_COMMAND: u8 = 0x01;
+const STATUS_UNKNOWN_CONNECTION_ID: u8 = 0x02;
+const STATUS_COMMAND_DISALLOWED: u8 = 0x0C;
+
+const FILTER_CONNECTION_WHITE_LIST: u8 = 0x02;
+
+const PERMISSION_AUTHEN_READ: u8 = 0x01;
+const PERMISSION_ENCRY_READ: u8 = 0x04;
+const PERMISSION_AUTHEN_WRITE: u8 = 0x08;
+const PERMISSION_ENCRY_WRITE: u8 = 0x20;
+
+const RANDOM_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+const FIRST_SERVICE_HANDLE: u16 = 0x0001;
+const GAP_SERVICE_ATTRIBUTE_RECORDS: u16 = 8;
+
+/// A command sent to the simulated controller.
+#[derive(Copy, Clone)]
+pub struct Command {
+    opcode: u16,
+    len: usize,
+    params: [u8; MAX_PARAMETER_LEN],
+}
+
+impl Command {
+    /// Returns the opcode of the command.
+    pub fn opcode(&self) -> u16 {
+        self.opcode
+    }
+
+    /// Returns the parameters of the command, without the packet type, opcode, or length.
+    pub fn params(&self) -> &[u8] {
+        &self.params[..self.len]
+    }
+}
+
+impl core::fmt::Debug for Command {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:#06x}: {:?}", self.opcode, self.params())
+    }
+}
+
+/// Version information reported by the simulated controller in response to Read Local Version
+/// Information.
+#[derive(Copy, Clone, Debug)]
+pub struct Version {
+    /// Hardware version of the controller.
+    pub hw_version: u8,
+    /// Major firmware version.
+    pub major: u8,
+    /// Minor firmware version.
+    pub minor: u8,
+    /// Firmware patch level.
+    pub patch: u8,
+}
+
+/// ATT error codes the simulated controller answers rejected requests with.
+pub mod att_error {
+    /// The link is not authenticated, and the attribute requires it.
+    pub const INSUFFICIENT_AUTHENTICATION: u8 = 0x05;
+    /// The link is not encrypted, and the attribute requires it.
+    pub const INSUFFICIENT_ENCRYPTION: u8 = 0x0F;
+}
+
+/// A request from the central that the simulated controller rejected without telling the host.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AttError {
+    /// Connection on which the request was made.
+    pub conn_handle: u16,
+    /// Attribute the central tried to access.
+    pub attribute_handle: u16,
+    /// ATT error code sent back to the central, one of the [`att_error`] codes.
+    pub error: u8,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Advertising {
+    Off,
+    Undirected { white_list_only: bool },
+    Directed([u8; 6]),
+}
+
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+enum LinkSecurity {
+    Unencrypted,
+    Encrypted,
+    Authenticated,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Transaction {
+    Idle,
+    Header,
+    Writing,
+    Reading,
+}
+
+struct Controller {
+    version: Version,
+
+    transaction: Transaction,
+    write_buffer: [u8; COMMAND_HEADER_LEN + MAX_PARAMETER_LEN],
+    write_len: usize,
+
+    events: [u8; MAX_PENDING_EVENT_BYTES],
+    events_len: usize,
+
+    commands: [Command; MAX_COMMANDS],
+    command_count: usize,
+
+    status_overrides: [Option<(u16, u8)>; MAX_OVERRIDES],
+
+    bonds: [[u8; 6]; MAX_BONDS],
+    bond_count: usize,
+    white_list: [[u8; 6]; MAX_BONDS],
+    white_list_len: usize,
+    advertising: Advertising,
+    discovering: bool,
+
+    link: Option<(u16, LinkSecurity)>,
+    permissions: [Option<(u16, u8)>; MAX_PROTECTED_ATTRIBUTES],
+    att_errors: [AttError; MAX_ATT_ERRORS],
+    att_error_count: usize,
+
+    random: u64,
+    random_address: Option<[u8; 6]>,
+
+    in_reset: bool,
+    next_service_handle: u16,
+    next_attribute_handle: u16,
+}
+
+/// Simulated SPBTLE-RF module.
+///
+/// The simulator starts out as if it had just been powered up and had already reported that it
+/// was initialized, so it has no pending events.
+pub struct Simulator {
+    controller: RefCell<Controller>,
+}
+
+impl Simulator {
+    /// Creates a simulator that reports firmware version 7.2.
+    pub fn new() -> Simulator {
+        Simulator::with_version(Version {
+            hw_version: 0x31,
+            major: 7,
+            minor: 2,
+            patch: 0,
+        })
+    }
+
+    /// Creates a simulator that reports the given version.
+    pub fn with_version(version: Version) -> Simulator {
+        Simulator {
+            controller: RefCell::new(Controller {
+                version: version,
+                transaction: Transaction::Idle,
+                write_buffer: [0; COMMAND_HEADER_LEN + MAX_PARAMETER_LEN],
+                write_len: 0,
+                events: [0; MAX_PENDING_EVENT_BYTES],
+                events_len: 0,
+                commands: [Command {
+                    opcode: 0,
+                    len: 0,
+                    params: [0; MAX_PARAMETER_LEN],
+                }; MAX_COMMANDS],
+                command_count: 0,
+                status_overrides: [None; MAX_OVERRIDES],
+                bonds: [[0; 6]; MAX_BONDS],
+                bond_count: 0,
+                white_list: [[0; 6]; MAX_BONDS],
+                white_list_len: 0,
+                advertising: Advertising::Off,
+                discovering: false,
+                link: None,
+                permissions: [None; MAX_PROTECTED_ATTRIBUTES],
+                att_errors: [AttError {
+                    conn_handle: 0,
+                    attribute_handle: 0,
+                    error: 0,
+                }; MAX_ATT_ERRORS],
+                att_error_count: 0,
+                random: RANDOM_SEED,
+                random_address: None,
+                in_reset: false,
+                next_service_handle: FIRST_SERVICE_HANDLE,
+                next_attribute_handle: FIRST_SERVICE_HANDLE,
+            }),
+        }
+    }
+
+    /// Returns the SPI bus connected to the simulated controller.
+    pub fn spi(&self) -> Spi {
+        Spi { sim: self }
+    }
+
+    /// Returns the chip select pin of the simulated controller.
+    pub fn chip_select(&self) -> ChipSelect {
+        ChipSelect { sim: self }
+    }
+
+    /// Returns the reset pin of the simulated controller.
+    pub fn reset_pin(&self) -> ResetPin {
+        ResetPin { sim: self }
+    }
+
+    /// Returns the data ready pin of the simulated controller.
+    pub fn data_ready(&self) -> DataReady {
+        DataReady { sim: self }
+    }
+
+    /// Returns the commands received so far, in the order they were sent.
+    pub fn commands(&self) -> Ref<[Command]> {
+        Ref::map(self.controller.borrow(), |c| &c.commands[..c.command_count])
+    }
+
+    /// Forgets all commands received so far.
+    pub fn clear_commands(&self) {
+        self.controller.borrow_mut().command_count = 0;
+    }
+
+    /// Returns the requests the controller rejected because the link was not secure enough, in
+    /// the order they were made.
+    pub fn att_errors(&self) -> Ref<[AttError]> {
+        Ref::map(self.controller.borrow(), |c| {
+            &c.att_errors[..c.att_error_count]
+        })
+    }
+
+    /// Forgets all rejected requests recorded so far.
+    pub fn clear_att_errors(&self) {
+        self.controller.borrow_mut().att_error_count = 0;
+    }
+
+    /// Makes the next command with the given opcode complete with `status` instead of success.
+    ///
+    /// # Panics
+    ///
+    /// Panics if too many overrides are pending.
+    pub fn fail_next(&self, opcode: u16, status: u8) {
+        let mut controller = self.controller.borrow_mut();
+        let slot = controller
+            .status_overrides
+            .iter_mut()
+            .find(|o| o.is_none())
+            .expect("too many pending status overrides");
+        *slot = Some((opcode, status));
+    }
+
+    /// Queues an arbitrary HCI event for the host to read. `event` is the event code, and
+    /// `params` are the event parameters.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event does not fit in the pending event buffer.
+    pub fn push_event(&self, event: u8, params: &[u8]) {
+        self.controller.borrow_mut().push_event(event, params);
+    }
+
+    /// Queues a Command Status event for the command with the given opcode, as the controller
+    /// sends for commands that finish later, or that fail before they start.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event does not fit in the pending event buffer.
+    pub fn command_status(&self, opcode: u16, status: u8) {
+        self.controller
+            .borrow_mut()
+            .push_command_status(opcode, status);
+    }
+
+    /// Queues a BlueNRG vendor-specific event for the host to read.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event does not fit in the pending event buffer.
+    pub fn push_vendor_event(&self, event: u16, params: &[u8]) {
+        self.controller
+            .borrow_mut()
+            .push_vendor_event(event, params);
+    }
+
+    /// Simulates a central device connecting. `peer` is the central's public address, and the
+    /// connection interval is in units of 1.25 ms.
+    ///
+    /// Returns false, without queuing any event, if the controller is not advertising, or does
+    /// not accept connections from `peer`. Connecting stops advertising.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event does not fit in the pending event buffer.
+    pub fn connect(&self, conn_handle: u16, peer: [u8; 6], interval: u16) -> bool {
+        if !self.controller.borrow().accepts_connection(&peer) {
+            return false;
+        }
+        let mut params = [0; 19];
+        params[0] = LE_SUBEVENT_CONNECTION_COMPLETE;
+        params[1] = STATUS_SUCCESS;
+        put_u16(&mut params[2..], conn_handle);
+        params[4] = ROLE_PERIPHERAL;
+        params[5] = 0x00; // Public address
+        params[6..12].copy_from_slice(&peer);
+        put_u16(&mut params[12..], interval);
+        put_u16(&mut params[14..], 0); // Latency
+        put_u16(&mut params[16..], 400); // Supervision timeout, in units of 10 ms
+        params[18] = 0x00; // Central clock accuracy
+        {
+            let mut controller = self.controller.borrow_mut();
+            controller.advertising = Advertising::Off;
+            controller.link = Some((conn_handle, LinkSecurity::Unencrypted));
+        }
+        self.push_event(EVENT_LE_META, &params);
+
+        true
+    }
+
+    /// Simulates directed advertising timing out without the central device connecting.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the controller is not advertising to a single device, or if the event does not
+    /// fit in the pending event buffer.
+    pub fn directed_advertising_timeout(&self) {
+        let peer = match self.controller.borrow().advertising {
+            Advertising::Directed(peer) => peer,
+            _ => panic!("not advertising to a single device"),
+        };
+        self.controller.borrow_mut().advertising = Advertising::Off;
+
+        let mut params = [0; 19];
+        params[0] = LE_SUBEVENT_CONNECTION_COMPLETE;
+        params[1] = STATUS_DIRECTED_ADVERTISING_TIMEOUT;
+        params[4] = ROLE_PERIPHERAL;
+        params[6..12].copy_from_slice(&peer);
+        // The connection parameters mean nothing without a connection, but they still have to be
+        // in range for the event to parse.
+        put_u16(&mut params[12..], 6); // Interval, in units of 1.25 ms
+        put_u16(&mut params[16..], 10); // Supervision timeout, in units of 10 ms
+        self.push_event(EVENT_LE_META, &params);
+    }
+
+    /// Simulates a nearby device advertising `data`, heard with the signal strength `rssi`, in
+    /// dBm. `peer` is the device's public address.
+    ///
+    /// Returns false, without queuing any event, if the controller is not discovering.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data` is longer than advertising data may be, or if the event does not fit in
+    /// the pending event buffer.
+    pub fn advertising_report(&self, peer: [u8; 6], rssi: i8, data: &[u8]) -> bool {
+        if !self.controller.borrow().discovering {
+            return false;
+        }
+        assert!(
+            data.len() <= crate::advertising::MAX_DATA_LEN,
+            "advertising data too long"
+        );
+
+        let mut params = [0; 12 + crate::advertising::MAX_DATA_LEN];
+        params[0] = LE_SUBEVENT_ADVERTISING_REPORT;
+        params[1] = 1; // Number of reports
+        params[2] = ADVERTISING_EVENT_CONNECTABLE_UNDIRECTED;
+        params[3] = 0x00; // Public address
+        params[4..10].copy_from_slice(&peer);
+        params[10] = data.len() as u8;
+        params[11..11 + data.len()].copy_from_slice(data);
+        params[11 + data.len()] = rssi as u8;
+        self.push_event(EVENT_LE_META, &params[..12 + data.len()]);
+
+        true
+    }
+
+    /// Simulates the general discovery procedure ending, which the controller reports with a GAP
+    /// Procedure Complete event.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the controller is not discovering, or if the event does not fit in the pending
+    /// event buffer.
+    pub fn discovery_complete(&self) {
+        {
+            let mut controller = self.controller.borrow_mut();
+            assert!(controller.discovering, "not discovering");
+            controller.discovering = false;
+        }
+        self.push_vendor_event(
+            VENDOR_EVENT_GAP_PROCEDURE_COMPLETE,
+            &[GAP_PROCEDURE_GENERAL_DISCOVERY, STATUS_SUCCESS],
+        );
+    }
+
+    /// Returns true if the controller is running the general discovery procedure.
+    pub fn is_discovering(&self) -> bool {
+        self.controller.borrow().discovering
+    }
+
+    /// Returns true if the controller is advertising.
+    pub fn is_advertising(&self) -> bool {
+        self.controller.borrow().advertising != Advertising::Off
+    }
+
+    /// Returns the random address the host set, if any since the last reset.
+    pub fn random_address(&self) -> Option<[u8; 6]> {
+        self.controller.borrow().random_address
+    }
+
+    /// Returns the addresses in the controller's white list.
+    pub fn white_list(&self) -> Ref<[[u8; 6]]> {
+        Ref::map(self.controller.borrow(), |c| {
+            &c.white_list[..c.white_list_len]
+        })
+    }
+
+    /// Simulates the central encrypting the link with the keys of an earlier bond, without
+    /// pairing again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event does not fit in the pending event buffer.
+    pub fn encrypt(&self, conn_handle: u16) {
+        self.controller
+            .borrow_mut()
+            .raise_link_security(conn_handle, LinkSecurity::Encrypted);
+        let mut params = [0; 4];
+        params[0] = STATUS_SUCCESS;
+        put_u16(&mut params[1..], conn_handle);
+        params[3] = 0x01; // Encryption on
+        self.push_event(EVENT_ENCRYPTION_CHANGE, &params);
+    }
+
+    /// Simulates the connection being closed, with the given HCI reason code.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event does not fit in the pending event buffer.
+    pub fn disconnect(&self, conn_handle: u16, reason: u8) {
+        let mut params = [0; 4];
+        params[0] = STATUS_SUCCESS;
+        put_u16(&mut params[1..], conn_handle);
+        params[3] = reason;
+        self.controller.borrow_mut().link = None;
+        self.push_event(EVENT_DISCONNECTION_COMPLETE, &params);
+    }
+
+    /// Simulates the connected central writing `data` to an attribute. If the link is not secure
+    /// enough for the attribute, the write is rejected and recorded in
+    /// [`att_errors`](Simulator::att_errors) instead.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event does not fit in the pending event buffer.
+    pub fn write_attribute(&self, conn_handle: u16, attribute_handle: u16, data: &[u8]) {
+        if !self
+            .controller
+            .borrow_mut()
+            .check_access(conn_handle, attribute_handle, true)
+        {
+            return;
+        }
+        let mut params = [0; MAX_PARAMETER_LEN];
+        put_u16(&mut params[0..], conn_handle);
+        put_u16(&mut params[2..], attribute_handle);
+        params[4] = data.len() as u8;
+        params[5..5 + data.len()].copy_from_slice(data);
+        self.push_vendor_event(
+            VENDOR_EVENT_GATT_ATTRIBUTE_MODIFIED,
+            &params[..5 + data.len()],
+        );
+    }
+
+    /// Simulates the connected central reading an attribute whose characteristic asked to confirm
+    /// reads. If the link is not secure enough for the attribute, the read is rejected and
+    /// recorded in [`att_errors`](Simulator::att_errors) instead.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event does not fit in the pending event buffer.
+    pub fn read_permit_request(&self, conn_handle: u16, attribute_handle: u16) {
+        if !self
+            .controller
+            .borrow_mut()
+            .check_access(conn_handle, attribute_handle, false)
+        {
+            return;
+        }
+        let mut params = [0; 7];
+        put_u16(&mut params[0..], conn_handle);
+        put_u16(&mut params[2..], attribute_handle);
+        params[4] = 2; // Data length
+        put_u16(&mut params[5..], 0); // Offset
+        self.push_vendor_event(VENDOR_EVENT_GATT_READ_PERMIT_REQUEST, &params);
+    }
+
+    /// Simulates the controller asking the host for the passkey to pair on `conn_handle`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event does not fit in the pending event buffer.
+    pub fn pass_key_request(&self, conn_handle: u16) {
+        let mut params = [0; 2];
+        put_u16(&mut params[0..], conn_handle);
+        self.push_vendor_event(VENDOR_EVENT_GAP_PASS_KEY_REQUEST, &params);
+    }
+
+    /// Simulates the controller asking the host whether the device on `conn_handle` may access
+    /// an attribute that needs authorization.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event does not fit in the pending event buffer.
+    pub fn authorization_request(&self, conn_handle: u16) {
+        let mut params = [0; 2];
+        put_u16(&mut params[0..], conn_handle);
+        self.push_vendor_event(VENDOR_EVENT_GAP_AUTHORIZATION_REQUEST, &params);
+    }
+
+    /// Simulates pairing on `conn_handle` finishing with `status`: 0 for success, 1 for a
+    /// timeout, and 2 for a failure. On success, `peer` is added to the bonded devices, and the
+    /// link is authenticated.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event does not fit in the pending event buffer, or if too many devices are
+    /// bonded.
+    pub fn pairing_complete(&self, conn_handle: u16, peer: [u8; 6], status: u8) {
+        if status == STATUS_SUCCESS {
+            self.add_bond(peer);
+            self.controller
+                .borrow_mut()
+                .raise_link_security(conn_handle, LinkSecurity::Authenticated);
+        }
+        let mut params = [0; 3];
+        put_u16(&mut params[0..], conn_handle);
+        params[2] = status;
+        self.push_vendor_event(VENDOR_EVENT_GAP_PAIRING_COMPLETE, &params);
+    }
+
+    /// Simulates a bonded central device that lost its keys trying to pair again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event does not fit in the pending event buffer.
+    pub fn bond_lost(&self) {
+        self.push_vendor_event(VENDOR_EVENT_GAP_BOND_LOST, &[]);
+    }
+
+    /// Adds a device with a public address to the bonded devices, as if it had paired earlier.
+    /// Bonds survive resets of the controller.
+    ///
+    /// # Panics
+    ///
+    /// Panics if too many devices are bonded.
+    pub fn add_bond(&self, peer: [u8; 6]) {
+        let mut controller = self.controller.borrow_mut();
+        if controller.bonds[..controller.bond_count].contains(&peer) {
+            return;
+        }
+        assert!(controller.bond_count < MAX_BONDS, "too many bonded devices");
+        let count = controller.bond_count;
+        controller.bonds[count] = peer;
+        controller.bond_count += 1;
+    }
+
+    /// Simulates the connected central confirming an indication.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event does not fit in the pending event buffer.
+    pub fn confirm_indication(&self, conn_handle: u16) {
+        let mut params = [0; 2];
+        put_u16(&mut params[0..], conn_handle);
+        self.push_vendor_event(VENDOR_EVENT_GATT_SERVER_CONFIRMATION, &params);
+    }
+
+    /// Returns true if the host has not yet read all of the events.
+    pub fn has_pending_events(&self) -> bool {
+        self.controller.borrow().events_len > 0
+    }
+}
+
+impl Controller {
+    fn select(&mut self) {
+        self.transaction = Transaction::Header;
+        self.write_len = 0;
+    }
+
+    fn deselect(&mut self) {
+        if self.transaction == Transaction::Writing && self.write_len > 0 {
+            self.process_command();
+        }
+        self.transaction = Transaction::Idle;
+    }
+
+    fn transfer(&mut self, words: &mut [u8]) {
+        match self.transaction {
+            Transaction::Header => {
+                let (write_len, read_len) = match words.first() {
+                    Some(&ACCESS_WRITE) => {
+                        self.transaction = Transaction::Writing;
+                        (self.write_buffer.len() - self.write_len, 0)
+                    }
+                    Some(&ACCESS_READ) => {
+                        self.transaction = Transaction::Reading;
+                        (0, self.events_len)
+                    }
+                    _ => {
+                        for w in words.iter_mut() {
+                            *w = 0;
+                        }
+                        return;
+                    }
+                };
+                let header = [
+                    READY,
+                    write_len as u8,
+                    (write_len >> 8) as u8,
+                    read_len as u8,
+                    (read_len >> 8) as u8,
+                ];
+                for (w, h) in words.iter_mut().zip(header.iter()) {
+                    *w = *h;
+                }
+            }
+            Transaction::Reading => {
+                let count = core::cmp::min(words.len(), self.events_len);
+                words[..count].copy_from_slice(&self.events[..count]);
+                for w in words[count..].iter_mut() {
+                    *w = 0;
+                }
+                for i in count..self.events_len {
+                    self.events[i - count] = self.events[i];
+                }
+                self.events_len -= count;
+            }
+            Transaction::Writing => self.write(words),
+            Transaction::Idle => (),
+        }
+    }
+
+    fn write(&mut self, words: &[u8]) {
+        if self.transaction != Transaction::Writing {
+            return;
+        }
+
+        let end = self.write_len + words.len();
+        assert!(end <= self.write_buffer.len(), "command too long");
+        self.write_buffer[self.write_len..end].copy_from_slice(words);
+        self.write_len = end;
+    }
+
+    fn set_reset(&mut self, asserted: bool) {
+        if asserted {
+            self.in_reset = true;
+            self.events_len = 0;
+        } else if self.in_reset {
+            self.in_reset = false;
+            self.next_service_handle = FIRST_SERVICE_HANDLE;
+            self.next_attribute_handle = FIRST_SERVICE_HANDLE;
+            self.link = None;
+            self.permissions = [None; MAX_PROTECTED_ATTRIBUTES];
+            self.white_list_len = 0;
+            self.advertising = Advertising::Off;
+            self.discovering = false;
+            self.random_address = None;
+            self.push_vendor_event(VENDOR_EVENT_HAL_INITIALIZED, &[RESET_REASON_NORMAL]);
+        }
+    }
+
+    fn process_command(&mut self) {
+        assert!(
+            self.write_len >= COMMAND_HEADER_LEN,
+            "command packet too short"
+        );
+        assert_eq!(
+            self.write_buffer[0], PACKET_TYPE_COMMAND,
+            "not a command packet"
+        );
+        let opcode = u16::from(self.write_buffer[1]) | (u16::from(self.write_buffer[2]) << 8);
+        let param_len = self.write_buffer[3] as usize;
+        assert_eq!(
+            COMMAND_HEADER_LEN + param_len,
+            self.write_len,
+            "command length does not match header"
+        );
+
+        let mut command = Command {
+            opcode: opcode,
+            len: param_len,
+            params: [0; MAX_PARAMETER_LEN],
+        };
+        command.params[..param_len]
+            .copy_from_slice(&self.write_buffer[COMMAND_HEADER_LEN..self.write_len]);
+        if self.command_count < MAX_COMMANDS {
+            self.commands[self.command_count] = command;
+            self.command_count += 1;
+        }
+
+        self.complete(&command);
+    }
+
+    fn take_status_override(&mut self, opcode: u16) -> u8 {
+        for slot in self.status_overrides.iter_mut() {
+            if let Some((o, status)) = *slot {
+                if o == opcode {
+                    *slot = None;
+                    return status;
+                }
+            }
+        }
+
+        STATUS_SUCCESS
+    }
+
+    fn fw_version_before_v72(&self) -> bool {
+        self.version.major < 7 || (self.version.major == 7 && self.version.minor < 2)
+    }
+
+    fn complete(&mut self, command: &Command) {
+        let status = self.take_status_override(command.opcode);
+        if command.opcode == opcode::GAP_START_GENERAL_DISCOVERY_PROCEDURE {
+            // The procedure reports that it started, and later that it ended.
+            let status = if status == STATUS_SUCCESS
+                && (self.discovering || self.advertising != Advertising::Off)
+            {
+                STATUS_COMMAND_DISALLOWED
+            } else {
+                status
+            };
+            if status == STATUS_SUCCESS {
+                self.discovering = true;
+            }
+            self.push_command_status(command.opcode, status);
+            return;
+        }
+        if command.opcode == opcode::DISCONNECT {
+            self.disconnect(command, status);
+            return;
+        }
+
+        let mut ret = [0; MAX_PARAMETER_LEN];
+        ret[0] = status;
+        let ret_len = match command.opcode {
+            opcode::READ_LOCAL_VERSION_INFORMATION => {
+                let hci_revision =
+                    (u16::from(self.version.hw_version) << 8) | u16::from(self.version.major);
+                let lmp_subversion = (u16::from(self.version.minor & 0xF) << 4)
+                    | u16::from(self.version.patch & 0xF);
+                ret[1..9].copy_from_slice(&[
+                    0x06, // HCI version 4.0
+                    hci_revision as u8,
+                    (hci_revision >> 8) as u8,
+                    0x06, // LMP version 4.0
+                    0x30, // STMicroelectronics
+                    0x00,
+                    lmp_subversion as u8,
+                    (lmp_subversion >> 8) as u8,
+                ]);
+                9
+            }
+            opcode::GAP_INIT => {
+                let service = self.add_service(GAP_SERVICE_ATTRIBUTE_RECORDS);
+                let dev_name = self.add_attributes(2);
+                let appearance = self.add_attributes(2);
+                put_u16(&mut ret[1..], service);
+                put_u16(&mut ret[3..], dev_name);
+                put_u16(&mut ret[5..], appearance);
+                7
+            }
+            opcode::GATT_ADD_SERVICE => {
+                let params = command.params();
+                let uuid_len = uuid_len(params[0]);
+                let max_attribute_records = params[1 + uuid_len + 1];
+                let service = self.add_service(u16::from(max_attribute_records));
+                put_u16(&mut ret[1..], service);
+                3
+            }
+            opcode::GATT_ADD_CHARACTERISTIC => {
+                const NOTIFY: u8 = 0x10;
+                const INDICATE: u8 = 0x20;
+
+                let params = command.params();
+                let uuid_len = uuid_len(params[2]);
+                let value_len_len = if self.fw_version_before_v72() { 1 } else { 2 };
+                let properties = params[3 + uuid_len + value_len_len];
+                let permissions = params[4 + uuid_len + value_len_len];
+                let attributes = if properties & (NOTIFY | INDICATE) != 0 {
+                    3
+                } else {
+                    2
+                };
+                let characteristic = self.add_attributes(attributes);
+                if permissions != 0 {
+                    self.protect(characteristic + 1, permissions);
+                }
+                put_u16(&mut ret[1..], characteristic);
+                3
+            }
+            opcode::GATT_ADD_CHARACTERISTIC_DESCRIPTOR => {
+                let descriptor = self.add_attributes(1);
+                put_u16(&mut ret[1..], descriptor);
+                3
+            }
+            opcode::GAP_SET_DISCOVERABLE
+            | opcode::GAP_SET_UNDIRECTED_CONNECTABLE
+            | opcode::GAP_SET_DIRECT_CONNECTABLE => {
+                if ret[0] == STATUS_SUCCESS {
+                    ret[0] = self.start_advertising(command);
+                }
+                1
+            }
+            opcode::GAP_SET_NONDISCOVERABLE => {
+                if ret[0] == STATUS_SUCCESS {
+                    self.advertising = Advertising::Off;
+                }
+                1
+            }
+            opcode::GAP_CONFIGURE_WHITE_LIST => {
+                if ret[0] == STATUS_SUCCESS {
+                    self.white_list = self.bonds;
+                    self.white_list_len = self.bond_count;
+                }
+                1
+            }
+            opcode::GAP_GET_BONDED_DEVICES => {
+                ret[1] = self.bond_count as u8;
+                for (i, bond) in self.bonds[..self.bond_count].iter().enumerate() {
+                    ret[2 + 7 * i] = 0x00; // Public address
+                    ret[3 + 7 * i..9 + 7 * i].copy_from_slice(bond);
+                }
+                2 + 7 * self.bond_count
+            }
+            opcode::LE_RAND => {
+                // xorshift64
+                self.random ^= self.random << 13;
+                self.random ^= self.random >> 7;
+                self.random ^= self.random << 17;
+                ret[1..9].copy_from_slice(&self.random.to_le_bytes());
+                9
+            }
+            opcode::LE_ENCRYPT => {
+                let params = command.params();
+                ret[1..17].copy_from_slice(&encrypt_block(&params[..16], &params[16..32]));
+                17
+            }
+            opcode::LE_SET_RANDOM_ADDRESS => {
+                if ret[0] == STATUS_SUCCESS {
+                    if self.advertising == Advertising::Off {
+                        let mut address = [0; 6];
+                        address.copy_from_slice(&command.params()[..6]);
+                        self.random_address = Some(address);
+                    } else {
+                        ret[0] = STATUS_COMMAND_DISALLOWED;
+                    }
+                }
+                1
+            }
+            opcode::LE_SET_SCAN_RESPONSE_DATA
+            | opcode::HAL_WRITE_CONFIG_DATA
+            | opcode::HAL_SET_TX_POWER_LEVEL
+            | opcode::GAP_SET_IO_CAPABILITY
+            | opcode::GAP_SET_AUTHENTICATION_REQUIREMENT
+            | opcode::GAP_PASS_KEY_RESPONSE
+            | opcode::GAP_AUTHORIZATION_RESPONSE
+            | opcode::GAP_ALLOW_REBOND
+            | opcode::GATT_INIT
+            | opcode::GATT_UPDATE_CHARACTERISTIC_VALUE
+            | opcode::GATT_ALLOW_READ => 1,
+            _ => {
+                ret[0] = STATUS_UNKNOWN_COMMAND;
+                1
+            }
+        };
+
+        let mut params = [0; MAX_PARAMETER_LEN];
+        params[0] = 1; // Number of HCI command packets the host may send
+        put_u16(&mut params[1..], command.opcode);
+        params[3..3 + ret_len].copy_from_slice(&ret[..ret_len]);
+        self.push_event(EVENT_COMMAND_COMPLETE, &params[..3 + ret_len]);
+    }
+
+    // Closes the link as the HCI Disconnect `command` asks. The host disconnects the link itself,
+    // so the Disconnection Complete event reports the reason the host gave.
+    fn disconnect(&mut self, command: &Command, status: u8) {
+        let params = command.params();
+        let conn_handle = u16::from(params[0]) | (u16::from(params[1]) << 8);
+        let status = match self.link {
+            Some((handle, _)) if handle == conn_handle => status,
+            _ => STATUS_UNKNOWN_CONNECTION_ID,
+        };
+        self.push_command_status(command.opcode, status);
+        if status != STATUS_SUCCESS {
+            return;
+        }
+
+        self.link = None;
+        let mut complete = [0; 4];
+        complete[0] = STATUS_SUCCESS;
+        put_u16(&mut complete[1..], conn_handle);
+        complete[3] = params[2];
+        self.push_event(EVENT_DISCONNECTION_COMPLETE, &complete);
+    }
+
+    // Starts advertising as `command` asks, and returns the status of the command.
+    fn start_advertising(&mut self, command: &Command) -> u8 {
+        if self.advertising != Advertising::Off || self.link.is_some() {
+            return STATUS_COMMAND_DISALLOWED;
+        }
+
+        let params = command.params();
+        self.advertising = match command.opcode {
+            opcode::GAP_SET_DISCOVERABLE => Advertising::Undirected {
+                white_list_only: params[6] & FILTER_CONNECTION_WHITE_LIST != 0,
+            },
+            opcode::GAP_SET_UNDIRECTED_CONNECTABLE => Advertising::Undirected {
+                white_list_only: params[0] & FILTER_CONNECTION_WHITE_LIST != 0,
+            },
+            _ => {
+                // The peer address comes last, unless the firmware also takes the advertising
+                // type and interval.
+                let start = if params.len() == 8 { 2 } else { 3 };
+                let mut peer = [0; 6];
+                peer.copy_from_slice(&params[start..start + 6]);
+                Advertising::Directed(peer)
+            }
+        };
+
+        STATUS_SUCCESS
+    }
+
+    fn accepts_connection(&self, peer: &[u8; 6]) -> bool {
+        match self.advertising {
+            Advertising::Off => false,
+            Advertising::Undirected {
+                white_list_only: false,
+            } => true,
+            Advertising::Undirected {
+                white_list_only: true,
+            } => self.white_list[..self.white_list_len].contains(peer),
+            Advertising::Directed(directed) => directed == *peer,
+        }
+    }
+
+    fn add_service(&mut self, max_attribute_records: u16) -> u16 {
+        let service = self.next_service_handle;
+        self.next_service_handle += core::cmp::max(max_attribute_records, 1);
+        self.next_attribute_handle = service + 1;
+
+        service
+    }
+
+    fn add_attributes(&mut self, count: u16) -> u16 {
+        let handle = self.next_attribute_handle;
+        self.next_attribute_handle += count;
+
+        handle
+    }
+
+    fn protect(&mut self, value_handle: u16, permissions: u8) {
+        let slot = self
+            .permissions
+            .iter_mut()
+            .find(|p| p.is_none())
+            .expect("too many protected attributes");
+        *slot = Some((value_handle, permissions));
+    }
+
+    fn raise_link_security(&mut self, conn_handle: u16, security: LinkSecurity) {
+        if let Some((handle, ref mut current)) = self.link {
+            if handle == conn_handle && *current < security {
+                *current = security;
+            }
+        }
+    }
+
+    // Returns true if the central on `conn_handle` may access the attribute, and records the ATT
+    // error the controller answers with if it may not.
+    fn check_access(&mut self, conn_handle: u16, attribute_handle: u16, write: bool) -> bool {
+        let required = self
+            .permissions
+            .iter()
+            .flatten()
+            .find(|&&(handle, _)| handle == attribute_handle)
+            .map_or(0, |&(_, permissions)| permissions);
+        let (authenticated, encrypted) = if write {
+            (PERMISSION_AUTHEN_WRITE, PERMISSION_ENCRY_WRITE)
+        } else {
+            (PERMISSION_AUTHEN_READ, PERMISSION_ENCRY_READ)
+        };
+        let security = match self.link {
+            Some((handle, security)) if handle == conn_handle => security,
+            _ => LinkSecurity::Unencrypted,
+        };
+
+        let error = if required & authenticated != 0 && security < LinkSecurity::Authenticated {
+            att_error::INSUFFICIENT_AUTHENTICATION
+        } else if required & encrypted != 0 && security < LinkSecurity::Encrypted {
+            att_error::INSUFFICIENT_ENCRYPTION
+        } else {
+            return true;
+        };
+        if self.att_error_count < MAX_ATT_ERRORS {
+            self.att_errors[self.att_error_count] = AttError {
+                conn_handle: conn_handle,
+                attribute_handle: attribute_handle,
+                error: error,
+            };
+            self.att_error_count += 1;
+        }
+
+        false
+    }
+
+    fn push_event(&mut self, event: u8, params: &[u8]) {
+        assert!(params.len() <= MAX_PARAMETER_LEN, "event too long");
+        let end = self.events_len + 3 + params.len();
+        assert!(end <= self.events.len(), "too many pending events");
+
+        self.events[self.events_len] = PACKET_TYPE_EVENT;
+        self.events[self.events_len + 1] = event;
+        self.events[self.events_len + 2] = params.len() as u8;
+        self.events[self.events_len + 3..end].copy_from_slice(params);
+        self.events_len = end;
+    }
+
+    fn push_command_status(&mut self, opcode: u16, status: u8) {
+        let mut params = [0; 4];
+        params[0] = status;
+        params[1] = 1; // Number of HCI command packets
+        put_u16(&mut params[2..], opcode);
+        self.push_event(EVENT_COMMAND_STATUS, &params);
+    }
+
+    fn push_vendor_event(&mut self, event: u16, params: &[u8]) {
+        let mut buffer = [0; MAX_PARAMETER_LEN];
+        put_u16(&mut buffer, event);
+        buffer[2..2 + params.len()].copy_from_slice(params);
+        self.push_event(EVENT_VENDOR, &buffer[..2 + params.len()]);
+    }
+}
+
+fn put_u16(buffer: &mut [u8], value: u16) {
+    buffer[0] = value as u8;
+    buffer[1] = (value >> 8) as u8;
+}
+
+/// Stands in for AES-128 in LE Encrypt. It is not AES, so the keys and addresses the host derives
+/// with it only match those of other simulators, but it is deterministic, and every bit of the
+/// key and the plaintext changes the result.
+fn encrypt_block(key: &[u8], plaintext: &[u8]) -> [u8; 16] {
+    let mut block = [0; 16];
+    block.copy_from_slice(plaintext);
+    let mut state: u8 = 0;
+    for round in 0..4 {
+        for i in 0..16 {
+            state = state.rotate_left(3) ^ block[i] ^ key[(i + round) % 16];
+            state = state.wrapping_mul(167).wrapping_add(13);
+            block[i] = state;
+        }
+    }
+
+    block
+}
+
+fn uuid_len(uuid_type: u8) -> usize {
+    match uuid_type {
+        0x01 => 2,
+        _ => 16,
+    }
+}
+
+/// SPI bus connected to the simulated controller.
+pub struct Spi<'sim> {
+    sim: &'sim Simulator,
+}
+
+impl<'sim> embedded_hal::blocking::spi::Transfer<u8> for Spi<'sim> {
+    type Error = Void;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Void> {
+        self.sim.controller.borrow_mut().transfer(words);
+
+        Ok(words)
+    }
+}
+
+impl<'sim> embedded_hal::blocking::spi::Write<u8> for Spi<'sim> {
+    type Error = Void;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Void> {
+        self.sim.controller.borrow_mut().write(words);
+
+        Ok(())
+    }
+}
+
+/// Chip select pin of the simulated controller. The controller is selected while the pin is low.
+pub struct ChipSelect<'sim> {
+    sim: &'sim Simulator,
+}
+
+impl<'sim> embedded_hal::digital::OutputPin for ChipSelect<'sim> {
+    fn set_low(&mut self) {
+        self.sim.controller.borrow_mut().select();
+    }
+
+    fn set_high(&mut self) {
+        self.sim.controller.borrow_mut().deselect();
+    }
+}
+
+/// Reset pin of the simulated controller. The controller is held in reset while the pin is low,
+/// and reports that it is initialized when the pin goes high again.
+pub struct ResetPin<'sim> {
+    sim: &'sim Simulator,
+}
+
+impl<'sim> embedded_hal::digital::OutputPin for ResetPin<'sim> {
+    fn set_low(&mut self) {
+        self.sim.controller.borrow_mut().set_reset(true);
+    }
+
+    fn set_high(&mut self) {
+        self.sim.controller.borrow_mut().set_reset(false);
+    }
+}
+
+/// Data ready pin of the simulated controller. The pin is high while there are events for the
+/// host to read.
+pub struct DataReady<'sim> {
+    sim: &'sim Simulator,
+}
+
+impl<'sim> embedded_hal::digital::InputPin for DataReady<'sim> {
+    fn is_high(&self) -> bool {
+        self.sim.has_pending_events()
+    }
+
+    fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+}
+
+/// Timer that expires immediately, for resetting the simulated controller.
+pub struct Timer;
+
+impl embedded_hal::timer::CountDown for Timer {
+    type Time = u32;
+
+    fn start<T>(&mut self, _count: T)
+    where
+        T: Into<u32>,
+    {
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Void> {
+        Ok(())
+    }
+}
@@ -0,0 +1,412 @@
+//! A small key/value store in flash.
+//!
+//! Values are appended to a log in one page of the [flash region](crate::flash). Writing a key
+//! again appends a new record instead of erasing anything, so each page is only erased once it
+//! fills up. At that point the latest value of every key is copied to the other page, which
+//! becomes the active page, and the full page is erased. The two pages therefore wear evenly.
+//!
+//! Each page starts with a half-word sequence number, which identifies the active page after a
+//! reset. Each record is a half-word holding the key and the value length, the value padded to
+//! an even length, and a half-word that is cleared once the record is completely written. An
+//! interrupted write or page copy leaves the previous values in place.
+
+use crate::flash::{Flash, FlashError, PAGE_SIZE};
+
+/// Maximum length of a value, in bytes.
+pub const MAX_VALUE_LEN: usize = 64;
+
+/// Keys of the values the firmware stores.
+pub mod key {
+    /// Device address, as a [`config::Address`](crate::config::Address).
+    pub const ADDRESS: u8 = 0x01;
+    /// Device name.
+    pub const NAME: u8 = 0x02;
+    /// GAP appearance.
+    pub const APPEARANCE: u8 = 0x03;
+    /// Last transmit power level used.
+    pub const TX_POWER: u8 = 0x04;
+    /// Number of times the device has booted.
+    pub const BOOT_COUNT: u8 = 0x05;
+    /// Identity root from which the controller derives its identity resolving key.
+    pub const IDENTITY_ROOT: u8 = 0x06;
+    /// First key of the bonded devices, one per key, as stored by the
+    /// [`BondStore`](crate::security::BondStore).
+    pub const FIRST_BOND: u8 = 0x10;
+    /// Last key available for bonded devices.
+    pub const LAST_BOND: u8 = 0x1F;
+}
+
+/// Errors that can occur while accessing the store.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The flash controller reported an error.
+    Flash(FlashError),
+
+    /// The key is reserved.
+    InvalidKey,
+
+    /// The value is longer than [`MAX_VALUE_LEN`].
+    ValueTooLong,
+
+    /// The latest values do not fit in a page.
+    Full,
+}
+
+impl From<FlashError> for Error {
+    fn from(e: FlashError) -> Self {
+        Error::Flash(e)
+    }
+}
+
+const ERASED: u16 = 0xFFFF;
+const COMMITTED: u16 = 0x0000;
+const HEADER_LEN: usize = 2;
+const RECORD_OVERHEAD: usize = 4;
+
+#[derive(Copy, Clone)]
+struct Record {
+    key: u8,
+    len: usize,
+    address: usize,
+    committed: bool,
+}
+
+impl Record {
+    fn size(&self) -> usize {
+        record_size(self.len)
+    }
+
+    fn data_address(&self) -> usize {
+        self.address + 2
+    }
+}
+
+fn record_size(len: usize) -> usize {
+    RECORD_OVERHEAD + len + len % 2
+}
+
+/// Key/value store over a flash region.
+pub struct Store<F> {
+    flash: F,
+    active: usize,
+    sequence: u16,
+    write_address: usize,
+}
+
+impl<F> Store<F>
+where
+    F: Flash,
+{
+    /// Opens the store in `flash`, formatting it if it does not contain a valid page.
+    pub fn open(flash: F) -> Result<Store<F>, Error> {
+        let mut store = Store {
+            flash: flash,
+            active: 0,
+            sequence: 0,
+            write_address: HEADER_LEN,
+        };
+
+        let sequences = [store.page_sequence(0), store.page_sequence(1)];
+        match sequences {
+            [None, None] => {
+                store.flash.erase(0)?;
+                store.flash.program(0, 0)?;
+            }
+            [Some(s), None] => store.sequence = s,
+            [None, Some(s)] => {
+                store.active = 1;
+                store.sequence = s;
+            }
+            [Some(a), Some(b)] => {
+                // A page copy was interrupted after the new page was complete. Keep the newer one.
+                let (active, sequence) = if b.wrapping_sub(a) < 0x8000 {
+                    (1, b)
+                } else {
+                    (0, a)
+                };
+                store.active = active;
+                store.sequence = sequence;
+                store.flash.erase(1 - active)?;
+            }
+        }
+        store.write_address = store.end_of_log();
+
+        Ok(store)
+    }
+
+    /// Returns the flash the store is in.
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    /// Reads the value of `key` into `buffer`, and returns its length. Returns `None` if the key
+    /// has no value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buffer` is shorter than the value.
+    pub fn read(&self, key: u8, buffer: &mut [u8]) -> Option<usize> {
+        let record = self.latest(self.active, key)?;
+        self.flash.read(
+            self.page_address(self.active) + record.data_address(),
+            &mut buffer[..record.len],
+        );
+
+        Some(record.len)
+    }
+
+    /// Sets the value of `key`.
+    pub fn write(&mut self, key: u8, value: &[u8]) -> Result<(), Error> {
+        if key == 0xFF {
+            return Err(Error::InvalidKey);
+        }
+        if value.len() > MAX_VALUE_LEN {
+            return Err(Error::ValueTooLong);
+        }
+        if value.is_empty() {
+            return self.remove(key);
+        }
+
+        // Rewriting an unchanged value would only wear the flash.
+        let mut current = [0; MAX_VALUE_LEN];
+        if self.read(key, &mut current) == Some(value.len()) && &current[..value.len()] == value {
+            return Ok(());
+        }
+
+        self.append(key, value)
+    }
+
+    /// Removes the value of `key`.
+    pub fn remove(&mut self, key: u8) -> Result<(), Error> {
+        if key == 0xFF {
+            return Err(Error::InvalidKey);
+        }
+        if self.latest(self.active, key).is_none() {
+            return Ok(());
+        }
+
+        // An empty record hides the previous value.
+        self.append(key, &[])
+    }
+
+    fn append(&mut self, key: u8, value: &[u8]) -> Result<(), Error> {
+        if self.write_address + record_size(value.len()) > PAGE_SIZE {
+            self.compact()?;
+            if self.write_address + record_size(value.len()) > PAGE_SIZE {
+                return Err(Error::Full);
+            }
+        }
+
+        let page = self.page_address(self.active);
+        let address = self.write_address;
+        self.write_address += record_size(value.len());
+
+        self.flash
+            .program(page + address, u16::from(key) | ((value.len() as u16) << 8))?;
+        for (i, chunk) in value.chunks(2).enumerate() {
+            let half_word = u16::from(chunk[0]) | (u16::from(*chunk.get(1).unwrap_or(&0xFF)) << 8);
+            self.flash.program(page + address + 2 + 2 * i, half_word)?;
+        }
+        self.flash
+            .program(page + address + record_size(value.len()) - 2, COMMITTED)?;
+
+        Ok(())
+    }
+
+    /// Copies the latest value of every key to the other page, and makes it the active page.
+    fn compact(&mut self) -> Result<(), Error> {
+        let from = self.active;
+        let to = 1 - from;
+        let from_page = self.page_address(from);
+        let to_page = self.page_address(to);
+
+        self.flash.erase(to)?;
+        let mut write_address = HEADER_LEN;
+        for key in 0..0xFF {
+            let record = match self.latest(from, key) {
+                Some(r) => r,
+                None => continue,
+            };
+            if write_address + record.size() > PAGE_SIZE {
+                return Err(Error::Full);
+            }
+            for offset in (0..record.size()).step_by(2) {
+                let mut half_word = [0; 2];
+                self.flash
+                    .read(from_page + record.address + offset, &mut half_word);
+                self.flash.program(
+                    to_page + write_address + offset,
+                    u16::from(half_word[0]) | (u16::from(half_word[1]) << 8),
+                )?;
+            }
+            write_address += record.size();
+        }
+
+        let sequence = match self.sequence.wrapping_add(1) {
+            ERASED => 0,
+            s => s,
+        };
+        self.flash.program(to_page, sequence)?;
+        self.flash.erase(from)?;
+
+        self.active = to;
+        self.sequence = sequence;
+        self.write_address = write_address;
+
+        Ok(())
+    }
+
+    fn page_address(&self, page: usize) -> usize {
+        page * PAGE_SIZE
+    }
+
+    fn read_u16(&self, address: usize) -> u16 {
+        let mut bytes = [0; 2];
+        self.flash.read(address, &mut bytes);
+        u16::from(bytes[0]) | (u16::from(bytes[1]) << 8)
+    }
+
+    fn page_sequence(&self, page: usize) -> Option<u16> {
+        match self.read_u16(self.page_address(page)) {
+            ERASED => None,
+            s => Some(s),
+        }
+    }
+
+    /// Calls `f` with every record in `page`, in the order they were written.
+    fn for_each_record<G>(&self, page: usize, mut f: G) -> usize
+    where
+        G: FnMut(Record),
+    {
+        let base = self.page_address(page);
+        let mut address = HEADER_LEN;
+        while address + RECORD_OVERHEAD <= PAGE_SIZE {
+            let header = self.read_u16(base + address);
+            if header == ERASED {
+                break;
+            }
+            let len = (header >> 8) as usize;
+            let size = record_size(len);
+            if address + size > PAGE_SIZE {
+                // A corrupted header. Nothing after it can be trusted.
+                return PAGE_SIZE;
+            }
+            f(Record {
+                key: header as u8,
+                len: len,
+                address: address,
+                committed: self.read_u16(base + address + size - 2) == COMMITTED,
+            });
+            address += size;
+        }
+
+        address
+    }
+
+    fn end_of_log(&self) -> usize {
+        self.for_each_record(self.active, |_| ())
+    }
+
+    /// Returns the latest committed record of `key` in `page`. Returns `None` if the key has no
+    /// value.
+    fn latest(&self, page: usize, key: u8) -> Option<Record> {
+        let mut latest = None;
+        self.for_each_record(page, |r| {
+            if r.key == key && r.committed {
+                latest = Some(r);
+            }
+        });
+
+        latest.filter(|r| r.len > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::RamFlash;
+
+    fn read(store: &Store<RamFlash>, key: u8) -> Option<std::vec::Vec<u8>> {
+        let mut buffer = [0; MAX_VALUE_LEN];
+        store
+            .read(key, &mut buffer)
+            .map(|len| buffer[..len].to_vec())
+    }
+
+    #[test]
+    fn round_trips_values_across_a_reset() {
+        let mut store = Store::open(RamFlash::new()).unwrap();
+        assert_eq!(read(&store, key::NAME), None);
+        store.write(key::NAME, b"BlueNRG").unwrap();
+        store.write(key::TX_POWER, &[0x07]).unwrap();
+        store.write(key::NAME, b"Sensor").unwrap();
+        assert_eq!(read(&store, key::NAME), Some(b"Sensor".to_vec()));
+
+        let mut store = Store::open(store.flash().clone()).unwrap();
+        assert_eq!(read(&store, key::NAME), Some(b"Sensor".to_vec()));
+        assert_eq!(read(&store, key::TX_POWER), Some(vec![0x07]));
+
+        store.remove(key::TX_POWER).unwrap();
+        let store = Store::open(store.flash().clone()).unwrap();
+        assert_eq!(read(&store, key::TX_POWER), None);
+        assert_eq!(read(&store, key::NAME), Some(b"Sensor".to_vec()));
+    }
+
+    #[test]
+    fn does_not_rewrite_unchanged_values() {
+        let mut store = Store::open(RamFlash::new()).unwrap();
+        store.write(key::NAME, b"BlueNRG").unwrap();
+        let memory = store.flash().memory().to_vec();
+        store.write(key::NAME, b"BlueNRG").unwrap();
+        assert_eq!(store.flash().memory(), &memory[..]);
+    }
+
+    #[test]
+    fn rejects_invalid_writes() {
+        let mut store = Store::open(RamFlash::new()).unwrap();
+        assert_eq!(store.write(0xFF, &[0]), Err(Error::InvalidKey));
+        assert_eq!(
+            store.write(key::NAME, &[0; MAX_VALUE_LEN + 1]),
+            Err(Error::ValueTooLong)
+        );
+    }
+
+    #[test]
+    fn compacts_into_the_other_page_when_full() {
+        let mut store = Store::open(RamFlash::new()).unwrap();
+        store.write(key::NAME, b"BlueNRG").unwrap();
+
+        // Each count takes an 8-byte record, so this fills both pages more than once.
+        for count in 0..1000u32 {
+            store.write(key::BOOT_COUNT, &count.to_le_bytes()).unwrap();
+        }
+        assert!(store.flash().erase_count(0) >= 2);
+        assert!(store.flash().erase_count(1) >= 2);
+
+        let store = Store::open(store.flash().clone()).unwrap();
+        assert_eq!(
+            read(&store, key::BOOT_COUNT),
+            Some(999u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(read(&store, key::NAME), Some(b"BlueNRG".to_vec()));
+    }
+
+    #[test]
+    fn keeps_the_previous_value_if_a_write_is_interrupted() {
+        let mut store = Store::open(RamFlash::new()).unwrap();
+        store.write(key::TX_POWER, &[0x07]).unwrap();
+
+        // A record header and value, without the half-word that commits it.
+        let mut flash = store.flash().clone();
+        let address = store.write_address;
+        flash
+            .program(address, u16::from(key::TX_POWER) | (1 << 8))
+            .unwrap();
+        flash.program(address + 2, 0xFF04).unwrap();
+
+        let mut store = Store::open(flash).unwrap();
+        assert_eq!(read(&store, key::TX_POWER), Some(vec![0x07]));
+        store.write(key::TX_POWER, &[0x04]).unwrap();
+        assert_eq!(read(&store, key::TX_POWER), Some(vec![0x04]));
+    }
+}
@@ -0,0 +1,217 @@
+//! Tracking of the notifications and indications each client has enabled.
+//!
+//! Clients enable notifications and indications by writing the Client Characteristic
+//! Configuration descriptor (0x2902) of a characteristic. The controller reports those writes
+//! with GATT Attribute Modified events, and [`Subscriptions`] records them per connection so the
+//! event loop only updates characteristics somebody listens to.
+
+/// Maximum number of subscriptions tracked across all connections.
+pub const MAX_SUBSCRIPTIONS: usize = 8;
+
+/// What a client has enabled for a characteristic.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Subscription {
+    /// True if the client wants notifications.
+    pub notify: bool,
+
+    /// True if the client wants indications.
+    pub indicate: bool,
+}
+
+impl Subscription {
+    /// Decodes a value written to a Client Characteristic Configuration descriptor. Missing bytes
+    /// are treated as 0.
+    pub fn decode(data: &[u8]) -> Subscription {
+        const NOTIFICATIONS_ENABLED: u8 = 0x01;
+        const INDICATIONS_ENABLED: u8 = 0x02;
+
+        let flags = data.first().cloned().unwrap_or(0);
+        Subscription {
+            notify: flags & NOTIFICATIONS_ENABLED != 0,
+            indicate: flags & INDICATIONS_ENABLED != 0,
+        }
+    }
+
+    /// Returns true if the client wants either notifications or indications.
+    pub fn is_active(&self) -> bool {
+        self.notify || self.indicate
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Entry {
+    conn_handle: hci::ConnectionHandle,
+    value_handle: u16,
+    subscription: Subscription,
+}
+
+/// Subscriptions of every connected client, and the indications waiting to be confirmed.
+pub struct Subscriptions {
+    entries: [Option<Entry>; MAX_SUBSCRIPTIONS],
+
+    // The Attribute Protocol allows one outstanding indication per connection.
+    unconfirmed: [Option<hci::ConnectionHandle>; MAX_SUBSCRIPTIONS],
+}
+
+impl Subscriptions {
+    /// Returns an empty table.
+    pub fn new() -> Subscriptions {
+        Subscriptions {
+            entries: [None; MAX_SUBSCRIPTIONS],
+            unconfirmed: [None; MAX_SUBSCRIPTIONS],
+        }
+    }
+
+    /// Records what the client on `conn_handle` enabled for the characteristic whose value has
+    /// the handle `value_handle`.
+    ///
+    /// The event loop serves one connection at a time, so when the table is full, a subscription
+    /// of another connection is evicted to make room. Returns false if every entry belongs to
+    /// `conn_handle` already.
+    pub fn set(
+        &mut self,
+        conn_handle: hci::ConnectionHandle,
+        value_handle: u16,
+        subscription: Subscription,
+    ) -> bool {
+        let existing = self.position(conn_handle, value_handle);
+        if !subscription.is_active() {
+            if let Some(i) = existing {
+                self.entries[i] = None;
+            }
+            return true;
+        }
+
+        let slot = existing
+            .or_else(|| self.entries.iter().position(|e| e.is_none()))
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .position(|e| e.map_or(false, |e| e.conn_handle != conn_handle))
+            });
+        match slot {
+            Some(i) => {
+                self.entries[i] = Some(Entry {
+                    conn_handle: conn_handle,
+                    value_handle: value_handle,
+                    subscription: subscription,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns what the client on `conn_handle` enabled for the characteristic whose value has
+    /// the handle `value_handle`.
+    pub fn get(&self, conn_handle: hci::ConnectionHandle, value_handle: u16) -> Subscription {
+        self.position(conn_handle, value_handle)
+            .and_then(|i| self.entries[i])
+            .map_or(Subscription::default(), |e| e.subscription)
+    }
+
+    /// Returns true if an update of the characteristic whose value has the handle `value_handle`
+    /// can be delivered to the client on `conn_handle` now: the client has subscribed, and if it
+    /// asked for indications, the previous indication has been confirmed.
+    pub fn can_send(&self, conn_handle: hci::ConnectionHandle, value_handle: u16) -> bool {
+        let subscription = self.get(conn_handle, value_handle);
+        if subscription.indicate {
+            !self.is_awaiting_confirmation(conn_handle)
+        } else {
+            subscription.notify
+        }
+    }
+
+    /// Records that an update of the characteristic whose value has the handle `value_handle` was
+    /// sent to the client on `conn_handle`. If the client asked for indications, no more
+    /// indications are sent on the connection until the client confirms it.
+    pub fn sent(&mut self, conn_handle: hci::ConnectionHandle, value_handle: u16) {
+        if !self.get(conn_handle, value_handle).indicate
+            || self.is_awaiting_confirmation(conn_handle)
+        {
+            return;
+        }
+        if let Some(slot) = self.unconfirmed.iter_mut().find(|c| c.is_none()) {
+            *slot = Some(conn_handle);
+        }
+    }
+
+    /// Returns true if an indication sent to the client on `conn_handle` has not been confirmed.
+    pub fn is_awaiting_confirmation(&self, conn_handle: hci::ConnectionHandle) -> bool {
+        self.unconfirmed.iter().any(|&c| c == Some(conn_handle))
+    }
+
+    /// Records that the client on `conn_handle` confirmed the outstanding indication.
+    pub fn confirmed(&mut self, conn_handle: hci::ConnectionHandle) {
+        for c in self.unconfirmed.iter_mut() {
+            if *c == Some(conn_handle) {
+                *c = None;
+            }
+        }
+    }
+
+    /// Forgets everything about the client on `conn_handle`, after it disconnected.
+    pub fn remove_connection(&mut self, conn_handle: hci::ConnectionHandle) {
+        for e in self.entries.iter_mut() {
+            if e.map_or(false, |e| e.conn_handle == conn_handle) {
+                *e = None;
+            }
+        }
+        self.confirmed(conn_handle);
+    }
+
+    /// Forgets every subscription, after the controller is reset.
+    pub fn clear(&mut self) {
+        self.entries = [None; MAX_SUBSCRIPTIONS];
+        self.unconfirmed = [None; MAX_SUBSCRIPTIONS];
+    }
+
+    fn position(&self, conn_handle: hci::ConnectionHandle, value_handle: u16) -> Option<usize> {
+        self.entries.iter().position(|e| {
+            e.map_or(false, |e| {
+                e.conn_handle == conn_handle && e.value_handle == value_handle
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTIFY: Subscription = Subscription {
+        notify: true,
+        indicate: false,
+    };
+
+    #[test]
+    fn evicts_other_connections_when_full() {
+        let stale = hci::ConnectionHandle(0x0801);
+        let current = hci::ConnectionHandle(0x0802);
+        let mut subscriptions = Subscriptions::new();
+        for handle in 0..MAX_SUBSCRIPTIONS as u16 {
+            assert!(subscriptions.set(stale, handle, NOTIFY));
+        }
+
+        assert!(subscriptions.set(current, 0x100, NOTIFY));
+        assert!(subscriptions.can_send(current, 0x100));
+        assert!(!subscriptions.can_send(stale, 0));
+        assert!(subscriptions.can_send(stale, 1));
+    }
+
+    #[test]
+    fn reports_a_table_full_of_one_connection() {
+        let conn = hci::ConnectionHandle(0x0801);
+        let mut subscriptions = Subscriptions::new();
+        for handle in 0..MAX_SUBSCRIPTIONS as u16 {
+            assert!(subscriptions.set(conn, handle, NOTIFY));
+        }
+
+        assert!(!subscriptions.set(conn, 0x100, NOTIFY));
+        assert!(!subscriptions.can_send(conn, 0x100));
+
+        // Updating or removing an existing subscription still works.
+        assert!(subscriptions.set(conn, 0, Subscription::default()));
+        assert!(subscriptions.set(conn, 0x100, NOTIFY));
+    }
+}
@@ -0,0 +1,1572 @@
+//! Tests of the event loop against the simulated controller.
+
+use super::*;
+use core::cell::{Cell, RefCell};
+use core::task::Waker;
+use std::vec::Vec;
+
+type SimEventLoop<'a> = EventLoop<
+    'a,
+    sim::Spi<'a>,
+    sim::ChipSelect<'a>,
+    sim::ResetPin<'a>,
+    sim::DataReady<'a>,
+    TestTimer<'a>,
+>;
+
+const CONN: u16 = 0x0801;
+const PEER: [u8; 6] = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+
+/// Timer that expires as soon as it is started, unless the test holds it.
+#[derive(Copy, Clone, Default)]
+struct TestTimer<'a> {
+    held: Option<&'a Cell<bool>>,
+}
+
+impl<'a> embedded_hal::timer::CountDown for TestTimer<'a> {
+    type Time = u32;
+
+    fn start<T>(&mut self, _count: T)
+    where
+        T: Into<u32>,
+    {
+    }
+
+    fn wait(&mut self) -> nb::Result<(), void::Void> {
+        match self.held {
+            Some(held) if held.get() => Err(nb::Error::WouldBlock),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Maximum number of steps a test waits for the event loop, so a broken state machine fails the
+/// test instead of hanging it.
+const MAX_STEPS: usize = 1000;
+
+/// Declares `$event_loop`, driving `$sim` with the default configuration, and reading events
+/// through `$events` once the test signals data ready. The timer expires immediately unless
+/// another one is given. Without `$events`, the event loop reads straight from `$sim`.
+macro_rules! sim_event_loop {
+    ($event_loop:ident, $sim:ident) => {
+        sim_event_loop!(@new $event_loop, $sim, TestTimer::default());
+    };
+    ($event_loop:ident, $sim:ident, $events:ident) => {
+        sim_event_loop!($event_loop, $sim, $events, TestTimer::default());
+    };
+    ($event_loop:ident, $sim:ident, $events:ident, $timer:expr) => {
+        sim_event_loop!(@new $event_loop, $sim, $timer);
+        $event_loop.set_event_queue(&$events);
+    };
+    (@new $event_loop:ident, $sim:ident, $timer:expr) => {
+        let mut rx_buffer = [0; 128];
+        let mut bnrg = bluenrg::BlueNRG::new(
+            &mut rx_buffer,
+            $sim.chip_select(),
+            $sim.data_ready(),
+            $sim.reset_pin(),
+        );
+        let mut $event_loop: SimEventLoop = EventLoop::new(
+            &mut bnrg,
+            $timer,
+            0,
+            $sim.spi(),
+            config::DeviceConfig::default(),
+        );
+    };
+}
+
+/// Steps the event loop until it is idle and the simulator has no more events, raising the data
+/// ready "interrupt" whenever the simulator has events. Returns the states the event loop
+/// entered, in order. Always steps at least once, so work found by polling gets done.
+fn run_until_idle(
+    event_loop: &mut SimEventLoop,
+    sim: &sim::Simulator,
+    events: &queue::PacketQueue,
+) -> Vec<State> {
+    let mut states = Vec::new();
+    for _ in 0..MAX_STEPS {
+        let before = event_loop.state();
+        event_loop.step().expect("event loop halted");
+        if event_loop.state() != before {
+            states.push(event_loop.state());
+        }
+        if sim.has_pending_events() {
+            events.data_ready();
+        }
+        if event_loop.is_idle() {
+            return states;
+        }
+    }
+
+    panic!("event loop did not go idle: {:?}", states);
+}
+
+/// Steps the event loop until it enters `state`, and returns the states it entered, in order.
+fn run_until(
+    event_loop: &mut SimEventLoop,
+    sim: &sim::Simulator,
+    events: &queue::PacketQueue,
+    state: State,
+) -> Vec<State> {
+    let mut states = Vec::new();
+    for _ in 0..MAX_STEPS {
+        let before = event_loop.state();
+        event_loop.step().expect("event loop halted");
+        if event_loop.state() != before {
+            states.push(event_loop.state());
+            if event_loop.state() == state {
+                return states;
+            }
+        }
+        if sim.has_pending_events() {
+            events.data_ready();
+        }
+    }
+
+    panic!("event loop did not reach {:?}: {:?}", state, states);
+}
+
+/// Returns the states that initialize the default configuration, from reading the version to
+/// setting the scan response.
+fn initialization(database: &database::Database) -> Vec<State> {
+    let mut states = vec![
+        State::Resetting,
+        State::SettingAddress,
+        State::InitGatt,
+        State::InitGap,
+        State::SetDeviceName,
+        State::SetAppearance,
+        State::SetAuthenticationRequirement,
+        State::SetIoCapability,
+        State::GettingBondedDevices,
+    ];
+    states.extend((0..database.len()).map(State::AddAttribute));
+    states.extend_from_slice(&[State::SetTxPowerLevel, State::SetScanResponse]);
+    states
+}
+
+fn count_commands(sim: &sim::Simulator, opcode: u16) -> usize {
+    sim.commands()
+        .iter()
+        .filter(|c| c.opcode() == opcode)
+        .count()
+}
+
+#[test]
+fn initializes_and_advertises() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    sim_event_loop!(event_loop, sim, events);
+
+    let mut expected = initialization(event_loop.database());
+    expected.extend_from_slice(&[
+        State::ConfiguringWhiteList,
+        State::SetDiscoverable,
+        State::Complete,
+    ]);
+    assert_eq!(
+        run_until(&mut event_loop, &sim, &events, State::Complete),
+        expected
+    );
+    let database = event_loop.database();
+    assert!((0..database.len()).all(|index| database.handle(index).is_some()));
+    assert_eq!(
+        event_loop.advertising_mode(),
+        Some(reconnection::AdvertisingMode::Discoverable)
+    );
+    assert!(sim.is_advertising());
+}
+
+#[test]
+fn sends_the_initialization_commands() {
+    use sim::opcode;
+
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    sim_event_loop!(event_loop, sim, events);
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+
+    let mut expected = vec![
+        opcode::READ_LOCAL_VERSION_INFORMATION,
+        opcode::HAL_WRITE_CONFIG_DATA,
+        opcode::GATT_INIT,
+        opcode::GAP_INIT,
+        opcode::GATT_UPDATE_CHARACTERISTIC_VALUE,
+        opcode::GATT_UPDATE_CHARACTERISTIC_VALUE,
+        opcode::GAP_SET_AUTHENTICATION_REQUIREMENT,
+        opcode::GAP_SET_IO_CAPABILITY,
+        opcode::GAP_GET_BONDED_DEVICES,
+    ];
+    let gatt = event_loop.database();
+    expected.extend((0..gatt.len()).map(|i| match gatt.attribute(i).unwrap() {
+        database::Attribute::Service(_) => opcode::GATT_ADD_SERVICE,
+        database::Attribute::Characteristic(_) => opcode::GATT_ADD_CHARACTERISTIC,
+        database::Attribute::Descriptor(_) => opcode::GATT_ADD_CHARACTERISTIC_DESCRIPTOR,
+    }));
+    expected.extend_from_slice(&[
+        opcode::HAL_SET_TX_POWER_LEVEL,
+        opcode::LE_SET_SCAN_RESPONSE_DATA,
+        opcode::GAP_CONFIGURE_WHITE_LIST,
+        opcode::GAP_SET_DISCOVERABLE,
+    ]);
+    let commands = sim.commands();
+    assert_eq!(
+        commands.iter().map(|c| c.opcode()).collect::<Vec<_>>(),
+        expected
+    );
+
+    // Version, public address, GATT and GAP.
+    assert!(commands[0].params().is_empty());
+    assert_eq!(
+        commands[1].params(),
+        [0x00, 0x06, 0x12, 0x34, 0x00, 0xE1, 0x80, 0x02]
+    );
+    assert!(commands[2].params().is_empty());
+    assert_eq!(commands[3].params(), [0x01, 0x00, 7]);
+
+    // The device name and appearance, written to the characteristics GAP Init created.
+    assert_eq!(
+        commands[4].params(),
+        [0x01, 0x00, 0x02, 0x00, 0x00, 7, b'B', b'l', b'u', b'e', b'N', b'R', b'G']
+    );
+    assert_eq!(
+        commands[5].params(),
+        [0x01, 0x00, 0x04, 0x00, 0x00, 2, 0x00, 0x00]
+    );
+
+    // MITM protection, no out-of-band data, 7 to 16 byte keys, the fixed passkey 123456, and
+    // bonding.
+    let mut authentication = [0; 26];
+    authentication[0] = 0x01;
+    authentication[18] = 7;
+    authentication[19] = 16;
+    authentication[20] = 0x00;
+    authentication[21..25].copy_from_slice(&123_456u32.to_le_bytes());
+    authentication[25] = 0x01;
+    assert_eq!(commands[6].params(), authentication);
+
+    // The device displays its passkey, and asks which devices are bonded.
+    assert_eq!(commands[7].params(), [0x00]);
+    assert!(commands[8].params().is_empty());
+
+    // The first attribute is the primary Acc service, with its 128-bit UUID.
+    let mut service = vec![0x02];
+    service.extend_from_slice(&[
+        0x02, 0x36, 0x6e, 0x80, 0xcf, 0x3a, 0x11, 0xe1, 0x9a, 0xb4, 0x00, 0x02, 0xa5, 0xd5, 0xc5,
+        0x1b,
+    ]);
+    service.push(0x01);
+    assert_eq!(commands[9].params()[..18], service[..]);
+
+    // An empty scan response, sent in a 31-byte field after its length.
+    let n = commands.len();
+    assert_eq!(commands[n - 3].params(), [0; 32]);
+    assert!(commands[n - 2].params().is_empty());
+
+    // Undirected advertising that any device may connect to, with the complete local name.
+    let discoverable = commands[n - 1].params();
+    assert_eq!(discoverable[0], 0x00);
+    assert_eq!(discoverable[6], 0x00);
+    assert_eq!(
+        discoverable[7..16],
+        [8, 0x09, b'B', b'l', b'u', b'e', b'N', b'R', b'G']
+    );
+}
+
+#[test]
+fn advertises_the_configured_data() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    sim_event_loop!(event_loop, sim, events);
+    let mut data = advertising::AdvertisingData::for_discoverable(b"BlueNRG");
+    let service = [
+        0x1b, 0xc5, 0xd5, 0xa5, 0x02, 0x00, 0xb4, 0x9a, 0xe1, 0x11, 0x3a, 0xcf, 0x80, 0x6e, 0x36,
+        0x02,
+    ];
+    let services = [service];
+    data.push(advertising::AdStructure::CompleteUuid128List(&services))
+        .unwrap();
+    data.push(advertising::AdStructure::ManufacturerSpecificData {
+        company_id: 0x0030,
+        data: &[0x01, 0x02],
+    })
+    .unwrap();
+    event_loop.set_advertising_data(data);
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+
+    // The manufacturer data does not fit next to the service UUIDs, so it is in the scan
+    // response.
+    let commands = sim.commands();
+    let n = commands.len();
+    let mut scan_response = [0; 32];
+    scan_response[..7].copy_from_slice(&[6, 5, 0xFF, 0x30, 0x00, 0x01, 0x02]);
+    assert_eq!(commands[n - 3].params(), scan_response);
+
+    let discoverable = commands[n - 1].params();
+    assert!(discoverable.windows(16).any(|window| window == service));
+}
+
+#[test]
+fn sleeps_until_data_ready() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    sim_event_loop!(event_loop, sim, events);
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+
+    // Without the data ready interrupt, the connection is not read.
+    sim.connect(CONN, PEER, 40);
+    for _ in 0..3 {
+        event_loop.step().unwrap();
+        assert!(event_loop.is_idle());
+    }
+    assert_eq!(event_loop.state(), State::Complete);
+
+    events.data_ready();
+    assert!(!event_loop.is_idle());
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [State::Connected]
+    );
+}
+
+#[test]
+fn serves_a_connection() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    let mut sensor = sensors::SimulatedEnvironmentalSensor::new();
+    let clock = time::SimulatedClock::new();
+    let mut shared_clock = &clock;
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_environmental_sensor(&mut sensor);
+    event_loop.set_clock(&mut shared_clock);
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+
+    sim.connect(CONN, PEER, 40);
+    assert_eq!(
+        run_until(&mut event_loop, &sim, &events, State::Connected),
+        [State::Connected]
+    );
+    let connection = event_loop.connection().unwrap();
+    assert_eq!(connection.conn_handle, hci::ConnectionHandle(CONN));
+    assert_eq!(connection.interval, core::time::Duration::from_millis(50));
+
+    // A read of a sensor value updates it first.
+    let temperature = event_loop
+        .database()
+        .characteristic(TEMPERATURE_CHARACTERISTIC_UUID)
+        .unwrap();
+    sim.read_permit_request(CONN, temperature.value_handle());
+    assert_eq!(
+        run_until(&mut event_loop, &sim, &events, State::Connected),
+        [
+            State::UpdatingCharacteristic,
+            State::AllowingRead,
+            State::Connected
+        ]
+    );
+    let commands = sim.commands();
+    let update = &commands[commands.len() - 2];
+    assert_eq!(
+        update.opcode(),
+        sim::opcode::GATT_UPDATE_CHARACTERISTIC_VALUE
+    );
+    assert_eq!(update.params()[4..], [0x00, 2, 0xD2, 0x00]);
+
+    // A read of a value the event loop does not supply is allowed at once.
+    let acceleration = event_loop.database().characteristic(ACC_UUID).unwrap();
+    sim.read_permit_request(CONN, acceleration.value_handle());
+    assert_eq!(
+        run_until(&mut event_loop, &sim, &events, State::AllowingRead),
+        [State::AllowingRead]
+    );
+    assert!(event_loop.is_answering_read());
+    assert_eq!(
+        run_until(&mut event_loop, &sim, &events, State::Connected),
+        [State::Connected]
+    );
+    assert!(!event_loop.is_answering_read());
+    assert_eq!(count_commands(&sim, sim::opcode::GATT_ALLOW_READ), 2);
+
+    // Notifications are sent when they become due.
+    let minute = event_loop
+        .database()
+        .characteristic(MINUTE_CHARACTERISTIC_UUID)
+        .unwrap();
+    sim.write_attribute(CONN, minute.client_config_handle().unwrap(), &[0x01, 0x00]);
+    run_until_idle(&mut event_loop, &sim, &events);
+    assert_eq!(event_loop.state(), State::Connected);
+    clock.advance(time::MS_PER_MINUTE);
+    assert_eq!(
+        run_until(&mut event_loop, &sim, &events, State::Connected),
+        [State::UpdatingCharacteristic, State::Connected]
+    );
+    let commands = sim.commands();
+    let notification = commands.last().unwrap();
+    assert_eq!(
+        notification.opcode(),
+        sim::opcode::GATT_UPDATE_CHARACTERISTIC_VALUE
+    );
+    assert_eq!(
+        notification.params()[4..],
+        [0x00, 4, 0x01, 0x00, 0x00, 0x00]
+    );
+
+    // Advertising starts again once the central disconnects.
+    sim.disconnect(CONN, 0x13);
+    assert_eq!(
+        run_until(&mut event_loop, &sim, &events, State::Complete),
+        [
+            State::Disconnected,
+            State::ConfiguringWhiteList,
+            State::SetDiscoverable,
+            State::Complete
+        ]
+    );
+    assert!(event_loop.connection().is_none());
+    assert_eq!(count_commands(&sim, sim::opcode::GAP_SET_DISCOVERABLE), 2);
+}
+
+#[test]
+fn waits_to_advertise_after_disconnecting() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    let held = Cell::new(false);
+    sim_event_loop!(event_loop, sim, events, TestTimer { held: Some(&held) });
+    event_loop.set_timeout_period(Some(1));
+    event_loop.set_advertising_restart_delay(Some(1));
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+    sim.connect(CONN, PEER, 40);
+    events.data_ready();
+    run_until_idle(&mut event_loop, &sim, &events);
+
+    // The event loop goes idle instead of waiting for the delay.
+    held.set(true);
+    sim.disconnect(CONN, 0x13);
+    events.data_ready();
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [State::Disconnected]
+    );
+    assert!(run_until_idle(&mut event_loop, &sim, &events).is_empty());
+    assert_eq!(count_commands(&sim, sim::opcode::GAP_SET_DISCOVERABLE), 1);
+
+    held.set(false);
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [
+            State::ConfiguringWhiteList,
+            State::SetDiscoverable,
+            State::Complete
+        ]
+    );
+    assert_eq!(count_commands(&sim, sim::opcode::GAP_SET_DISCOVERABLE), 2);
+}
+
+#[test]
+fn answers_security_requests() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    sim_event_loop!(event_loop, sim, events);
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+    sim.connect(CONN, PEER, 40);
+    run_until(&mut event_loop, &sim, &events, State::Connected);
+
+    sim.pass_key_request(CONN);
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [State::AnsweringSecurityRequest, State::Connected]
+    );
+    assert_eq!(count_commands(&sim, sim::opcode::GAP_PASS_KEY_RESPONSE), 1);
+
+    sim.authorization_request(CONN);
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [State::AnsweringSecurityRequest, State::Connected]
+    );
+
+    // A device that lost its bond may only pair again once the user opens the pairing window.
+    sim.bond_lost();
+    assert!(run_until_idle(&mut event_loop, &sim, &events).is_empty());
+    assert_eq!(count_commands(&sim, sim::opcode::GAP_ALLOW_REBOND), 0);
+
+    event_loop.open_pairing_window();
+    sim.bond_lost();
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [State::AnsweringSecurityRequest, State::Connected]
+    );
+    assert_eq!(count_commands(&sim, sim::opcode::GAP_ALLOW_REBOND), 1);
+
+    sim.pairing_complete(CONN, PEER, 0);
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [State::UpdatingBondedDevices, State::Connected]
+    );
+    assert_eq!(
+        event_loop.security().bonded_devices(),
+        [hci::BdAddrType::Public(hci::BdAddr(PEER))]
+    );
+}
+
+/// Pairing agent whose user never enters a passkey.
+struct NoPasskeyAgent;
+
+impl security::PairingAgent for NoPasskeyAgent {
+    fn io_capability(&self) -> bluenrg::gap::IoCapability {
+        bluenrg::gap::IoCapability::Display
+    }
+
+    fn passkey(&mut self, _conn_handle: hci::ConnectionHandle) -> Option<u32> {
+        None
+    }
+
+    fn authorize(&mut self, _conn_handle: hci::ConnectionHandle) -> bool {
+        false
+    }
+
+    fn allow_rebond(&mut self, _conn_handle: hci::ConnectionHandle) -> bool {
+        false
+    }
+
+    fn pairing_complete(&mut self, _outcome: security::PairingOutcome) {}
+}
+
+#[test]
+fn rejects_pairing_without_a_passkey() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    let mut agent = NoPasskeyAgent;
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_pin_mode(security::PinMode::Agent);
+    event_loop.set_pairing_agent(&mut agent);
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+    sim.connect(CONN, PEER, 40);
+    run_until(&mut event_loop, &sim, &events, State::Connected);
+
+    sim.pass_key_request(CONN);
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [
+            State::AnsweringSecurityRequest,
+            State::Connected,
+            State::Disconnected,
+            State::ConfiguringWhiteList,
+            State::SetDiscoverable,
+            State::Complete
+        ]
+    );
+
+    // Disconnected with an authentication failure.
+    let commands = sim.commands();
+    let disconnect = commands
+        .iter()
+        .find(|c| c.opcode() == sim::opcode::DISCONNECT)
+        .unwrap();
+    assert_eq!(disconnect.params(), [0x01, 0x08, 0x05]);
+    assert_eq!(count_commands(&sim, sim::opcode::GAP_PASS_KEY_RESPONSE), 0);
+}
+
+#[test]
+fn persists_the_bonded_devices() {
+    let mut store = storage::Store::open(flash::RamFlash::new()).unwrap();
+    {
+        let sim = sim::Simulator::new();
+        let events = queue::PacketQueue::new();
+        sim.add_bond(PEER);
+        sim_event_loop!(event_loop, sim, events);
+        event_loop.set_bond_store(&mut store);
+        run_until(&mut event_loop, &sim, &events, State::Complete);
+    }
+
+    // After a power cycle, the bonds are known before the controller is asked for them.
+    let mut store = storage::Store::open(store.flash().clone()).unwrap();
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_bond_store(&mut store);
+    assert_eq!(
+        event_loop.security().bonded_devices(),
+        [hci::BdAddrType::Public(hci::BdAddr(PEER))]
+    );
+}
+
+#[test]
+fn notifies_accelerometer_readings_and_free_falls() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    let accelerometer = accelerometer::SimulatedAccelerometer::new();
+    let mut shared_accelerometer = &accelerometer;
+    let clock = time::SimulatedClock::new();
+    let mut shared_clock = &clock;
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_accelerometer(&mut shared_accelerometer);
+    event_loop.set_clock(&mut shared_clock);
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+    sim.connect(CONN, PEER, 40);
+    run_until(&mut event_loop, &sim, &events, State::Connected);
+    let free_fall = event_loop
+        .database()
+        .characteristic(ACC_FREE_FALL_UUID)
+        .unwrap();
+    let acceleration = event_loop.database().characteristic(ACC_UUID).unwrap();
+    for c in &[free_fall, acceleration] {
+        sim.write_attribute(CONN, c.client_config_handle().unwrap(), &[0x01, 0x00]);
+    }
+    run_until_idle(&mut event_loop, &sim, &events);
+    let notified = |sim: &sim::Simulator| {
+        let commands = sim.commands();
+        let update = commands.last().unwrap();
+        assert_eq!(
+            update.opcode(),
+            sim::opcode::GATT_UPDATE_CHARACTERISTIC_VALUE
+        );
+        let params = update.params();
+        (
+            u16::from(params[2]) | u16::from(params[3]) << 8,
+            params[6..].to_vec(),
+        )
+    };
+
+    // The first reading is sent as soon as the central subscribes.
+    assert_eq!(
+        run_until(&mut event_loop, &sim, &events, State::Connected),
+        [State::UpdatingCharacteristic, State::Connected]
+    );
+    assert_eq!(notified(&sim).1, [0xCE, 0xFF, 0x1E, 0x00, 0xDE, 0x03]);
+
+    // A free fall is reported once the readings stay low for long enough.
+    accelerometer.set_falling(true);
+    clock.advance(DEFAULT_ACCELERATION_PERIOD_MS);
+    run_until(&mut event_loop, &sim, &events, State::Connected);
+    assert_eq!(notified(&sim).1, [0; 6]);
+    clock.advance(DEFAULT_ACCELERATION_PERIOD_MS);
+    run_until(&mut event_loop, &sim, &events, State::Connected);
+    assert_eq!(notified(&sim).1, [0; 6]);
+    run_until(&mut event_loop, &sim, &events, State::Connected);
+    assert_eq!(
+        notified(&sim),
+        (
+            free_fall.characteristic.0,
+            accelerometer::FREE_FALL_DETECTED.to_vec()
+        )
+    );
+}
+
+#[test]
+fn answers_reads_requested_while_a_command_is_in_flight() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    let mut sensor = sensors::SimulatedEnvironmentalSensor::new();
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_environmental_sensor(&mut sensor);
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+    sim.connect(CONN, PEER, 40);
+    run_until(&mut event_loop, &sim, &events, State::Connected);
+    sim.clear_commands();
+
+    // The second and third reads arrive while the first value is being updated.
+    for &uuid in &[
+        TEMPERATURE_CHARACTERISTIC_UUID,
+        PRESSURE_CHARACTERISTIC_UUID,
+        HUMIDITY_CHARACTERISTIC_UUID,
+    ] {
+        let characteristic = event_loop.database().characteristic(uuid).unwrap();
+        sim.read_permit_request(CONN, characteristic.value_handle());
+    }
+    events.data_ready();
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [
+            State::UpdatingCharacteristic,
+            State::AllowingRead,
+            State::UpdatingCharacteristic,
+            State::AllowingRead,
+            State::UpdatingCharacteristic,
+            State::AllowingRead,
+            State::Connected
+        ]
+    );
+    assert_eq!(count_commands(&sim, sim::opcode::GATT_ALLOW_READ), 3);
+}
+
+#[test]
+fn ignores_replies_to_commands_that_are_not_in_flight() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    sim_event_loop!(event_loop, sim, events);
+    run_until_idle(&mut event_loop, &sim, &events);
+    assert_eq!(event_loop.state(), State::Complete);
+
+    // A late reply, a flow control credit, and a failure of a command that is not in flight.
+    sim.push_event(0x0E, &[0x01, 0x83, 0xFC, 0x00]);
+    sim.push_event(0x0E, &[0x01, 0x00, 0x00]);
+    sim.command_status(sim::opcode::GAP_INIT, 0x12);
+    events.data_ready();
+    assert!(run_until_idle(&mut event_loop, &sim, &events).is_empty());
+    assert_eq!(event_loop.state(), State::Complete);
+
+    // Nor does a failure of another command fail the command in flight.
+    sim.connect(CONN, PEER, 40);
+    run_until(&mut event_loop, &sim, &events, State::Connected);
+    sim.clear_commands();
+    let temperature = event_loop
+        .database()
+        .characteristic(TEMPERATURE_CHARACTERISTIC_UUID)
+        .unwrap();
+    sim.read_permit_request(CONN, temperature.value_handle());
+    sim.command_status(sim::opcode::GAP_INIT, 0x12);
+    events.data_ready();
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [State::AllowingRead, State::Connected]
+    );
+    assert_eq!(count_commands(&sim, sim::opcode::GATT_ALLOW_READ), 1);
+}
+
+/// LED that remembers the last command applied to it.
+#[derive(Default)]
+struct TestLed {
+    last: Cell<Option<led::Command>>,
+}
+
+impl<'t> led::LedControl for &'t TestLed {
+    fn apply(&mut self, command: led::Command) {
+        self.last.set(Some(command));
+    }
+
+    fn tick(&mut self, _now_ms: u32) {}
+}
+
+#[test]
+fn applies_led_commands() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    let test_led = TestLed::default();
+    let mut shared_led = &test_led;
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_led(&mut shared_led);
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+    sim.connect(CONN, PEER, 40);
+    run_until(&mut event_loop, &sim, &events, State::Connected);
+
+    let led_value = event_loop
+        .database()
+        .characteristic(LED_CHARACTERISTIC_UUID)
+        .unwrap()
+        .value_handle();
+    let temperature_value = event_loop
+        .database()
+        .characteristic(TEMPERATURE_CHARACTERISTIC_UUID)
+        .unwrap()
+        .value_handle();
+    let mut write = |handle, data: &[u8]| {
+        sim.write_attribute(CONN, handle, data);
+        run_until_idle(&mut event_loop, &sim, &events);
+        assert_eq!(event_loop.state(), State::Connected);
+    };
+
+    write(led_value, &[0x03, 5, 10, 2]);
+    assert_eq!(
+        test_led.last.get(),
+        Some(led::Command::Blink {
+            on_ms: 50,
+            off_ms: 100,
+            count: Some(2),
+        })
+    );
+
+    // Invalid commands and writes to other attributes leave the LED alone.
+    test_led.last.set(None);
+    write(led_value, &[0x03, 0, 10, 2]);
+    write(temperature_value, &[0x01]);
+    assert_eq!(test_led.last.get(), None);
+
+    write(led_value, &[0x01]);
+    assert_eq!(test_led.last.get(), Some(led::Command::On));
+}
+
+#[test]
+fn enforces_the_security_permissions_of_characteristics() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    let test_led = TestLed::default();
+    let mut shared_led = &test_led;
+    let clock = time::SimulatedClock::new();
+    let mut shared_clock = &clock;
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_led(&mut shared_led);
+    event_loop.set_clock(&mut shared_clock);
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+    sim.connect(CONN, PEER, 40);
+    run_until(&mut event_loop, &sim, &events, State::Connected);
+    sim.clear_commands();
+
+    let led_value = event_loop
+        .database()
+        .characteristic(LED_CHARACTERISTIC_UUID)
+        .unwrap()
+        .value_handle();
+    let time_value = event_loop
+        .database()
+        .characteristic(TIME_CHARACTERISTIC_UUID)
+        .unwrap()
+        .value_handle();
+    let att_error = |attribute_handle, error| sim::AttError {
+        conn_handle: CONN,
+        attribute_handle: attribute_handle,
+        error: error,
+    };
+
+    // Before the link is encrypted, the controller answers the central without asking the host.
+    sim.write_attribute(CONN, led_value, &[0x01]);
+    sim.read_permit_request(CONN, time_value);
+    assert!(!sim.has_pending_events());
+    assert!(run_until_idle(&mut event_loop, &sim, &events).is_empty());
+    assert_eq!(
+        *sim.att_errors(),
+        [
+            att_error(led_value, sim::att_error::INSUFFICIENT_AUTHENTICATION),
+            att_error(time_value, sim::att_error::INSUFFICIENT_ENCRYPTION)
+        ]
+    );
+    assert_eq!(test_led.last.get(), None);
+    assert_eq!(count_commands(&sim, sim::opcode::GATT_ALLOW_READ), 0);
+
+    // The keys of an earlier bond encrypt the link, which is enough to read the time but not to
+    // control the LED.
+    sim.clear_att_errors();
+    sim.encrypt(CONN);
+    sim.read_permit_request(CONN, time_value);
+    sim.write_attribute(CONN, led_value, &[0x01]);
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [
+            State::UpdatingCharacteristic,
+            State::AllowingRead,
+            State::Connected
+        ]
+    );
+    assert_eq!(
+        *sim.att_errors(),
+        [att_error(
+            led_value,
+            sim::att_error::INSUFFICIENT_AUTHENTICATION
+        )]
+    );
+    assert_eq!(test_led.last.get(), None);
+    assert_eq!(count_commands(&sim, sim::opcode::GATT_ALLOW_READ), 1);
+
+    // Pairing with MITM protection authenticates the link.
+    sim.clear_att_errors();
+    sim.pairing_complete(CONN, PEER, 0);
+    run_until_idle(&mut event_loop, &sim, &events);
+    sim.write_attribute(CONN, led_value, &[0x01]);
+    run_until_idle(&mut event_loop, &sim, &events);
+    assert!(sim.att_errors().is_empty());
+    assert_eq!(test_led.last.get(), Some(led::Command::On));
+}
+
+#[test]
+fn reconnects_to_bonded_devices() {
+    let sim = sim::Simulator::new();
+    sim.add_bond(PEER);
+    let events = queue::PacketQueue::new();
+    sim_event_loop!(event_loop, sim, events);
+    run_until_idle(&mut event_loop, &sim, &events);
+    assert_eq!(
+        event_loop.advertising_mode(),
+        Some(reconnection::AdvertisingMode::WhiteList)
+    );
+
+    assert!(sim.connect(CONN, PEER, 40));
+    events.data_ready();
+    run_until_idle(&mut event_loop, &sim, &events);
+    sim.disconnect(CONN, 0x13);
+    events.data_ready();
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [
+            State::Disconnected,
+            State::ConfiguringWhiteList,
+            State::SetDiscoverable,
+            State::Complete
+        ]
+    );
+    assert_eq!(
+        event_loop.advertising_mode(),
+        Some(reconnection::AdvertisingMode::Directed(
+            hci::BdAddrType::Public(hci::BdAddr(PEER))
+        ))
+    );
+
+    sim.directed_advertising_timeout();
+    events.data_ready();
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [State::SetDiscoverable, State::Complete]
+    );
+    assert_eq!(
+        event_loop.advertising_mode(),
+        Some(reconnection::AdvertisingMode::WhiteList)
+    );
+
+    event_loop.open_pairing_window();
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [
+            State::StoppingAdvertising,
+            State::SetDiscoverable,
+            State::Complete
+        ]
+    );
+    assert_eq!(
+        event_loop.advertising_mode(),
+        Some(reconnection::AdvertisingMode::Discoverable)
+    );
+}
+
+#[test]
+fn finishes_the_command_in_flight_after_disconnecting() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    let mut sensor = sensors::SimulatedEnvironmentalSensor::new();
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_environmental_sensor(&mut sensor);
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+    assert!(sim.connect(CONN, PEER, 40));
+    events.data_ready();
+    run_until_idle(&mut event_loop, &sim, &events);
+
+    let temperature = event_loop
+        .database()
+        .characteristic(TEMPERATURE_CHARACTERISTIC_UUID)
+        .unwrap();
+    sim.read_permit_request(CONN, temperature.value_handle());
+    sim.disconnect(CONN, 0x13);
+    events.data_ready();
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [
+            State::UpdatingCharacteristic,
+            State::Disconnected,
+            State::ConfiguringWhiteList,
+            State::SetDiscoverable,
+            State::Complete
+        ]
+    );
+}
+
+#[test]
+fn generates_private_addresses() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    let mut key_store = storage::Store::open(flash::RamFlash::new()).unwrap();
+    let clock = time::SimulatedClock::new();
+    let mut shared_clock = &clock;
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_clock(&mut shared_clock);
+    event_loop.set_privacy(&mut key_store, privacy::DEFAULT_ROTATION_PERIOD_MS);
+
+    let mut expected = vec![
+        State::Resetting,
+        State::SettingAddress,
+        State::GeneratingIdentityRoot(0),
+        State::GeneratingIdentityRoot(1),
+        State::WritingIdentityRoot,
+        State::DerivingIdentityKey,
+    ];
+    expected.extend_from_slice(&initialization(event_loop.database())[2..]);
+    expected.extend_from_slice(&[
+        State::ConfiguringWhiteList,
+        State::GeneratingPrivateAddress,
+        State::HashingPrivateAddress,
+        State::SettingPrivateAddress,
+        State::SetDiscoverable,
+        State::Complete,
+    ]);
+    assert_eq!(run_until_idle(&mut event_loop, &sim, &events), expected);
+    let address = event_loop.private_address().unwrap();
+    assert_eq!(sim.random_address(), Some(address.0));
+
+    // The controller must not rotate the address behind the event loop's back.
+    {
+        let commands = sim.commands();
+        let gap_init = commands
+            .iter()
+            .find(|c| c.opcode() == command::opcode::GAP_INIT)
+            .unwrap();
+        assert_eq!(gap_init.params()[1], 0);
+    }
+
+    clock.advance(privacy::DEFAULT_ROTATION_PERIOD_MS);
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [
+            State::StoppingAdvertising,
+            State::GeneratingPrivateAddress,
+            State::HashingPrivateAddress,
+            State::SettingPrivateAddress,
+            State::SetDiscoverable,
+            State::Complete
+        ]
+    );
+    assert_ne!(event_loop.private_address(), Some(address));
+}
+
+#[test]
+fn lets_the_controller_own_the_private_address_on_7x() {
+    for &(major, controller_owned) in &[(7, true), (6, false)] {
+        let sim = sim::Simulator::with_version(sim::Version {
+            hw_version: 0x31,
+            major,
+            minor: 2,
+            patch: 0,
+        });
+        let events = queue::PacketQueue::new();
+        let mut key_store = storage::Store::open(flash::RamFlash::new()).unwrap();
+        sim_event_loop!(event_loop, sim, events);
+        event_loop.set_privacy(&mut key_store, privacy::DEFAULT_ROTATION_PERIOD_MS);
+        event_loop.set_private_address_owner(privacy::AddressOwner::Controller);
+
+        let states = run_until_idle(&mut event_loop, &sim, &events);
+        assert_eq!(states.last(), Some(&State::Complete));
+        assert_eq!(
+            states.contains(&State::GeneratingPrivateAddress),
+            !controller_owned
+        );
+        assert_eq!(
+            count_commands(&sim, command::opcode::LE_SET_RANDOM_ADDRESS),
+            if controller_owned { 0 } else { 1 }
+        );
+        assert_eq!(event_loop.private_address().is_some(), !controller_owned);
+
+        // GAP Init only takes the privacy flag on 7.x, and the controller then advertises with
+        // its own resolvable private address, falling back to the public address.
+        let commands = sim.commands();
+        let gap_init = commands
+            .iter()
+            .find(|c| c.opcode() == command::opcode::GAP_INIT)
+            .unwrap();
+        assert_eq!(
+            gap_init.params().get(1).cloned().unwrap_or(0),
+            controller_owned as u8
+        );
+        let set_discoverable = commands
+            .iter()
+            .find(|c| c.opcode() == command::opcode::GAP_SET_DISCOVERABLE)
+            .unwrap();
+        assert_eq!(
+            set_discoverable.params()[5],
+            if controller_owned { 0x02 } else { 0x01 }
+        );
+    }
+}
+
+#[test]
+fn reuses_the_stored_identity_root() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    let mut key_store = storage::Store::open(flash::RamFlash::new()).unwrap();
+    assert!(privacy::KeyStore::save_identity_root(
+        &mut key_store,
+        &[0x5A; privacy::KEY_LEN]
+    ));
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_privacy(&mut key_store, privacy::DEFAULT_ROTATION_PERIOD_MS);
+
+    let states = run_until(&mut event_loop, &sim, &events, State::InitGatt);
+    assert_eq!(
+        states,
+        [
+            State::Resetting,
+            State::SettingAddress,
+            State::WritingIdentityRoot,
+            State::DerivingIdentityKey,
+            State::InitGatt
+        ]
+    );
+    assert_eq!(count_commands(&sim, command::opcode::LE_RAND), 0);
+}
+
+#[test]
+fn discovers_in_the_observer_role() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    let clock = time::SimulatedClock::new();
+    let mut shared_clock = &clock;
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_clock(&mut shared_clock);
+    event_loop.set_role(scanner::Role::Observer);
+    let peer = hci::BdAddrType::Public(hci::BdAddr(PEER));
+    let other = [0x11, 0x12, 0x13, 0x14, 0x15, 0x16];
+
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [
+            State::Resetting,
+            State::SettingAddress,
+            State::InitGatt,
+            State::InitGap,
+            State::StartingDiscovery,
+            State::Discovering
+        ]
+    );
+    assert!(sim.is_discovering());
+
+    // A device heard more than once keeps one entry, which keeps its name.
+    assert!(sim.advertising_report(PEER, -60, &[0x05, 0x09, b'n', b'o', b'd', b'e']));
+    assert!(sim.advertising_report(other, -80, &[0x03, 0x03, 0x0F, 0x18]));
+    assert!(sim.advertising_report(PEER, -50, &[]));
+    events.data_ready();
+    assert!(run_until_idle(&mut event_loop, &sim, &events).is_empty());
+    assert_eq!(event_loop.devices().devices().len(), 2);
+    let device = event_loop.devices().get(&peer).unwrap();
+    assert_eq!(device.rssi(), Some(-50));
+    assert_eq!(device.name(), Some(&b"node"[..]));
+    assert_eq!(device.last_seen_ms(), Some(0));
+    let device = event_loop
+        .devices()
+        .get(&hci::BdAddrType::Public(hci::BdAddr(other)))
+        .unwrap();
+    assert_eq!(
+        device.service_uuids(),
+        [bluenrg::gatt::Uuid::Uuid16(0x180F)]
+    );
+
+    // Discovery starts again when the procedure ends, and the table carries over.
+    sim.discovery_complete();
+    assert!(!sim.advertising_report(PEER, -40, &[]));
+    events.data_ready();
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [State::StartingDiscovery, State::Discovering]
+    );
+    assert!(sim.is_discovering());
+
+    clock.advance(1000);
+    assert!(sim.advertising_report(PEER, -40, &[]));
+    events.data_ready();
+    assert!(run_until_idle(&mut event_loop, &sim, &events).is_empty());
+    assert_eq!(event_loop.devices().devices().len(), 2);
+    let device = event_loop.devices().get(&peer).unwrap();
+    assert_eq!(device.rssi(), Some(-40));
+    assert_eq!(device.last_seen_ms(), Some(1000));
+}
+
+#[test]
+fn discovers_with_the_private_address() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    let mut key_store = storage::Store::open(flash::RamFlash::new()).unwrap();
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_role(scanner::Role::Observer);
+    event_loop.set_privacy(&mut key_store, privacy::DEFAULT_ROTATION_PERIOD_MS);
+
+    assert_eq!(
+        run_until_idle(&mut event_loop, &sim, &events),
+        [
+            State::Resetting,
+            State::SettingAddress,
+            State::GeneratingIdentityRoot(0),
+            State::GeneratingIdentityRoot(1),
+            State::WritingIdentityRoot,
+            State::DerivingIdentityKey,
+            State::InitGatt,
+            State::InitGap,
+            State::GeneratingPrivateAddress,
+            State::HashingPrivateAddress,
+            State::SettingPrivateAddress,
+            State::StartingDiscovery,
+            State::Discovering
+        ]
+    );
+    assert_eq!(
+        sim.random_address(),
+        Some(event_loop.private_address().unwrap().0)
+    );
+
+    // The scan requests go out from the random address just set.
+    let commands = sim.commands();
+    let discovery = commands
+        .iter()
+        .find(|c| c.opcode() == command::opcode::GAP_START_GENERAL_DISCOVERY_PROCEDURE)
+        .unwrap();
+    assert_eq!(discovery.params()[4], 0x01);
+}
+
+#[test]
+fn retries_a_failed_command() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    sim.fail_next(sim::opcode::GATT_INIT, 0x0C);
+    sim_event_loop!(event_loop, sim, events);
+
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+    assert_eq!(count_commands(&sim, sim::opcode::GATT_INIT), 2);
+    assert_eq!(
+        count_commands(&sim, sim::opcode::READ_LOCAL_VERSION_INFORMATION),
+        1
+    );
+}
+
+#[test]
+fn restarts_once_retries_are_exhausted() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    for _ in 0..3 {
+        sim.fail_next(sim::opcode::HAL_SET_TX_POWER_LEVEL, 0x0C);
+    }
+    sim_event_loop!(event_loop, sim, events);
+
+    let states = run_until(&mut event_loop, &sim, &events, State::Complete);
+    let restart = states
+        .iter()
+        .position(|&s| s == State::GettingVersionInfo)
+        .unwrap();
+    assert_eq!(states[restart - 1], State::SetTxPowerLevel);
+    assert_eq!(states[restart + 1], State::Resetting);
+    assert_eq!(count_commands(&sim, sim::opcode::HAL_SET_TX_POWER_LEVEL), 4);
+}
+
+#[test]
+fn restarts_after_an_unexpected_event() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    // A late reply to a command sent before the controller was reset.
+    sim.push_event(0x0E, &[0x01, 0x83, 0xFC, 0x00]);
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_recovery_policy(|_| Recovery::Halt);
+
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+    assert_eq!(
+        count_commands(&sim, sim::opcode::READ_LOCAL_VERSION_INFORMATION),
+        2
+    );
+}
+
+#[test]
+fn restarts_again_after_initializing() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    sim.fail_next(sim::opcode::READ_LOCAL_VERSION_INFORMATION, 0x0C);
+    let mut sensor = sensors::SimulatedEnvironmentalSensor::new();
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_environmental_sensor(&mut sensor);
+    event_loop.set_max_restarts(1);
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+    sim.connect(CONN, PEER, 40);
+    run_until(&mut event_loop, &sim, &events, State::Connected);
+
+    // The restart used up before initialization completed does not count any more.
+    for _ in 0..3 {
+        sim.fail_next(sim::opcode::GATT_UPDATE_CHARACTERISTIC_VALUE, 0x0C);
+    }
+    let temperature = event_loop
+        .database()
+        .characteristic(TEMPERATURE_CHARACTERISTIC_UUID)
+        .unwrap();
+    sim.read_permit_request(CONN, temperature.value_handle());
+    let states = run_until(&mut event_loop, &sim, &events, State::Complete);
+    assert_eq!(
+        states[..2],
+        [State::UpdatingCharacteristic, State::GettingVersionInfo]
+    );
+}
+
+/// Logger that keeps the warnings and errors.
+#[derive(Default)]
+struct TestLogger {
+    messages: Vec<std::string::String>,
+}
+
+impl logger::Logger for TestLogger {
+    fn log(&mut self, level: logger::Level, args: core::fmt::Arguments) {
+        if level <= logger::Level::Warn {
+            self.messages.push(format!("[{}] {}", level, args));
+        }
+    }
+}
+
+#[test]
+fn logs_recoveries() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    sim.fail_next(sim::opcode::GATT_INIT, 0x0C);
+    for _ in 0..3 {
+        sim.fail_next(sim::opcode::HAL_SET_TX_POWER_LEVEL, 0x0C);
+    }
+    let mut logger = TestLogger::default();
+    {
+        sim_event_loop!(event_loop, sim, events);
+        event_loop.set_logger(&mut logger);
+        run_until(&mut event_loop, &sim, &events, State::Complete);
+    }
+
+    assert_eq!(logger.messages.len(), 4);
+    assert!(logger.messages[0].starts_with("[WARN] Retrying state InitGatt after error"));
+    assert!(
+        logger.messages[3].starts_with("[WARN] Restarting after error in state SetTxPowerLevel")
+    );
+}
+
+#[test]
+fn halts_once_restarts_are_exhausted() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    sim.fail_next(sim::opcode::READ_LOCAL_VERSION_INFORMATION, 0x0C);
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_max_restarts(0);
+
+    let mut result = Ok(());
+    for _ in 0..MAX_STEPS {
+        result = event_loop.step();
+        if result.is_err() {
+            break;
+        }
+        if sim.has_pending_events() {
+            events.data_ready();
+        }
+    }
+    match result {
+        Err(Error::CommandFailed(_)) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert_eq!(event_loop.state(), State::GettingVersionInfo);
+}
+
+#[test]
+fn times_out_when_the_controller_does_not_answer() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_timeout_period(Some(1));
+    event_loop.set_timeout_policy(|_| Some(2));
+    event_loop.set_max_restarts(0);
+    run_until(&mut event_loop, &sim, &events, State::Complete);
+
+    // No central connects, so the deadline passes while the event loop waits for one.
+    let mut result = Ok(());
+    for _ in 0..MAX_STEPS {
+        result = event_loop.step();
+        if result.is_err() {
+            break;
+        }
+    }
+    match result {
+        Err(Error::Timeout) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert_eq!(event_loop.state(), State::Complete);
+}
+
+/// Transport that wakes the async event loop when the test raises the data ready "interrupt",
+/// while the event loop reads straight from the simulator.
+struct SimTransport<'s> {
+    sim: &'s sim::Simulator,
+    raised: Cell<bool>,
+    waker: RefCell<Option<Waker>>,
+}
+
+impl<'s> SimTransport<'s> {
+    fn new(sim: &'s sim::Simulator) -> SimTransport<'s> {
+        SimTransport {
+            sim,
+            raised: Cell::new(false),
+            waker: RefCell::new(None),
+        }
+    }
+
+    /// Raises the interrupt if the simulator has events, and returns true if that woke the
+    /// event loop.
+    fn interrupt(&self) -> bool {
+        if !self.sim.has_pending_events() {
+            return false;
+        }
+        self.raised.set(true);
+        let waker = self.waker.borrow_mut().take();
+        match waker {
+            Some(waker) => {
+                waker.wake();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<'s> async_loop::Transport for SimTransport<'s> {
+    fn is_ready(&self) -> bool {
+        self.raised.get()
+    }
+
+    fn register_waker(&self, waker: &Waker) {
+        // The event loop has read everything the last interrupt signalled.
+        self.raised.set(false);
+        *self.waker.borrow_mut() = Some(waker.clone());
+    }
+
+    fn clear_waker(&self) {
+        *self.waker.borrow_mut() = None;
+    }
+}
+
+#[test]
+fn async_event_loop_awaits_replies_from_the_transport() {
+    let sim = sim::Simulator::new();
+    let transport = SimTransport::new(&sim);
+    sim_event_loop!(event_loop, sim);
+    let mut event_loop = event_loop.into_async();
+    event_loop.set_transport(&transport);
+    static EXECUTOR: executor::Executor = executor::Executor::new();
+
+    // Each wait for a reply ends when the interrupt wakes the event loop.
+    let wakes = Cell::new(0);
+    let interrupt = || {
+        if transport.interrupt() {
+            wakes.set(wakes.get() + 1);
+        }
+    };
+    EXECUTOR
+        .block_on_with(event_loop.reach(State::Complete), interrupt)
+        .unwrap();
+    assert_eq!(count_commands(&sim, sim::opcode::GAP_SET_DISCOVERABLE), 1);
+    assert!(wakes.get() > 0);
+    let database = event_loop.event_loop().database();
+    assert!((0..database.len()).all(|index| database.handle(index).is_some()));
+
+    // The connected central device is served with the reactions of the event loop.
+    sim.connect(CONN, PEER, 40);
+    EXECUTOR
+        .block_on_with(event_loop.reach(State::Connected), || {
+            transport.interrupt();
+        })
+        .unwrap();
+    let temperature = event_loop
+        .event_loop()
+        .database()
+        .characteristic(TEMPERATURE_CHARACTERISTIC_UUID)
+        .unwrap();
+    sim.read_permit_request(CONN, temperature.value_handle());
+    EXECUTOR
+        .block_on_with(event_loop.reach(State::AllowingRead), || {
+            transport.interrupt();
+        })
+        .unwrap();
+    EXECUTOR
+        .block_on_with(event_loop.reach(State::Connected), || {
+            transport.interrupt();
+        })
+        .unwrap();
+    assert_eq!(count_commands(&sim, sim::opcode::GATT_ALLOW_READ), 1);
+}
+
+#[test]
+fn async_event_loop_handles_events_while_awaiting_a_reply() {
+    let sim = sim::Simulator::new();
+    let transport = SimTransport::new(&sim);
+    sim_event_loop!(event_loop, sim);
+    let mut event_loop = event_loop.into_async();
+    event_loop.set_transport(&transport);
+    static EXECUTOR: executor::Executor = executor::Executor::new();
+
+    // A late reply to another command arrives before the version does, and does not answer the
+    // command in flight.
+    sim.push_event(0x0E, &[0x01, 0x83, 0xFC, 0x00]);
+    EXECUTOR
+        .block_on_with(event_loop.reach(State::Complete), || {
+            transport.interrupt();
+        })
+        .unwrap();
+    assert_eq!(
+        count_commands(&sim, sim::opcode::READ_LOCAL_VERSION_INFORMATION),
+        1
+    );
+    assert_eq!(count_commands(&sim, sim::opcode::GAP_INIT), 1);
+}
+
+#[test]
+fn async_event_loop_sleeps_until_data_ready() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    sim_event_loop!(event_loop, sim, events);
+    let mut event_loop = event_loop.into_async();
+    static EXECUTOR: executor::Executor = executor::Executor::new();
+
+    // The executor only sleeps while the event loop waits for the controller.
+    let sleeps = Cell::new(0);
+    let interrupt = || {
+        sleeps.set(sleeps.get() + 1);
+        if sim.has_pending_events() {
+            events.data_ready();
+        }
+    };
+    EXECUTOR
+        .block_on_with(event_loop.reach(State::Complete), interrupt)
+        .unwrap();
+    assert_eq!(count_commands(&sim, sim::opcode::GAP_SET_DISCOVERABLE), 1);
+    assert!(sleeps.get() > 0);
+
+    // Nothing wakes the event loop until the central device connects.
+    sleeps.set(0);
+    let connect = || {
+        sleeps.set(sleeps.get() + 1);
+        if sleeps.get() == 3 {
+            sim.connect(CONN, PEER, 40);
+            events.data_ready();
+        }
+    };
+    EXECUTOR
+        .block_on_with(event_loop.reach(State::Connected), connect)
+        .unwrap();
+    assert_eq!(sleeps.get(), 3);
+    assert!(event_loop.event_loop().connection().is_some());
+}
+
+#[test]
+fn async_event_loop_applies_the_recovery_policy() {
+    let sim = sim::Simulator::new();
+    sim.fail_next(sim::opcode::GATT_INIT, 0x0C);
+    let events = queue::PacketQueue::new();
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_recovery_policy(|_| Recovery::Halt);
+    let mut event_loop = event_loop.into_async();
+    static EXECUTOR: executor::Executor = executor::Executor::new();
+
+    let interrupt = || {
+        if sim.has_pending_events() {
+            events.data_ready();
+        }
+    };
+    match EXECUTOR.block_on_with(event_loop.run(), interrupt) {
+        Error::CommandFailed(_) => (),
+        e => panic!("unexpected error {:?}", e),
+    }
+    assert_eq!(event_loop.state(), State::InitGatt);
+    assert_eq!(count_commands(&sim, sim::opcode::GATT_INIT), 1);
+}
+
+#[test]
+fn async_event_loop_applies_the_timeout_policy() {
+    let sim = sim::Simulator::new();
+    let events = queue::PacketQueue::new();
+    sim_event_loop!(event_loop, sim, events);
+    event_loop.set_timeout_period(Some(1));
+    event_loop.set_timeout_policy(|_| Some(2));
+    event_loop.set_max_restarts(0);
+    let mut event_loop = event_loop.into_async();
+    static EXECUTOR: executor::Executor = executor::Executor::new();
+
+    // Only the periodic interrupt wakes the event loop, so the controller never answers.
+    let wakes = Cell::new(0);
+    let periodic_interrupt = || {
+        wakes.set(wakes.get() + 1);
+        events.wake();
+    };
+    match EXECUTOR.block_on_with(event_loop.run(), periodic_interrupt) {
+        Error::Timeout => (),
+        e => panic!("unexpected error {:?}", e),
+    }
+    assert_eq!(event_loop.state(), State::GettingVersionInfo);
+    assert_eq!(wakes.get(), 1);
+}
@@ -0,0 +1,113 @@
+//! Monotonic time sources for the Time service and other periodic work.
+
+use core::cell::{Cell, RefCell};
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::SYST;
+
+/// Number of milliseconds in a minute.
+pub const MS_PER_MINUTE: u32 = 60_000;
+
+/// A monotonic clock.
+pub trait Clock {
+    /// Returns the number of milliseconds since the clock started. The value wraps around after
+    /// about 49 days.
+    fn now_ms(&mut self) -> u32;
+}
+
+/// A clock shared by several users, such as the event loop and an HCI [trace](crate::trace).
+impl<'c, C> Clock for &'c RefCell<C>
+where
+    C: Clock + ?Sized,
+{
+    fn now_ms(&mut self) -> u32 {
+        self.borrow_mut().now_ms()
+    }
+}
+
+/// Encodes the Time characteristic: the uptime in seconds, as a little-endian `u32`.
+pub fn encode_uptime(now_ms: u32) -> [u8; 4] {
+    encode_u32(now_ms / 1000)
+}
+
+/// Encodes the Minute characteristic: the uptime in whole minutes, as a little-endian `u32`.
+pub fn encode_minutes(now_ms: u32) -> [u8; 4] {
+    encode_u32(now_ms / MS_PER_MINUTE)
+}
+
+fn encode_u32(value: u32) -> [u8; 4] {
+    [
+        value as u8,
+        (value >> 8) as u8,
+        (value >> 16) as u8,
+        (value >> 24) as u8,
+    ]
+}
+
+/// Clock that counts SysTick ticks without using the SysTick interrupt.
+///
+/// SysTick is run freely from its full 24-bit reload value, and the ticks that elapsed since the
+/// previous call are accumulated whenever the time is read. The clock must therefore be read at
+/// least once per SysTick period (about 16 seconds with a 1 MHz SysTick clock) to stay accurate.
+pub struct SysTickClock {
+    _syst: SYST,
+    ticks_per_ms: u32,
+    last: u32,
+    ticks: u64,
+}
+
+impl SysTickClock {
+    /// Starts SysTick from the external (core clock / 8) source. `ticks_per_ms` is the number of
+    /// SysTick ticks in one millisecond.
+    pub fn new(mut syst: SYST, ticks_per_ms: u32) -> SysTickClock {
+        const RELOAD: u32 = 0x00FF_FFFF;
+
+        syst.set_clock_source(SystClkSource::External);
+        syst.set_reload(RELOAD);
+        syst.clear_current();
+        syst.enable_counter();
+
+        SysTickClock {
+            _syst: syst,
+            ticks_per_ms: ticks_per_ms,
+            last: SYST::get_current(),
+            ticks: 0,
+        }
+    }
+}
+
+impl Clock for SysTickClock {
+    fn now_ms(&mut self) -> u32 {
+        // SysTick counts down.
+        let current = SYST::get_current();
+        let elapsed = self.last.wrapping_sub(current) & 0x00FF_FFFF;
+        self.last = current;
+        self.ticks += u64::from(elapsed);
+
+        (self.ticks / u64::from(self.ticks_per_ms)) as u32
+    }
+}
+
+/// Clock that only moves when told to, for tests on the host.
+pub struct SimulatedClock {
+    now_ms: Cell<u32>,
+}
+
+impl SimulatedClock {
+    /// Creates a clock that reads 0.
+    pub fn new() -> SimulatedClock {
+        SimulatedClock {
+            now_ms: Cell::new(0),
+        }
+    }
+
+    /// Moves the clock forward by `ms` milliseconds.
+    pub fn advance(&self, ms: u32) {
+        self.now_ms.set(self.now_ms.get().wrapping_add(ms));
+    }
+}
+
+impl<'c> Clock for &'c SimulatedClock {
+    fn now_ms(&mut self) -> u32 {
+        self.now_ms.get()
+    }
+}
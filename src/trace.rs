@@ -0,0 +1,546 @@
+//! Capture of HCI traffic in btsnoop format.
+//!
+//! A [`Tracer`] wraps the SPI bus and chip select pin given to [`bluenrg::BlueNRG`], follows the
+//! BlueNRG SPI framing, and records every HCI command written and every event read, with a
+//! timestamp, in a ring buffer. When the buffer is full, the oldest packets are dropped.
+//!
+//! [`Tracer::drain`] turns the buffered packets into btsnoop records with the H4 data link type,
+//! which Wireshark can open once they follow [`btsnoop_header`]. On the target, with the
+//! `semihosting` feature, `write_btsnoop` drains them to a file on the host through semihosting.
+//! On the host, with the `std` feature,
+//! [`write_btsnoop_file`] does the same for traces of the [simulator](crate::sim).
+
+use crate::time::Clock;
+use core::cell::RefCell;
+
+/// Size of the ring buffer, in bytes. Each packet takes 7 bytes in addition to its contents.
+pub const TRACE_BUFFER_LEN: usize = 4096;
+
+/// Maximum length of a traced packet, including the H4 packet type.
+pub const MAX_PACKET_LEN: usize = 4 + 255;
+
+/// Length of the btsnoop file header.
+pub const BTSNOOP_HEADER_LEN: usize = 16;
+
+/// Length of the header of each btsnoop record.
+pub const BTSNOOP_RECORD_HEADER_LEN: usize = 24;
+
+const BTSNOOP_VERSION: u32 = 1;
+const BTSNOOP_DATALINK_H4: u32 = 1002;
+const BTSNOOP_FLAG_RECEIVED: u32 = 0x01;
+const BTSNOOP_FLAG_COMMAND_OR_EVENT: u32 = 0x02;
+
+// Microseconds between midnight, January 1st, 0 AD, and the Unix epoch. Timestamps are uptimes,
+// so captures start on January 1st, 1970.
+const BTSNOOP_EPOCH_OFFSET_US: u64 = 0x00DC_DDB3_0F2F_8000;
+
+const ACCESS_WRITE: u8 = 0x0A;
+const ACCESS_READ: u8 = 0x0B;
+
+const H4_COMMAND: u8 = 0x01;
+const H4_EVENT: u8 = 0x04;
+
+const RECORD_OVERHEAD: usize = 7;
+
+/// Returns the header of a btsnoop file of H4 packets.
+pub fn btsnoop_header() -> [u8; BTSNOOP_HEADER_LEN] {
+    let mut header = [0; BTSNOOP_HEADER_LEN];
+    header[..8].copy_from_slice(b"btsnoop\0");
+    put_u32_be(&mut header[8..], BTSNOOP_VERSION);
+    put_u32_be(&mut header[12..], BTSNOOP_DATALINK_H4);
+
+    header
+}
+
+/// Direction of a traced packet.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    /// A command sent to the controller.
+    Sent,
+
+    /// An event received from the controller.
+    Received,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Transaction {
+    Idle,
+    Header,
+    Writing,
+    Reading,
+    Other,
+}
+
+/// Reassembles H4 packets from the bytes of SPI transactions.
+struct Assembler {
+    packet_type: u8,
+    packet: [u8; MAX_PACKET_LEN],
+    len: usize,
+}
+
+impl Assembler {
+    fn new(packet_type: u8) -> Assembler {
+        Assembler {
+            packet_type: packet_type,
+            packet: [0; MAX_PACKET_LEN],
+            len: 0,
+        }
+    }
+
+    /// Adds a byte, and returns true if it completed a packet.
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == 0 && byte != self.packet_type {
+            // Not the start of a packet; skip it until the stream is back in sync.
+            return false;
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+
+        let header_len = if self.packet_type == H4_COMMAND { 4 } else { 3 };
+        self.len >= header_len && self.len == header_len + usize::from(self.packet[header_len - 1])
+    }
+
+    fn take(&mut self) -> &[u8] {
+        let len = self.len;
+        self.len = 0;
+        &self.packet[..len]
+    }
+}
+
+struct State {
+    transaction: Transaction,
+    commands: Assembler,
+    events: Assembler,
+
+    buffer: [u8; TRACE_BUFFER_LEN],
+    head: usize,
+    used: usize,
+    dropped: u32,
+}
+
+impl State {
+    fn byte_at(&self, offset: usize) -> u8 {
+        self.buffer[(self.head + offset) % TRACE_BUFFER_LEN]
+    }
+
+    fn record_len(&self) -> usize {
+        usize::from(self.byte_at(0)) | (usize::from(self.byte_at(1)) << 8)
+    }
+
+    fn discard_oldest(&mut self) {
+        let size = RECORD_OVERHEAD + self.record_len();
+        self.head = (self.head + size) % TRACE_BUFFER_LEN;
+        self.used -= size;
+    }
+
+    fn record(&mut self, direction: Direction, timestamp_ms: u32) {
+        let packet = match direction {
+            Direction::Sent => self.commands.take(),
+            Direction::Received => self.events.take(),
+        };
+        let mut data = [0; MAX_PACKET_LEN];
+        let len = packet.len();
+        data[..len].copy_from_slice(packet);
+
+        let size = RECORD_OVERHEAD + len;
+        while TRACE_BUFFER_LEN - self.used < size {
+            self.discard_oldest();
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+
+        let mut header = [0; RECORD_OVERHEAD];
+        header[0] = len as u8;
+        header[1] = (len >> 8) as u8;
+        header[2] = match direction {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        };
+        header[3..7].copy_from_slice(&[
+            timestamp_ms as u8,
+            (timestamp_ms >> 8) as u8,
+            (timestamp_ms >> 16) as u8,
+            (timestamp_ms >> 24) as u8,
+        ]);
+        for &byte in header.iter().chain(data[..len].iter()) {
+            let tail = (self.head + self.used) % TRACE_BUFFER_LEN;
+            self.buffer[tail] = byte;
+            self.used += 1;
+        }
+    }
+}
+
+/// Records the HCI packets exchanged over a BlueNRG SPI bus.
+pub struct Tracer<'c> {
+    state: RefCell<State>,
+    clock: Option<&'c RefCell<dyn Clock>>,
+}
+
+impl<'c> Tracer<'c> {
+    /// Creates an empty trace. Packets are timestamped with `clock`, or with 0 if there is no
+    /// clock.
+    pub fn new(clock: Option<&'c RefCell<dyn Clock>>) -> Tracer<'c> {
+        Tracer {
+            state: RefCell::new(State {
+                transaction: Transaction::Idle,
+                commands: Assembler::new(H4_COMMAND),
+                events: Assembler::new(H4_EVENT),
+                buffer: [0; TRACE_BUFFER_LEN],
+                head: 0,
+                used: 0,
+                dropped: 0,
+            }),
+            clock: clock,
+        }
+    }
+
+    /// Wraps the SPI bus connected to the BlueNRG.
+    pub fn spi<'t, SPI>(&'t self, spi: SPI) -> TracedSpi<'t, 'c, SPI> {
+        TracedSpi {
+            tracer: self,
+            spi: spi,
+        }
+    }
+
+    /// Wraps the chip select pin of the BlueNRG.
+    pub fn chip_select<'t, CS>(&'t self, pin: CS) -> TracedChipSelect<'t, 'c, CS> {
+        TracedChipSelect {
+            tracer: self,
+            pin: pin,
+        }
+    }
+
+    /// Returns the number of packets dropped because the buffer was full.
+    pub fn dropped(&self) -> u32 {
+        self.state.borrow().dropped
+    }
+
+    /// Returns true if no packets are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.state.borrow().used == 0
+    }
+
+    /// Removes every buffered packet, and passes each one to `out` as a btsnoop record, in the
+    /// order they were recorded. Returns the number of records.
+    pub fn drain<F>(&self, mut out: F) -> usize
+    where
+        F: FnMut(&[u8]),
+    {
+        let mut count = 0;
+        loop {
+            let mut record = [0; BTSNOOP_RECORD_HEADER_LEN + MAX_PACKET_LEN];
+            let len = {
+                let mut state = self.state.borrow_mut();
+                if state.used == 0 {
+                    return count;
+                }
+
+                let len = state.record_len();
+                let direction = state.byte_at(2);
+                let timestamp_ms =
+                    (0..4).fold(0, |t, i| t | (u32::from(state.byte_at(3 + i)) << (8 * i)));
+                for i in 0..len {
+                    record[BTSNOOP_RECORD_HEADER_LEN + i] = state.byte_at(RECORD_OVERHEAD + i);
+                }
+
+                let mut flags = BTSNOOP_FLAG_COMMAND_OR_EVENT;
+                if direction != 0 {
+                    flags |= BTSNOOP_FLAG_RECEIVED;
+                }
+                let timestamp = BTSNOOP_EPOCH_OFFSET_US + 1000 * u64::from(timestamp_ms);
+                put_u32_be(&mut record[0..], len as u32);
+                put_u32_be(&mut record[4..], len as u32);
+                put_u32_be(&mut record[8..], flags);
+                put_u32_be(&mut record[12..], state.dropped);
+                put_u32_be(&mut record[16..], (timestamp >> 32) as u32);
+                put_u32_be(&mut record[20..], timestamp as u32);
+
+                state.discard_oldest();
+                len
+            };
+
+            out(&record[..BTSNOOP_RECORD_HEADER_LEN + len]);
+            count += 1;
+        }
+    }
+
+    fn now_ms(&self) -> u32 {
+        match self.clock {
+            Some(clock) => clock.borrow_mut().now_ms(),
+            None => 0,
+        }
+    }
+
+    fn select(&self) {
+        self.state.borrow_mut().transaction = Transaction::Header;
+    }
+
+    fn deselect(&self) {
+        self.state.borrow_mut().transaction = Transaction::Idle;
+    }
+
+    /// Follows the bytes written to the controller.
+    fn sent(&self, bytes: &[u8]) {
+        let mut state = self.state.borrow_mut();
+        if state.transaction == Transaction::Header {
+            state.transaction = match bytes.first() {
+                Some(&ACCESS_WRITE) => Transaction::Writing,
+                Some(&ACCESS_READ) => Transaction::Reading,
+                _ => Transaction::Other,
+            };
+            return;
+        }
+        if state.transaction != Transaction::Writing {
+            return;
+        }
+
+        for &byte in bytes {
+            if state.commands.push(byte) {
+                let now = self.now_ms();
+                state.record(Direction::Sent, now);
+            }
+        }
+    }
+
+    /// Follows the bytes read from the controller.
+    fn received(&self, bytes: &[u8]) {
+        let mut state = self.state.borrow_mut();
+        if state.transaction != Transaction::Reading {
+            return;
+        }
+
+        for &byte in bytes {
+            if state.events.push(byte) {
+                let now = self.now_ms();
+                state.record(Direction::Received, now);
+            }
+        }
+    }
+}
+
+/// SPI bus whose traffic is recorded by a [`Tracer`].
+pub struct TracedSpi<'t, 'c: 't, SPI> {
+    tracer: &'t Tracer<'c>,
+    spi: SPI,
+}
+
+impl<'t, 'c, SPI, E> embedded_hal::blocking::spi::Transfer<u8> for TracedSpi<'t, 'c, SPI>
+where
+    SPI: embedded_hal::blocking::spi::Transfer<u8, Error = E>,
+{
+    type Error = E;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], E> {
+        // The header of a transaction is sent with a transfer, so the bytes sent must be
+        // followed as well as the bytes received.
+        let reading = self.tracer.state.borrow().transaction == Transaction::Reading;
+        if !reading {
+            self.tracer.sent(words);
+        }
+        let received = self.spi.transfer(words)?;
+        if reading {
+            self.tracer.received(received);
+        }
+
+        Ok(received)
+    }
+}
+
+impl<'t, 'c, SPI, E> embedded_hal::blocking::spi::Write<u8> for TracedSpi<'t, 'c, SPI>
+where
+    SPI: embedded_hal::blocking::spi::Write<u8, Error = E>,
+{
+    type Error = E;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), E> {
+        self.tracer.sent(words);
+        self.spi.write(words)
+    }
+}
+
+/// Chip select pin that marks the boundaries of the transactions a [`Tracer`] follows.
+pub struct TracedChipSelect<'t, 'c: 't, CS> {
+    tracer: &'t Tracer<'c>,
+    pin: CS,
+}
+
+impl<'t, 'c, CS> embedded_hal::digital::OutputPin for TracedChipSelect<'t, 'c, CS>
+where
+    CS: embedded_hal::digital::OutputPin,
+{
+    fn set_low(&mut self) {
+        self.tracer.select();
+        self.pin.set_low();
+    }
+
+    fn set_high(&mut self) {
+        self.pin.set_high();
+        self.tracer.deselect();
+    }
+}
+
+/// Writes every packet buffered in `tracer` to the file `name` on the host, through semihosting,
+/// in btsnoop format.
+///
+/// `name` must be terminated by a nul character.
+#[cfg(feature = "semihosting")]
+pub fn write_btsnoop(tracer: &Tracer, name: &str) -> Result<(), ()> {
+    use cortex_m_semihosting::{nr, syscall};
+
+    let fd = unsafe {
+        syscall!(
+            OPEN,
+            name.as_ptr(),
+            nr::open::W_TRUNC_BINARY,
+            name.len() - 1
+        )
+    };
+    if fd as isize == -1 {
+        return Err(());
+    }
+
+    // WRITE returns the number of bytes that were not written.
+    let write = |bytes: &[u8]| match unsafe { syscall!(WRITE, fd, bytes.as_ptr(), bytes.len()) } {
+        0 => Ok(()),
+        _ => Err(()),
+    };
+    let mut result = write(&btsnoop_header());
+    tracer.drain(|record| {
+        if result.is_ok() {
+            result = write(record);
+        }
+    });
+
+    unsafe {
+        syscall!(CLOSE, fd);
+    }
+
+    result
+}
+
+/// Writes every packet buffered in `tracer` to the file at `path`, in btsnoop format.
+#[cfg(any(test, feature = "std"))]
+pub fn write_btsnoop_file<P>(tracer: &Tracer, path: P) -> std::io::Result<()>
+where
+    P: AsRef<std::path::Path>,
+{
+    use std::io::Write;
+
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    file.write_all(&btsnoop_header())?;
+    let mut result = Ok(());
+    tracer.drain(|record| {
+        if result.is_ok() {
+            result = file.write_all(record);
+        }
+    });
+    result?;
+
+    file.flush()
+}
+
+fn put_u32_be(buffer: &mut [u8], value: u32) {
+    buffer[..4].copy_from_slice(&[
+        (value >> 24) as u8,
+        (value >> 16) as u8,
+        (value >> 8) as u8,
+        value as u8,
+    ]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config, sim, EventLoop, State};
+    use std::vec::Vec;
+
+    /// Runs the start of initialization against the simulator, with its SPI traffic traced.
+    fn trace_initialization(tracer: &Tracer) {
+        let sim = sim::Simulator::new();
+        let mut rx_buffer = [0; 128];
+        let mut bnrg = bluenrg::BlueNRG::new(
+            &mut rx_buffer,
+            tracer.chip_select(sim.chip_select()),
+            sim.data_ready(),
+            sim.reset_pin(),
+        );
+        let mut event_loop = EventLoop::new(
+            &mut bnrg,
+            sim::Timer,
+            0,
+            tracer.spi(sim.spi()),
+            config::DeviceConfig::default(),
+        );
+        while event_loop.state() != State::InitGatt {
+            event_loop.step().expect("event loop halted");
+        }
+    }
+
+    fn read_u32_be(bytes: &[u8]) -> u32 {
+        bytes[..4]
+            .iter()
+            .fold(0, |value, &byte| (value << 8) | u32::from(byte))
+    }
+
+    #[test]
+    fn records_commands_and_events() {
+        let tracer = Tracer::new(None);
+        trace_initialization(&tracer);
+        assert_eq!(tracer.dropped(), 0);
+
+        let mut records = Vec::new();
+        let count = tracer.drain(|record| records.push(record.to_vec()));
+        assert_eq!(count, records.len());
+        assert!(tracer.is_empty());
+
+        // Read Local Version Information, then its Command Complete event.
+        let command = &records[0];
+        assert_eq!(read_u32_be(&command[0..]), 4);
+        assert_eq!(read_u32_be(&command[4..]), 4);
+        assert_eq!(read_u32_be(&command[8..]), BTSNOOP_FLAG_COMMAND_OR_EVENT);
+        assert_eq!(read_u32_be(&command[12..]), 0);
+        let timestamp =
+            (u64::from(read_u32_be(&command[16..])) << 32) | u64::from(read_u32_be(&command[20..]));
+        assert_eq!(timestamp, BTSNOOP_EPOCH_OFFSET_US);
+        assert_eq!(
+            command[BTSNOOP_RECORD_HEADER_LEN..],
+            [H4_COMMAND, 0x01, 0x10, 0x00]
+        );
+
+        let event = &records[1];
+        let packet = &event[BTSNOOP_RECORD_HEADER_LEN..];
+        assert_eq!(read_u32_be(&event[0..]) as usize, packet.len());
+        assert_eq!(
+            read_u32_be(&event[8..]),
+            BTSNOOP_FLAG_COMMAND_OR_EVENT | BTSNOOP_FLAG_RECEIVED
+        );
+        assert_eq!(packet[..2], [H4_EVENT, 0x0E]);
+        assert_eq!(usize::from(packet[2]), packet.len() - 3);
+        assert_eq!(packet[4..6], [0x01, 0x10]);
+    }
+
+    #[test]
+    fn writes_a_btsnoop_file() {
+        let tracer = Tracer::new(None);
+        trace_initialization(&tracer);
+        let path = std::env::temp_dir().join(format!("trace-{}.btsnoop", std::process::id()));
+        write_btsnoop_file(&tracer, &path).unwrap();
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(tracer.is_empty());
+
+        assert_eq!(contents[..BTSNOOP_HEADER_LEN], btsnoop_header());
+        assert_eq!(&contents[..8], b"btsnoop\0");
+        assert_eq!(read_u32_be(&contents[8..]), 1);
+        assert_eq!(read_u32_be(&contents[12..]), 1002);
+
+        // The records follow each other up to the end of the file.
+        let mut offset = BTSNOOP_HEADER_LEN;
+        let mut count = 0;
+        while offset < contents.len() {
+            offset += BTSNOOP_RECORD_HEADER_LEN + read_u32_be(&contents[offset..]) as usize;
+            count += 1;
+        }
+        assert_eq!(offset, contents.len());
+        assert!(count >= 4);
+    }
+}